@@ -0,0 +1,151 @@
+//! `#[derive(FromTokens)]`, for parsing custom attributes (or any other
+//! tokens) with a grammar, into a struct with a field for every field of
+//! the grammar (see `grammer::proc_macro::FieldsMatch`), e.g.:
+//!
+//! ```ignore
+//! use grammer::proc_macro::{builtin, Context, FromTokens};
+//! use grammer::rule::{call, eat, SepKind};
+//!
+//! fn my_attr(cx: &Context) -> grammer::Grammar {
+//!     let mut g = builtin(cx);
+//!     let arg = (eat("size") + eat("=") + call("LITERAL").field("size"))
+//!         | (eat("name") + eat("=") + call("LITERAL").field("name"));
+//!     g.define(
+//!         cx.intern("MyAttr"),
+//!         arg.repeat_many_sep(eat(","), SepKind::Trailing).finish(cx),
+//!     );
+//!     g
+//! }
+//!
+//! #[derive(FromTokens)]
+//! #[grammer(grammar = my_attr)]
+//! struct MyAttr {
+//!     size: u32,
+//!     name: Option<String>,
+//! }
+//! ```
+//!
+//! Then `MyAttr::from_tokens` parses e.g. the `size = 4, name = "x"` in
+//! `#[my_attr(size = 4, name = "x")]`. Struct fields of type `Option<T>`
+//! are optional, those of type `Vec<T>` collect every match of the field,
+//! and all others have to be matched exactly once. Their types all have to
+//! implement `FromTokens` (which the derived structs do, too).
+//!
+//! On the struct, `#[grammer(rule = "...")]` picks the rule to match (which
+//! is named after the struct by default), and on its fields,
+//! `#[grammer(field = "...")]` renames the grammar field they come from.
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitStr, Path, Type};
+
+#[proc_macro_derive(FromTokens, attributes(grammer))]
+pub fn derive_from_tokens(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    from_tokens(&input)
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+/// How a struct field is parsed, based on (the syntax of) its type.
+enum Arity {
+    One,
+    Opt,
+    Many,
+}
+
+impl Arity {
+    fn of(ty: &Type) -> Self {
+        let last = match ty {
+            Type::Path(ty) => ty.path.segments.last(),
+            _ => None,
+        };
+        match last {
+            Some(segment) if segment.ident == "Option" => Arity::Opt,
+            Some(segment) if segment.ident == "Vec" => Arity::Many,
+            _ => Arity::One,
+        }
+    }
+}
+
+fn from_tokens(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let mut grammar: Option<Path> = None;
+    let mut rule = input.ident.to_string();
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("grammer")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("grammar") {
+                grammar = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("rule") {
+                rule = meta.value()?.parse::<LitStr>()?.value();
+            } else {
+                return Err(meta.error("expected `grammar` or `rule`"));
+            }
+            Ok(())
+        })?;
+    }
+    let grammar = grammar.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            "missing `#[grammer(grammar = ...)]`, with the path of a \
+             `fn(&grammer::proc_macro::Context) -> grammer::Grammar`",
+        )
+    })?;
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "only structs with named fields are supported",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "only structs are supported",
+            ))
+        }
+    };
+
+    let mut inits = vec![];
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+        let mut name = ident.to_string();
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("grammer")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("field") {
+                    name = meta.value()?.parse::<LitStr>()?.value();
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `field`"))
+                }
+            })?;
+        }
+        let method = match Arity::of(&field.ty) {
+            Arity::One => quote!(field),
+            Arity::Opt => quote!(opt_field),
+            Arity::Many => quote!(fields),
+        };
+        inits.push(quote!(#ident: fields.#method(#name)?));
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::grammer::proc_macro::FromTokens for #ident #ty_generics
+            #where_clause
+        {
+            fn from_tokens(
+                tokens: ::grammer::proc_macro::TokenStream,
+            ) -> ::core::result::Result<Self, ::grammer::proc_macro::Error> {
+                let cx = ::grammer::proc_macro::Context::new();
+                let grammar = #grammar(&cx);
+                let fields =
+                    ::grammer::proc_macro::FieldsMatch::parse(&cx, &grammar, #rule, tokens)?;
+                ::core::result::Result::Ok(#ident { #(#inits),* })
+            }
+        }
+    })
+}
@@ -0,0 +1,169 @@
+#ifndef GRAMMER_H
+#define GRAMMER_H
+
+/* Generated by cbindgen (see cbindgen.toml), do not edit by hand. */
+
+#include <stddef.h>
+
+// A named field (e.g. a pest `#tag`) matched in a node (or in another
+// field), only valid as long as the tree is.
+typedef struct GrammerField GrammerField;
+
+// A grammar, along with the `Context` its rules were interned in.
+typedef struct GrammerGrammar GrammerGrammar;
+
+// A node of a `GrammerTree`, only valid as long as the tree is.
+typedef struct GrammerNode GrammerNode;
+
+// The result of a successful parse (see `grammer_parse`), which owns all
+// of its nodes.
+typedef struct GrammerTree GrammerTree;
+
+// Create a grammar from the source of a pest grammar (see `pest::import`),
+// returning `NULL` on errors (including `Grammar::validate` errors).
+//
+// # Safety
+//
+// `src` must point to `src_len` bytes, and `error` must be `NULL`
+// or valid for writes.
+GrammerGrammar *grammer_grammar_from_pest(const char *src, size_t src_len, char **error);
+
+// All the diagnostics for a grammar (see `Grammar::validate`), including
+// warnings, as a JSON array (see `diagnostics::to_json`), or `NULL` on
+// errors (in which case `*error` is also set).
+//
+// # Safety
+//
+// `grammar` must have been returned by `grammer_grammar_from_pest` (and
+// not freed yet), and `error` must be `NULL` or valid for writes.
+char *grammer_grammar_validate(const GrammerGrammar *grammar, char **error);
+
+// Parse all of `input` with the rule named `rule`, returning `NULL` if it
+// doesn't match, or on errors (in which case `*error` is also set).
+//
+// The nesting of rules and the number of rule calls are limited by
+// `recursion_limit` and `step_limit` (see `Interpreter::recursion_limit`
+// and `Interpreter::step_limit`), `0` meaning a default limit for either.
+//
+// # Safety
+//
+// `grammar` must be valid (see `grammer_grammar_validate`), `rule` and
+// `input` must point to `rule_len` and `input_len` bytes, respectively,
+// and `error` must be `NULL` or valid for writes.
+GrammerTree *grammer_parse(const GrammerGrammar *grammar,
+                           const char *rule,
+                           size_t rule_len,
+                           const char *input,
+                           size_t input_len,
+                           size_t recursion_limit,
+                           size_t step_limit,
+                           char **error);
+
+// # Safety
+//
+// `tree` must have been returned by `grammer_parse` (and not freed yet).
+const GrammerNode *grammer_tree_root(const GrammerTree *tree);
+
+// The name of the rule a node matched, which is only valid as long as the
+// tree is (and not NUL-terminated, its length is written to `*len`).
+//
+// # Safety
+//
+// `node` must be a node of a valid tree, and `len` valid for writes.
+const char *grammer_node_rule(const GrammerNode *node, size_t *len);
+
+// The start of the part of the input matched by a node (in bytes).
+//
+// # Safety
+//
+// `node` must be a node of a valid tree.
+size_t grammer_node_start(const GrammerNode *node);
+
+// The end of the part of the input matched by a node (in bytes).
+//
+// # Safety
+//
+// `node` must be a node of a valid tree.
+size_t grammer_node_end(const GrammerNode *node);
+
+// # Safety
+//
+// `node` must be a node of a valid tree.
+size_t grammer_node_child_count(const GrammerNode *node);
+
+// The child at `index` of a node, or `NULL` if it doesn't have that many.
+//
+// # Safety
+//
+// `node` must be a node of a valid tree.
+const GrammerNode *grammer_node_child(const GrammerNode *node, size_t index);
+
+// The number of fields matched by a node (but not by its children).
+//
+// # Safety
+//
+// `node` must be a node of a valid tree.
+size_t grammer_node_field_count(const GrammerNode *node);
+
+// The field at `index` of a node (in the order they were matched in),
+// or `NULL` if it doesn't have that many.
+//
+// # Safety
+//
+// `node` must be a node of a valid tree.
+const GrammerField *grammer_node_field(const GrammerNode *node, size_t index);
+
+// The name of a field, which is only valid as long as the tree is (and
+// not NUL-terminated, its length is written to `*len`).
+//
+// # Safety
+//
+// `field` must be a field of a valid tree, and `len` valid for writes.
+const char *grammer_field_name(const GrammerField *field, size_t *len);
+
+// The start of the part of the input matched by a field (in bytes).
+//
+// # Safety
+//
+// `field` must be a field of a valid tree.
+size_t grammer_field_start(const GrammerField *field);
+
+// The end of the part of the input matched by a field (in bytes).
+//
+// # Safety
+//
+// `field` must be a field of a valid tree.
+size_t grammer_field_end(const GrammerField *field);
+
+// The number of fields nested in a field.
+//
+// # Safety
+//
+// `field` must be a field of a valid tree.
+size_t grammer_field_field_count(const GrammerField *field);
+
+// The field at `index` nested in a field, or `NULL` if it doesn't have
+// that many.
+//
+// # Safety
+//
+// `field` must be a field of a valid tree.
+const GrammerField *grammer_field_field(const GrammerField *field, size_t index);
+
+// # Safety
+//
+// `grammar` must be `NULL`, or returned by `grammer_grammar_from_pest`
+// (and not freed yet).
+void grammer_grammar_free(GrammerGrammar *grammar);
+
+// # Safety
+//
+// `tree` must be `NULL`, or returned by `grammer_parse` (and not freed yet).
+void grammer_tree_free(GrammerTree *tree);
+
+// # Safety
+//
+// `s` must be `NULL`, or a string returned by this API (and not freed yet).
+void grammer_string_free(char *s);
+
+#endif /* GRAMMER_H */
@@ -0,0 +1,325 @@
+//! Python bindings, for prototyping grammars (e.g. in notebooks), built with
+//! `maturin` (see `pyproject.toml`), e.g.:
+//!
+//! ```python
+//! import grammer
+//! g = grammer.Grammar()
+//! g.define("Num", grammer.range("0", "9").repeat_more())
+//! g.define("Sum", grammer.call("Num").field("terms").repeat_more(sep=grammer.eat("+")))
+//! g.parse("Sum", "1+23")
+//! ```
+//!
+//! Grammars are checked with `Grammar::validate` before they're used, and
+//! all errors are raised as Python exceptions (instead of panicking).
+
+use grammer::context::Context;
+use grammer::diagnostics::Severity;
+use grammer::interpreter::{Derivation, FieldMatch, Interpreter};
+use grammer::rule::{self, RuleWithFields, SepKind};
+use grammer::scannerless::Pat;
+use pyo3::exceptions::{PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+
+/// A rule being built in Python, which is only interned (in the `Context`
+/// of a grammar) once it's defined (see `Grammar.define`), so that rules
+/// can be reused across grammars.
+#[derive(Clone)]
+enum Expr {
+    Empty,
+    Eat(String),
+    Range(char, char),
+    Call(String),
+    Concat(Vec<Expr>),
+    Or(Vec<Expr>),
+    Opt(Box<Expr>),
+    RepeatMany(Box<Expr>, Option<(Box<Expr>, SepKind)>),
+    RepeatMore(Box<Expr>, Option<(Box<Expr>, SepKind)>),
+    Field(Box<Expr>, String),
+}
+
+impl Expr {
+    fn finish(&self, cx: &Context<Pat<String>>) -> RuleWithFields {
+        match self {
+            Expr::Empty => rule::empty().finish(cx),
+            Expr::Eat(s) => rule::eat(&s[..]).finish(cx),
+            &Expr::Range(start, end) => rule::eat(start..=end).finish(cx),
+            Expr::Call(name) => rule::call(name).finish(cx),
+            Expr::Concat(elems) => rule::seq(elems.iter().map(|e| e.finish(cx))).finish(cx),
+            Expr::Or(cases) => rule::any_of(cases.iter().map(|e| e.finish(cx))).finish(cx),
+            Expr::Opt(e) => e.finish(cx).opt().finish(cx),
+            Expr::RepeatMany(e, None) => e.finish(cx).repeat_many().finish(cx),
+            Expr::RepeatMany(e, Some((sep, kind))) => e
+                .finish(cx)
+                .repeat_many_sep(sep.finish(cx), *kind)
+                .finish(cx),
+            Expr::RepeatMore(e, None) => e.finish(cx).repeat_more().finish(cx),
+            Expr::RepeatMore(e, Some((sep, kind))) => e
+                .finish(cx)
+                .repeat_more_sep(sep.finish(cx), *kind)
+                .finish(cx),
+            Expr::Field(e, name) => e.finish(cx).field(name).finish(cx),
+        }
+    }
+}
+
+/// A rule, built with `eat`, `range`, `call`, `empty`, and combined with
+/// `+` (concatenation), `|` (alternation), and the methods below.
+#[pyclass(name = "Rule", frozen)]
+#[derive(Clone)]
+struct PyRule(Expr);
+
+/// Also allow a lone `sep` (with no elements) around `repeat`, if `lone_sep`.
+fn lone_sep(repeat: Expr, sep: Option<PyRule>, lone_sep: bool) -> PyRule {
+    match sep {
+        Some(sep) if lone_sep => PyRule(Expr::Or(vec![repeat, sep.0])),
+        _ => PyRule(repeat),
+    }
+}
+
+fn sep(sep: Option<PyRule>, trailing: bool) -> Option<(Box<Expr>, SepKind)> {
+    let kind = if trailing {
+        SepKind::Trailing
+    } else {
+        SepKind::Simple
+    };
+    sep.map(|sep| (Box::new(sep.0), kind))
+}
+
+#[pymethods]
+impl PyRule {
+    fn __add__(&self, other: PyRule) -> PyRule {
+        let mut elems = match &self.0 {
+            Expr::Concat(elems) => elems.clone(),
+            e => vec![e.clone()],
+        };
+        elems.push(other.0);
+        PyRule(Expr::Concat(elems))
+    }
+
+    fn __or__(&self, other: PyRule) -> PyRule {
+        let mut cases = match &self.0 {
+            Expr::Or(cases) => cases.clone(),
+            e => vec![e.clone()],
+        };
+        cases.push(other.0);
+        PyRule(Expr::Or(cases))
+    }
+
+    fn opt(&self) -> PyRule {
+        PyRule(Expr::Opt(Box::new(self.0.clone())))
+    }
+
+    /// Zero or more repetitions, separated by `sep` (if given), which can
+    /// also be trailing (after the last repetition), if `trailing` is set,
+    /// or on its own (with no repetitions), if `lone_sep` is set.
+    #[pyo3(signature = (sep=None, trailing=false, lone_sep=false))]
+    fn repeat_many(&self, sep: Option<PyRule>, trailing: bool, lone_sep: bool) -> PyRule {
+        let repeat = Expr::RepeatMany(Box::new(self.0.clone()), self::sep(sep.clone(), trailing));
+        self::lone_sep(repeat, sep, lone_sep)
+    }
+
+    /// Like `repeat_many`, but for one or more repetitions.
+    #[pyo3(signature = (sep=None, trailing=false, lone_sep=false))]
+    fn repeat_more(&self, sep: Option<PyRule>, trailing: bool, lone_sep: bool) -> PyRule {
+        let repeat = Expr::RepeatMore(Box::new(self.0.clone()), self::sep(sep.clone(), trailing));
+        self::lone_sep(repeat, sep, lone_sep)
+    }
+
+    fn field(&self, name: String) -> PyRule {
+        PyRule(Expr::Field(Box::new(self.0.clone()), name))
+    }
+}
+
+#[pyfunction]
+fn empty() -> PyRule {
+    PyRule(Expr::Empty)
+}
+
+#[pyfunction]
+fn eat(s: String) -> PyRule {
+    PyRule(Expr::Eat(s))
+}
+
+/// Any character between `start` and `end` (inclusive).
+#[pyfunction]
+fn range(start: char, end: char) -> PyRule {
+    PyRule(Expr::Range(start, end))
+}
+
+#[pyfunction]
+fn call(name: String) -> PyRule {
+    PyRule(Expr::Call(name))
+}
+
+#[pyclass(name = "Grammar")]
+struct PyGrammar {
+    cx: Context<Pat<String>>,
+    grammar: grammer::Grammar,
+}
+
+impl PyGrammar {
+    /// Raise all the errors `Grammar::validate` finds (if any), as they
+    /// could otherwise cause panics (e.g. calls to undefined rules).
+    fn check(&self) -> PyResult<()> {
+        let errors: Vec<_> = self
+            .grammar
+            .validate(&self.cx)
+            .into_iter()
+            .filter(|d| d.severity() == Severity::Error)
+            .map(|d| d.to_string())
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(PyValueError::new_err(errors.join("\n")))
+        }
+    }
+
+    fn rule(&self, name: &str) -> PyResult<grammer::context::IStr> {
+        let interned = self.cx.intern(name);
+        if self.grammar.rule(interned).is_some() {
+            Ok(interned)
+        } else {
+            Err(PyKeyError::new_err(format!("no rule named `{}`", name)))
+        }
+    }
+
+    fn derivation_to_py(
+        py: Python<'_>,
+        derivation: &Derivation,
+        input: &str,
+    ) -> PyResult<PyObject> {
+        let dict = PyDict::new_bound(py);
+        dict.set_item("rule", &derivation.rule)?;
+        dict.set_item("span", (derivation.span.start, derivation.span.end))?;
+        dict.set_item("text", derivation.text(input))?;
+        let children = derivation
+            .children
+            .iter()
+            .map(|child| Self::derivation_to_py(py, child, input))
+            .collect::<PyResult<Vec<_>>>()?;
+        dict.set_item("children", PyList::new_bound(py, children))?;
+        let fields = derivation
+            .fields
+            .iter()
+            .map(|field| Self::field_to_py(py, field, input))
+            .collect::<PyResult<Vec<_>>>()?;
+        dict.set_item("fields", PyList::new_bound(py, fields))?;
+        Ok(dict.into_any().unbind())
+    }
+
+    fn field_to_py(py: Python<'_>, field: &FieldMatch, input: &str) -> PyResult<PyObject> {
+        let dict = PyDict::new_bound(py);
+        dict.set_item("name", &field.name)?;
+        dict.set_item("span", (field.span.start, field.span.end))?;
+        dict.set_item("text", field.text(input))?;
+        let fields = field
+            .fields
+            .iter()
+            .map(|field| Self::field_to_py(py, field, input))
+            .collect::<PyResult<Vec<_>>>()?;
+        dict.set_item("fields", PyList::new_bound(py, fields))?;
+        Ok(dict.into_any().unbind())
+    }
+}
+
+#[pymethods]
+impl PyGrammar {
+    #[new]
+    fn new() -> Self {
+        PyGrammar {
+            cx: Context::new(),
+            grammar: grammer::Grammar::new(),
+        }
+    }
+
+    /// Import a grammar from the contents of a `.pest` file.
+    #[staticmethod]
+    fn from_pest(src: &str) -> PyResult<Self> {
+        let cx = Context::new();
+        let imported = grammer::pest::import(&cx, src)
+            .map_err(|error| PyValueError::new_err(error.to_string()))?;
+        Ok(PyGrammar {
+            cx,
+            grammar: imported.grammar,
+        })
+    }
+
+    /// Define (or redefine) the rule `name`.
+    fn define(&mut self, name: &str, rule: PyRule) {
+        let rule = rule.0.finish(&self.cx);
+        self.grammar.define(self.cx.intern(name), rule);
+    }
+
+    /// Allow `rule` (e.g. whitespace) between any two terminals.
+    fn set_trivia(&mut self, rule: PyRule) {
+        let rule = rule.0.finish(&self.cx);
+        self.grammar.set_trivia(rule);
+    }
+
+    fn rules(&self) -> Vec<String> {
+        self.grammar
+            .rules()
+            .keys()
+            .map(|&name| self.cx[name].to_string())
+            .collect()
+    }
+
+    /// All the diagnostics (errors and warnings) for the grammar, as strings.
+    fn validate(&self) -> Vec<String> {
+        self.grammar
+            .validate(&self.cx)
+            .iter()
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    /// All the sentences `rule` matches, with at most `max_len` terminals
+    /// each, as lists of strings (the text of each terminal, where known).
+    #[pyo3(signature = (rule, max_len=3))]
+    fn sentences(&self, rule: &str, max_len: usize) -> PyResult<Vec<Vec<String>>> {
+        self.check()?;
+        let rule = self.rule(rule)?;
+        let sentences = grammer::enumerate::sentences(&self.cx, &self.grammar, max_len);
+        Ok(sentences[&rule]
+            .iter()
+            .map(|sentence| {
+                sentence
+                    .iter()
+                    .map(|&terminal| match &self.cx[terminal] {
+                        rule::Rule::Eat(Pat::String(s)) => s.clone(),
+                        _ => terminal.node_desc(&self.cx),
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Parse all of `input` with `rule`, returning `None` if it doesn't
+    /// match, or a tree of dicts (with `rule`, `span`, `text`, `children`,
+    /// and `fields`, themselves dicts with `name`, `span`, `text`, and their
+    /// nested `fields`).
+    fn parse(&self, py: Python<'_>, rule: &str, input: &str) -> PyResult<Option<PyObject>> {
+        self.check()?;
+        let rule = self.rule(rule)?;
+        let derivation = Interpreter::new(&self.cx, &self.grammar)
+            .explain(rule, input)
+            .map_err(|error| PyValueError::new_err(error.to_string()))?;
+        derivation
+            .map(|derivation| Self::derivation_to_py(py, &derivation, input))
+            .transpose()
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "grammer")]
+fn grammer_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyGrammar>()?;
+    m.add_class::<PyRule>()?;
+    m.add_function(wrap_pyfunction!(empty, m)?)?;
+    m.add_function(wrap_pyfunction!(eat, m)?)?;
+    m.add_function(wrap_pyfunction!(range, m)?)?;
+    m.add_function(wrap_pyfunction!(call, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,209 @@
+//! A common interface to the different ways of parsing with a grammar (see
+//! `ParserBackend`), so that they can be swapped for one another, e.g. by
+//! going from `Grammar::parse_with::<Backtracking<_>, _>` (one derivation)
+//! to `Grammar::parse_with::<Generalized<_>, _>` (all of them, as a forest).
+
+use crate::compat::prelude::*;
+use crate::compat::IndexSet;
+use crate::context::{Context, IRule, IStr};
+use crate::forest::OwnedParseForestAndNode;
+use crate::input::InputMatch;
+use crate::interpreter::{self, Derivation, Interpreter, Reflector};
+use crate::rule::{MatchesEmpty, MaybeKnown, Rule};
+use crate::Grammar;
+use alloc::collections::BTreeMap;
+use core::fmt;
+use core::hash::Hash;
+
+/// What a `ParserBackend` can handle, beyond the basics.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Left-recursive rules (e.g. `Expr = Expr "+" Term | Term`), which are
+    /// otherwise rejected by `Grammar::parse_with` (see `left_recursion`).
+    pub left_recursion: bool,
+    /// Ambiguous inputs result in all of their derivations, instead of one.
+    pub ambiguity: bool,
+}
+
+/// A parsing engine, e.g. the `interpreter` (as `Backtracking` or as
+/// `Generalized`), a generated parser, or one from another crate.
+pub trait ParserBackend<'a, Pat>: Sized {
+    const CAPABILITIES: Capabilities;
+
+    /// The result of a successful parse, which can borrow from the input.
+    type Output<'i>;
+    type Error;
+
+    fn new(cx: &'a Context<Pat>, grammar: &'a Grammar) -> Self;
+
+    /// Parse all of `input` with `rule`, returning `None` if it doesn't match.
+    fn parse<'i>(
+        &self,
+        rule: IStr,
+        input: &'i str,
+    ) -> Result<Option<Self::Output<'i>>, Self::Error>;
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The grammar uses something the backend can't handle, e.g. it has
+    /// left recursion (in `rule`) without `Capabilities::left_recursion`.
+    Unsupported {
+        rule: String,
+        reason: &'static str,
+    },
+    Backend(E),
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unsupported { rule, reason } => {
+                write!(f, "rule `{}` is not supported: {}", rule, reason)
+            }
+            Error::Backend(e) => e.fmt(f),
+        }
+    }
+}
+
+/// The `Interpreter`, finding one derivation (see `Interpreter::explain`).
+pub struct Backtracking<'a, Pat>(pub Interpreter<'a, Pat>);
+
+impl<'a, Pat> ParserBackend<'a, Pat> for Backtracking<'a, Pat>
+where
+    str: InputMatch<Pat>,
+{
+    const CAPABILITIES: Capabilities = Capabilities {
+        left_recursion: true,
+        ambiguity: false,
+    };
+
+    type Output<'i> = Derivation;
+    type Error = interpreter::Error;
+
+    fn new(cx: &'a Context<Pat>, grammar: &'a Grammar) -> Self {
+        Backtracking(Interpreter::new(cx, grammar))
+    }
+
+    fn parse(&self, rule: IStr, input: &str) -> Result<Option<Derivation>, Self::Error> {
+        self.0.explain(rule, input)
+    }
+}
+
+/// The `Interpreter`, with memoization, building a parse forest of all
+/// the derivations (see `Interpreter::parse`).
+pub struct Generalized<'a, Pat>(pub Interpreter<'a, Pat>);
+
+impl<'a, Pat: Eq + Hash + fmt::Debug> ParserBackend<'a, Pat> for Generalized<'a, Pat>
+where
+    str: InputMatch<Pat>,
+{
+    const CAPABILITIES: Capabilities = Capabilities {
+        left_recursion: true,
+        ambiguity: true,
+    };
+
+    type Output<'i> = OwnedParseForestAndNode<Reflector<'a, Pat>, &'i str>;
+    type Error = interpreter::Error;
+
+    fn new(cx: &'a Context<Pat>, grammar: &'a Grammar) -> Self {
+        let mut interpreter = Interpreter::new(cx, grammar);
+        interpreter.memo_limit = Some(usize::MAX);
+        Generalized(interpreter)
+    }
+
+    fn parse<'i>(
+        &self,
+        rule: IStr,
+        input: &'i str,
+    ) -> Result<Option<Self::Output<'i>>, Self::Error> {
+        self.0.parse(rule, input)
+    }
+}
+
+/// The first rule (in definition order) which can call itself before having
+/// matched any input (directly, or through other rules), if there is one.
+pub fn left_recursion<Pat: MatchesEmpty>(cx: &Context<Pat>, grammar: &Grammar) -> Option<IStr> {
+    let mut cache = BTreeMap::new();
+    let left_calls: BTreeMap<_, _> = grammar
+        .rules()
+        .iter()
+        .map(|(&name, rule)| {
+            let mut calls = IndexSet::default();
+            collect_left_calls(cx, grammar, &mut cache, rule.rule, &mut calls);
+            (name, calls)
+        })
+        .collect();
+
+    grammar.rules().keys().copied().find(|&name| {
+        let mut reached: IndexSet<_> = left_calls[&name].iter().copied().collect();
+        let mut i = 0;
+        while let Some(&callee) = reached.get_index(i) {
+            if callee == name {
+                return true;
+            }
+            if let Some(calls) = left_calls.get(&callee) {
+                reached.extend(calls.iter().copied());
+            }
+            i += 1;
+        }
+        false
+    })
+}
+
+/// Collect the rules `rule` can call before having matched any input.
+fn collect_left_calls<Pat: MatchesEmpty>(
+    cx: &Context<Pat>,
+    grammar: &Grammar,
+    cache: &mut BTreeMap<IRule, MaybeKnown<bool>>,
+    rule: IRule,
+    calls: &mut IndexSet<IStr>,
+) {
+    match cx[rule] {
+        Rule::Empty
+        | Rule::Anchor(_)
+        | Rule::Eat(_)
+        | Rule::EatUntil(_)
+        | Rule::FlagGuard(..)
+        | Rule::Guard(_)
+        | Rule::Feature(..)
+        | Rule::Backref(_) => {}
+        Rule::Call(name) => {
+            calls.insert(name);
+        }
+        Rule::WithFlag(_, _, rule) | Rule::Silent(rule) | Rule::Opt(rule) => {
+            collect_left_calls(cx, grammar, cache, rule, calls)
+        }
+        Rule::Capture(_, delim, body) => {
+            collect_left_calls(cx, grammar, cache, delim, calls);
+            if delim.can_be_empty(cache, cx, grammar) != MaybeKnown::Known(false) {
+                collect_left_calls(cx, grammar, cache, body, calls);
+            }
+        }
+        Rule::Concat(ref rules) => {
+            for &rule in rules {
+                collect_left_calls(cx, grammar, cache, rule, calls);
+                // NOTE `Unknown` is treated as "can be empty", as
+                // it's better to reject a grammar than to loop forever.
+                if rule.can_be_empty(cache, cx, grammar) == MaybeKnown::Known(false) {
+                    break;
+                }
+            }
+        }
+        Rule::Or(ref rules) => {
+            for &rule in rules {
+                collect_left_calls(cx, grammar, cache, rule, calls);
+            }
+        }
+        Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => {
+            collect_left_calls(cx, grammar, cache, elem, calls);
+            if let Some((sep, kind)) = sep {
+                if kind.allows_leading()
+                    || elem.can_be_empty(cache, cx, grammar) != MaybeKnown::Known(false)
+                {
+                    collect_left_calls(cx, grammar, cache, sep, calls);
+                }
+            }
+        }
+    }
+}
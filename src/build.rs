@@ -0,0 +1,1226 @@
+//! Helpers for build scripts (`build.rs`), turning grammar files into
+//! Rust code to `include!` in the crate being built.
+
+use crate::context::{Context, IRule, IStr};
+use crate::diagnostics::{Code, Diagnostic, Severity};
+use crate::rule::{Anchor, Fields, Rule, RuleWithFields, SepKind};
+use crate::{pest, scannerless, Grammar};
+use indexmap::{IndexMap, IndexSet};
+use std::fmt;
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Import(pest::ImportError),
+    Invalid(Vec<Diagnostic>),
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => error.fmt(f),
+            Error::Import(error) => error.fmt(f),
+            Error::Invalid(diagnostics) => {
+                for diagnostic in diagnostics {
+                    writeln!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Options for the generated code (see `generate`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CodegenOptions {
+    /// Wrap the generated code in a module with this name.
+    pub module: Option<String>,
+    /// The visibility of the generated items (and module), e.g. `pub(crate)`.
+    pub visibility: String,
+    /// Refer to `core` and `alloc`, instead of `std`, in the generated code.
+    pub no_std: bool,
+    /// Mark the generated functions `#[inline]`.
+    pub inline: bool,
+    /// Lints to `#[allow(...)]` on the generated items.
+    pub allow: Vec<String>,
+    /// Also generate a `&str` constant for every rule name, in a `rules`
+    /// module, and for every field name, in a `fields` module, named after
+    /// them using this `Naming`, along with `RULE_IDENTS` and `FIELD_IDENTS`,
+    /// listing the `(identifier, name)` pairs, to map identifiers back to
+    /// names (as names can't always be turned back into identifiers).
+    pub name_consts: Option<Naming>,
+    /// Also generate an `ast` module, with a type for every rule, holding
+    /// its fields (see `generate_ast`), and `Visit`/`Fold` traits for them.
+    pub ast: bool,
+    /// In the `ast` module, collapse the types of "newtype" rules, i.e. which
+    /// only call another rule (e.g. `Expr = AssignExpr`, possibly with a field
+    /// around the call), into type aliases, except for the rules named in
+    /// `keep_newtypes`, for which a struct is generated anyway.
+    pub collapse_newtypes: bool,
+    pub keep_newtypes: Vec<String>,
+}
+
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        CodegenOptions {
+            module: None,
+            visibility: "pub".to_string(),
+            no_std: false,
+            inline: false,
+            allow: vec!["clippy::all".to_string()],
+            name_consts: None,
+            ast: false,
+            collapse_newtypes: true,
+            keep_newtypes: vec![],
+        }
+    }
+}
+
+/// Process the (pest) grammar file at `path`, validating it, and writing the
+/// generated code (see `generate`) to a `.rs` file of the same name, in
+/// `out_dir` (usually `env!("OUT_DIR")`), returning the path of that file.
+///
+/// Also tells Cargo to rerun the build script if the grammar file changes,
+/// and leaves the generated file untouched if the grammar hasn't changed
+/// (according to `Grammar::fingerprint`), to avoid needless rebuilds.
+pub fn process(path: impl AsRef<Path>, out_dir: impl AsRef<Path>) -> Result<PathBuf, Error> {
+    process_with(path, out_dir, &CodegenOptions::default())
+}
+
+/// Like `process`, but with non-default `options` for the generated code.
+pub fn process_with(
+    path: impl AsRef<Path>,
+    out_dir: impl AsRef<Path>,
+    options: &CodegenOptions,
+) -> Result<PathBuf, Error> {
+    let path = path.as_ref();
+    println!("cargo:rerun-if-changed={}", path.display());
+
+    let cx = scannerless::Context::<String>::new();
+    let src = fs::read_to_string(path)?;
+    let grammar = pest::import(&cx, &src).map_err(Error::Import)?.grammar;
+    let errors: Vec<_> = grammar
+        .validate(&cx)
+        .into_iter()
+        .filter(|diagnostic| diagnostic.severity() == Severity::Error)
+        .collect();
+    if !errors.is_empty() {
+        return Err(Error::Invalid(errors));
+    }
+
+    let out_path = out_dir
+        .as_ref()
+        .join(path.file_stem().unwrap_or(path.as_os_str()))
+        .with_extension("rs");
+    // NOTE the options are recorded too, as changing them
+    // also requires regenerating the code.
+    let header = format!(
+        "// @generated by `grammer::build` from `{}`, do not edit.\n\
+         // fingerprint: {:016x}\n\
+         // options: {:?}\n",
+        path.display(),
+        grammar.fingerprint(&cx),
+        options
+    );
+    if let Ok(old) = fs::read_to_string(&out_path) {
+        if old.starts_with(&header) {
+            return Ok(out_path);
+        }
+    }
+
+    let code = format!("{}\n{}", header, generate(&cx, &grammar, options));
+    fs::write(&out_path, code)?;
+    Ok(out_path)
+}
+
+/// Generate a Rust function, `grammar(cx)`, building `grammar` in `cx`,
+/// which can have any `Pat` convertible from string literals and ranges.
+pub fn generate<S: AsRef<str> + Eq + Hash>(
+    cx: &Context<scannerless::Pat<S>>,
+    grammar: &Grammar,
+    options: &CodegenOptions,
+) -> String {
+    let (core, alloc) = if options.no_std {
+        ("::core", "::alloc")
+    } else {
+        ("::std", "::std")
+    };
+    let mut attrs = String::new();
+    if !options.allow.is_empty() {
+        attrs += &format!("#[allow({})]\n", options.allow.join(", "));
+    }
+
+    let mut code = attrs.clone();
+    if options.inline {
+        code += "#[inline]\n";
+    }
+    code += &format!(
+        "{} fn grammar<Pat>(cx: &::grammer::context::Context<Pat>) -> ::grammer::Grammar\n",
+        options.visibility
+    );
+    code += "where\n";
+    code += &format!("    Pat: Eq + {}::hash::Hash + for<'s> From<&'s str>", core);
+    code += &format!(" + From<{}::ops::RangeInclusive<char>>,\n", core);
+    code += "{\n";
+    code += "    #[allow(unused_imports)]\n";
+    code += "    use ::grammer::rule::*;\n";
+    code += "    #[allow(unused_imports)]\n";
+    code += &format!("    use {}::vec;\n\n", alloc);
+    code += "    let mut grammar = ::grammer::Grammar::new();\n";
+    for (&name, &rule) in grammar.rules() {
+        code += &format!(
+            "    grammar.define(cx.intern({:?}), {});\n",
+            &cx[name],
+            generate_rule(cx, rule)
+        );
+        let modifiers = grammar.modifiers(name);
+        if !modifiers.is_default() {
+            code += &format!(
+                "    grammar.set_modifiers(cx.intern({:?}), Modifiers {{ \
+                 atomicity: Atomicity::{:?}, silent: {}, ignore_case: {}, exhaustive: {}, \
+                 shape: Shape::{:?}, open: {}, reserved: {}, label: {} }});\n",
+                &cx[name],
+                modifiers.atomicity,
+                modifiers.silent,
+                modifiers.ignore_case,
+                modifiers.exhaustive,
+                modifiers.shape,
+                modifiers.open,
+                generate_opt_str(cx, modifiers.reserved),
+                generate_opt_str(cx, modifiers.label)
+            );
+        }
+    }
+    if let Some(trivia) = grammar.trivia() {
+        code += &format!("    grammar.set_trivia({});\n", generate_rule(cx, trivia));
+    }
+    let normalization = grammar.input_normalization();
+    if !normalization.is_identity() {
+        code += &format!(
+            "    grammar.set_input_normalization(::grammer::normalize::Normalization \
+             {{ nfc: {}, case_fold: {} }});\n",
+            normalization.nfc, normalization.case_fold
+        );
+    }
+    for (&terminal, &label) in &grammar.terminal_labels {
+        let terminal = RuleWithFields {
+            rule: terminal,
+            fields: cx.intern(Fields::Leaf(None)),
+        };
+        code += &format!(
+            "    grammar.set_terminal_label(cx, {}.rule, {:?});\n",
+            generate_rule(cx, terminal),
+            &cx[label]
+        );
+    }
+    for (&name, &shape) in &grammar.field_shapes {
+        code += &format!(
+            "    grammar.set_field_shape(cx.intern({:?}), Shape::{:?});\n",
+            &cx[name], shape
+        );
+    }
+    code += "    grammar\n";
+    code += "}\n";
+
+    if let Some(naming) = &options.name_consts {
+        let mut field_names = IndexSet::new();
+        for rule in grammar.rules().values().copied().chain(grammar.trivia()) {
+            field_names.extend(rule.fields.names(cx));
+        }
+        let names = [
+            (
+                "rules",
+                "RULE_IDENTS",
+                grammar.rules().keys().copied().collect(),
+            ),
+            ("fields", "FIELD_IDENTS", field_names),
+        ];
+        for (module, table, names) in names {
+            let idents = naming.idents(names.iter().map(|&name| &cx[name]));
+            code += &format!("\n{}{} mod {} {{\n", attrs, options.visibility, module);
+            for (name, ident) in idents.iter() {
+                code += &format!("    pub const {}: &str = {:?};\n", ident, name);
+            }
+            code += "}\n";
+            code += &format!(
+                "{}{} const {}: &[(&str, &str)] = &[\n",
+                attrs, options.visibility, table
+            );
+            for (name, ident) in idents.iter() {
+                code += &format!("    ({:?}, {:?}),\n", ident, name);
+            }
+            code += "];\n";
+        }
+    }
+
+    if options.ast {
+        code += &format!("\n{}{} mod ast {{\n", attrs, options.visibility);
+        for line in generate_ast(cx, grammar, options).lines() {
+            if !line.is_empty() {
+                code += "    ";
+            }
+            code += line;
+            code += "\n";
+        }
+        code += "}\n";
+    }
+
+    match &options.module {
+        None => code,
+        Some(module) => {
+            let code: Vec<_> = code
+                .lines()
+                .map(|line| {
+                    if line.is_empty() {
+                        String::new()
+                    } else {
+                        format!("    {}", line)
+                    }
+                })
+                .collect();
+            format!(
+                "{}{} mod {} {{\n{}\n}}\n",
+                attrs,
+                options.visibility,
+                module,
+                code.join("\n")
+            )
+        }
+    }
+}
+
+/// `Some(cx.intern("..."))` or `None`, for a string in e.g. `Modifiers`.
+fn generate_opt_str<Pat>(cx: &Context<Pat>, s: Option<IStr>) -> String {
+    match s {
+        Some(s) => format!("Some(cx.intern({:?}))", &cx[s]),
+        None => "None".to_string(),
+    }
+}
+
+fn generate_rule<S: AsRef<str> + Eq + Hash>(
+    cx: &Context<scannerless::Pat<S>>,
+    rule: RuleWithFields,
+) -> String {
+    if let Fields::Leaf(Some(field)) = cx[rule.fields] {
+        let sub = RuleWithFields {
+            rule: rule.rule,
+            fields: field.sub,
+        };
+        return format!(
+            "{}.field({:?}).finish(cx)",
+            generate_rule(cx, sub),
+            &cx[field.name]
+        );
+    }
+    let child = |child: IRule, i| {
+        generate_rule(
+            cx,
+            RuleWithFields {
+                rule: child,
+                fields: rule.fields.child(cx, i),
+            },
+        )
+    };
+    let list = |rules: &[IRule]| {
+        let rules: Vec<_> = rules
+            .iter()
+            .enumerate()
+            .map(|(i, &rule)| child(rule, i))
+            .collect();
+        format!("vec![{}]", rules.join(", "))
+    };
+    let repeat = |elem, sep: Option<(IRule, SepKind)>, repeat| match sep {
+        None => format!("{}.{}()", child(elem, 0), repeat),
+        Some((sep, kind)) => format!(
+            "{}.{}_sep({}, SepKind::{:?})",
+            child(elem, 0),
+            repeat,
+            child(sep, 1),
+            kind
+        ),
+    };
+    let builder = match cx[rule.rule] {
+        Rule::Empty => "empty()".to_string(),
+        Rule::Anchor(Anchor::Start) => "soi()".to_string(),
+        Rule::Anchor(Anchor::End) => "eoi()".to_string(),
+        Rule::Anchor(Anchor::Column(column)) => format!("at_column({})", column),
+        Rule::Eat(scannerless::Pat::String(ref s)) => format!("eat({:?})", s.as_ref()),
+        Rule::Eat(scannerless::Pat::Range(start, end)) => {
+            format!("eat({:?}..={:?})", start, end)
+        }
+        Rule::EatUntil(scannerless::Pat::String(ref s)) => format!("until({:?})", s.as_ref()),
+        Rule::EatUntil(scannerless::Pat::Range(start, end)) => {
+            format!("until({:?}..={:?})", start, end)
+        }
+        Rule::Backref(name) => format!("backref({:?})", &cx[name]),
+        Rule::Call(name) => format!("call({:?})", &cx[name]),
+        Rule::FlagGuard(flag, true) => format!("if_flag({:?})", &cx[flag]),
+        Rule::FlagGuard(flag, false) => format!("unless_flag({:?})", &cx[flag]),
+        Rule::Guard(name) => format!("guard({:?})", &cx[name]),
+        Rule::Feature(feature, true) => format!("if_feature({:?})", &cx[feature]),
+        Rule::Feature(feature, false) => format!("unless_feature({:?})", &cx[feature]),
+        Rule::WithFlag(flag, true, rule) => {
+            format!("{}.with_flag({:?})", child(rule, 0), &cx[flag])
+        }
+        Rule::WithFlag(flag, false, rule) => {
+            format!("{}.without_flag({:?})", child(rule, 0), &cx[flag])
+        }
+        Rule::Concat(ref elems) => format!("seq({})", list(elems)),
+        Rule::Or(ref cases) => format!("any_of({})", list(cases)),
+        Rule::Capture(name, delim, body) => format!(
+            "capture({:?}, {}, {})",
+            &cx[name],
+            child(delim, 0),
+            child(body, 1)
+        ),
+        Rule::Silent(rule) => format!("{}.silent()", child(rule, 0)),
+        Rule::Opt(rule) => format!("{}.opt()", child(rule, 0)),
+        Rule::RepeatMany(elem, sep) => repeat(elem, sep, "repeat_many"),
+        Rule::RepeatMore(elem, sep) => repeat(elem, sep, "repeat_more"),
+    };
+    format!("{}.finish(cx)", builder)
+}
+
+/// Generate the contents of the `ast` module (see `CodegenOptions::ast`):
+/// for every rule, a struct with the `span` it matched, and a field for
+/// every field name in it, typed according to what the field matches,
+/// and where (e.g. a field in an `Opt` is an `Option`), along with `Visit`
+/// and `Fold` traits, with methods (defaulting to traversing the fields)
+/// for every rule, named after it (e.g. `visit_expr` for `Expr`).
+///
+/// Fields matching a `Call` hold (boxed) the type generated for the rule,
+/// and all other fields (e.g. of terminals) only hold their `Span`.
+///
+/// Rules only wrapping a call to another rule get a type alias instead (see
+/// `CodegenOptions::collapse_newtypes`), and their `Visit`/`Fold` methods
+/// default to those of the rule they wrap.
+///
+/// FIXME this only generates the types, building them from parse
+/// results isn't supported yet, and fields nested in other fields are
+/// left out of them (as only the outer field is kept).
+pub fn generate_ast<S: AsRef<str> + Eq + Hash>(
+    cx: &Context<scannerless::Pat<S>>,
+    grammar: &Grammar,
+    options: &CodegenOptions,
+) -> String {
+    let ast = AstCodegen::new(cx, grammar, options);
+
+    let mut code = format!(
+        "/// The part of the input matched by a node (or field).\n\
+         pub type Span = {}::ops::Range<usize>;\n",
+        ast.core
+    );
+    let mut visit = String::new();
+    let mut fold = String::new();
+    let mut visit_methods = String::new();
+    let mut fold_methods = String::new();
+    for (&name, &rule) in grammar.rules() {
+        let ty = ast.types.ident(&cx[name]).unwrap();
+        let method = ast.method(name);
+
+        visit_methods += &format!(
+            "\n    fn visit_{}(&mut self, node: &{}) {{\n        visit_{}(self, node)\n    }}\n",
+            method, ty, method
+        );
+        fold_methods += &format!(
+            "\n    fn fold_{}(&mut self, node: {}) -> {} {{\n        fold_{}(self, node)\n    }}\n",
+            method, ty, ty, method
+        );
+
+        if let Some(&target) = ast.aliases.get(&name) {
+            let target_method = ast.method(target);
+            code += &format!(
+                "\npub type {} = {};\n",
+                ty,
+                ast.types.ident(&cx[target]).unwrap()
+            );
+            visit += &format!(
+                "\npub fn visit_{}<V: Visit + ?Sized>(v: &mut V, node: &{}) {{\n    \
+                 v.visit_{}(node)\n}}\n",
+                method, ty, target_method
+            );
+            fold += &format!(
+                "\npub fn fold_{}<F: Fold + ?Sized>(f: &mut F, node: {}) -> {} {{\n    \
+                 f.fold_{}(node)\n}}\n",
+                method, ty, ty, target_method
+            );
+            continue;
+        }
+
+        let fields: Vec<_> = ast
+            .rule_fields(rule)
+            .into_iter()
+            .map(|(_, ident, field_ty)| (ident, field_ty))
+            .collect();
+
+        code += "\n#[derive(Clone, Debug, PartialEq, Eq)]\n";
+        code += &format!("pub struct {} {{\n", ty);
+        code += "    pub span: Span,\n";
+        for (ident, field_ty) in &fields {
+            code += &format!("    pub {}: {},\n", ident, ast.rust_type(field_ty));
+        }
+        code += "}\n";
+
+        visit += &format!(
+            "\npub fn visit_{}<V: Visit + ?Sized>(v: &mut V, node: &{}) {{\n",
+            method, ty
+        );
+        let visits: Vec<_> = fields
+            .iter()
+            .filter_map(|(ident, field_ty)| ast.visit(field_ty, &format!("&node.{}", ident), 0))
+            .collect();
+        if visits.is_empty() {
+            visit += "    let _ = (v, node);\n";
+        }
+        for stmt in visits {
+            visit += &format!("    {}\n", stmt);
+        }
+        visit += "}\n";
+
+        fold += &format!(
+            "\npub fn fold_{}<F: Fold + ?Sized>(f: &mut F, node: {}) -> {} {{\n",
+            method, ty, ty
+        );
+        if !fields.iter().any(|(_, field_ty)| field_ty.has_nodes()) {
+            fold += "    let _ = f;\n";
+        }
+        fold += &format!("    {} {{\n", ty);
+        fold += "        span: node.span,\n";
+        for (ident, field_ty) in &fields {
+            fold += &format!(
+                "        {}: {},\n",
+                ident,
+                ast.fold(field_ty, &format!("node.{}", ident), 0)
+            );
+        }
+        fold += "    }\n";
+        fold += "}\n";
+    }
+
+    code += "\n/// Traversing an AST by reference, where every method defaults to\n";
+    code += "/// visiting the fields of its node (with the function of the same name).\n";
+    code += &format!("pub trait Visit {{{}}}\n", visit_methods);
+    code += &visit;
+    code += "\n/// Rebuilding an AST, where every method defaults to rebuilding its\n";
+    code += "/// node from its folded fields (with the function of the same name).\n";
+    code += &format!("pub trait Fold {{{}}}\n", fold_methods);
+    code += &fold;
+    code
+}
+
+/// The signatures of the types `generate_ast` generates (with the same
+/// `options`), e.g. to check that the stable ones don't change between
+/// versions of the grammar (see `check_stability`).
+pub fn ast_signatures<S: AsRef<str> + Eq + Hash>(
+    cx: &Context<scannerless::Pat<S>>,
+    grammar: &Grammar,
+    options: &CodegenOptions,
+) -> AstSignatures {
+    let ast = AstCodegen::new(cx, grammar, options);
+    let types = grammar
+        .rules()
+        .iter()
+        .map(|(&name, &rule)| {
+            let alias = ast
+                .aliases
+                .get(&name)
+                .map(|&target| ast.types.ident(&cx[target]).unwrap().to_string());
+            let fields = if alias.is_some() {
+                IndexMap::new()
+            } else {
+                ast.rule_fields(rule)
+                    .into_iter()
+                    .map(|(field, ident, ty)| (cx[field].to_string(), (ident, ast.rust_type(&ty))))
+                    .collect()
+            };
+            let signature = TypeSignature {
+                ident: ast.types.ident(&cx[name]).unwrap().to_string(),
+                alias,
+                fields,
+            };
+            (cx[name].to_string(), signature)
+        })
+        .collect();
+    AstSignatures { types }
+}
+
+/// The signatures of the types in a generated AST (see `ast_signatures`),
+/// keyed by the names of the rules they were generated for.
+///
+/// Displaying them results in one line per type, listing its fields.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AstSignatures {
+    pub types: IndexMap<String, TypeSignature>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeSignature {
+    pub ident: String,
+    /// The type this one is an alias of, for collapsed "newtype" rules
+    /// (see `CodegenOptions::collapse_newtypes`), which have no fields.
+    pub alias: Option<String>,
+    /// The identifier and (Rust) type of every field, keyed by field name
+    /// (not including the `span` every type has).
+    pub fields: IndexMap<String, (String, String)>,
+}
+
+impl fmt::Display for AstSignatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ty in self.types.values() {
+            if let Some(alias) = &ty.alias {
+                writeln!(f, "type {} = {};", ty.ident, alias)?;
+                continue;
+            }
+            write!(f, "struct {} {{ span: Span", ty.ident)?;
+            for (ident, field_ty) in ty.fields.values() {
+                write!(f, ", {}: {}", ident, field_ty)?;
+            }
+            writeln!(f, " }}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stability {
+    Stable,
+    Unstable,
+}
+
+/// Which rules and fields are `#[stable]` or `#[unstable]`, i.e. whether
+/// the types (and fields) generated for them (see `generate_ast`) are part
+/// of a stable public API, and can't change (see `check_stability`).
+///
+/// Fields default to the stability of their rule, and rules which aren't
+/// marked are unstable, but their fields can still be marked stable.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StabilityMarks {
+    rules: IndexMap<String, Stability>,
+    fields: IndexMap<(String, String), Stability>,
+}
+
+impl StabilityMarks {
+    pub fn new() -> Self {
+        StabilityMarks::default()
+    }
+
+    pub fn mark_rule(&mut self, rule: &str, stability: Stability) {
+        self.rules.insert(rule.to_string(), stability);
+    }
+
+    pub fn mark_field(&mut self, rule: &str, field: &str, stability: Stability) {
+        self.fields
+            .insert((rule.to_string(), field.to_string()), stability);
+    }
+
+    pub fn rule(&self, rule: &str) -> Stability {
+        self.rules.get(rule).copied().unwrap_or(Stability::Unstable)
+    }
+
+    pub fn field(&self, rule: &str, field: &str) -> Stability {
+        self.fields
+            .get(&(rule.to_string(), field.to_string()))
+            .copied()
+            .unwrap_or_else(|| self.rule(rule))
+    }
+}
+
+/// Compare the `old` and `new` signatures of a generated AST (see
+/// `ast_signatures`), reporting every change to its stable parts (see
+/// `StabilityMarks`), i.e. stable types being removed or renamed, stable
+/// fields being removed or changing type, and fields (unless unstable)
+/// being added to stable types (which breaks constructing them).
+pub fn check_stability(
+    old: &AstSignatures,
+    new: &AstSignatures,
+    marks: &StabilityMarks,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut report = |rule: &str, node: String, message: String, message_id, args| {
+        diagnostics.push(Diagnostic {
+            code: Code::StableAstChanged,
+            rule: rule.to_string(),
+            node,
+            message,
+            help: Some(
+                "mark the changed parts `#[unstable]`, or release a new major version".to_string(),
+            ),
+            message_id,
+            args,
+        })
+    };
+    for (rule, old_ty) in &old.types {
+        let rule_is_stable = marks.rule(rule) == Stability::Stable;
+        let new_ty = match new.types.get(rule) {
+            Some(ty) => ty,
+            None => {
+                let stable_fields = old_ty
+                    .fields
+                    .keys()
+                    .any(|field| marks.field(rule, field) == Stability::Stable);
+                if rule_is_stable || stable_fields {
+                    report(
+                        rule,
+                        old_ty.ident.clone(),
+                        format!("stable type `{}` was removed", old_ty.ident),
+                        "stable-type-removed",
+                        vec![("type", old_ty.ident.clone())],
+                    );
+                }
+                continue;
+            }
+        };
+        if rule_is_stable && old_ty.ident != new_ty.ident {
+            report(
+                rule,
+                old_ty.ident.clone(),
+                format!(
+                    "stable type `{}` was renamed to `{}`",
+                    old_ty.ident, new_ty.ident
+                ),
+                "stable-type-renamed",
+                vec![
+                    ("type", old_ty.ident.clone()),
+                    ("new_type", new_ty.ident.clone()),
+                ],
+            );
+        }
+        if rule_is_stable && old_ty.alias != new_ty.alias {
+            let mut args = vec![("type", old_ty.ident.clone())];
+            let (new_kind, message_id) = match &new_ty.alias {
+                Some(alias) => {
+                    args.push(("alias", alias.clone()));
+                    (format!("an alias of `{}`", alias), "stable-type-now-alias")
+                }
+                None => ("a struct".to_string(), "stable-type-now-struct"),
+            };
+            report(
+                rule,
+                old_ty.ident.clone(),
+                format!("stable type `{}` changed to {}", old_ty.ident, new_kind),
+                message_id,
+                args,
+            );
+        }
+        for (field, (ident, ty)) in &old_ty.fields {
+            if marks.field(rule, field) != Stability::Stable {
+                continue;
+            }
+            let node = format!("{}.{}: {}", old_ty.ident, ident, ty);
+            match new_ty.fields.get(field) {
+                None => report(
+                    rule,
+                    node,
+                    format!("stable field `{}.{}` was removed", old_ty.ident, ident),
+                    "stable-field-removed",
+                    vec![("type", old_ty.ident.clone()), ("field", ident.clone())],
+                ),
+                Some(new_field) if *new_field != (ident.clone(), ty.clone()) => report(
+                    rule,
+                    node,
+                    format!(
+                        "stable field `{}.{}` changed to `{}: {}`",
+                        old_ty.ident, ident, new_field.0, new_field.1
+                    ),
+                    "stable-field-changed",
+                    vec![
+                        ("type", old_ty.ident.clone()),
+                        ("field", ident.clone()),
+                        ("new_field", new_field.0.clone()),
+                        ("new_field_type", new_field.1.to_string()),
+                    ],
+                ),
+                Some(_) => {}
+            }
+        }
+        if rule_is_stable {
+            for (field, (ident, ty)) in &new_ty.fields {
+                if !old_ty.fields.contains_key(field)
+                    && marks.field(rule, field) != Stability::Unstable
+                {
+                    report(
+                        rule,
+                        format!("{}.{}: {}", new_ty.ident, ident, ty),
+                        format!(
+                            "field `{}` was added to stable type `{}`",
+                            ident, new_ty.ident
+                        ),
+                        "stable-type-field-added",
+                        vec![("type", new_ty.ident.clone()), ("field", ident.clone())],
+                    );
+                }
+            }
+        }
+    }
+    diagnostics
+}
+
+/// The type of a field in the generated AST (see `generate_ast`).
+#[derive(Clone, PartialEq, Eq)]
+enum AstType {
+    Span,
+    /// The type generated for a rule (boxed).
+    Node(IStr),
+    Option(Box<AstType>),
+    Vec(Box<AstType>),
+}
+
+/// How many times a field can be matched by a rule, ordered such that
+/// combining several matches of it can be done with `max`.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Multiplicity {
+    One,
+    Opt,
+    Many,
+}
+
+impl AstType {
+    fn has_nodes(&self) -> bool {
+        match self {
+            AstType::Span => false,
+            AstType::Node(_) => true,
+            AstType::Option(ty) | AstType::Vec(ty) => ty.has_nodes(),
+        }
+    }
+
+    fn with_multiplicity(self, multiplicity: Multiplicity) -> Self {
+        match (multiplicity, self) {
+            (Multiplicity::One, ty) | (Multiplicity::Opt, ty @ AstType::Option(_)) => ty,
+            (Multiplicity::Opt, ty) => AstType::Option(Box::new(ty)),
+            (Multiplicity::Many, ty) => AstType::Vec(Box::new(ty)),
+        }
+    }
+}
+
+/// The naming of the types and fields (and methods) in the generated AST.
+fn ast_naming(case: Case) -> Naming {
+    Naming {
+        case,
+        keywords: KeywordEscape::Raw,
+    }
+}
+
+struct AstCodegen<'a, Pat> {
+    cx: &'a Context<Pat>,
+    grammar: &'a Grammar,
+    core: &'a str,
+    alloc: &'a str,
+    /// The identifiers of the types generated for every rule.
+    types: Idents,
+    /// The identifiers of every rule, for the `Visit`/`Fold` methods.
+    methods: Idents,
+    /// The rules collapsed into type aliases (see `newtype_target`), and the
+    /// rules whose types they're aliases of.
+    aliases: IndexMap<IStr, IStr>,
+}
+
+impl<'a, Pat: Eq + Hash> AstCodegen<'a, Pat> {
+    fn new(cx: &'a Context<Pat>, grammar: &'a Grammar, options: &CodegenOptions) -> Self {
+        let (core, alloc) = if options.no_std {
+            ("::core", "::alloc")
+        } else {
+            ("::std", "::std")
+        };
+        let names = || grammar.rules().keys().map(|&name| &cx[name]);
+        let mut ast = AstCodegen {
+            cx,
+            grammar,
+            core,
+            alloc,
+            types: ast_naming(Case::UpperCamel)
+                .idents_avoiding(&["Span", "Visit", "Fold"], names()),
+            methods: ast_naming(Case::Snake).idents(names()),
+            aliases: IndexMap::new(),
+        };
+        if options.collapse_newtypes {
+            let mut aliases: IndexMap<_, _> = grammar
+                .rules()
+                .iter()
+                .filter(|(&name, _)| !options.keep_newtypes.iter().any(|keep| *keep == cx[name]))
+                .filter_map(|(&name, &rule)| Some((name, ast.newtype_target(rule)?)))
+                .collect();
+            // NOTE cycles of newtype rules (e.g. `A = B; B = A;`) would
+            // result in cycles of type aliases, so they're kept as structs.
+            let cyclic: Vec<_> = aliases
+                .keys()
+                .copied()
+                .filter(|&name| {
+                    let mut current = name;
+                    for _ in 0..aliases.len() {
+                        match aliases.get(&current) {
+                            Some(&target) if target == name => return true,
+                            Some(&target) => current = target,
+                            None => return false,
+                        }
+                    }
+                    false
+                })
+                .collect();
+            for name in cyclic {
+                aliases.shift_remove(&name);
+            }
+            ast.aliases = aliases;
+        }
+        ast
+    }
+
+    /// The rule `rule` (the body of a rule) only calls, if it's a "newtype"
+    /// rule, i.e. it's a `Call`, with at most one field around it.
+    fn newtype_target(&self, rule: RuleWithFields) -> Option<IStr> {
+        let cx = self.cx;
+        let no_nested_fields = match cx[rule.fields] {
+            Fields::Leaf(None) => true,
+            Fields::Leaf(Some(field)) => cx[field.sub] == Fields::Leaf(None),
+            Fields::Aggregate(_) => false,
+        };
+        match cx[rule.rule] {
+            Rule::Call(name) if no_nested_fields && self.grammar.rules().contains_key(&name) => {
+                Some(name)
+            }
+            _ => None,
+        }
+    }
+
+    fn method(&self, rule: IStr) -> &str {
+        let ident = self.methods.ident(&self.cx[rule]).unwrap();
+        // NOTE `visit_` (or `fold_`) is prepended, so keywords
+        // don't need escaping (and as suffixed identifiers are never raw,
+        // removing `r#` can't cause collisions).
+        ident.trim_start_matches("r#")
+    }
+
+    /// The type of (the input matched by) `rule`, if it was in a field.
+    fn ty(&self, rule: IRule) -> AstType {
+        match self.cx[rule] {
+            Rule::Call(name) if self.grammar.rules().contains_key(&name) => AstType::Node(name),
+            Rule::Opt(rule) => AstType::Option(Box::new(self.ty(rule))),
+            Rule::RepeatMany(elem, _) | Rule::RepeatMore(elem, _) => {
+                AstType::Vec(Box::new(self.ty(elem)))
+            }
+            Rule::WithFlag(_, _, rule) | Rule::Silent(rule) => self.ty(rule),
+            _ => AstType::Span,
+        }
+    }
+
+    /// The fields of the type generated for `rule`, with their names,
+    /// identifiers and types (taking into account their multiplicity).
+    fn rule_fields(&self, rule: RuleWithFields) -> Vec<(IStr, String, AstType)> {
+        let cx = self.cx;
+        let fields = self.fields(rule);
+        let field_idents = ast_naming(Case::Snake)
+            .idents_avoiding(&["span"], fields.keys().map(|&field| &cx[field]));
+        fields
+            .into_iter()
+            .map(|(field, (field_ty, multiplicity))| {
+                let ident = field_idents.ident(&cx[field]).unwrap().to_string();
+                (field, ident, field_ty.with_multiplicity(multiplicity))
+            })
+            .collect()
+    }
+
+    /// All the (outermost) fields in `rule`, with their types, and how many
+    /// times they can be matched.
+    fn fields(&self, rule: RuleWithFields) -> IndexMap<IStr, (AstType, Multiplicity)> {
+        let cx = self.cx;
+        match cx[rule.fields] {
+            Fields::Leaf(None) => return IndexMap::new(),
+            Fields::Leaf(Some(field)) => {
+                let mut fields = IndexMap::new();
+                fields.insert(field.name, (self.ty(rule.rule), Multiplicity::One));
+                return fields;
+            }
+            Fields::Aggregate(_) => {}
+        }
+        let children: Vec<_> = (rule.rule.children(cx).into_iter().enumerate())
+            .map(|(i, child)| {
+                self.fields(RuleWithFields {
+                    rule: child,
+                    fields: rule.fields.child(cx, i),
+                })
+            })
+            .collect();
+
+        // NOTE a field matched in more than one child is repeated,
+        // unless the children are `Or` cases (i.e. only one is matched).
+        let is_or = matches!(cx[rule.rule], Rule::Or(_));
+        let mut fields: IndexMap<_, (_, _)> = IndexMap::new();
+        for (name, (ty, multiplicity)) in children.iter().flatten() {
+            match fields.get_mut(name) {
+                None => {
+                    fields.insert(*name, (ty.clone(), *multiplicity));
+                }
+                Some((old_ty, old_multiplicity)) => {
+                    // Fields matching different things can only be kept as spans.
+                    if old_ty != ty {
+                        *old_ty = AstType::Span;
+                    }
+                    *old_multiplicity = if is_or {
+                        (*old_multiplicity).max(*multiplicity)
+                    } else {
+                        Multiplicity::Many
+                    };
+                }
+            }
+        }
+        let min = match cx[rule.rule] {
+            Rule::Opt(_) => Multiplicity::Opt,
+            Rule::RepeatMany(..) | Rule::RepeatMore(..) => Multiplicity::Many,
+            _ => Multiplicity::One,
+        };
+        for (name, (_, multiplicity)) in &mut fields {
+            let in_every_case = !is_or || children.iter().all(|case| case.contains_key(name));
+            if !in_every_case {
+                *multiplicity = (*multiplicity).max(Multiplicity::Opt);
+            }
+            *multiplicity = (*multiplicity).max(min);
+        }
+        fields
+    }
+
+    fn rust_type(&self, ty: &AstType) -> String {
+        match ty {
+            AstType::Span => "Span".to_string(),
+            AstType::Node(name) => format!(
+                "{}::boxed::Box<{}>",
+                self.alloc,
+                self.types.ident(&self.cx[*name]).unwrap()
+            ),
+            AstType::Option(ty) => {
+                format!("{}::option::Option<{}>", self.core, self.rust_type(ty))
+            }
+            AstType::Vec(ty) => format!("{}::vec::Vec<{}>", self.alloc, self.rust_type(ty)),
+        }
+    }
+
+    /// The statement visiting the nodes in `value` (a reference to a `ty`),
+    /// if there are any, with `depth` used to name variables.
+    fn visit(&self, ty: &AstType, value: &str, depth: usize) -> Option<String> {
+        let var = format!("x{}", depth);
+        match ty {
+            AstType::Span => None,
+            AstType::Node(name) => Some(format!("v.visit_{}({});", self.method(*name), value)),
+            AstType::Option(ty) => self
+                .visit(ty, &var, depth + 1)
+                .map(|stmt| format!("if let Some({}) = {} {{ {} }}", var, value, stmt)),
+            AstType::Vec(ty) => self
+                .visit(ty, &var, depth + 1)
+                .map(|stmt| format!("for {} in {} {{ {} }}", var, value, stmt)),
+        }
+    }
+
+    /// The expression folding the nodes in `value` (a `ty`), with `depth`
+    /// used to name variables.
+    fn fold(&self, ty: &AstType, value: &str, depth: usize) -> String {
+        let var = format!("x{}", depth);
+        match ty {
+            _ if !ty.has_nodes() => value.to_string(),
+            AstType::Node(name) => format!(
+                "{}::boxed::Box::new(f.fold_{}(*{}))",
+                self.alloc,
+                self.method(*name),
+                value
+            ),
+            AstType::Option(ty) => format!(
+                "{}.map(|{}| {})",
+                value,
+                var,
+                self.fold(ty, &var, depth + 1)
+            ),
+            AstType::Vec(ty) => format!(
+                "{}.into_iter().map(|{}| {}).collect()",
+                value,
+                var,
+                self.fold(ty, &var, depth + 1)
+            ),
+            AstType::Span => unreachable!(),
+        }
+    }
+}
+
+/// How to turn names from a grammar (of rules or fields) into Rust
+/// identifiers (see `Naming::idents`), e.g. `value-list` into `VALUE_LIST`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Naming {
+    pub case: Case,
+    pub keywords: KeywordEscape,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Case {
+    /// Keep the name as-is, other than replacing characters which can't
+    /// appear in identifiers with `_`.
+    Preserve,
+    /// `snake_case`.
+    Snake,
+    /// `UpperCamelCase`.
+    UpperCamel,
+    /// `SCREAMING_SNAKE_CASE`.
+    ScreamingSnake,
+}
+
+/// How to turn identifiers which are Rust keywords into valid ones.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeywordEscape {
+    /// Raw identifiers, e.g. `r#type` (except for `self`, `Self`, `super`
+    /// and `crate`, which can't be raw identifiers, and get `_` appended).
+    Raw,
+    /// Append `_`, e.g. `type_`.
+    Suffix,
+}
+
+/// All the keywords (including reserved ones) of the 2021 edition.
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in",
+    "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "Self", "static", "struct", "super", "trait", "true", "try", "type",
+    "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+impl Naming {
+    /// The identifier for `name`, before resolving collisions with the
+    /// identifiers of other names (see `idents`).
+    pub fn ident(&self, name: &str) -> String {
+        let mut ident = match self.case {
+            Case::Preserve => name
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect(),
+            Case::Snake => words(name)
+                .iter()
+                .map(|word| word.to_lowercase())
+                .collect::<Vec<_>>()
+                .join("_"),
+            Case::ScreamingSnake => words(name)
+                .iter()
+                .map(|word| word.to_uppercase())
+                .collect::<Vec<_>>()
+                .join("_"),
+            Case::UpperCamel => words(name)
+                .iter()
+                .map(|word| {
+                    let mut chars = word.chars();
+                    let first = chars.next().into_iter().flat_map(char::to_uppercase);
+                    first
+                        .chain(chars.flat_map(char::to_lowercase))
+                        .collect::<String>()
+                })
+                .collect(),
+        };
+        if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+            ident.insert(0, '_');
+        }
+        if ident == "_" || KEYWORDS.contains(&&ident[..]) {
+            let raw_allowed = !["_", "self", "Self", "super", "crate"].contains(&&ident[..]);
+            match self.keywords {
+                KeywordEscape::Raw if raw_allowed => ident.insert_str(0, "r#"),
+                _ => ident.push('_'),
+            }
+        }
+        ident
+    }
+
+    /// The identifiers for all of `names`, where names which would end up
+    /// with the same identifier get numbered suffixes (e.g. `a-b` and `a_b`
+    /// become `a_b` and `a_b_2`, or `AB` and `AB2` in `UpperCamel` case), in
+    /// the order they're given in.
+    pub fn idents<'a>(&self, names: impl IntoIterator<Item = &'a str>) -> Idents {
+        self.idents_avoiding(&[], names)
+    }
+
+    /// Like `idents`, but also avoiding the identifiers in `reserved`.
+    fn idents_avoiding<'a>(
+        &self,
+        reserved: &[&str],
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> Idents {
+        let mut idents = Idents::default();
+        for name in names {
+            if idents.idents.contains_key(name) {
+                continue;
+            }
+            let base = self.ident(name);
+            let mut ident = base.clone();
+            let mut i = 1;
+            while idents.names.contains_key(&ident) || reserved.contains(&&ident[..]) {
+                i += 1;
+                let sep = if self.case == Case::UpperCamel {
+                    ""
+                } else {
+                    "_"
+                };
+                ident = format!("{}{}{}", base.trim_start_matches("r#"), sep, i);
+            }
+            idents.idents.insert(name.to_string(), ident.clone());
+            idents.names.insert(ident, name.to_string());
+        }
+        idents
+    }
+}
+
+/// The words in `name`, split at non-alphanumeric characters, and case
+/// changes (e.g. `HTTPServer-list` has the words `HTTP`, `Server`, `list`).
+fn words(name: &str) -> Vec<String> {
+    let chars: Vec<char> = name.chars().collect();
+    let mut words = vec![];
+    let mut word = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        let prev = if i > 0 { Some(chars[i - 1]) } else { None };
+        let next = chars.get(i + 1).copied();
+        let boundary = c.is_uppercase()
+            && prev.is_some_and(|prev| {
+                prev.is_lowercase()
+                    || prev.is_ascii_digit()
+                    || (prev.is_uppercase() && next.is_some_and(char::is_lowercase))
+            });
+        if boundary && !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// The identifiers picked by `Naming::idents`, for a set of names, which
+/// are all distinct, so names can also be looked up by their identifiers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Idents {
+    idents: IndexMap<String, String>,
+    names: IndexMap<String, String>,
+}
+
+impl Idents {
+    pub fn ident(&self, name: &str) -> Option<&str> {
+        self.idents.get(name).map(|ident| &ident[..])
+    }
+
+    pub fn name(&self, ident: &str) -> Option<&str> {
+        self.names.get(ident).map(|name| &name[..])
+    }
+
+    /// All the `(name, identifier)` pairs, in the order the names were given in.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.idents
+            .iter()
+            .map(|(name, ident)| (&name[..], &ident[..]))
+    }
+}
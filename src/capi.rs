@@ -0,0 +1,406 @@
+//! C API, for embedding the grammar engine in non-Rust languages (see
+//! `include/grammer.h`, generated with `cbindgen`, from `cbindgen.toml`).
+//!
+//! The crate has to be built as a C library for this to be usable, e.g. with
+//! `cargo rustc --release --features capi --crate-type cdylib`.
+//!
+//! All strings passed in are UTF-8, as a pointer and a length (so they don't
+//! have to be NUL-terminated), while all strings returned are NUL-terminated,
+//! and must be freed with `grammer_string_free`. Errors are reported through
+//! an (optional) `error` out-parameter, which is set to `NULL` on success.
+//!
+//! Panics are caught (and reported as errors), instead of unwinding into C.
+
+use crate::context::Context;
+use crate::diagnostics::{self, Severity};
+use crate::ids::FrozenGrammar;
+use crate::interpreter::{Derivation, FieldMatch, Interpreter};
+use crate::scannerless::Pat;
+use std::ffi::{c_char, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::str;
+
+/// A grammar, along with the `Context` its rules were interned in.
+pub struct GrammerGrammar {
+    cx: Context<Pat<String>>,
+    grammar: FrozenGrammar,
+}
+
+/// The result of a successful parse (see `grammer_parse`), which owns all
+/// of its nodes.
+pub struct GrammerTree {
+    root: Derivation,
+}
+
+/// A node of a `GrammerTree`, only valid as long as the tree is.
+#[repr(transparent)]
+pub struct GrammerNode(Derivation);
+
+/// A named field (e.g. a pest `#tag`) matched in a node (or in another
+/// field), only valid as long as the tree is.
+#[repr(transparent)]
+pub struct GrammerField(FieldMatch);
+
+/// The `Interpreter::recursion_limit` used by `grammer_parse` by default, as
+/// overflowing the stack (e.g. on deeply nested input) aborts the process.
+const DEFAULT_RECURSION_LIMIT: usize = 512;
+
+/// The `Interpreter::step_limit` used by `grammer_parse` by default, to bound
+/// the time spent on any one input (which can be exponential otherwise).
+const DEFAULT_STEP_LIMIT: usize = 10_000_000;
+
+fn c_string(s: &str) -> *mut c_char {
+    // NOTE C strings can't contain NULs, so they're escaped instead.
+    CString::new(s.replace('\0', "\\0")).unwrap().into_raw()
+}
+
+/// Set `*error` (if `error` isn't `NULL`), to `NULL` on success, and
+/// otherwise to the error message.
+unsafe fn set_error(error: *mut *mut c_char, result: Result<(), String>) {
+    if !error.is_null() {
+        *error = match result {
+            Ok(()) => ptr::null_mut(),
+            Err(message) => c_string(&message),
+        };
+    }
+}
+
+unsafe fn derivation<'a>(node: *const GrammerNode) -> &'a Derivation {
+    &(*node).0
+}
+
+unsafe fn field_match<'a>(field: *const GrammerField) -> &'a FieldMatch {
+    &(*field).0
+}
+
+fn field_ptr(fields: &[FieldMatch], index: usize) -> *const GrammerField {
+    fields.get(index).map_or(ptr::null(), |field| {
+        field as *const FieldMatch as *const GrammerField
+    })
+}
+
+unsafe fn str_arg<'a>(ptr: *const c_char, len: usize) -> Result<&'a str, String> {
+    if ptr.is_null() {
+        return if len == 0 {
+            Ok("")
+        } else {
+            Err("unexpected NULL string".to_string())
+        };
+    }
+    str::from_utf8(slice::from_raw_parts(ptr as *const u8, len))
+        .map_err(|error| format!("invalid UTF-8: {}", error))
+}
+
+/// Run `f`, catching panics (and turning them into errors), and reporting
+/// errors through `error`, returning `default` for them.
+unsafe fn ffi_try<T>(
+    error: *mut *mut c_char,
+    default: T,
+    f: impl FnOnce() -> Result<T, String>,
+) -> T {
+    let result = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        Err(format!("panicked: {}", message))
+    });
+    match result {
+        Ok(x) => {
+            set_error(error, Ok(()));
+            x
+        }
+        Err(message) => {
+            set_error(error, Err(message));
+            default
+        }
+    }
+}
+
+/// Create a grammar from the source of a pest grammar (see `pest::import`),
+/// returning `NULL` on errors (including `Grammar::validate` errors).
+///
+/// # Safety
+///
+/// `src` must point to `src_len` bytes, and `error` must be `NULL`
+/// or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_grammar_from_pest(
+    src: *const c_char,
+    src_len: usize,
+    error: *mut *mut c_char,
+) -> *mut GrammerGrammar {
+    ffi_try(error, ptr::null_mut(), || {
+        let src = str_arg(src, src_len)?;
+        let cx = Context::new();
+        let grammar = crate::pest::import(&cx, src)
+            .map_err(|error| error.to_string())?
+            .grammar;
+        let errors: Vec<_> = grammar
+            .validate(&cx)
+            .into_iter()
+            .filter(|d| d.severity() == Severity::Error)
+            .map(|d| d.to_string())
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+        let grammar = grammar.freeze(&cx);
+        Ok(Box::into_raw(Box::new(GrammerGrammar { cx, grammar })))
+    })
+}
+
+/// All the diagnostics for a grammar (see `Grammar::validate`), including
+/// warnings, as a JSON array (see `diagnostics::to_json`), or `NULL` on
+/// errors (in which case `*error` is also set).
+///
+/// # Safety
+///
+/// `grammar` must have been returned by `grammer_grammar_from_pest` (and
+/// not freed yet), and `error` must be `NULL` or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_grammar_validate(
+    grammar: *const GrammerGrammar,
+    error: *mut *mut c_char,
+) -> *mut c_char {
+    ffi_try(error, ptr::null_mut(), || {
+        let GrammerGrammar { cx, grammar } = &*grammar;
+        Ok(c_string(&diagnostics::to_json(&grammar.validate(cx))))
+    })
+}
+
+/// Parse all of `input` with the rule named `rule`, returning `NULL` if it
+/// doesn't match, or on errors (in which case `*error` is also set).
+///
+/// The nesting of rules and the number of rule calls are limited by
+/// `recursion_limit` and `step_limit` (see `Interpreter::recursion_limit`
+/// and `Interpreter::step_limit`), `0` meaning a default limit for either.
+///
+/// # Safety
+///
+/// `grammar` must be valid (see `grammer_grammar_validate`), `rule` and
+/// `input` must point to `rule_len` and `input_len` bytes, respectively,
+/// and `error` must be `NULL` or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_parse(
+    grammar: *const GrammerGrammar,
+    rule: *const c_char,
+    rule_len: usize,
+    input: *const c_char,
+    input_len: usize,
+    recursion_limit: usize,
+    step_limit: usize,
+    error: *mut *mut c_char,
+) -> *mut GrammerTree {
+    ffi_try(error, ptr::null_mut(), || {
+        let GrammerGrammar { cx, grammar } = &*grammar;
+        let rule = str_arg(rule, rule_len)?;
+        let input = str_arg(input, input_len)?;
+        if !grammar.rules().contains_key(&cx.intern(rule)) {
+            return Err(format!("no rule named `{}`", rule));
+        }
+        let mut interpreter = Interpreter::new(cx, grammar);
+        interpreter.recursion_limit = Some(match recursion_limit {
+            0 => DEFAULT_RECURSION_LIMIT,
+            limit => limit,
+        });
+        interpreter.step_limit = Some(match step_limit {
+            0 => DEFAULT_STEP_LIMIT,
+            limit => limit,
+        });
+        let derivation = interpreter
+            .explain(cx.intern(rule), input)
+            .map_err(|error| error.to_string())?;
+        Ok(derivation.map_or(ptr::null_mut(), |root| {
+            Box::into_raw(Box::new(GrammerTree { root }))
+        }))
+    })
+}
+
+/// # Safety
+///
+/// `tree` must have been returned by `grammer_parse` (and not freed yet).
+#[no_mangle]
+pub unsafe extern "C" fn grammer_tree_root(tree: *const GrammerTree) -> *const GrammerNode {
+    &(*tree).root as *const Derivation as *const GrammerNode
+}
+
+/// The name of the rule a node matched, which is only valid as long as the
+/// tree is (and not NUL-terminated, its length is written to `*len`).
+///
+/// # Safety
+///
+/// `node` must be a node of a valid tree, and `len` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_node_rule(
+    node: *const GrammerNode,
+    len: *mut usize,
+) -> *const c_char {
+    let rule = &derivation(node).rule;
+    *len = rule.len();
+    rule.as_ptr() as *const c_char
+}
+
+/// The start of the part of the input matched by a node (in bytes).
+///
+/// # Safety
+///
+/// `node` must be a node of a valid tree.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_node_start(node: *const GrammerNode) -> usize {
+    derivation(node).span.start
+}
+
+/// The end of the part of the input matched by a node (in bytes).
+///
+/// # Safety
+///
+/// `node` must be a node of a valid tree.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_node_end(node: *const GrammerNode) -> usize {
+    derivation(node).span.end
+}
+
+/// # Safety
+///
+/// `node` must be a node of a valid tree.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_node_child_count(node: *const GrammerNode) -> usize {
+    derivation(node).children.len()
+}
+
+/// The child at `index` of a node, or `NULL` if it doesn't have that many.
+///
+/// # Safety
+///
+/// `node` must be a node of a valid tree.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_node_child(
+    node: *const GrammerNode,
+    index: usize,
+) -> *const GrammerNode {
+    derivation(node)
+        .children
+        .get(index)
+        .map_or(ptr::null(), |child| {
+            child as *const Derivation as *const GrammerNode
+        })
+}
+
+/// The number of fields matched by a node (but not by its children).
+///
+/// # Safety
+///
+/// `node` must be a node of a valid tree.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_node_field_count(node: *const GrammerNode) -> usize {
+    derivation(node).fields.len()
+}
+
+/// The field at `index` of a node (in the order they were matched in),
+/// or `NULL` if it doesn't have that many.
+///
+/// # Safety
+///
+/// `node` must be a node of a valid tree.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_node_field(
+    node: *const GrammerNode,
+    index: usize,
+) -> *const GrammerField {
+    field_ptr(&derivation(node).fields, index)
+}
+
+/// The name of a field, which is only valid as long as the tree is (and
+/// not NUL-terminated, its length is written to `*len`).
+///
+/// # Safety
+///
+/// `field` must be a field of a valid tree, and `len` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_field_name(
+    field: *const GrammerField,
+    len: *mut usize,
+) -> *const c_char {
+    let name = &field_match(field).name;
+    *len = name.len();
+    name.as_ptr() as *const c_char
+}
+
+/// The start of the part of the input matched by a field (in bytes).
+///
+/// # Safety
+///
+/// `field` must be a field of a valid tree.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_field_start(field: *const GrammerField) -> usize {
+    field_match(field).span.start
+}
+
+/// The end of the part of the input matched by a field (in bytes).
+///
+/// # Safety
+///
+/// `field` must be a field of a valid tree.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_field_end(field: *const GrammerField) -> usize {
+    field_match(field).span.end
+}
+
+/// The number of fields nested in a field.
+///
+/// # Safety
+///
+/// `field` must be a field of a valid tree.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_field_field_count(field: *const GrammerField) -> usize {
+    field_match(field).fields.len()
+}
+
+/// The field at `index` nested in a field, or `NULL` if it doesn't have
+/// that many.
+///
+/// # Safety
+///
+/// `field` must be a field of a valid tree.
+#[no_mangle]
+pub unsafe extern "C" fn grammer_field_field(
+    field: *const GrammerField,
+    index: usize,
+) -> *const GrammerField {
+    field_ptr(&field_match(field).fields, index)
+}
+
+/// # Safety
+///
+/// `grammar` must be `NULL`, or returned by `grammer_grammar_from_pest`
+/// (and not freed yet).
+#[no_mangle]
+pub unsafe extern "C" fn grammer_grammar_free(grammar: *mut GrammerGrammar) {
+    if !grammar.is_null() {
+        drop(Box::from_raw(grammar));
+    }
+}
+
+/// # Safety
+///
+/// `tree` must be `NULL`, or returned by `grammer_parse` (and not freed yet).
+#[no_mangle]
+pub unsafe extern "C" fn grammer_tree_free(tree: *mut GrammerTree) {
+    if !tree.is_null() {
+        drop(Box::from_raw(tree));
+    }
+}
+
+/// # Safety
+///
+/// `s` must be `NULL`, or a string returned by this API (and not freed yet).
+#[no_mangle]
+pub unsafe extern "C" fn grammer_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
@@ -0,0 +1,105 @@
+//! Stand-ins for the parts of `std` used by the crate which aren't in `core`
+//! or `alloc`, for building without the `std` feature (i.e. `no_std`).
+//!
+//! Without `std`, nothing here (and so neither `Context` nor `Grammar`) is
+//! `Sync`, and hashing (which can't be randomly seeded) uses FNV-1a.
+
+/// The parts of the `std` prelude which aren't in the `core` one.
+pub(crate) mod prelude {
+    pub(crate) use alloc::borrow::ToOwned;
+    pub(crate) use alloc::boxed::Box;
+    pub(crate) use alloc::format;
+    pub(crate) use alloc::string::{String, ToString};
+    pub(crate) use alloc::vec;
+    pub(crate) use alloc::vec::Vec;
+}
+
+#[cfg(feature = "std")]
+pub(crate) use elsa::sync::FrozenVec;
+#[cfg(feature = "std")]
+pub(crate) use indexmap::{IndexMap, IndexSet};
+#[cfg(feature = "std")]
+pub(crate) use std::collections::{HashMap, HashSet};
+#[cfg(feature = "std")]
+pub(crate) use std::sync::{OnceLock, RwLock};
+
+#[cfg(not(feature = "std"))]
+pub(crate) use self::no_std::*;
+
+#[cfg(not(feature = "std"))]
+mod no_std {
+    use super::prelude::*;
+    use crate::Fnv1a;
+    use core::cell::{Cell, OnceCell, Ref, RefCell, RefMut};
+    use core::convert::Infallible;
+    use core::hash::BuildHasherDefault;
+    use core::ops::Deref;
+
+    pub(crate) use core::cell::OnceCell as OnceLock;
+
+    type Fnv1aBuilder = BuildHasherDefault<Fnv1a>;
+
+    pub(crate) type HashMap<K, V> = hashbrown::HashMap<K, V, Fnv1aBuilder>;
+    pub(crate) type HashSet<T> = hashbrown::HashSet<T, Fnv1aBuilder>;
+    pub(crate) type IndexMap<K, V> = indexmap::IndexMap<K, V, Fnv1aBuilder>;
+    pub(crate) type IndexSet<T> = indexmap::IndexSet<T, Fnv1aBuilder>;
+
+    /// Like `std::sync::RwLock`, but without any actual locking, as it's
+    /// never shared between threads (not being `Sync`).
+    pub(crate) struct RwLock<T>(RefCell<T>);
+
+    impl<T> RwLock<T> {
+        pub(crate) fn new(value: T) -> Self {
+            RwLock(RefCell::new(value))
+        }
+
+        pub(crate) fn read(&self) -> Result<Ref<'_, T>, Infallible> {
+            Ok(self.0.borrow())
+        }
+
+        pub(crate) fn write(&self) -> Result<RefMut<'_, T>, Infallible> {
+            Ok(self.0.borrow_mut())
+        }
+    }
+
+    /// Like `elsa::sync::FrozenVec`, an append-only `Vec` which can be pushed
+    /// to through a shared reference, as its elements never move.
+    pub(crate) struct FrozenVec<T> {
+        // NOTE segment `i` holds `2^i` elements, and is only allocated once
+        // all the previous segments are full, instead of reallocating them.
+        segments: [OnceCell<Box<[OnceCell<T>]>>; usize::BITS as usize],
+        len: Cell<usize>,
+    }
+
+    impl<T: Deref> FrozenVec<T> {
+        pub(crate) fn new() -> Self {
+            FrozenVec {
+                segments: [(); usize::BITS as usize].map(|()| OnceCell::new()),
+                len: Cell::new(0),
+            }
+        }
+
+        /// The segment holding the element at `index`, and its index therein.
+        fn locate(index: usize) -> (usize, usize) {
+            let segment = (usize::BITS - 1 - (index + 1).leading_zeros()) as usize;
+            (segment, index + 1 - (1 << segment))
+        }
+
+        pub(crate) fn len(&self) -> usize {
+            self.len.get()
+        }
+
+        pub(crate) fn push(&self, value: T) {
+            let (segment, i) = Self::locate(self.len.get());
+            let segment = self.segments[segment]
+                .get_or_init(|| (0..1 << segment).map(|_| OnceCell::new()).collect());
+            assert!(segment[i].set(value).is_ok());
+            self.len.set(self.len.get() + 1);
+        }
+
+        pub(crate) fn get(&self, index: usize) -> Option<&T::Target> {
+            let (segment, i) = Self::locate(index);
+            Some(&**self.segments[segment].get()?[i].get()?)
+        }
+    }
+}
@@ -1,13 +1,15 @@
+use crate::compat::{FrozenVec, HashMap, RwLock};
 use crate::rule::{Fields, Rule};
-use elsa::FrozenVec;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::convert::TryInto;
-use std::hash::Hash;
-use std::rc::Rc;
+use alloc::sync::Arc;
+use core::convert::TryInto;
+use core::hash::Hash;
 
 /// Context object with global resources for working with grammar,
 /// such as interners.
+///
+/// With the `std` feature, it's `Send + Sync` (if `Pat` is), so it can be
+/// shared between threads, e.g. for parsing with the same (frozen) grammar
+/// concurrently.
 pub struct Context<Pat> {
     interners: Interners<Pat>,
 }
@@ -34,27 +36,34 @@ impl<Pat: Eq + Hash> Context<Pat> {
 
 struct Interner<T: ?Sized> {
     // FIXME(Manishearth/elsa#6) switch to `FrozenIndexSet` when available.
-    map: RefCell<HashMap<Rc<T>, u32>>,
-    vec: FrozenVec<Rc<T>>,
+    map: RwLock<HashMap<Arc<T>, u32>>,
+    vec: FrozenVec<Arc<T>>,
 }
 
 impl<T: ?Sized + Eq + Hash> Default for Interner<T> {
     fn default() -> Self {
         Interner {
-            map: RefCell::new(HashMap::default()),
+            map: RwLock::new(HashMap::default()),
             vec: FrozenVec::new(),
         }
     }
 }
 
 impl<T: ?Sized + Eq + Hash> Interner<T> {
-    fn intern(&self, value: impl AsRef<T> + Into<Rc<T>>) -> u32 {
-        if let Some(&i) = self.map.borrow().get(value.as_ref()) {
+    fn intern(&self, value: impl AsRef<T> + Into<Arc<T>>) -> u32 {
+        if let Some(&i) = self.map.read().unwrap().get(value.as_ref()) {
+            return i;
+        }
+        // NOTE the write lock is held while pushing to `vec`, so that
+        // another thread can't intern the same value (or use the same index),
+        // after the check above.
+        let mut map = self.map.write().unwrap();
+        if let Some(&i) = map.get(value.as_ref()) {
             return i;
         }
         let value = value.into();
         let next = self.vec.len().try_into().unwrap();
-        self.map.borrow_mut().insert(value.clone(), next);
+        map.insert(value.clone(), next);
         self.vec.push(value);
         next
     }
@@ -79,11 +88,11 @@ macro_rules! interners {
             #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
             pub struct $name(u32);
 
-            impl<Pat> std::ops::Index<$name> for Context<Pat> {
+            impl<Pat> core::ops::Index<$name> for Context<Pat> {
                 type Output = $ty;
 
                 fn index(&self, interned: $name) -> &Self::Output {
-                    &self.interners.$name.vec[interned.0 as usize]
+                    self.interners.$name.vec.get(interned.0 as usize).unwrap()
                 }
             }
         )*
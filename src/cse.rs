@@ -0,0 +1,203 @@
+//! Common subexpression extraction: factoring parts of rules repeated across
+//! the grammar (e.g. the same delimited list, written out in many rules) into
+//! shared helper rules (see `Grammar::extract_common`), i.e. the inverse of
+//! inlining them.
+
+use crate::compat::prelude::*;
+use crate::compat::IndexMap;
+use crate::context::{Context, IRule, IStr};
+use crate::rewrite::Rewrite;
+use crate::rule::{call, Atomicity, Fields, Modifiers, Rule, RuleWithFields};
+use crate::synthetic::{self, Kind, Namer};
+use crate::Grammar;
+use core::hash::Hash;
+
+/// A helper rule created by `Grammar::extract_common`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Helper {
+    pub name: IStr,
+    /// How many times the helper's body was repeated (and is now called).
+    pub occurrences: usize,
+    /// How many nodes (see `size`) were saved by extracting it.
+    pub saved: usize,
+}
+
+/// What `Grammar::extract_common` did, and how much smaller the grammar got.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// In the order they were extracted (largest savings first).
+    pub helpers: Vec<Helper>,
+    /// The total size of all the rules (see `size`), before extraction.
+    pub size_before: usize,
+    pub size_after: usize,
+}
+
+/// The number of nodes in `rule` (with `Call`s counting as one node).
+pub fn size<Pat>(cx: &Context<Pat>, rule: IRule) -> usize {
+    1 + rule
+        .children(cx)
+        .into_iter()
+        .map(|child| size(cx, child))
+        .sum::<usize>()
+}
+
+fn grammar_size<Pat>(cx: &Context<Pat>, grammar: &Grammar) -> usize {
+    grammar
+        .rules()
+        .values()
+        .map(|rule| size(cx, rule.rule))
+        .sum()
+}
+
+pub(crate) fn extract_common<Pat: Eq + Hash>(
+    cx: &Context<Pat>,
+    mut grammar: Grammar,
+    min_size: usize,
+) -> (Grammar, Report) {
+    let mut report = Report {
+        size_before: grammar_size(cx, &grammar),
+        ..Report::default()
+    };
+    let mut namer = Namer::new(&grammar);
+    loop {
+        // Count every (extractable) part of every rule, along with the first
+        // rule it was found in, to name the helper after.
+        // NOTE parts are only shared between rules with the same `ignore_case`
+        // and atomicity, as the helper can only have one of each, and calling
+        // it from both atomic and non-atomic rules would make it non-atomic
+        // (see `Grammar::atomic_rules`).
+        let atomic_rules = grammar.atomic_rules(cx);
+        let key = |name: IStr| {
+            (
+                grammar.modifiers(name).ignore_case,
+                atomic_rules.contains(&name),
+            )
+        };
+        let mut candidates: IndexMap<(IRule, (bool, bool)), (IStr, usize)> = IndexMap::default();
+        for (&name, &rule) in grammar.rules() {
+            let mut found = vec![];
+            collect(cx, rule, true, &mut found);
+            for part in found {
+                candidates.entry((part, key(name))).or_insert((name, 0)).1 += 1;
+            }
+        }
+
+        // NOTE every occurrence becomes a `Call` (one node), and the
+        // helper needs one copy, so those aren't savings.
+        let mut best = None;
+        for (&(part, part_key), &(parent, occurrences)) in &candidates {
+            let part_size = size(cx, part);
+            let savings = ((occurrences - 1) * part_size).saturating_sub(occurrences);
+            if part_size < min_size || savings == 0 {
+                continue;
+            }
+            if best.is_none_or(|(_, _, _, _, best_savings)| savings > best_savings) {
+                best = Some((part, part_key, parent, occurrences, savings));
+            }
+        }
+        let (part, part_key, parent, occurrences, saved) = match best {
+            Some(best) => best,
+            None => break,
+        };
+
+        let name = namer.fresh(
+            cx,
+            synthetic::Origin {
+                parent,
+                kind: Kind::Shared,
+            },
+        );
+        let body = RuleWithFields {
+            rule: part,
+            fields: cx.intern(Fields::Leaf(None)),
+        };
+        let rewrite = Rewrite::new(cx, &[], body, call(&cx[name]).finish(cx));
+        let rule_keys: IndexMap<_, _> = grammar
+            .rules()
+            .keys()
+            .map(|&name| (name, key(name)))
+            .collect();
+        let rules: Vec<_> = grammar.rules().iter().map(|(&n, &r)| (n, r)).collect();
+        for (rule_name, rule) in rules {
+            // NOTE rules whose whole body is the helper's are left
+            // as-is, as replacing them would only add a `Call` to them.
+            if rule.rule != part && rule_keys[&rule_name] == part_key {
+                grammar.define(rule_name, rewrite.apply(cx, rule));
+            }
+        }
+        grammar.define(name, body);
+        // NOTE helpers are silent, so derivations (and fields) stay
+        // the same, and have the `ignore_case` of the rules they came from
+        // (as it's not inherited through `Call`s), and are atomic if those
+        // are, while keeping their derivations (like pest's `$`).
+        let (ignore_case, atomic) = part_key;
+        grammar.set_modifiers(
+            name,
+            Modifiers {
+                silent: true,
+                ignore_case,
+                atomicity: if atomic {
+                    Atomicity::CompoundAtomic
+                } else {
+                    Atomicity::Inherited
+                },
+                ..Modifiers::default()
+            },
+        );
+        report.helpers.push(Helper {
+            name,
+            occurrences,
+            saved,
+        });
+    }
+    grammar.synthetic.extend(namer.picked);
+    report.size_after = grammar_size(cx, &grammar);
+    (grammar, report)
+}
+
+/// Collect every part of `rule` which can be extracted into a helper, i.e.
+/// which isn't a leaf (e.g. a `Call`), or the whole rule (if `top`), and
+/// which has no fields inside it (fields around it stay on the `Call`).
+fn collect<Pat: Eq + Hash>(
+    cx: &Context<Pat>,
+    rule: RuleWithFields,
+    top: bool,
+    found: &mut Vec<IRule>,
+) {
+    if let Fields::Leaf(Some(field)) = cx[rule.fields] {
+        return collect(
+            cx,
+            RuleWithFields {
+                rule: rule.rule,
+                fields: field.sub,
+            },
+            top,
+            found,
+        );
+    }
+    let extractable = match cx[rule.rule] {
+        // NOTE `Capture`s aren't rewritten (see `rewrite::Rewriter`).
+        Rule::Concat(_)
+        | Rule::Or(_)
+        | Rule::Opt(_)
+        | Rule::RepeatMany(..)
+        | Rule::RepeatMore(..)
+        | Rule::WithFlag(..)
+        | Rule::Silent(_) => true,
+        _ => false,
+    };
+    if extractable && !top && cx[rule.fields] == Fields::Leaf(None) {
+        found.push(rule.rule);
+    }
+    for (i, child) in rule.rule.children(cx).into_iter().enumerate() {
+        collect(
+            cx,
+            RuleWithFields {
+                rule: child,
+                fields: rule.fields.child(cx, i),
+            },
+            false,
+            found,
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//! Structured diagnostics (e.g. from `Grammar::validate`), with stable codes,
+//! meant to be consumed by tools (e.g. as JSON), not just read by humans.
+//!
+//! Messages are in English, but can be localized through a `Catalog` (see
+//! `Diagnostic::localize`, and `Localize`, implemented by every error, e.g.
+//! `interpreter::Failure` for parse errors), by their (stable) message ids
+//! and arguments.
+
+use crate::compat::prelude::*;
+use crate::compat::IndexMap;
+use crate::input::LineColumnRange;
+use crate::source_map::SourceFile;
+use core::fmt;
+use core::ops::Range;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// Stable codes for every kind of diagnostic (never reused, even if
+/// the diagnostic they were assigned to is removed).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Code {
+    /// G0001: `Call` to a rule not defined in the grammar.
+    UndefinedRule,
+    /// G0002: `Opt` of a rule which can (or might) match empty.
+    EmptyOpt,
+    /// G0003: repeat element which can (or might) match empty.
+    EmptyRepeat,
+    /// G0004: `exhaustive` rule missing some of the tokens it should cover
+    /// (see `tokens::check_exhaustive`).
+    NonExhaustive,
+    /// G0005: stable part of a generated AST changed between versions of
+    /// the grammar (see `build::check_stability`).
+    StableAstChanged,
+    /// G0006: `Or` cases which can start the same way (or both be empty),
+    /// see `dispatch::ambiguity_lints` (as are G0007 and G0008).
+    OverlappingCases,
+    /// G0007: element which can be empty next to a repeat, both of which
+    /// can start the same way, e.g. `"a"* "a"?`.
+    NullableBesideRepeat,
+    /// G0008: repeat separator which can be empty.
+    NullableSeparator,
+}
+
+impl Code {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Code::UndefinedRule => "G0001",
+            Code::EmptyOpt => "G0002",
+            Code::EmptyRepeat => "G0003",
+            Code::NonExhaustive => "G0004",
+            Code::StableAstChanged => "G0005",
+            Code::OverlappingCases => "G0006",
+            Code::NullableBesideRepeat => "G0007",
+            Code::NullableSeparator => "G0008",
+        }
+    }
+
+    pub fn severity(self) -> Severity {
+        match self {
+            Code::UndefinedRule | Code::EmptyOpt | Code::EmptyRepeat | Code::StableAstChanged => {
+                Severity::Error
+            }
+            Code::NonExhaustive
+            | Code::OverlappingCases
+            | Code::NullableBesideRepeat
+            | Code::NullableSeparator => Severity::Warning,
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The arguments of a message (e.g. `Diagnostic::args`), by name.
+pub type Args = Vec<(&'static str, String)>;
+
+/// A source of localized messages, looked up by message id (e.g. `Diagnostic::message_id`)
+/// and arguments (e.g. `Diagnostic::args`), in the style of Fluent.
+///
+/// NOTE syntax errors from importers (`pest::ImportError` and `ebnf::ImportError`)
+/// only have one message id each, with the specific error passed as an English
+/// `message` argument, as are errors from other libraries (e.g. an `io::Error`),
+/// and from user-provided loaders (`reload::ReloadError::Load`).
+pub trait Catalog {
+    /// The localized message `id`, with `args` interpolated into it, or
+    /// `None` for the default (English) message to be used instead.
+    fn message(&self, id: &str, args: &[(&'static str, String)]) -> Option<String>;
+}
+
+/// Errors which can be localized by a `Catalog`, with their `Display` output
+/// being the default (English) message.
+pub trait Localize: fmt::Display {
+    /// The stable id of the message (e.g. `"undefined-rule"`).
+    fn message_id(&self) -> &'static str;
+
+    /// The values in the message, e.g. the `name` of an undefined rule.
+    fn args(&self) -> Args;
+
+    /// Like `to_string`, but localized by `catalog`, if it has the message.
+    fn localize(&self, catalog: &dyn Catalog) -> String {
+        catalog
+            .message(self.message_id(), &self.args())
+            .unwrap_or_else(|| self.to_string())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub code: Code,
+    /// The name of the rule the diagnostic was found in.
+    pub rule: String,
+    /// The part of the rule the diagnostic is about (see `IRule::node_desc`).
+    pub node: String,
+    pub message: String,
+    pub help: Option<String>,
+    /// The stable id of `message` (e.g. `"undefined-rule"`), more specific
+    /// than `code` (as one code can have several messages), with `help`
+    /// (if any) having the same id, followed by `.help`.
+    pub message_id: &'static str,
+    /// The values in `message` (and `help`), e.g. the `name` of an undefined rule.
+    pub args: Args,
+}
+
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        self.code.severity()
+    }
+
+    /// A copy of this diagnostic with its `message` and `help` localized
+    /// by `catalog` (keeping the English ones it doesn't have).
+    pub fn localize(&self, catalog: &dyn Catalog) -> Diagnostic {
+        let message = catalog.message(self.message_id, &self.args);
+        let help = self.help.as_ref().map(|help| {
+            let id = format!("{}.help", self.message_id);
+            catalog
+                .message(&id, &self.args)
+                .unwrap_or_else(|| help.clone())
+        });
+        Diagnostic {
+            message: message.unwrap_or_else(|| self.message.clone()),
+            help,
+            ..self.clone()
+        }
+    }
+
+    /// Serialize to a JSON object, with `code`, `severity`, `rule`, `node`,
+    /// `message`, `help` and `message_id` keys (all strings, `help` can be
+    /// `null`), and an `args` key (an object with string values).
+    pub fn to_json(&self) -> String {
+        let args: Vec<_> = self
+            .args
+            .iter()
+            .map(|(name, value)| format!("{}:{}", json_str(name), json_str(value)))
+            .collect();
+        format!(
+            "{{\"code\":{},\"severity\":{},\"rule\":{},\"node\":{},\"message\":{},\"help\":{},\
+             \"message_id\":{},\"args\":{{{}}}}}",
+            json_str(self.code.as_str()),
+            json_str(self.severity().as_str()),
+            json_str(&self.rule),
+            json_str(&self.node),
+            json_str(&self.message),
+            self.help.as_deref().map_or("null".to_string(), json_str),
+            json_str(self.message_id),
+            args.join(","),
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}[{}]: {}",
+            self.severity().as_str(),
+            self.code,
+            self.message
+        )?;
+        write!(f, "  in rule `{}`: `{}`", self.rule, self.node)?;
+        if let Some(help) = &self.help {
+            write!(f, "\n  help: {}", help)?;
+        }
+        Ok(())
+    }
+}
+
+/// The names among `candidates` within a small edit distance of `name`
+/// (e.g. typos), closest first.
+pub(crate) fn similar_names<'a>(
+    name: &str,
+    candidates: impl Iterator<Item = &'a str>,
+) -> Vec<&'a str> {
+    // NOTE roughly one typo per 3 characters, like rustc.
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut similar: Vec<_> = candidates
+        .filter_map(|candidate| {
+            let distance = edit_distance(name, candidate);
+            if distance <= max_distance {
+                Some((distance, candidate))
+            } else {
+                None
+            }
+        })
+        .collect();
+    similar.sort();
+    similar.truncate(3);
+    similar
+        .into_iter()
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// Levenshtein distance, with case changes counting as half an edit
+/// (rounded down), as e.g. `expr` vs `Expr` is a common mistake.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Costs are doubled, to allow for the half-cost case changes.
+    let mut prev: Vec<usize> = (0..=b.len()).map(|j| j * 2).collect();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        cur[0] = (i + 1) * 2;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = if ca == cb {
+                0
+            } else if ca.to_lowercase().eq(cb.to_lowercase()) {
+                1
+            } else {
+                2
+            };
+            cur[j + 1] = (prev[j] + substitute).min(prev[j + 1] + 2).min(cur[j] + 2);
+        }
+        core::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()] / 2
+}
+
+/// Byte ranges in the source of a textual grammar (e.g. `pest::import`),
+/// used to point diagnostics at their origin.
+#[derive(Clone, Debug, Default)]
+pub struct Spans {
+    /// The definition of every rule, by name.
+    pub rules: IndexMap<String, Range<usize>>,
+    /// The `Call`s in every rule, by the name of the calling rule,
+    /// each with the called name.
+    pub calls: IndexMap<String, Vec<(String, Range<usize>)>>,
+}
+
+impl Spans {
+    /// The most precise source ranges `diagnostic` refers to, if any.
+    pub fn find(&self, diagnostic: &Diagnostic) -> Vec<Range<usize>> {
+        if diagnostic.code == Code::UndefinedRule {
+            let calls = self.calls.get(&diagnostic.rule).into_iter().flatten();
+            let spans: Vec<_> = calls
+                .filter(|(name, _)| *name == diagnostic.node)
+                .map(|(_, span)| span.clone())
+                .collect();
+            if !spans.is_empty() {
+                return spans;
+            }
+        }
+        self.rules
+            .get(&diagnostic.rule)
+            .cloned()
+            .into_iter()
+            .collect()
+    }
+
+    /// Like `find`, but with lines and columns in `file` (the source of the
+    /// textual grammar these spans were found in).
+    pub fn find_line_columns(
+        &self,
+        diagnostic: &Diagnostic,
+        file: &SourceFile,
+    ) -> Vec<LineColumnRange> {
+        self.find(diagnostic)
+            .into_iter()
+            .map(|span| file.line_column_range(span))
+            .collect()
+    }
+}
+
+/// Render `diagnostic` with excerpts from `source` (named `source_name`,
+/// e.g. a path), underlining the parts found through `spans`.
+#[cfg(feature = "pretty-diagnostics")]
+pub fn render(
+    diagnostic: &Diagnostic,
+    spans: &Spans,
+    source_name: &str,
+    source: &str,
+    color: bool,
+) -> String {
+    use ariadne::{Config, IndexType, Label, Report, ReportKind, Source};
+
+    let kind = match diagnostic.severity() {
+        Severity::Error => ReportKind::Error,
+        Severity::Warning => ReportKind::Warning,
+    };
+    let found = spans.find(diagnostic);
+    let start = found.first().map_or(0, |span| span.start);
+    let mut report = Report::build(kind, (source_name, start..start))
+        .with_config(
+            Config::default()
+                .with_color(color)
+                .with_index_type(IndexType::Byte),
+        )
+        .with_code(diagnostic.code)
+        .with_message(&diagnostic.message)
+        .with_labels(found.into_iter().map(|span| {
+            Label::new((source_name, span)).with_message(format!("`{}`", diagnostic.node))
+        }));
+    if let Some(help) = &diagnostic.help {
+        report = report.with_help(help);
+    }
+
+    let mut out = vec![];
+    // NOTE writing to a `Vec` can't fail.
+    report
+        .finish()
+        .write((source_name, Source::from(source)), &mut out)
+        .unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+/// Serialize `diagnostics` to a JSON array (see `Diagnostic::to_json`).
+pub fn to_json(diagnostics: &[Diagnostic]) -> String {
+    let diagnostics: Vec<_> = diagnostics.iter().map(|d| d.to_json()).collect();
+    format!("[{}]", diagnostics.join(","))
+}
+
+pub(crate) fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            _ if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,518 @@
+//! Compiling the choice between alternatives (e.g. the cases of an `Or`)
+//! into a decision table over the next character of the input, based on
+//! which characters each alternative can start with (its "FIRST set"),
+//! for scannerless parsers to only try the alternatives which can match,
+//! instead of sequentially trying (and comparing the input against) all
+//! of their terminals.
+
+use crate::compat::prelude::*;
+use crate::compat::IndexMap;
+use crate::context::{Context, IRule, IStr};
+use crate::diagnostics::{Code, Diagnostic};
+use crate::rule::{any_of, Folder, MaybeKnown, Pattern, Rule, RuleWithFields};
+use crate::scannerless::char_after;
+use crate::Grammar;
+use core::char;
+use core::cmp::Reverse;
+use core::hash::Hash;
+use core::ops::RangeInclusive;
+
+/// The characters a match can start with.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FirstSet {
+    /// Sorted, disjoint and non-adjacent ranges.
+    pub chars: Vec<RangeInclusive<char>>,
+    /// Whether a match can also start with no character at all, i.e. be
+    /// empty (or start with a zero-width rule, like a `Guard`), in which
+    /// case its alternative has to be tried regardless of the next one.
+    pub nullable: bool,
+}
+
+impl FirstSet {
+    fn any() -> Self {
+        FirstSet {
+            chars: vec!['\0'..=char::MAX],
+            nullable: true,
+        }
+    }
+
+    fn nullable() -> Self {
+        FirstSet {
+            chars: vec![],
+            nullable: true,
+        }
+    }
+
+    fn union(&mut self, other: &FirstSet) {
+        self.nullable |= other.nullable;
+        self.chars.extend(other.chars.iter().cloned());
+        self.chars.sort_by_key(|range| *range.start());
+        let mut merged: Vec<RangeInclusive<char>> = vec![];
+        for range in self.chars.drain(..) {
+            if let Some(last) = merged.last_mut() {
+                if range.start() <= last.end() || Some(*range.start()) == char_after(*last.end()) {
+                    if range.end() > last.end() {
+                        *last = *last.start()..=*range.end();
+                    }
+                    continue;
+                }
+            }
+            merged.push(range);
+        }
+        self.chars = merged;
+    }
+
+    pub fn contains(&self, c: char) -> bool {
+        self.chars.iter().any(|range| range.contains(&c))
+    }
+
+    /// The first range of characters both `self` and `other` contain, if any.
+    fn common_chars(&self, other: &FirstSet) -> Option<RangeInclusive<char>> {
+        self.chars.iter().find_map(|a| {
+            other.chars.iter().find_map(|b| {
+                let (start, end) = (a.start().max(b.start()), a.end().min(b.end()));
+                if start <= end {
+                    Some(*start..=*end)
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
+    fn is_any(&self) -> bool {
+        *self == FirstSet::any()
+    }
+
+    /// Whether any match starting with a character in `self` could also
+    /// start with it in `other` (with nullable sets overlapping anything).
+    pub fn overlaps(&self, other: &FirstSet) -> bool {
+        self.nullable
+            || other.nullable
+            || self.chars.iter().any(|a| {
+                other
+                    .chars
+                    .iter()
+                    .any(|b| a.start() <= b.end() && b.start() <= a.end())
+            })
+    }
+}
+
+/// Compute the FIRST set of every rule in `grammar` (iterating to a fixpoint,
+/// as rules can be recursive). `Call`s to undefined rules, and patterns with
+/// unknown FIRST characters (see `Pattern::first_chars`), are assumed to be
+/// able to start with anything.
+///
+/// NOTE this is only used through `Grammar::first_sets`, which caches it.
+pub(crate) fn first_sets<Pat: Pattern + Eq + Hash>(
+    cx: &Context<Pat>,
+    grammar: &Grammar,
+) -> IndexMap<IStr, FirstSet> {
+    let mut sets: IndexMap<_, _> = grammar
+        .rules()
+        .keys()
+        .map(|&name| (name, FirstSet::default()))
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (&name, rule) in grammar.rules() {
+            let set = first(cx, &sets, rule.rule);
+            if set != sets[&name] {
+                sets[&name] = set;
+                changed = true;
+            }
+        }
+    }
+    sets
+}
+
+/// Compute the FIRST set of `rule`, given those of all rules (see `Grammar::first_sets`).
+pub fn first<Pat: Pattern + Eq + Hash>(
+    cx: &Context<Pat>,
+    sets: &IndexMap<IStr, FirstSet>,
+    rule: IRule,
+) -> FirstSet {
+    match cx[rule] {
+        Rule::Empty
+        | Rule::Anchor(_)
+        | Rule::FlagGuard(..)
+        | Rule::Guard(_)
+        | Rule::Feature(..) => FirstSet::nullable(),
+        Rule::Eat(ref pat) => match pat.first_chars() {
+            MaybeKnown::Known(chars) => FirstSet {
+                chars,
+                nullable: pat.matches_empty() != MaybeKnown::Known(false),
+            },
+            MaybeKnown::Unknown => FirstSet::any(),
+        },
+        // NOTE this can start with anything but the pattern, which
+        // isn't worth representing, as it's usually (almost) everything.
+        Rule::EatUntil(_) | Rule::Backref(_) => FirstSet::any(),
+        Rule::Call(name) => sets.get(&name).cloned().unwrap_or_else(FirstSet::any),
+        Rule::WithFlag(_, _, rule) | Rule::Silent(rule) => first(cx, sets, rule),
+        Rule::Opt(rule) => {
+            let mut set = first(cx, sets, rule);
+            set.nullable = true;
+            set
+        }
+        Rule::Capture(_, delim, body) => {
+            let mut set = first(cx, sets, delim);
+            if set.nullable {
+                set.nullable = false;
+                set.union(&first(cx, sets, body));
+            }
+            set
+        }
+        Rule::Concat(ref rules) => {
+            let mut set = FirstSet::nullable();
+            for &rule in rules {
+                set.nullable = false;
+                set.union(&first(cx, sets, rule));
+                if !set.nullable {
+                    break;
+                }
+            }
+            set
+        }
+        Rule::Or(ref rules) => {
+            let mut set = FirstSet::default();
+            for &rule in rules {
+                set.union(&first(cx, sets, rule));
+            }
+            set
+        }
+        Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => {
+            let mut set = first(cx, sets, elem);
+            // NOTE with an empty `elem` (or a leading `sep` allowed),
+            // a match can start with `sep`.
+            if let Some((sep, kind)) = sep {
+                if set.nullable || kind.allows_leading() {
+                    set.union(&first(cx, sets, sep));
+                }
+            }
+            if let Rule::RepeatMany(..) = cx[rule] {
+                set.nullable = true;
+            }
+            set
+        }
+    }
+}
+
+/// Heuristically find constructs which often make a grammar ambiguous (or
+/// at least nondeterministic, i.e. requiring backtracking), based on FIRST
+/// sets (see `Grammar::first_sets`), which is much cheaper than searching for actual
+/// ambiguities, but can have false positives (and negatives), so all the
+/// diagnostics are warnings (see `diagnostics::Code` for the full list).
+///
+/// FIRST sets which could be anything (e.g. for patterns with unknown FIRST
+/// characters, like tokens) aren't compared, as they'd overlap everything.
+pub fn ambiguity_lints<Pat: Pattern + Eq + Hash>(
+    cx: &Context<Pat>,
+    grammar: &Grammar,
+) -> Vec<Diagnostic> {
+    let sets = grammar.first_sets(cx);
+    let mut diagnostics = vec![];
+    for (&name, rule) in grammar.rules() {
+        lint_ambiguity(cx, sets, name, rule.rule, &mut diagnostics);
+    }
+    diagnostics
+}
+
+fn lint_ambiguity<Pat: Pattern + Eq + Hash>(
+    cx: &Context<Pat>,
+    sets: &IndexMap<IStr, FirstSet>,
+    rule_name: IStr,
+    rule: IRule,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let mut push = |code, message, help: &str, message_id, args| {
+        diagnostics.push(Diagnostic {
+            code,
+            rule: cx[rule_name].to_string(),
+            node: rule.node_desc(cx),
+            message,
+            help: Some(help.to_string()),
+            message_id,
+            args,
+        })
+    };
+    let desc = |chars: RangeInclusive<char>| {
+        if chars.start() == chars.end() {
+            format!("{:?}", chars.start())
+        } else {
+            format!("{:?}", chars)
+        }
+    };
+    match cx[rule] {
+        Rule::Or(ref cases) => {
+            let case_sets: Vec<_> = cases.iter().map(|&case| first(cx, sets, case)).collect();
+            'cases: for (i, a) in case_sets.iter().enumerate() {
+                for (j, b) in case_sets.iter().enumerate().skip(i + 1) {
+                    if a.is_any() || b.is_any() {
+                        continue;
+                    }
+                    let mut args = vec![("first", i.to_string()), ("second", j.to_string())];
+                    let (message, message_id) = match a.common_chars(b) {
+                        Some(chars) => {
+                            args.push(("chars", desc(chars.clone())));
+                            (
+                                format!(
+                                    "cases {} and {} can both start with {}",
+                                    i,
+                                    j,
+                                    desc(chars)
+                                ),
+                                "overlapping-cases",
+                            )
+                        }
+                        None if a.nullable && b.nullable => (
+                            format!("cases {} and {} can both be empty", i, j),
+                            "empty-overlapping-cases",
+                        ),
+                        None => continue,
+                    };
+                    push(
+                        Code::OverlappingCases,
+                        message,
+                        "factor out their common prefix, or make them start differently",
+                        message_id,
+                        args,
+                    );
+                    // NOTE one overlap per `Or` is enough of a hint.
+                    break 'cases;
+                }
+            }
+        }
+        Rule::Concat(ref elems) => {
+            for pair in elems.windows(2) {
+                let (a, b) = (first(cx, sets, pair[0]), first(cx, sets, pair[1]));
+                let is_repeat =
+                    |rule| matches!(cx[rule], Rule::RepeatMany(..) | Rule::RepeatMore(..));
+                let beside_repeat =
+                    (is_repeat(pair[0]) && b.nullable) || (is_repeat(pair[1]) && a.nullable);
+                if !beside_repeat || a.is_any() || b.is_any() {
+                    continue;
+                }
+                if let Some(chars) = a.common_chars(&b) {
+                    let (first, second, chars) =
+                        (pair[0].node_desc(cx), pair[1].node_desc(cx), desc(chars));
+                    push(
+                        Code::NullableBesideRepeat,
+                        format!(
+                            "`{}` and `{}` can both start with {}, and either can be \
+                             empty, so which one matches it is ambiguous",
+                            first, second, chars
+                        ),
+                        "merge them into one repetition, or separate them with a terminal",
+                        "nullable-beside-repeat",
+                        vec![("first", first), ("second", second), ("chars", chars)],
+                    );
+                }
+            }
+        }
+        Rule::RepeatMany(_, Some((sep, _))) | Rule::RepeatMore(_, Some((sep, _)))
+            if first(cx, sets, sep).nullable =>
+        {
+            push(
+                Code::NullableSeparator,
+                format!("separator `{}` can be empty", sep.node_desc(cx)),
+                "make the separator required, or use a repetition without one",
+                "nullable-separator",
+                vec![("separator", sep.node_desc(cx))],
+            );
+        }
+        _ => {}
+    }
+    for child in rule.children(cx) {
+        lint_ambiguity(cx, sets, rule_name, child, diagnostics);
+    }
+}
+
+/// Which alternatives can match, depending on the next character, as a
+/// table of character ranges (see `DecisionTable::cases`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecisionTable {
+    /// Sorted and disjoint ranges, with the (indices of the) alternatives
+    /// to try for the characters in them (always including `nullable`).
+    pub ranges: Vec<(RangeInclusive<char>, Vec<usize>)>,
+    /// The nullable alternatives, to try for any other characters, and at
+    /// the end of the input.
+    pub nullable: Vec<usize>,
+}
+
+impl DecisionTable {
+    /// Build the table for choosing between alternatives with the FIRST
+    /// sets `cases` (e.g. computed by `first`, for each case of an `Or`).
+    pub fn new(cases: &[FirstSet]) -> Self {
+        let nullable: Vec<_> = (0..cases.len()).filter(|&i| cases[i].nullable).collect();
+
+        // Split the characters into intervals at every range boundary, so
+        // each interval is either entirely inside, or outside, every range.
+        let mut bounds: Vec<u32> = cases
+            .iter()
+            .flat_map(|case| &case.chars)
+            .flat_map(|range| [*range.start() as u32, *range.end() as u32 + 1])
+            .collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let mut ranges: Vec<(RangeInclusive<char>, Vec<usize>)> = vec![];
+        for window in bounds.windows(2) {
+            // NOTE bounds can fall in the surrogate range (which has
+            // no `char`s), in which case they're moved just outside of it.
+            let start = char::from_u32(window[0]).unwrap_or('\u{e000}');
+            let end = char::from_u32(window[1] - 1).unwrap_or('\u{d7ff}');
+            if start > end {
+                continue;
+            }
+            let mut matching: Vec<_> = (0..cases.len())
+                .filter(|&i| cases[i].contains(start))
+                .collect();
+            if matching.is_empty() {
+                continue;
+            }
+            matching.extend(&nullable);
+            matching.sort_unstable();
+            matching.dedup();
+
+            // Merge with the previous interval, if adjacent and identical.
+            if let Some((last, last_matching)) = ranges.last_mut() {
+                if char_after(*last.end()) == Some(start) && *last_matching == matching {
+                    *last = *last.start()..=end;
+                    continue;
+                }
+            }
+            ranges.push((start..=end, matching));
+        }
+
+        DecisionTable { ranges, nullable }
+    }
+
+    /// The alternatives to try, if `next` is the next character (or `None`
+    /// at the end of the input), in their original order.
+    pub fn cases(&self, next: Option<char>) -> &[usize] {
+        let c = match next {
+            Some(c) => c,
+            None => return &self.nullable,
+        };
+        let i = self.ranges.partition_point(|(range, _)| *range.end() < c);
+        match self.ranges.get(i) {
+            Some((range, cases)) if range.contains(&c) => cases,
+            _ => &self.nullable,
+        }
+    }
+
+    /// Generate a Rust `match` expression on `scrutinee` (an expression of
+    /// type `Option<char>`), evaluating to the `&[usize]` from `cases`,
+    /// for generated parsers to use as a jump table.
+    pub fn to_rust_match(&self, scrutinee: &str) -> String {
+        let mut code = format!("match {} {{\n", scrutinee);
+        for (range, cases) in &self.ranges {
+            let pat = if range.start() == range.end() {
+                format!("{:?}", range.start())
+            } else {
+                format!("{:?}..={:?}", range.start(), range.end())
+            };
+            code += &format!("    Some({}) => &{:?},\n", pat, cases);
+        }
+        code += &format!("    _ => &{:?},\n", self.nullable);
+        code += "}";
+        code
+    }
+}
+
+/// An `Or` whose cases weren't reordered by `reorder_alternatives`, as two
+/// of them can start with the same character (or be empty), i.e. reordering
+/// could change which one ordered choice picks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Overlap {
+    /// The rule the `Or` is in.
+    pub rule: IStr,
+    pub or: IRule,
+    /// The (indices of the) first two overlapping cases.
+    pub cases: (usize, usize),
+}
+
+/// Reorder the cases of every `Or` in `grammar` by how often they match
+/// (most often first), according to `frequencies` (e.g. measured with
+/// `profile::Profile::case_frequencies`, or estimated), to speed up
+/// backends which try cases in order (backtracking or predictive ones).
+///
+/// Only the `Or`s whose cases have disjoint FIRST sets (see `first`), and
+/// none of which are nullable, are reordered, as ordered choice between
+/// them picks the same case in any order, unless `allow_overlap` is set,
+/// and all other `Or`s which would've been reordered are returned instead.
+///
+/// `Or`s missing from `frequencies` are left as-is, while cases missing
+/// from it (or with equal frequencies) keep their relative order. Note
+/// that reordering cases changes e.g. `Derivation::choices`, and which
+/// derivation `Interpreter::explain` prefers, for ambiguous inputs.
+pub fn reorder_alternatives<Pat: Pattern + Eq + Hash>(
+    cx: &Context<Pat>,
+    grammar: Grammar,
+    frequencies: &IndexMap<IRule, Vec<usize>>,
+    allow_overlap: bool,
+) -> (Grammar, Vec<Overlap>) {
+    let mut reorderer = Reorderer {
+        cx,
+        sets: grammar.first_sets(cx).clone(),
+        frequencies,
+        allow_overlap,
+        rule: None,
+        overlaps: vec![],
+    };
+    let mut reordered = grammar.empty_like();
+    for (&name, rule) in grammar.rules() {
+        reorderer.rule = Some(name);
+        reordered.define(name, rule.fold(&mut reorderer));
+    }
+    (reordered, reorderer.overlaps)
+}
+
+struct Reorderer<'a, Pat> {
+    cx: &'a Context<Pat>,
+    sets: IndexMap<IStr, FirstSet>,
+    frequencies: &'a IndexMap<IRule, Vec<usize>>,
+    allow_overlap: bool,
+    rule: Option<IStr>,
+    overlaps: Vec<Overlap>,
+}
+
+impl<'a, Pat: Pattern + Eq + Hash> Folder<'a, Pat> for Reorderer<'a, Pat> {
+    fn cx(&self) -> &'a Context<Pat> {
+        self.cx
+    }
+
+    fn fold_or(&mut self, cases: impl Iterator<Item = (usize, RuleWithFields)>) -> RuleWithFields {
+        let cx = self.cx;
+        let cases: Vec<_> = cases.map(|(_, case)| case).collect();
+        let or = cx.intern(Rule::Or(cases.iter().map(|case| case.rule).collect()));
+        let cases: Vec<_> = cases.into_iter().map(|case| case.fold(self)).collect();
+
+        let mut order: Vec<_> = (0..cases.len()).collect();
+        if let Some(frequencies) = self.frequencies.get(&or) {
+            let frequency = |i: usize| frequencies.get(i).copied().unwrap_or(0);
+            order.sort_by_key(|&i| Reverse(frequency(i)));
+        }
+        let reordering = order.iter().enumerate().any(|(i, &j)| i != j);
+        if reordering && !self.allow_overlap {
+            let sets: Vec<_> = cases
+                .iter()
+                .map(|case| first(cx, &self.sets, case.rule))
+                .collect();
+            let overlap = (0..sets.len())
+                .flat_map(|i| (i + 1..sets.len()).map(move |j| (i, j)))
+                .find(|&(i, j)| sets[i].overlaps(&sets[j]));
+            if let Some(overlap) = overlap {
+                self.overlaps.push(Overlap {
+                    rule: self.rule.unwrap(),
+                    or,
+                    cases: overlap,
+                });
+                order.sort_unstable();
+            }
+        }
+        any_of(order.into_iter().map(|i| cases[i])).finish(cx)
+    }
+}
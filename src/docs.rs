@@ -0,0 +1,697 @@
+//! Generating a cross-linked reference for a grammar, with a definition,
+//! and some analyses, for every rule, either as HTML (see `export`), or as
+//! Markdown (see `export_markdown`), e.g. for mdBook-based specifications.
+
+use crate::compat::prelude::*;
+use crate::compat::{IndexMap, IndexSet};
+use crate::context::{Context, IRule, IStr};
+use crate::dispatch::FirstSet;
+use crate::rule::{Fields, MaybeKnown, Pattern, Rule, RuleWithFields};
+use crate::Grammar;
+use core::fmt::Write;
+use core::hash::Hash;
+
+/// Export `grammar` as a standalone HTML page, titled `title`, with one
+/// section per rule (in definition order), containing:
+/// * its definition, with every `Call` linking to the called rule
+/// * its railroad diagram (as inline SVG), also with links
+/// * its FIRST set (see `Grammar::first_sets`)
+/// * whether it can match the empty string (see `Grammar::can_be_empty`)
+/// * the rules which call it
+///
+/// Fields are shown in definitions, but not in railroad diagrams.
+pub fn export<Pat>(cx: &Context<Pat>, grammar: &Grammar, title: &str) -> String
+where
+    Pat: Pattern + Eq + Hash,
+{
+    let format = Format::Html;
+    let first_sets = grammar.first_sets(cx);
+    let can_be_empty = grammar.can_be_empty(cx);
+    let callers = callers(cx, grammar);
+
+    let mut html = String::new();
+    html += "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n";
+    let _ = writeln!(html, "<title>{}</title>", escape(title));
+    html += STYLE;
+    html += "</head>\n<body>\n";
+    let _ = writeln!(html, "<h1>{}</h1>", escape(title));
+
+    html += "<nav>\n<ul>\n";
+    for &name in grammar.rules().keys() {
+        let _ = writeln!(html, "<li>{}</li>", format.link(cx, name));
+    }
+    html += "</ul>\n</nav>\n";
+
+    for (&name, &rule) in grammar.rules() {
+        let _ = writeln!(
+            html,
+            "<section id=\"{}\">\n<h2>{}</h2>",
+            anchor(cx, name),
+            escape(&cx[name])
+        );
+
+        let _ = writeln!(
+            html,
+            "<pre class=\"definition\">{} ={};</pre>",
+            escape(&cx[name]),
+            definition(cx, rule, format)
+        );
+        html += &railroad::Diagram::from_rule(cx, rule.rule).to_svg();
+
+        html += "<dl>\n";
+        let _ = writeln!(
+            html,
+            "<dt>FIRST</dt><dd>{}</dd>",
+            describe_first_set(&first_sets[&name], format)
+        );
+        let _ = writeln!(
+            html,
+            "<dt>Nullable</dt><dd>{}</dd>",
+            describe_nullable(can_be_empty[&name])
+        );
+        let _ = writeln!(
+            html,
+            "<dt>Referenced by</dt><dd>{}</dd>",
+            describe_callers(cx, callers.get(&name), format)
+        );
+        html += "</dl>\n</section>\n";
+    }
+
+    html += "</body>\n</html>\n";
+    html
+}
+
+/// Export `grammar` as a single Markdown file, titled `title`, with the
+/// same contents as `export` (except for railroad diagrams), and a stable
+/// anchor for every rule (`rule-` followed by the rule name, with every
+/// character other than ASCII alphanumerics and `_` replaced by `-` and its
+/// hex code, followed by `-`, e.g. `rule-Expr-3c--2b-NoStruct-3e-` for
+/// `Expr<+NoStruct>`), so that prose can link to the rule's definition.
+///
+/// The output only depends on `grammar` (with rules in definition order),
+/// so it can be checked in, and diffed, when the grammar changes.
+pub fn export_markdown<Pat>(cx: &Context<Pat>, grammar: &Grammar, title: &str) -> String
+where
+    Pat: Pattern + Eq + Hash,
+{
+    let format = Format::Markdown;
+    let first_sets = grammar.first_sets(cx);
+    let can_be_empty = grammar.can_be_empty(cx);
+    let callers = callers(cx, grammar);
+
+    let mut md = format!("# {}\n\n", title);
+    for &name in grammar.rules().keys() {
+        let _ = writeln!(md, "- {}", format.link(cx, name));
+    }
+
+    for (&name, &rule) in grammar.rules() {
+        let _ = write!(
+            md,
+            "\n<a id=\"{}\"></a>\n\n## `{}`\n\n```text\n{} ={};\n```\n\n",
+            anchor(cx, name),
+            &cx[name],
+            &cx[name],
+            definition(cx, rule, format)
+        );
+        // NOTE the trailing double spaces are Markdown line breaks.
+        let _ = writeln!(
+            md,
+            "**FIRST:** {}  ",
+            describe_first_set(&first_sets[&name], format)
+        );
+        let _ = writeln!(
+            md,
+            "**Nullable:** {}  ",
+            describe_nullable(can_be_empty[&name])
+        );
+        let _ = writeln!(
+            md,
+            "**Referenced by:** {}",
+            describe_callers(cx, callers.get(&name), format)
+        );
+    }
+    md
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Format {
+    Html,
+    Markdown,
+}
+
+impl Format {
+    /// Emphasized (plain) text.
+    fn em(self, text: &str) -> String {
+        match self {
+            Format::Html => format!("<em>{}</em>", text),
+            Format::Markdown => format!("*{}*", text),
+        }
+    }
+
+    /// Code (e.g. a pattern) outside of definitions.
+    fn code(self, text: &str) -> String {
+        match self {
+            Format::Html => escape(text),
+            Format::Markdown => format!("`{}`", text),
+        }
+    }
+
+    /// Highlighted part of a definition, which is plain text in Markdown,
+    /// as definitions are put in code blocks there.
+    fn span(self, class: &str, text: &str) -> String {
+        match self {
+            Format::Html => format!("<span class=\"{}\">{}</span>", class, escape(text)),
+            Format::Markdown => text.to_string(),
+        }
+    }
+
+    fn link<Pat>(self, cx: &Context<Pat>, name: IStr) -> String {
+        match self {
+            Format::Html => format!(
+                "<a href=\"#{}\">{}</a>",
+                anchor(cx, name),
+                escape(&cx[name])
+            ),
+            Format::Markdown => format!("[`{}`](#{})", &cx[name], anchor(cx, name)),
+        }
+    }
+}
+
+/// The rules calling each rule.
+fn callers<Pat>(cx: &Context<Pat>, grammar: &Grammar) -> IndexMap<IStr, IndexSet<IStr>> {
+    let mut callers: IndexMap<IStr, IndexSet<IStr>> = IndexMap::default();
+    for (&name, rule) in grammar.rules() {
+        for callee in rule.rule.call_names(cx) {
+            callers.entry(callee).or_default().insert(name);
+        }
+    }
+    callers
+}
+
+const STYLE: &str = "<style>
+body { font-family: sans-serif; max-width: 60em; margin: auto; }
+pre.definition { background: #f4f4f4; padding: 0.5em; white-space: pre-wrap; }
+.field { color: #737; }
+.terminal { color: #262; }
+.special { color: #777; }
+dt { font-weight: bold; }
+svg.railroad path { stroke: #333; stroke-width: 2; fill: none; }
+svg.railroad rect { stroke: #333; stroke-width: 2; fill: #fff; }
+svg.railroad .terminal rect { fill: #dfd; }
+svg.railroad .nonterminal rect { fill: #ddf; }
+svg.railroad .special rect { fill: #eee; stroke-dasharray: 4 2; }
+svg.railroad text { font-family: monospace; font-size: 13px; text-anchor: middle; }
+</style>
+";
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// The `id` of the section for the rule `name` (see `export_markdown`).
+fn anchor<Pat>(cx: &Context<Pat>, name: IStr) -> String {
+    let mut anchor = "rule-".to_string();
+    for c in cx[name].chars() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            anchor.push(c);
+        } else {
+            let _ = write!(anchor, "-{:x}-", c as u32);
+        }
+    }
+    anchor
+}
+
+fn describe_first_set(set: &FirstSet, format: Format) -> String {
+    let mut parts: Vec<_> = set
+        .chars
+        .iter()
+        .map(|range| {
+            if range.start() == range.end() {
+                format.code(&format!("{:?}", range.start()))
+            } else {
+                format.code(&format!("{:?}", range))
+            }
+        })
+        .collect();
+    if set.nullable {
+        parts.push(format.em("empty"));
+    }
+    if parts.is_empty() {
+        return format.em("none");
+    }
+    parts.join(", ")
+}
+
+fn describe_nullable(can_be_empty: MaybeKnown<bool>) -> &'static str {
+    match can_be_empty {
+        MaybeKnown::Known(true) => "yes",
+        MaybeKnown::Known(false) => "no",
+        MaybeKnown::Unknown => "unknown",
+    }
+}
+
+fn describe_callers<Pat>(
+    cx: &Context<Pat>,
+    callers: Option<&IndexSet<IStr>>,
+    format: Format,
+) -> String {
+    match callers {
+        Some(callers) => callers
+            .iter()
+            .map(|&caller| format.link(cx, caller))
+            .collect::<Vec<_>>()
+            .join(", "),
+        None => format.em("none"),
+    }
+}
+
+/// The definition of a rule, in the notation of `grammar_grammar` (with
+/// the `node_desc` notation for everything that can't be written in it),
+/// with every top-level alternative on its own line (and
+/// otherwise, with a leading space, to go after `=`).
+fn definition<Pat>(cx: &Context<Pat>, rule: RuleWithFields, format: Format) -> String
+where
+    Pat: Eq + Hash + core::fmt::Debug,
+{
+    if let (Fields::Leaf(None) | Fields::Aggregate(_), Rule::Or(cases)) =
+        (&cx[rule.fields], &cx[rule.rule])
+    {
+        let cases: Vec<_> = cases
+            .iter()
+            .enumerate()
+            .map(|(i, &case)| {
+                definition_rule(
+                    cx,
+                    RuleWithFields {
+                        rule: case,
+                        fields: rule.fields.child(cx, i),
+                    },
+                    Prec::Concat,
+                    format,
+                )
+            })
+            .collect();
+        return format!("\n    {}", cases.join("\n  | "));
+    }
+    format!(" {}", definition_rule(cx, rule, Prec::Or, format))
+}
+
+/// Operator precedence, from loosest to tightest binding.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Prec {
+    Or,
+    Concat,
+    Postfix,
+}
+
+fn definition_rule<Pat>(
+    cx: &Context<Pat>,
+    rule: RuleWithFields,
+    prec: Prec,
+    format: Format,
+) -> String
+where
+    Pat: Eq + Hash + core::fmt::Debug,
+{
+    let group = |s: String, s_prec: Prec| {
+        if s_prec < prec {
+            format!("{{ {} }}", s)
+        } else {
+            s
+        }
+    };
+    if let Fields::Leaf(Some(field)) = cx[rule.fields] {
+        let inner = definition_rule(
+            cx,
+            RuleWithFields {
+                rule: rule.rule,
+                fields: field.sub,
+            },
+            Prec::Postfix,
+            format,
+        );
+        return format!("{}:{}", format.span("field", &cx[field.name]), inner);
+    }
+    let child = |child: IRule, i, prec| {
+        definition_rule(
+            cx,
+            RuleWithFields {
+                rule: child,
+                fields: rule.fields.child(cx, i),
+            },
+            prec,
+            format,
+        )
+    };
+    let list = |rules: &[IRule], sep: &str, prec| {
+        rules
+            .iter()
+            .enumerate()
+            .map(|(i, &rule)| child(rule, i, prec))
+            .collect::<Vec<_>>()
+            .join(sep)
+    };
+    let special = |text: &str| format.span("special", text);
+    match cx[rule.rule] {
+        Rule::Empty => "{}".to_string(),
+        Rule::Eat(_) | Rule::EatUntil(_) => format.span("terminal", &rule.rule.node_desc(cx)),
+        Rule::Call(name) => match format {
+            Format::Html => format.link(cx, name),
+            Format::Markdown => cx[name].to_string(),
+        },
+        Rule::Anchor(_)
+        | Rule::FlagGuard(..)
+        | Rule::Guard(_)
+        | Rule::Feature(..)
+        | Rule::Backref(_) => special(&rule.rule.node_desc(cx)),
+        Rule::WithFlag(flag, value, inner) => format!(
+            "{}{}",
+            child(inner, 0, Prec::Postfix),
+            special(&format!("<{}{}>", if value { "+" } else { "!" }, &cx[flag]))
+        ),
+        Rule::Silent(inner) => format!(
+            "{}{}{}",
+            special("_{"),
+            child(inner, 0, Prec::Or),
+            special("}")
+        ),
+        Rule::Capture(name, delim, body) => format!(
+            "{}{} {}{}",
+            special(&format!("(${}=", &cx[name])),
+            child(delim, 0, Prec::Postfix),
+            child(body, 1, Prec::Postfix),
+            special(")")
+        ),
+        Rule::Concat(ref elems) => group(list(elems, " ", Prec::Postfix), Prec::Concat),
+        Rule::Or(ref cases) => group(list(cases, " | ", Prec::Concat), Prec::Or),
+        Rule::Opt(inner) => format!("{}?", child(inner, 0, Prec::Postfix)),
+        Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => {
+            let op = match cx[rule.rule] {
+                Rule::RepeatMany(..) => "*",
+                _ => "+",
+            };
+            let elem = child(elem, 0, Prec::Postfix);
+            let s = match sep {
+                None => format!("{}{}", elem, op),
+                Some((sep, kind)) => {
+                    format!(
+                        "{}{} {} {}",
+                        elem,
+                        op,
+                        kind.op(),
+                        child(sep, 1, Prec::Postfix)
+                    )
+                }
+            };
+            // NOTE separators make this ambiguous as an operand.
+            if sep.is_some() {
+                group(s, Prec::Concat)
+            } else {
+                s
+            }
+        }
+    }
+}
+
+/// Laying out railroad diagrams, and rendering them as SVG.
+mod railroad {
+    use super::{anchor, escape};
+    use crate::compat::prelude::*;
+    use crate::context::{Context, IRule};
+    use crate::rule::Rule;
+    use core::fmt::{self, Write};
+
+    /// The radius of the curves joining (and splitting) tracks.
+    const ARC: usize = 10;
+    /// The space between the elements of a sequence (or branches of a choice).
+    const GAP: usize = 10;
+    /// Half the height of a box.
+    const HALF_BOX: usize = 11;
+
+    enum Kind {
+        Skip,
+        /// A box, with a CSS class, its text, and an optional link.
+        Box(&'static str, String, Option<String>),
+        Sequence(Vec<Diagram>),
+        /// The first branch is on the main track, the rest stacked below it,
+        /// each at its offset (downwards from the main track).
+        Choice(Vec<(usize, Diagram)>),
+        /// The element on the main track, and the separator on the way back,
+        /// at its offset (downwards from the main track).
+        Loop(Box<Diagram>, Box<Diagram>, usize),
+    }
+
+    /// A laid out diagram, with its track running horizontally across it.
+    pub struct Diagram {
+        kind: Kind,
+        width: usize,
+        /// The heights above and below the track.
+        up: usize,
+        down: usize,
+    }
+
+    impl Diagram {
+        fn skip() -> Self {
+            Diagram {
+                kind: Kind::Skip,
+                width: 0,
+                up: 0,
+                down: 0,
+            }
+        }
+
+        fn boxed(class: &'static str, text: String, link: Option<String>) -> Self {
+            Diagram {
+                width: text.chars().count() * 8 + 2 * GAP,
+                up: HALF_BOX,
+                down: HALF_BOX,
+                kind: Kind::Box(class, text, link),
+            }
+        }
+
+        fn sequence(elems: Vec<Diagram>) -> Self {
+            Diagram {
+                width: elems.iter().map(|elem| elem.width).sum::<usize>()
+                    + GAP * elems.len().saturating_sub(1),
+                up: elems.iter().map(|elem| elem.up).max().unwrap_or(0),
+                down: elems.iter().map(|elem| elem.down).max().unwrap_or(0),
+                kind: Kind::Sequence(elems),
+            }
+        }
+
+        fn choice(cases: Vec<Diagram>) -> Self {
+            let width = cases.iter().map(|case| case.width).max().unwrap_or(0);
+            let up = cases[0].up;
+            let mut down = 0;
+            let cases = cases
+                .into_iter()
+                .enumerate()
+                .map(|(i, case)| {
+                    // NOTE branches need room for the curves to reach them.
+                    let offset = if i == 0 {
+                        0
+                    } else {
+                        (down + GAP + case.up).max(2 * ARC)
+                    };
+                    down = offset + case.down;
+                    (offset, case)
+                })
+                .collect();
+            Diagram {
+                kind: Kind::Choice(cases),
+                width: width + 4 * ARC,
+                up,
+                down,
+            }
+        }
+
+        fn repeat(elem: Diagram, sep: Diagram) -> Self {
+            let offset = (elem.down + GAP + sep.up).max(2 * ARC);
+            Diagram {
+                width: elem.width.max(sep.width) + 4 * ARC,
+                up: elem.up,
+                down: offset + sep.down,
+                kind: Kind::Loop(Box::new(elem), Box::new(sep), offset),
+            }
+        }
+
+        pub fn from_rule<Pat: fmt::Debug>(cx: &Context<Pat>, rule: IRule) -> Self {
+            let special = |text| Diagram::boxed("special", text, None);
+            let list = |rules: &[IRule]| {
+                rules
+                    .iter()
+                    .map(|&rule| Diagram::from_rule(cx, rule))
+                    .collect()
+            };
+            match cx[rule] {
+                Rule::Empty => Diagram::skip(),
+                Rule::Eat(_) | Rule::EatUntil(_) => {
+                    Diagram::boxed("terminal", rule.node_desc(cx), None)
+                }
+                Rule::Call(name) => {
+                    Diagram::boxed("nonterminal", cx[name].to_string(), Some(anchor(cx, name)))
+                }
+                Rule::Anchor(_)
+                | Rule::FlagGuard(..)
+                | Rule::Guard(_)
+                | Rule::Feature(..)
+                | Rule::Backref(_) => special(rule.node_desc(cx)),
+                // NOTE these only affect how their contents match,
+                // so they're shown as a box in front of them.
+                Rule::WithFlag(flag, value, inner) => Diagram::sequence(vec![
+                    special(format!("<{}{}>", if value { "+" } else { "!" }, &cx[flag])),
+                    Diagram::from_rule(cx, inner),
+                ]),
+                Rule::Capture(name, delim, body) => Diagram::sequence(vec![
+                    special(format!("${}=", &cx[name])),
+                    Diagram::from_rule(cx, delim),
+                    Diagram::from_rule(cx, body),
+                ]),
+                Rule::Silent(inner) => Diagram::from_rule(cx, inner),
+                Rule::Concat(ref elems) => Diagram::sequence(list(elems)),
+                Rule::Or(ref cases) => Diagram::choice(list(cases)),
+                Rule::Opt(inner) => {
+                    Diagram::choice(vec![Diagram::from_rule(cx, inner), Diagram::skip()])
+                }
+                Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => {
+                    let repeat = Diagram::repeat(
+                        Diagram::from_rule(cx, elem),
+                        sep.map_or_else(Diagram::skip, |(sep, _)| Diagram::from_rule(cx, sep)),
+                    );
+                    match cx[rule] {
+                        Rule::RepeatMany(..) => Diagram::choice(vec![repeat, Diagram::skip()]),
+                        _ => repeat,
+                    }
+                }
+            }
+        }
+
+        pub fn to_svg(&self) -> String {
+            let (width, height) = (self.width + 4 * GAP, self.up + self.down + 2 * GAP);
+            let y = self.up + GAP;
+            let mut svg = format!(
+                "<svg class=\"railroad\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">\n",
+                w = width,
+                h = height
+            );
+            let _ = writeln!(
+                svg,
+                "<path d=\"M0 {y} h{g} M{end} {y} h{g}\"/>",
+                y = y,
+                g = 2 * GAP,
+                end = width - 2 * GAP
+            );
+            self.render(&mut svg, 2 * GAP, y);
+            svg += "</svg>\n";
+            svg
+        }
+
+        /// Render this diagram with its track starting at `(x, y)`.
+        fn render(&self, svg: &mut String, x: usize, y: usize) {
+            // The width available to branches (or loop elements), between curves.
+            let inner = self.width.saturating_sub(4 * ARC);
+            match &self.kind {
+                Kind::Skip => {}
+                Kind::Box(class, text, link) => {
+                    if let Some(link) = link {
+                        let _ = write!(svg, "<a href=\"#{}\">", link);
+                    }
+                    let _ = write!(
+                        svg,
+                        "<g class=\"{}\"><rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" \
+                         rx=\"{}\"/><text x=\"{}\" y=\"{}\">{}</text></g>",
+                        class,
+                        x,
+                        y - HALF_BOX,
+                        self.width,
+                        2 * HALF_BOX,
+                        if *class == "terminal" { HALF_BOX } else { 0 },
+                        x + self.width / 2,
+                        y + 4,
+                        escape(text)
+                    );
+                    if link.is_some() {
+                        svg.push_str("</a>");
+                    }
+                    svg.push('\n');
+                }
+                Kind::Sequence(elems) => {
+                    let mut x = x;
+                    for (i, elem) in elems.iter().enumerate() {
+                        if i > 0 {
+                            let _ = writeln!(svg, "<path d=\"M{} {} h{}\"/>", x, y, GAP);
+                            x += GAP;
+                        }
+                        elem.render(svg, x, y);
+                        x += elem.width;
+                    }
+                }
+                Kind::Choice(cases) => {
+                    for &(offset, ref case) in cases {
+                        let end = x + 2 * ARC + case.width;
+                        if offset == 0 {
+                            let _ = writeln!(
+                                svg,
+                                "<path d=\"M{x} {y} h{a} M{end} {y} h{h}\"/>",
+                                x = x,
+                                y = y,
+                                a = 2 * ARC,
+                                end = end,
+                                h = inner - case.width + 2 * ARC,
+                            );
+                        } else {
+                            let _ = writeln!(
+                                svg,
+                                "<path d=\"M{x} {y} a{r} {r} 0 0 1 {r} {r} v{v} \
+                                 a{r} {r} 0 0 0 {r} {r} M{end} {y2} h{h} \
+                                 a{r} {r} 0 0 0 {r} -{r} v-{v} a{r} {r} 0 0 1 {r} -{r}\"/>",
+                                x = x,
+                                y = y,
+                                r = ARC,
+                                v = offset - 2 * ARC,
+                                end = end,
+                                y2 = y + offset,
+                                h = inner - case.width,
+                            );
+                        }
+                        case.render(svg, x + 2 * ARC, y + offset);
+                    }
+                }
+                Kind::Loop(elem, sep, offset) => {
+                    let start = x + 2 * ARC;
+                    let _ = writeln!(
+                        svg,
+                        "<path d=\"M{x} {y} h{a} M{elem_end} {y} h{h} \
+                         M{inner_end} {y} a{r} {r} 0 0 1 {r} {r} v{v} a{r} {r} 0 0 1 -{r} {r} \
+                         h-{h_sep} M{start} {y2} a{r} {r} 0 0 1 -{r} -{r} v-{v} \
+                         a{r} {r} 0 0 1 {r} -{r}\"/>",
+                        x = x,
+                        y = y,
+                        a = 2 * ARC,
+                        elem_end = start + elem.width,
+                        h = inner - elem.width + 2 * ARC,
+                        inner_end = start + inner,
+                        r = ARC,
+                        v = offset - 2 * ARC,
+                        h_sep = inner - sep.width,
+                        start = start,
+                        y2 = y + offset,
+                    );
+                    elem.render(svg, start, y);
+                    sep.render(svg, start, y + offset);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,534 @@
+//! Exporting (and importing) grammars in the W3C EBNF notation (as used by
+//! the XML and XQuery specifications), understood by e.g. railroad diagram
+//! generators.
+
+use crate::compat::prelude::*;
+use crate::compat::HashMap;
+use crate::context::{Context, IRule, IStr};
+use crate::diagnostics::{Args, Localize};
+use crate::input::LineColumn;
+use crate::limits::Limits;
+use crate::rule::{any_of, call, eat, seq, Rule, RuleWithFields, SepKind};
+use crate::scannerless;
+use crate::source_map::line_column;
+use crate::Grammar;
+use core::fmt;
+use core::hash::Hash;
+use core::ops::RangeInclusive;
+
+/// Patterns which can be written in W3C EBNF.
+pub trait W3cEbnfPat {
+    /// The pattern in W3C EBNF, or `None` if it only matches the empty
+    /// string (which has no notation of its own, see `export`).
+    fn to_w3c_ebnf(&self) -> Option<String>;
+}
+
+fn char_to_w3c_ebnf(c: char) -> String {
+    if c.is_ascii_graphic() && !"[]^-#".contains(c) {
+        c.to_string()
+    } else {
+        format!("#x{:X}", c as u32)
+    }
+}
+
+impl<S: AsRef<str>> W3cEbnfPat for scannerless::Pat<S> {
+    fn to_w3c_ebnf(&self) -> Option<String> {
+        match self {
+            scannerless::Pat::String(s) => {
+                let s = s.as_ref();
+                if s.is_empty() {
+                    None
+                } else if !s.contains('"') {
+                    Some(format!("\"{}\"", s))
+                } else if !s.contains('\'') {
+                    Some(format!("'{}'", s))
+                } else {
+                    // NOTE there's no escaping in string literals,
+                    // so split the string around the double quotes.
+                    let mut parts = vec![];
+                    for (i, part) in s.split('"').enumerate() {
+                        if i > 0 {
+                            parts.push("'\"'".to_string());
+                        }
+                        if !part.is_empty() {
+                            parts.push(format!("\"{}\"", part));
+                        }
+                    }
+                    Some(format!("({})", parts.join(" ")))
+                }
+            }
+            &scannerless::Pat::Range(start, end) => Some(format!(
+                "[{}-{}]",
+                char_to_w3c_ebnf(start),
+                char_to_w3c_ebnf(end)
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ExportError {
+    /// The name of the rule containing the unsupported part.
+    pub rule: String,
+    pub unsupported: String,
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` (in rule `{}`) has no W3C EBNF equivalent",
+            self.unsupported, self.rule
+        )
+    }
+}
+
+/// Export `grammar` as W3C EBNF, one `Name ::= ...` production per rule.
+///
+/// There's no notion of fields, so they're dropped, and zero-width rules
+/// (`Anchor`s, `Guard`s, etc.) can't be exported at all (so flags and
+/// features should be resolved beforehand).
+///
+/// There's also no notation for the empty string, so e.g. `A | ()` is
+/// exported as `A?`, but rules only matching the empty string can't be.
+///
+/// FIXME rule names aren't sanitized, e.g. `Expr<+NoStruct>`.
+pub fn export<Pat>(cx: &Context<Pat>, grammar: &Grammar) -> Result<String, ExportError>
+where
+    Pat: Eq + Hash + fmt::Debug + W3cEbnfPat,
+{
+    let width = grammar
+        .rules()
+        .keys()
+        .map(|&name| cx[name].chars().count())
+        .max()
+        .unwrap_or(0);
+
+    let mut ebnf = String::new();
+    for (&name, rule) in grammar.rules() {
+        let rule = export_rule(cx, rule.rule, Prec::Or)
+            .and_then(|rule| rule.ok_or_else(|| "the empty string".to_string()))
+            .map_err(|unsupported| ExportError {
+                rule: cx[name].to_string(),
+                unsupported,
+            })?;
+        ebnf += &format!("{:width$} ::= {}\n", &cx[name], rule, width = width);
+    }
+    Ok(ebnf)
+}
+
+/// Operator precedence, from loosest to tightest binding.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Prec {
+    Or,
+    Concat,
+    Postfix,
+}
+
+/// Export `rule` (see `export`), or `None` if it only matches the empty string.
+fn export_rule<Pat>(cx: &Context<Pat>, rule: IRule, prec: Prec) -> Result<Option<String>, String>
+where
+    Pat: Eq + Hash + fmt::Debug + W3cEbnfPat,
+{
+    let group = |s: String, s_prec: Prec| {
+        if s_prec < prec {
+            format!("({})", s)
+        } else {
+            s
+        }
+    };
+    // NOTE the parts only matching the empty string are left out.
+    let list = |rules: &[IRule], prec| -> Result<Vec<_>, String> {
+        Ok(rules
+            .iter()
+            .map(|&rule| export_rule(cx, rule, prec))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .collect())
+    };
+    let repeat_more = |elem, sep: Option<(IRule, SepKind)>, postfix| -> Result<_, String> {
+        let elem = export_rule(cx, elem, Prec::Postfix)?;
+        let sep = match sep {
+            Some((sep, kind)) => export_rule(cx, sep, Prec::Postfix)?.map(|sep| (sep, kind)),
+            None => None,
+        };
+        Ok(match (elem, sep) {
+            (None, None) => None,
+            // `A* % S` (and `A+ % S`) is just `S*` if `A` is empty.
+            (None, Some((sep, _))) => Some((format!("{}*", sep), Prec::Postfix)),
+            (Some(elem), None) => Some((format!("{}{}", elem, postfix), Prec::Postfix)),
+            (Some(elem), Some((sep, kind))) => {
+                let leading = if kind.allows_leading() {
+                    format!("{}? ", sep)
+                } else {
+                    String::new()
+                };
+                let trailing = if kind.allows_trailing() {
+                    format!(" {}?", sep)
+                } else {
+                    String::new()
+                };
+                // `A* % S` is `(A (S A)* S?)?`, i.e. an `A+ % S` that's optional.
+                let rule = format!("{}{} ({} {})*{}", leading, elem, sep, elem, trailing);
+                Some(match postfix {
+                    "+" => (rule, Prec::Concat),
+                    _ => (format!("({})?", rule), Prec::Postfix),
+                })
+            }
+        })
+    };
+    Ok(match cx[rule] {
+        Rule::Empty => None,
+        Rule::Eat(ref pat) => pat.to_w3c_ebnf(),
+        Rule::Call(name) => Some(cx[name].to_string()),
+        Rule::Silent(rule) => export_rule(cx, rule, prec)?,
+        Rule::Anchor(_)
+        | Rule::EatUntil(_)
+        | Rule::Capture(..)
+        | Rule::Backref(_)
+        | Rule::FlagGuard(..)
+        | Rule::WithFlag(..)
+        | Rule::Guard(_)
+        | Rule::Feature(..) => return Err(rule.node_desc(cx)),
+        Rule::Concat(ref elems) => {
+            let mut elems = list(elems, Prec::Postfix)?;
+            match elems.len() {
+                0 => None,
+                1 => elems.pop(),
+                _ => Some(group(elems.join(" "), Prec::Concat)),
+            }
+        }
+        Rule::Or(ref cases) => {
+            let mut nonempty = vec![];
+            for &case in cases {
+                if let Some(exported) = export_rule(cx, case, Prec::Concat)? {
+                    nonempty.push((case, exported));
+                }
+            }
+            let exported: Vec<_> = nonempty.iter().map(|(_, case)| &case[..]).collect();
+            let exported = exported.join(" | ");
+            match nonempty[..] {
+                [] => None,
+                _ if nonempty.len() == cases.len() => Some(group(exported, Prec::Or)),
+                [(case, _)] => {
+                    export_rule(cx, case, Prec::Postfix)?.map(|case| format!("{}?", case))
+                }
+                _ => Some(format!("({})?", exported)),
+            }
+        }
+        Rule::Opt(rule) => export_rule(cx, rule, Prec::Postfix)?.map(|rule| format!("{}?", rule)),
+        Rule::RepeatMany(elem, sep) => {
+            repeat_more(elem, sep, "*")?.map(|(rule, rule_prec)| group(rule, rule_prec))
+        }
+        Rule::RepeatMore(elem, sep) => {
+            repeat_more(elem, sep, "+")?.map(|(rule, rule_prec)| group(rule, rule_prec))
+        }
+    })
+}
+
+#[derive(Debug)]
+pub struct ImportError {
+    pub at: LineColumn,
+    pub message: String,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.at, self.message)
+    }
+}
+
+impl Localize for ImportError {
+    /// `ebnf-import-error`, with `at` and `message` (in English) arguments.
+    fn message_id(&self) -> &'static str {
+        "ebnf-import-error"
+    }
+
+    fn args(&self) -> Args {
+        vec![
+            ("at", format!("{:?}", self.at)),
+            ("message", self.message.clone()),
+        ]
+    }
+}
+
+/// Import a grammar from W3C EBNF, i.e. `Name ::= ...` productions, with
+/// `/* ... */` comments between them.
+///
+/// Exclusions (`A - B`) have no equivalent, and aren't supported, but
+/// everything else is, including the character classes `export` doesn't
+/// produce, i.e. with several ranges (`[a-zA-Z_]`), or negated (`[^"]`).
+pub fn import<Pat>(cx: &Context<Pat>, src: &str) -> Result<Grammar, ImportError>
+where
+    Pat: Eq + Hash + for<'s> From<&'s str> + From<RangeInclusive<char>>,
+{
+    import_with_limits(cx, src, &Limits::default())
+}
+
+/// Like `import`, but with non-default `limits` (e.g. for untrusted input),
+/// enforced while parsing (instead of only on the result).
+pub fn import_with_limits<Pat>(
+    cx: &Context<Pat>,
+    src: &str,
+    limits: &Limits,
+) -> Result<Grammar, ImportError>
+where
+    Pat: Eq + Hash + for<'s> From<&'s str> + From<RangeInclusive<char>>,
+{
+    let mut parser = Parser {
+        cx,
+        src,
+        pos: 0,
+        limits,
+        depth: 0,
+    };
+    let mut grammar = Grammar::new();
+    let mut starts: HashMap<IStr, usize> = HashMap::default();
+    loop {
+        parser.skip_trivia()?;
+        if parser.pos == src.len() {
+            break;
+        }
+        let start = parser.pos;
+        let name = cx.intern(parser.name()?);
+        parser.expect("::=")?;
+        let rule = parser.choice()?;
+        if grammar.rules().contains_key(&name) {
+            return Err(parser.error_at(start, format!("rule `{}` is already defined", &cx[name])));
+        }
+        grammar.define(name, rule);
+        starts.insert(name, start);
+    }
+    limits.check(cx, &grammar).map_err(|error| {
+        let start = starts.get(&cx.intern(&error.rule[..])).copied();
+        parser.error_at(start.unwrap_or(0), error.to_string())
+    })?;
+    Ok(grammar)
+}
+
+struct Parser<'a, 'cx, Pat> {
+    cx: &'cx Context<Pat>,
+    src: &'a str,
+    pos: usize,
+    limits: &'a Limits,
+    /// How many `term`s are being parsed, one inside the other.
+    depth: usize,
+}
+
+impl<'a, Pat> Parser<'a, '_, Pat>
+where
+    Pat: Eq + Hash + for<'s> From<&'s str> + From<RangeInclusive<char>>,
+{
+    fn error(&self, message: impl Into<String>) -> ImportError {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at(&self, pos: usize, message: impl Into<String>) -> ImportError {
+        ImportError {
+            at: line_column(self.src, pos),
+            message: message.into(),
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn skip_trivia(&mut self) -> Result<(), ImportError> {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if !trimmed.starts_with("/*") {
+                return Ok(());
+            }
+            match trimmed.find("*/") {
+                Some(i) => self.pos += i + 2,
+                None => return Err(self.error("unterminated comment")),
+            }
+        }
+    }
+
+    fn eat(&mut self, s: &str) -> Result<bool, ImportError> {
+        self.skip_trivia()?;
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), ImportError> {
+        if self.eat(s)? {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{}`", s)))
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a str, ImportError> {
+        self.skip_trivia()?;
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_alphanumeric() || "_-.:".contains(c)))
+            .unwrap_or(rest.len());
+        if len == 0 || rest.starts_with(|c: char| c.is_ascii_digit() || "-.".contains(c)) {
+            return Err(self.error("expected name"));
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    /// Whether the next production starts here (i.e. `Name ::=` follows).
+    fn at_production(&mut self) -> bool {
+        let pos = self.pos;
+        let found = self.name().is_ok() && matches!(self.eat("::="), Ok(true));
+        self.pos = pos;
+        found
+    }
+
+    fn choice(&mut self) -> Result<RuleWithFields, ImportError> {
+        let mut cases = vec![self.sequence()?];
+        while self.eat("|")? {
+            if cases.len() == self.limits.max_alternatives {
+                return Err(self.error("too many alternatives"));
+            }
+            cases.push(self.sequence()?);
+        }
+        Ok(any_of(cases).finish(self.cx))
+    }
+
+    fn sequence(&mut self) -> Result<RuleWithFields, ImportError> {
+        let mut elems = vec![];
+        loop {
+            self.skip_trivia()?;
+            let rest = self.rest();
+            if rest.is_empty() || rest.starts_with(['|', ')']) || self.at_production() {
+                break;
+            }
+            if rest.starts_with('-') {
+                return Err(self.error("unsupported exclusion (`A - B`)"));
+            }
+            elems.push(self.term()?);
+        }
+        if elems.is_empty() {
+            return Err(self.error("expected an expression"));
+        }
+        Ok(seq(elems).finish(self.cx))
+    }
+
+    fn term(&mut self) -> Result<RuleWithFields, ImportError> {
+        if self.depth == self.limits.max_depth {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        let rule = self.term_unlimited();
+        self.depth -= 1;
+        rule
+    }
+
+    fn term_unlimited(&mut self) -> Result<RuleWithFields, ImportError> {
+        let cx = self.cx;
+        let mut rule = self.primary()?;
+        loop {
+            rule = if self.eat("?")? {
+                rule.opt().finish(cx)
+            } else if self.eat("*")? {
+                rule.repeat_many().finish(cx)
+            } else if self.eat("+")? {
+                rule.repeat_more().finish(cx)
+            } else {
+                return Ok(rule);
+            };
+        }
+    }
+
+    fn primary(&mut self) -> Result<RuleWithFields, ImportError> {
+        let cx = self.cx;
+        self.skip_trivia()?;
+        if self.eat("(")? {
+            let rule = self.choice()?;
+            self.expect(")")?;
+            return Ok(rule);
+        }
+        if let Some(quote) = self
+            .rest()
+            .chars()
+            .next()
+            .filter(|&c| c == '"' || c == '\'')
+        {
+            self.pos += 1;
+            let len = self
+                .rest()
+                .find(quote)
+                .ok_or_else(|| self.error("unterminated literal"))?;
+            let s = &self.rest()[..len];
+            self.pos += len + 1;
+            return Ok(eat(s).finish(cx));
+        }
+        if self.rest().starts_with("#x") {
+            let c = self.char()?;
+            return Ok(eat(&c.to_string()[..]).finish(cx));
+        }
+        if self.eat("[")? {
+            let negated = self.rest().starts_with('^');
+            if negated {
+                self.pos += 1;
+            }
+            let mut ranges = vec![];
+            while !self.rest().starts_with(']') {
+                let range_start = self.pos;
+                let start = self.char()?;
+                let end = if self.rest().starts_with('-') && !self.rest()[1..].starts_with(']') {
+                    self.pos += 1;
+                    self.char()?
+                } else {
+                    start
+                };
+                if start > end {
+                    return Err(self.error_at(range_start, "character range out of order"));
+                }
+                ranges.push((start, end));
+            }
+            self.pos += 1;
+            if negated {
+                ranges = scannerless::negate_ranges(ranges);
+            }
+            if ranges.is_empty() {
+                return Err(self.error("character class without any characters"));
+            }
+            return Ok(any_of(
+                ranges
+                    .into_iter()
+                    .map(|(start, end)| eat(start..=end).finish(cx)),
+            )
+            .finish(cx));
+        }
+        Ok(call(self.name()?).finish(cx))
+    }
+
+    /// Parse a character, either `#x` followed by its code (in hex), or
+    /// itself (e.g. in a character class).
+    fn char(&mut self) -> Result<char, ImportError> {
+        let rest = self.rest();
+        if let Some(hex) = rest.strip_prefix("#x") {
+            let len = hex
+                .find(|c: char| !c.is_ascii_hexdigit())
+                .unwrap_or(hex.len());
+            let c = u32::from_str_radix(&hex[..len], 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| self.error("invalid character code"))?;
+            self.pos += 2 + len;
+            return Ok(c);
+        }
+        let c = rest
+            .chars()
+            .next()
+            .ok_or_else(|| self.error("unterminated character class"))?;
+        self.pos += c.len_utf8();
+        Ok(c)
+    }
+}
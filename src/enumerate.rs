@@ -0,0 +1,176 @@
+//! Bounded enumeration of the sentences a grammar can generate.
+
+use crate::cancel::{CancellationToken, Cancelled};
+use crate::compat::prelude::*;
+use crate::compat::{IndexMap, IndexSet};
+use crate::context::{Context, IRule, IStr};
+use crate::rule::{Rule, SepKind};
+use crate::Grammar;
+use core::hash::Hash;
+
+/// A sequence of terminals, each being the (interned) `Eat` rule eating it.
+///
+/// Using the rules instead of the patterns makes sentences cheap to compare
+/// (and hash), even across different grammars sharing the same `Context`.
+pub type Sentence = Vec<IRule>;
+
+/// All the sentences of every rule in `grammar`, with at most `max_len`
+/// terminals each (in a deterministic order, shortest derivations first).
+///
+/// Zero-width rules (`Anchor`s, `Guard`s, etc.) are assumed to always
+/// match, so flags and features should be resolved beforehand (see e.g.
+/// `Grammar::instantiate_flags` and `Grammar::configure`), while `Call`s
+/// to rules not in `grammar` never match.
+pub fn sentences<Pat: Eq + Hash>(
+    cx: &Context<Pat>,
+    grammar: &Grammar,
+    max_len: usize,
+) -> IndexMap<IStr, IndexSet<Sentence>> {
+    // NOTE nothing else can cancel this new token.
+    sentences_cancellable(cx, grammar, max_len, &CancellationToken::new()).unwrap()
+}
+
+/// Like `sentences`, but checking `cancellation` after every rule enumerated
+/// (as the number of sentences can grow exponentially with `max_len`).
+pub fn sentences_cancellable<Pat: Eq + Hash>(
+    cx: &Context<Pat>,
+    grammar: &Grammar,
+    max_len: usize,
+    cancellation: &CancellationToken,
+) -> Result<IndexMap<IStr, IndexSet<Sentence>>, Cancelled> {
+    let mut enumerator = Enumerator {
+        cx,
+        max_len,
+        named: grammar
+            .rules()
+            .keys()
+            .map(|&name| (name, IndexSet::default()))
+            .collect(),
+    };
+
+    // NOTE this is a fixpoint, because the sentences of each rule
+    // only ever grow, and there's a finite number of them (within `max_len`).
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (&name, rule) in grammar.rules() {
+            cancellation.check()?;
+            let sentences = enumerator.rule_sentences(rule.rule);
+            let named = &mut enumerator.named[&name];
+            for sentence in sentences {
+                changed |= named.insert(sentence);
+            }
+        }
+    }
+
+    Ok(enumerator.named)
+}
+
+struct Enumerator<'cx, Pat> {
+    cx: &'cx Context<Pat>,
+    max_len: usize,
+    named: IndexMap<IStr, IndexSet<Sentence>>,
+}
+
+impl<Pat: Eq + Hash> Enumerator<'_, Pat> {
+    fn empty() -> IndexSet<Sentence> {
+        let mut sentences = IndexSet::default();
+        sentences.insert(vec![]);
+        sentences
+    }
+
+    fn concat(&self, a: &IndexSet<Sentence>, b: &IndexSet<Sentence>) -> IndexSet<Sentence> {
+        let mut sentences = IndexSet::default();
+        for x in a {
+            for y in b {
+                if x.len() + y.len() <= self.max_len {
+                    sentences.insert(x.iter().chain(y).copied().collect());
+                }
+            }
+        }
+        sentences
+    }
+
+    /// `elem (sep elem)*`, followed by an optional `sep`, for `Trailing`.
+    fn repeat_more(&self, elem: IRule, sep: Option<(IRule, SepKind)>) -> IndexSet<Sentence> {
+        let elem = self.rule_sentences(elem);
+        let sep = sep.map(|(sep, kind)| (self.rule_sentences(sep), kind));
+        let step = match &sep {
+            Some((sep, _)) => self.concat(sep, &elem),
+            None => elem.clone(),
+        };
+
+        let mut sentences = elem.clone();
+        let mut last = elem;
+        while !last.is_empty() {
+            last = self
+                .concat(&last, &step)
+                .into_iter()
+                .filter(|sentence| !sentences.contains(sentence))
+                .collect();
+            sentences.extend(last.iter().cloned());
+        }
+
+        if let Some((sep, kind)) = &sep {
+            if kind.allows_trailing() {
+                let trailing = self.concat(&sentences, sep);
+                sentences.extend(trailing);
+            }
+            if kind.allows_leading() {
+                let leading = self.concat(sep, &sentences);
+                sentences.extend(leading);
+            }
+        }
+        sentences
+    }
+
+    fn rule_sentences(&self, rule: IRule) -> IndexSet<Sentence> {
+        match self.cx[rule] {
+            Rule::Empty
+            | Rule::Anchor(_)
+            | Rule::FlagGuard(..)
+            | Rule::Guard(_)
+            | Rule::Feature(..) => Self::empty(),
+            // NOTE the shortest (and simplest) sentence is the one
+            // with the terminator (presumably following this) right away.
+            Rule::EatUntil(_) => Self::empty(),
+            // NOTE only empty captures are used, so that the sentences
+            // don't depend on what was captured (see also `Rule::Capture`).
+            Rule::Backref(_) => Self::empty(),
+            Rule::Capture(_, delim, body) => {
+                if self.rule_sentences(delim).contains(&vec![]) {
+                    self.rule_sentences(body)
+                } else {
+                    IndexSet::default()
+                }
+            }
+            Rule::Eat(_) => {
+                let mut sentences = IndexSet::default();
+                if self.max_len > 0 {
+                    sentences.insert(vec![rule]);
+                }
+                sentences
+            }
+            Rule::Call(name) => self.named.get(&name).cloned().unwrap_or_default(),
+            Rule::WithFlag(_, _, rule) | Rule::Silent(rule) => self.rule_sentences(rule),
+            Rule::Concat(ref elems) => elems.iter().fold(Self::empty(), |sentences, &elem| {
+                self.concat(&sentences, &self.rule_sentences(elem))
+            }),
+            Rule::Or(ref cases) => cases
+                .iter()
+                .flat_map(|&case| self.rule_sentences(case))
+                .collect(),
+            Rule::Opt(rule) => {
+                let mut sentences = Self::empty();
+                sentences.extend(self.rule_sentences(rule));
+                sentences
+            }
+            Rule::RepeatMany(elem, sep) => {
+                let mut sentences = Self::empty();
+                sentences.extend(self.repeat_more(elem, sep));
+                sentences
+            }
+            Rule::RepeatMore(elem, sep) => self.repeat_more(elem, sep),
+        }
+    }
+}
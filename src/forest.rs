@@ -1,12 +1,12 @@
+use crate::compat::prelude::*;
+use crate::compat::{IndexMap, IndexSet};
 use crate::high::{type_lambda, ExistsL, PairL};
 use crate::input::{Input, Range};
+use alloc::collections::BTreeSet;
+use core::cmp::Ordering;
+use core::fmt;
+use core::hash::{Hash, Hasher};
 use indexing::{self, Container};
-use std::cmp::Ordering;
-use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
-use std::fmt;
-use std::hash::{Hash, Hasher};
-use std::io::{self, Write};
-use std::str;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum NodeShape<T> {
@@ -15,6 +15,10 @@ pub enum NodeShape<T> {
     Choice(usize),
     Opt(T),
     Split(T, T),
+    /// A sequence of this many children (see `GrammarReflector::node_shape_seq_get`),
+    /// e.g. the elements of a `Rule::Concat`, all at the same depth (like
+    /// their fields), instead of nested in `Split`s.
+    Seq(usize),
 }
 
 impl<T: fmt::Display> fmt::Display for NodeShape<T> {
@@ -25,6 +29,7 @@ impl<T: fmt::Display> fmt::Display for NodeShape<T> {
             NodeShape::Choice(count) => write!(f, "Choice({})", count),
             NodeShape::Opt(inner) => write!(f, "Opt({})", inner),
             NodeShape::Split(left, right) => write!(f, "Split({}, {})", left, right),
+            NodeShape::Seq(count) => write!(f, "Seq({})", count),
         }
     }
 }
@@ -37,6 +42,7 @@ impl<T> NodeShape<T> {
             NodeShape::Choice(count) => NodeShape::Choice(count),
             NodeShape::Opt(inner) => NodeShape::Opt(f(inner)),
             NodeShape::Split(left, right) => NodeShape::Split(f(left), f(right)),
+            NodeShape::Seq(count) => NodeShape::Seq(count),
         }
     }
 }
@@ -52,6 +58,7 @@ pub trait GrammarReflector {
 
     fn node_shape(&self, kind: Self::NodeKind) -> NodeShape<Self::NodeKind>;
     fn node_shape_choice_get(&self, kind: Self::NodeKind, i: usize) -> Self::NodeKind;
+    fn node_shape_seq_get(&self, kind: Self::NodeKind, i: usize) -> Self::NodeKind;
     fn node_desc(&self, kind: Self::NodeKind) -> String;
 }
 
@@ -112,7 +119,12 @@ pub struct ParseForest<'i, G: GrammarReflector, I: Input> {
     pub grammar: G,
     // HACK(eddyb) `pub(crate)` only for `parser`.
     pub(crate) input: Container<'i, I::Container>,
-    pub(crate) possibilities: HashMap<Node<'i, G>, BTreeSet<usize>>,
+    // NOTE this is an `IndexMap` so that e.g. `dump_graphviz`'s
+    // output doesn't depend on hash order (and is reproducible).
+    pub(crate) possibilities: IndexMap<Node<'i, G>, BTreeSet<usize>>,
+    /// The ranges of the children of `Seq` nodes (relative to the start of
+    /// the node), which the possibilities of those nodes are indices into.
+    pub(crate) seqs: IndexSet<Vec<(usize, usize)>>,
 }
 
 type_lambda! {
@@ -215,6 +227,49 @@ impl<'i, G: GrammarReflector, I: Input> ParseForest<'i, G, I> {
             .map(move |split| self.split_children(node, split))
     }
 
+    // NOTE this is a private helper and should never be exported.
+    fn seq_children(&self, node: Node<'i, G>, seq: usize) -> Vec<Node<'i, G>> {
+        match self.grammar.node_shape(node.kind) {
+            NodeShape::Seq(_) => self.seqs[seq]
+                .iter()
+                .enumerate()
+                .map(|(i, &(start, end))| {
+                    let (_, after, _) = node.range.split_at(start);
+                    Node {
+                        kind: self.grammar.node_shape_seq_get(node.kind, i),
+                        range: Range(after.split_at(end - start).0),
+                    }
+                })
+                .collect(),
+            shape => unreachable!(
+                "seq_children({:?}, {}): non-seq shape {:?}",
+                node, seq, shape
+            ),
+        }
+    }
+
+    pub fn one_seq(&self, node: Node<'i, G>) -> Result<Vec<Node<'i, G>>, MoreThanOne> {
+        let seqs = &self.possibilities[&node];
+        if seqs.len() > 1 {
+            return Err(MoreThanOne);
+        }
+        let &seq = seqs.iter().next().unwrap();
+        Ok(self.seq_children(node, seq))
+    }
+
+    pub fn all_seqs<'a>(
+        &'a self,
+        node: Node<'i, G>,
+    ) -> impl Iterator<Item = Vec<Node<'i, G>>> + Clone + 'a
+    where
+        G::NodeKind: 'a,
+    {
+        self.possibilities[&node]
+            .iter()
+            .cloned()
+            .map(move |seq| self.seq_children(node, seq))
+    }
+
     pub fn unpack_alias(&self, node: Node<'i, G>) -> Node<'i, G> {
         match self.grammar.node_shape(node.kind) {
             NodeShape::Alias(inner) => Node {
@@ -241,7 +296,12 @@ impl<'i, G: GrammarReflector, I: Input> ParseForest<'i, G, I> {
         }
     }
 
-    pub fn dump_graphviz(&self, out: &mut dyn Write) -> io::Result<()> {
+    #[cfg(feature = "std")]
+    pub fn dump_graphviz(&self, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+        use crate::compat::HashSet;
+        use alloc::collections::VecDeque;
+        use std::io;
+
         writeln!(out, "digraph forest {{")?;
         let mut queue: VecDeque<_> = self.possibilities.keys().cloned().collect();
         let mut seen: HashSet<_> = queue.iter().cloned().collect();
@@ -298,6 +358,13 @@ impl<'i, G: GrammarReflector, I: Input> ParseForest<'i, G, I> {
                         add_children(&[("sw", left), ("se", right)])?;
                     }
                 }
+
+                NodeShape::Seq(_) => {
+                    for children in self.all_seqs(source) {
+                        let children: Vec<_> = children.into_iter().map(|c| ("s", c)).collect();
+                        add_children(&children)?;
+                    }
+                }
             }
         }
         writeln!(out, "}}")
@@ -312,7 +379,7 @@ pub mod typed {
     enum Void {}
 
     // HACK(eddyb) this type uses `T` but is also uninhabited.
-    type PhantomVoid<T> = (Void, std::marker::PhantomData<T>);
+    type PhantomVoid<T> = (Void, core::marker::PhantomData<T>);
 
     pub trait Shaped {
         type Shape: Shape;
@@ -578,7 +645,7 @@ pub mod typed {
                     true
                 }
                 || ({
-                    use std::ops::Bound::*;
+                    use core::ops::Bound::*;
                     forest.possibilities[&node]
                         .range((Excluded(*state_split), Unbounded))
                         .next()
@@ -644,7 +711,7 @@ pub mod typed {
                     true
                 }
                 || ({
-                    use std::ops::Bound::*;
+                    use core::ops::Bound::*;
                     forest.possibilities[&node]
                         .range((Excluded(state_cases[0]), Unbounded))
                         .next()
@@ -0,0 +1,65 @@
+//! Entry points for fuzzing (used by the `fuzz/` targets), which take
+//! arbitrary bytes and exercise everything untrusted grammars can reach.
+//!
+//! None of these should ever panic (or hang), regardless of input, as
+//! invalid input should always result in an `Err` (or diagnostics), so
+//! any panic found by fuzzing them is a bug.
+
+use crate::limits::Limits;
+use crate::{pest, scannerless};
+use std::str;
+
+/// Smaller than the default `Limits`, to keep each fuzzing iteration fast.
+pub fn limits() -> Limits {
+    Limits {
+        max_depth: 64,
+        max_alternatives: 256,
+        max_nodes: 1 << 12,
+    }
+}
+
+/// Import `data` as a pest grammar and, if that succeeds, validate it,
+/// and, if it's valid, export it back.
+pub fn pest(data: &[u8]) {
+    let src = match str::from_utf8(data) {
+        Ok(src) => src,
+        Err(_) => return,
+    };
+    let cx = scannerless::Context::<String>::new();
+    let imported = match pest::import_with_limits(&cx, src, &limits()) {
+        Ok(imported) => imported,
+        Err(_) => return,
+    };
+    let grammar = imported.grammar;
+    if !grammar.validate(&cx).is_empty() {
+        return;
+    }
+    grammar.sccs(&cx);
+    grammar.fingerprint(&cx);
+    let _ = pest::export(&cx, &grammar);
+    let _ = crate::ebnf::export(&cx, &grammar);
+}
+
+/// Import `data` as a tree-sitter `grammar.json` and, if that succeeds,
+/// validate it, and, if it's valid, export it back.
+#[cfg(feature = "tree-sitter")]
+pub fn tree_sitter(data: &[u8]) {
+    use crate::tree_sitter;
+
+    let json = match str::from_utf8(data) {
+        Ok(json) => json,
+        Err(_) => return,
+    };
+    let cx = scannerless::Context::<String>::new();
+    let imported = match tree_sitter::import(&cx, json) {
+        Ok(imported) => imported,
+        Err(_) => return,
+    };
+    let grammar = imported.grammar;
+    if limits().check(&cx, &grammar).is_err() || !grammar.validate(&cx).is_empty() {
+        return;
+    }
+    grammar.sccs(&cx);
+    grammar.fingerprint(&cx);
+    let _ = tree_sitter::export(&cx, &grammar, &imported.name);
+}
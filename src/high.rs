@@ -1,7 +1,7 @@
 //! Utilities for emulating HKTs (over lifetimes) in Rust.
 
-use std::mem;
-use std::ops::{Deref, DerefMut};
+use core::mem;
+use core::ops::{Deref, DerefMut};
 
 /// Type lambda application, with a lifetime.
 pub trait ApplyL<'a> {
@@ -26,7 +26,7 @@ macro_rules! __high_type_lambda {
     ($($vis:vis type<$lt:lifetime> $name:ident $(<$($T:ident $(: $bound:path)*),*>)* = $ty:ty;)+) => {
         $($vis struct $name $(<$($T $(: $bound)*),*>)* {
             #[allow(unused_parens)]
-            _marker: ::std::marker::PhantomData<($($($T),*)*)>,
+            _marker: ::core::marker::PhantomData<($($($T),*)*)>,
         }
         impl<$lt, $($($T $(: $bound)*),*)*> $crate::high::ApplyL<$lt>
             for $name $(<$($T),*>)*
@@ -74,13 +74,13 @@ impl<T: LambdaL> DerefMut for RefMutApplyL<'_, '_, T> {
 /// data, but rather only self-contained (e.g. `indexing` or `owning_ref`).
 #[derive(Copy, Clone)]
 pub struct ErasableL<'a> {
-    _marker: ::std::marker::PhantomData<&'a mut &'a ()>,
+    _marker: ::core::marker::PhantomData<&'a mut &'a ()>,
 }
 
 impl ErasableL<'_> {
     /// Trivial proof that `'static` is erasable (it's always valid).
     pub const STATIC: ErasableL<'static> = ErasableL {
-        _marker: ::std::marker::PhantomData,
+        _marker: ::core::marker::PhantomData,
     };
 
     /// Enter an `indexing::scope`, where the closure also receives a proof that
@@ -92,7 +92,7 @@ impl ErasableL<'_> {
         ::indexing::scope(a, |container| {
             f(
                 ErasableL {
-                    _marker: ::std::marker::PhantomData,
+                    _marker: ::core::marker::PhantomData,
                 },
                 container,
             )
@@ -124,7 +124,7 @@ impl<T: LambdaL> ExistsL<T> {
         mem::forget(self);
         f(
             ErasableL {
-                _marker: ::std::marker::PhantomData,
+                _marker: ::core::marker::PhantomData,
             },
             skolem,
         )
@@ -139,7 +139,7 @@ impl<T: LambdaL> ExistsL<T> {
     ) -> R {
         f(
             ErasableL {
-                _marker: ::std::marker::PhantomData,
+                _marker: ::core::marker::PhantomData,
             },
             RefApplyL(unsafe { &*(&self.0 as *const _ as *const _) }),
         )
@@ -154,7 +154,7 @@ impl<T: LambdaL> ExistsL<T> {
     ) -> R {
         f(
             ErasableL {
-                _marker: ::std::marker::PhantomData,
+                _marker: ::core::marker::PhantomData,
             },
             RefMutApplyL(unsafe { &mut *(&mut self.0 as *mut _ as *mut _) }),
         )
@@ -0,0 +1,203 @@
+//! Dense numeric IDs for the rules, alternatives (i.e. `Or` cases), and
+//! field names of a grammar (see `Ids`), assigned once it's done being
+//! built (see `Grammar::freeze`), for lookups (and tables) by index,
+//! instead of hashing names (or paths).
+//!
+//! The interpreter keys its state (and `Derivation`s) by `RuleId`, which
+//! match `FrozenGrammar::ids` when interpreting a `FrozenGrammar`.
+//!
+//! FIXME most analyses still look rules up by name internally, and should
+//! use `RuleId`s (with a `FrozenGrammar`) instead.
+
+use crate::compat::IndexSet;
+use crate::context::{Context, IRule, IStr};
+use crate::rule::{FieldPath, MatchesEmpty, MaybeKnown, Rule, RuleWithFields};
+use crate::Grammar;
+use core::hash::Hash;
+use core::ops::Deref;
+
+/// A rule, numbered in definition order (i.e. its index in `Grammar::rules`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RuleId(pub u32);
+
+/// A case of an `Or`, numbered in the order the rules are defined in, and
+/// then in the order the `Or`s are found in each rule (outer ones first),
+/// with all the cases of the same `Or` numbered consecutively.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AltId(pub u32);
+
+/// A field name, numbered in the order it's first found in (in rules
+/// numbered in definition order).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FieldId(pub u32);
+
+/// The IDs of everything in a grammar, with lookups in both directions.
+///
+/// Alternatives are located by their rule and path (numbered like `Fields`
+/// children, see `IRule::children`), e.g. `(Expr, 1.0)` is the first case
+/// of an `Or` which is the second element of the `Concat` `Expr` is defined as.
+#[derive(Clone, Debug, Default)]
+pub struct Ids {
+    rules: IndexSet<IStr>,
+    alts: IndexSet<(IStr, FieldPath)>,
+    fields: IndexSet<IStr>,
+}
+
+pub(crate) fn id(i: usize) -> u32 {
+    u32::try_from(i).expect("more than `u32::MAX` IDs")
+}
+
+impl Ids {
+    pub fn new<Pat: Eq + Hash>(cx: &Context<Pat>, grammar: &Grammar) -> Self {
+        let mut ids = Ids {
+            rules: grammar.rules().keys().copied().collect(),
+            ..Ids::default()
+        };
+        for (&name, rule) in grammar.rules() {
+            ids.collect_alts(cx, name, rule.rule, &FieldPath::root());
+            ids.fields.extend(rule.fields.names(cx));
+        }
+        ids
+    }
+
+    fn collect_alts<Pat>(&mut self, cx: &Context<Pat>, name: IStr, rule: IRule, path: &FieldPath) {
+        let children = rule.children(cx);
+        if let Rule::Or(_) = cx[rule] {
+            for i in 0..children.len() {
+                self.alts.insert((name, path.appended(i)));
+            }
+        }
+        for (i, child) in children.into_iter().enumerate() {
+            self.collect_alts(cx, name, child, &path.appended(i));
+        }
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn rule(&self, name: IStr) -> Option<RuleId> {
+        self.rules.get_index_of(&name).map(|i| RuleId(id(i)))
+    }
+
+    pub fn rule_name(&self, rule: RuleId) -> IStr {
+        self.rules[rule.0 as usize]
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = (RuleId, IStr)> + '_ {
+        self.rules
+            .iter()
+            .enumerate()
+            .map(|(i, &name)| (RuleId(id(i)), name))
+    }
+
+    pub fn alt_count(&self) -> usize {
+        self.alts.len()
+    }
+
+    /// The alternative at `path` in `rule`, if there's an `Or` case there.
+    pub fn alt(&self, rule: IStr, path: &FieldPath) -> Option<AltId> {
+        // FIXME avoid cloning `path`, e.g. with `indexmap::Equivalent`.
+        self.alts
+            .get_index_of(&(rule, path.clone()))
+            .map(|i| AltId(id(i)))
+    }
+
+    /// The rule and path an alternative is found at.
+    pub fn alt_location(&self, alt: AltId) -> (IStr, &FieldPath) {
+        let (rule, path) = &self.alts[alt.0 as usize];
+        (*rule, path)
+    }
+
+    pub fn field_count(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn field(&self, name: IStr) -> Option<FieldId> {
+        self.fields.get_index_of(&name).map(|i| FieldId(id(i)))
+    }
+
+    pub fn field_name(&self, field: FieldId) -> IStr {
+        self.fields[field.0 as usize]
+    }
+}
+
+/// A `Grammar` which can't be mutated anymore (other than by `thaw`ing it),
+/// so that its `Ids` stay valid, and rules can be looked up by `RuleId`.
+///
+/// With the `std` feature, it's `Send + Sync`, so it can be loaded once and
+/// shared (e.g. in an `Arc`, along with its `Context`) by many threads, each
+/// parsing with its own `Interpreter` (which is cheap to create). Whole-grammar
+/// analyses are only computed once: `Grammar::sccs` and `Grammar::reachable`
+/// when frozen, and others (e.g. `Grammar::can_be_empty`) by the first thread
+/// needing them.
+pub struct FrozenGrammar {
+    grammar: Grammar,
+    ids: Ids,
+}
+
+// NOTE this is only here to catch `FrozenGrammar` (or `Context`)
+// accidentally losing `Send + Sync` (e.g. by using `Cell`s for caching).
+#[cfg(feature = "std")]
+#[allow(dead_code)]
+fn assert_send_sync() {
+    fn is_send_sync<T: Send + Sync>() {}
+    is_send_sync::<FrozenGrammar>();
+    is_send_sync::<Context<crate::scannerless::Pat>>();
+}
+
+impl FrozenGrammar {
+    pub(crate) fn new<Pat: Eq + Hash>(cx: &Context<Pat>, grammar: Grammar) -> Self {
+        // Compute the analyses which can be computed for any `Pat` up front,
+        // instead of having the first threads to use them wait on each other.
+        grammar.sccs(cx);
+        grammar.reachable(cx);
+        FrozenGrammar {
+            ids: Ids::new(cx, &grammar),
+            grammar,
+        }
+    }
+
+    pub fn ids(&self) -> &Ids {
+        &self.ids
+    }
+
+    /// Get back the `Grammar`, e.g. to mutate it (and `freeze` it again).
+    pub fn thaw(self) -> Grammar {
+        self.grammar
+    }
+
+    pub fn rule(&self, rule: RuleId) -> RuleWithFields {
+        self.grammar.rules()[rule.0 as usize]
+    }
+
+    /// Like `Grammar::can_be_empty`, but for the rule `rule`.
+    pub fn rule_can_be_empty<Pat: MatchesEmpty>(
+        &self,
+        cx: &Context<Pat>,
+        rule: RuleId,
+    ) -> MaybeKnown<bool> {
+        self.grammar.can_be_empty(cx)[rule.0 as usize]
+    }
+
+    /// Like `Grammar::reachable`, but for the rule `rule`, and as IDs.
+    pub fn reachable_from<'a, Pat>(
+        &'a self,
+        cx: &Context<Pat>,
+        rule: RuleId,
+    ) -> impl Iterator<Item = RuleId> + 'a {
+        self.grammar.reachable(cx)[rule.0 as usize]
+            .iter()
+            .map(move |&name| self.ids.rules.get_index_of(&name).unwrap())
+            .map(|i| RuleId(id(i)))
+    }
+}
+
+// NOTE only `Deref` is provided, as mutating the grammar could
+// invalidate the IDs (which `DerefMut` would allow).
+impl Deref for FrozenGrammar {
+    type Target = Grammar;
+    fn deref(&self) -> &Grammar {
+        &self.grammar
+    }
+}
@@ -2,9 +2,9 @@
 // FIXME(eddyb) ensure `indexing::Range` can't break
 // `str`'s UTF-8 requirement, without overhead
 
+use core::ops::Deref;
 use indexing::container_traits::{Contiguous, Trustworthy};
 use indexing::{Container, Range};
-use std::ops::Deref;
 
 pub struct Str(str);
 
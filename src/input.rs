@@ -1,11 +1,15 @@
+use crate::compat::prelude::*;
 use crate::indexing_str;
+use crate::normalize::{Normalization, Normalized};
+use crate::source_map;
+use alloc::borrow::Cow;
+use core::cmp::Ordering;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::{self, Deref, RangeInclusive};
+use core::str;
 use indexing::container_traits::Trustworthy;
 use indexing::{self, Container, Index, Unknown};
-use std::cmp::Ordering;
-use std::fmt;
-use std::hash::{Hash, Hasher};
-use std::ops::{self, Deref, RangeInclusive};
-use std::str;
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct Range<'i>(pub indexing::Range<'i>);
@@ -54,17 +58,6 @@ impl fmt::Debug for LineColumn {
     }
 }
 
-impl LineColumn {
-    fn count(prefix: &str) -> Self {
-        let (line, column) = prefix
-            .split('\n')
-            .enumerate()
-            .last()
-            .map_or((0, 0), |(i, s)| (i, s.chars().count()));
-        LineColumn { line, column }
-    }
-}
-
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct LineColumnRange {
     pub start: LineColumn,
@@ -96,6 +89,15 @@ pub trait Input: Sized {
         input: &Container<'i, Self::Container>,
         index: Index<'i, Unknown>,
     ) -> Self::SourceInfoPoint;
+    /// The column of `index`, if it's only preceded by whitespace on its
+    /// line (i.e. it's right after the indentation), for inputs with lines,
+    /// with tabs expanded (see `indentation_width`).
+    fn indentation<'i>(
+        _input: &Container<'i, Self::Container>,
+        _index: Index<'i, Unknown>,
+    ) -> Option<usize> {
+        None
+    }
 }
 
 impl<T> Input for &[T] {
@@ -143,8 +145,9 @@ impl<'a> Input for &'a str {
     ) -> Self::SourceInfo {
         let start = Self::source_info_point(input, range.first());
         // HACK(eddyb) add up `LineColumn`s to avoid counting twice.
-        // Ideally we'd cache around a line map, like rustc's `SourceMap`.
-        let mut end = LineColumn::count(Self::slice(input, range));
+        // Ideally we'd cache a `source_map::SourceFile` around.
+        let slice = Self::slice(input, range);
+        let mut end = source_map::line_column(slice, slice.len());
         end.line += start.line;
         if end.line == start.line {
             end.column += start.column;
@@ -155,14 +158,436 @@ impl<'a> Input for &'a str {
         input: &Container<'i, Self::Container>,
         index: Index<'i, Unknown>,
     ) -> Self::SourceInfoPoint {
-        let prefix_range = Range(input.split_at(index).0);
-        LineColumn::count(Self::slice(input, prefix_range))
+        let prefix = Self::slice(input, Range(input.split_at(index).0));
+        source_map::line_column(prefix, prefix.len())
+    }
+    fn indentation<'i>(
+        input: &Container<'i, Self::Container>,
+        index: Index<'i, Unknown>,
+    ) -> Option<usize> {
+        decoded_indentation(Self::slice(input, Range(input.split_at(index).0)))
     }
 }
 
+/// Inputs with `usize` positions (byte offsets for text, indices for other
+/// slices), for the `interpreter`, which, unlike `Input`, don't have to be
+/// contiguous in memory (e.g. `Rope`), see also `IndexedInputMatch`.
+pub trait IndexedInput {
+    /// The type of the parts of the input given to guards (see
+    /// `parser::Guards`), before and after some position, e.g. `str`.
+    type Slice: ?Sized + ToOwned;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// The input before and after `pos`, which non-contiguous inputs have
+    /// to copy (but this is only needed by guards, so it should be rare).
+    fn split_at(&self, pos: usize) -> (Cow<'_, Self::Slice>, Cow<'_, Self::Slice>);
+    /// Like `Input::indentation`.
+    fn indentation(&self, _pos: usize) -> Option<usize> {
+        None
+    }
+    /// The position right after `pos`, e.g. the next character boundary
+    /// for text (or the end of the input, if `pos` is already there).
+    fn next_position(&self, pos: usize) -> usize {
+        (pos + 1).min(self.len())
+    }
+    /// Whether the input at `pos` starts with the same contents as `range`
+    /// (e.g. for `Rule::Backref`), which is never the case by default, for
+    /// inputs which can't compare parts of themselves.
+    fn repeats_at(&self, _pos: usize, _range: ops::Range<usize>) -> bool {
+        false
+    }
+    /// Call `f` with this input normalized (see `Grammar::set_input_normalization`),
+    /// and with how to map positions in it back (if anything was normalized),
+    /// which only text inputs support, so by default, `f` gets the input as-is.
+    ///
+    /// FIXME only `str` is normalized so far, not other text inputs (e.g. `Rope`).
+    fn with_normalized<R>(
+        &self,
+        _normalization: Normalization,
+        f: impl FnOnce(&Self, Option<&Normalized>) -> R,
+    ) -> R {
+        f(self, None)
+    }
+}
+
+pub trait IndexedInputMatch<Pat: ?Sized>: IndexedInput {
+    /// Match `pat` at `pos`, returning the length of the match.
+    fn match_at(&self, pos: usize, pat: &Pat) -> Option<usize>;
+    /// Like `match_at`, but ignoring differences in case (for terminals in
+    /// rules with `rule::Modifiers::ignore_case`), which inputs without a
+    /// notion of case don't support (i.e. this is `match_at` by default).
+    fn match_at_ignore_case(&self, pos: usize, pat: &Pat) -> Option<usize> {
+        self.match_at(pos, pat)
+    }
+}
+
+impl IndexedInput for str {
+    type Slice = str;
+    fn len(&self) -> usize {
+        self.len()
+    }
+    fn split_at(&self, pos: usize) -> (Cow<'_, str>, Cow<'_, str>) {
+        let (before, after) = self.split_at(pos);
+        (Cow::Borrowed(before), Cow::Borrowed(after))
+    }
+    fn indentation(&self, pos: usize) -> Option<usize> {
+        decoded_indentation(&self[..pos])
+    }
+    fn next_position(&self, pos: usize) -> usize {
+        pos + self[pos..].chars().next().map_or(0, |c| c.len_utf8())
+    }
+    fn repeats_at(&self, pos: usize, range: ops::Range<usize>) -> bool {
+        self[pos..].starts_with(&self[range])
+    }
+    fn with_normalized<R>(
+        &self,
+        normalization: Normalization,
+        f: impl FnOnce(&Self, Option<&Normalized>) -> R,
+    ) -> R {
+        if normalization.is_identity() {
+            return f(self, None);
+        }
+        let normalized = Normalized::new(self, normalization);
+        f(normalized.as_str(), Some(&normalized))
+    }
+}
+
+impl<Pat: ?Sized> IndexedInputMatch<Pat> for str
+where
+    str: InputMatch<Pat>,
+{
+    fn match_at(&self, pos: usize, pat: &Pat) -> Option<usize> {
+        self[pos..].match_left(pat)
+    }
+    fn match_at_ignore_case(&self, pos: usize, pat: &Pat) -> Option<usize> {
+        self[pos..].match_left_ignore_case(pat)
+    }
+}
+
+impl<T: Clone> IndexedInput for [T] {
+    type Slice = [T];
+    fn len(&self) -> usize {
+        self.len()
+    }
+    fn split_at(&self, pos: usize) -> (Cow<'_, [T]>, Cow<'_, [T]>) {
+        let (before, after) = self.split_at(pos);
+        (Cow::Borrowed(before), Cow::Borrowed(after))
+    }
+}
+
+impl<T: Clone, Pat: ?Sized> IndexedInputMatch<Pat> for [T]
+where
+    [T]: InputMatch<Pat>,
+{
+    fn match_at(&self, pos: usize, pat: &Pat) -> Option<usize> {
+        self[pos..].match_left(pat)
+    }
+}
+
+/// Text split into chunks, e.g. those of a rope (as editors tend to keep
+/// text in), which can be matched against without concatenating them.
+#[derive(Clone, Debug, Default)]
+pub struct Rope<'a> {
+    /// The (non-empty) chunks, each with its offset in the whole text.
+    chunks: Vec<(usize, &'a str)>,
+    len: usize,
+}
+
+impl<'a> Rope<'a> {
+    pub fn new(chunks: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut rope = Rope::default();
+        for chunk in chunks {
+            if !chunk.is_empty() {
+                rope.chunks.push((rope.len, chunk));
+                rope.len += chunk.len();
+            }
+        }
+        rope
+    }
+
+    /// The chunks after `pos`, the first one starting exactly at `pos`.
+    fn chunks_from(&self, pos: usize) -> impl Iterator<Item = &'a str> + '_ {
+        let i = self
+            .chunks
+            .partition_point(|&(start, chunk)| start + chunk.len() <= pos);
+        self.chunks[i..].iter().enumerate().map(
+            move |(j, &(start, chunk))| {
+                if j == 0 {
+                    &chunk[pos - start..]
+                } else {
+                    chunk
+                }
+            },
+        )
+    }
+
+    /// The chunks before `pos`, in reverse, the first one ending at `pos`.
+    fn chunks_before(&self, pos: usize) -> impl Iterator<Item = &'a str> + '_ {
+        let i = self.chunks.partition_point(|&(start, _)| start < pos);
+        self.chunks[..i]
+            .iter()
+            .rev()
+            .enumerate()
+            .map(
+                move |(j, &(start, chunk))| {
+                    if j == 0 {
+                        &chunk[..pos - start]
+                    } else {
+                        chunk
+                    }
+                },
+            )
+    }
+
+    /// Whether `s` is found at `pos`.
+    pub fn starts_with_at(&self, pos: usize, s: &str) -> bool {
+        let mut s = s.as_bytes();
+        for chunk in self.chunks_from(pos) {
+            let n = chunk.len().min(s.len());
+            if chunk.as_bytes()[..n] != s[..n] {
+                return false;
+            }
+            s = &s[n..];
+            if s.is_empty() {
+                break;
+            }
+        }
+        s.is_empty()
+    }
+
+    /// The character at `pos`, if any.
+    pub fn char_at(&self, pos: usize) -> Option<char> {
+        self.chunks_from(pos).flat_map(|chunk| chunk.chars()).next()
+    }
+
+    fn slice(&self, chunks: impl Iterator<Item = &'a str>) -> Cow<'a, str> {
+        let mut chunks = chunks.peekable();
+        let first = chunks.next().unwrap_or("");
+        if chunks.peek().is_none() {
+            return Cow::Borrowed(first);
+        }
+        let mut s = first.to_string();
+        s.extend(chunks);
+        Cow::Owned(s)
+    }
+}
+
+impl IndexedInput for Rope<'_> {
+    type Slice = str;
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn split_at(&self, pos: usize) -> (Cow<'_, str>, Cow<'_, str>) {
+        let mut before: Vec<_> = self.chunks_before(pos).collect();
+        before.reverse();
+        (
+            self.slice(before.into_iter()),
+            self.slice(self.chunks_from(pos)),
+        )
+    }
+    fn indentation(&self, pos: usize) -> Option<usize> {
+        let mut indentation = String::new();
+        for chunk in self.chunks_before(pos) {
+            let line = &chunk[chunk.rfind('\n').map_or(0, |i| i + 1)..];
+            if !line.chars().all(|c| c.is_whitespace()) {
+                return None;
+            }
+            indentation.insert_str(0, line);
+            if line.len() < chunk.len() {
+                break;
+            }
+        }
+        Some(indentation_width(&indentation, TAB_WIDTH))
+    }
+    fn next_position(&self, pos: usize) -> usize {
+        pos + self.char_at(pos).map_or(0, |c| c.len_utf8())
+    }
+    fn repeats_at(&self, pos: usize, range: ops::Range<usize>) -> bool {
+        // FIXME this copies everything before `range.end`, if it
+        // spans more than one chunk, which could be avoided.
+        let (before, _) = IndexedInput::split_at(self, range.end);
+        self.starts_with_at(pos, &before[range.start..])
+    }
+}
+
+/// UTF-16 text (e.g. from an editor, or a Windows API), decoded on the fly,
+/// with positions in UTF-16 code units (e.g. as used by LSP), instead of
+/// UTF-8 byte offsets.
+///
+/// Unpaired surrogates are decoded as `char::REPLACEMENT_CHARACTER`.
+#[derive(Copy, Clone, Debug)]
+pub struct Utf16<'a>(pub &'a [u16]);
+
+impl Utf16<'_> {
+    /// The character at `pos` (if any), and its length in code units.
+    pub fn char_at(&self, pos: usize) -> Option<(char, usize)> {
+        let units = self.0.get(pos..)?;
+        let c = char::decode_utf16(units.iter().copied()).next()?;
+        Some(match c {
+            Ok(c) => (c, c.len_utf16()),
+            Err(_) => (char::REPLACEMENT_CHARACTER, 1),
+        })
+    }
+
+    /// The length (in code units) of `s`, if it's found at `pos`.
+    pub fn match_str_at(&self, pos: usize, s: &str) -> Option<usize> {
+        decoded_match_str_at(|pos| self.char_at(pos), pos, s)
+    }
+
+    fn decode(units: &[u16]) -> String {
+        char::decode_utf16(units.iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+}
+
+impl IndexedInput for Utf16<'_> {
+    type Slice = str;
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+    fn split_at(&self, pos: usize) -> (Cow<'_, str>, Cow<'_, str>) {
+        let (before, after) = self.0.split_at(pos);
+        (
+            Cow::Owned(Self::decode(before)),
+            Cow::Owned(Self::decode(after)),
+        )
+    }
+    fn indentation(&self, pos: usize) -> Option<usize> {
+        decoded_indentation(&Self::decode(&self.0[..pos]))
+    }
+    fn next_position(&self, pos: usize) -> usize {
+        pos + self.char_at(pos).map_or(0, |(_, len)| len)
+    }
+    fn repeats_at(&self, pos: usize, range: ops::Range<usize>) -> bool {
+        self.0[pos..].starts_with(&self.0[range])
+    }
+}
+
+/// Latin-1 (ISO-8859-1) text, decoded on the fly, with positions in bytes
+/// of the original text (i.e. one per character), instead of UTF-8 bytes.
+#[derive(Copy, Clone, Debug)]
+pub struct Latin1<'a>(pub &'a [u8]);
+
+impl Latin1<'_> {
+    /// The character at `pos` (if any), and its length (always `1`).
+    pub fn char_at(&self, pos: usize) -> Option<(char, usize)> {
+        self.0.get(pos).map(|&b| (char::from(b), 1))
+    }
+
+    /// The length (in bytes) of `s`, if it's found at `pos`.
+    pub fn match_str_at(&self, pos: usize, s: &str) -> Option<usize> {
+        decoded_match_str_at(|pos| self.char_at(pos), pos, s)
+    }
+
+    fn decode(bytes: &[u8]) -> String {
+        bytes.iter().map(|&b| char::from(b)).collect()
+    }
+}
+
+impl IndexedInput for Latin1<'_> {
+    type Slice = str;
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+    fn split_at(&self, pos: usize) -> (Cow<'_, str>, Cow<'_, str>) {
+        let (before, after) = self.0.split_at(pos);
+        (
+            Cow::Owned(Self::decode(before)),
+            Cow::Owned(Self::decode(after)),
+        )
+    }
+    fn indentation(&self, pos: usize) -> Option<usize> {
+        decoded_indentation(&Self::decode(&self.0[..pos]))
+    }
+    fn repeats_at(&self, pos: usize, range: ops::Range<usize>) -> bool {
+        self.0[pos..].starts_with(&self.0[range])
+    }
+}
+
+/// Match `s` at `pos`, against an input decoded by `char_at` (which returns
+/// each character and its length in the input), returning the length of
+/// the match in the input (which may differ from `s.len()`).
+fn decoded_match_str_at(
+    char_at: impl Fn(usize) -> Option<(char, usize)>,
+    pos: usize,
+    s: &str,
+) -> Option<usize> {
+    let mut end = pos;
+    for expected in s.chars() {
+        let (c, len) = char_at(end)?;
+        if c != expected {
+            return None;
+        }
+        end += len;
+    }
+    Some(end - pos)
+}
+
+/// Whether `a` and `b` are the same character, ignoring case.
+// FIXME this should use case folding (see `normalize`), which also
+// handles e.g. `ß` matching `ss`, but that doesn't map characters one-to-one.
+pub(crate) fn eq_ignore_case(a: char, b: char) -> bool {
+    a == b || a.to_lowercase().eq(b.to_lowercase())
+}
+
+/// Whether `c` is in `start..=end`, ignoring case (see `eq_ignore_case`).
+pub(crate) fn in_range_ignore_case(c: char, start: char, end: char) -> bool {
+    let in_range = |c: char| start <= c && c <= end;
+    in_range(c) || c.to_lowercase().any(in_range) || c.to_uppercase().any(in_range)
+}
+
+/// Like `decoded_match_str_at`, but ignoring case (see `eq_ignore_case`).
+pub(crate) fn decoded_match_str_ignore_case_at(
+    char_at: impl Fn(usize) -> Option<(char, usize)>,
+    pos: usize,
+    s: &str,
+) -> Option<usize> {
+    let mut end = pos;
+    for expected in s.chars() {
+        let (c, len) = char_at(end)?;
+        if !eq_ignore_case(c, expected) {
+            return None;
+        }
+        end += len;
+    }
+    Some(end - pos)
+}
+
+/// Like `IndexedInput::indentation`, but for the (decoded) `prefix` of an
+/// input, before the position the indentation is wanted for.
+fn decoded_indentation(prefix: &str) -> Option<usize> {
+    let line = &prefix[prefix.rfind('\n').map_or(0, |i| i + 1)..];
+    if line.chars().all(|c| c.is_whitespace()) {
+        Some(indentation_width(line, TAB_WIDTH))
+    } else {
+        None
+    }
+}
+
+/// The tab width inputs use for their `indentation` (and the default one
+/// for `layout::OffsideRule`).
+pub const TAB_WIDTH: usize = 8;
+
+/// The width (in columns) of `indentation`, i.e. the whitespace at the start
+/// of a line, with tabs advancing it to the next multiple of `tab_width`.
+pub fn indentation_width(indentation: &str, tab_width: usize) -> usize {
+    indentation.chars().fold(0, |width, c| match c {
+        '\t' => (width / tab_width + 1) * tab_width,
+        _ => width + 1,
+    })
+}
+
 pub trait InputMatch<Pat: ?Sized> {
     fn match_left(&self, pat: &Pat) -> Option<usize>;
     fn match_right(&self, pat: &Pat) -> Option<usize>;
+    /// Like `match_left`, but ignoring differences in case (see
+    /// `IndexedInputMatch::match_at_ignore_case`).
+    fn match_left_ignore_case(&self, pat: &Pat) -> Option<usize> {
+        self.match_left(pat)
+    }
 }
 
 impl<I: ?Sized + InputMatch<Pat>, Pat: ?Sized> InputMatch<&'_ Pat> for I {
@@ -172,6 +597,9 @@ impl<I: ?Sized + InputMatch<Pat>, Pat: ?Sized> InputMatch<&'_ Pat> for I {
     fn match_right(&self, &pat: &&Pat) -> Option<usize> {
         self.match_right(pat)
     }
+    fn match_left_ignore_case(&self, &pat: &&Pat) -> Option<usize> {
+        self.match_left_ignore_case(pat)
+    }
 }
 
 impl<T: PartialEq> InputMatch<[T]> for [T] {
@@ -225,6 +653,15 @@ impl InputMatch<str> for str {
             None
         }
     }
+    fn match_left_ignore_case(&self, pat: &str) -> Option<usize> {
+        decoded_match_str_ignore_case_at(|pos| str_char_at(self, pos), 0, pat)
+    }
+}
+
+/// The character at `pos` in `s`, and its length, like `Utf16::char_at`.
+fn str_char_at(s: &str, pos: usize) -> Option<(char, usize)> {
+    let c = s[pos..].chars().next()?;
+    Some((c, c.len_utf8()))
 }
 
 impl InputMatch<RangeInclusive<char>> for str {
@@ -244,4 +681,12 @@ impl InputMatch<RangeInclusive<char>> for str {
             None
         }
     }
+    fn match_left_ignore_case(&self, pat: &RangeInclusive<char>) -> Option<usize> {
+        let c = self.chars().next()?;
+        if in_range_ignore_case(c, *pat.start(), *pat.end()) {
+            Some(c.len_utf8())
+        } else {
+            None
+        }
+    }
 }
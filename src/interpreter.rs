@@ -0,0 +1,2141 @@
+//! An interpreter, matching a grammar directly against some input text,
+//! without generating a parser first (e.g. to try out, or debug, a grammar).
+//!
+//! It finds all the possible ends of every rule at every position (as the
+//! grammar is context-free, i.e. `Or` is unordered), by backtracking, which
+//! can take exponential time in the worst case (unless memoization is enabled
+//! with `Interpreter::memo_limit`).
+//!
+//! Left recursion (e.g. `Expr = Expr "+" Term | Term`) is supported by "seed
+//! growing" (like Warth et al.'s packrat parsing extension): a rule calling
+//! itself at the same position, without matching anything in between, gets
+//! the ends found so far (initially none), and the rule is matched again,
+//! with the new ends, until no more are found.
+//!
+//! The grammar's trivia (see `Grammar::set_trivia`) is skipped between the
+//! elements of sequences and repetitions, in non-atomic rules, and is left
+//! out of derivations (and of the ranges of parse forest nodes).
+//!
+//! All the derivations of an ambiguous input can also be collected into a
+//! parse forest (see `Interpreter::parse`), which, with memoization, makes
+//! this a generalized (GLL-like) parser, for any context-free grammar.
+
+use crate::cancel::CancellationToken;
+use crate::compat::prelude::*;
+use crate::compat::{HashMap, HashSet, IndexMap, IndexSet};
+use crate::context::{Context, IFields, IRule, IStr};
+use crate::diagnostics::{json_str, Args, Localize};
+use crate::forest::{GrammarReflector, Node, NodeShape, OwnedParseForestAndNode};
+use crate::ids::{self, RuleId};
+use crate::input::{self, IndexedInput, IndexedInputMatch};
+use crate::normalize::Normalized;
+use crate::parser::{Guards, Parser};
+use crate::rule::{Anchor, Atomicity, Fields, Rule, RuleWithFields, SepKind, Shape};
+use crate::Grammar;
+use alloc::borrow::Cow;
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::cell::Cell;
+use core::fmt;
+use core::hash::Hash;
+use core::mem;
+use core::ops::Range;
+
+/// An interpreter for `grammar`, matching against inputs of type `I`
+/// (e.g. `str`, a `input::Rope`, or `input::Utf16`, see `input::IndexedInput`).
+///
+/// Rules are tracked by `RuleId` while matching, which, for an `ids::FrozenGrammar`
+/// (passed through `Deref`), are the same as the ones in its `ids`.
+pub struct Interpreter<'a, Pat, I: ?Sized + IndexedInput = str> {
+    cx: &'a Context<Pat>,
+    grammar: &'a Grammar,
+    /// The enabled features (see `Rule::Feature`), all others are disabled.
+    pub features: BTreeSet<IStr>,
+    /// Predicates for `Rule::Guard`s (missing ones result in errors).
+    pub guards: Guards<'a, I::Slice>,
+    /// Enable packrat-style memoization (of the ends of every rule matched
+    /// at every position), keeping at most this many results (later ones
+    /// are recomputed whenever needed), for a bounded amount of memory.
+    ///
+    /// Without it, backtracking-heavy grammars can take exponential time,
+    /// whereas with (enough of) it, every rule is only ever matched once at
+    /// every position, bounding the time taken by a polynomial instead.
+    pub memo_limit: Option<usize>,
+    /// Checked periodically while matching, failing with `Error::Cancelled`
+    /// once cancelled (e.g. to bound the time taken on pathological inputs).
+    pub cancellation: Option<CancellationToken>,
+    /// The most nodes the parse forests built by `parse` can have, before
+    /// failing with `Error::ResourceExhausted` (e.g. for ambiguous grammars
+    /// on adversarial inputs, where forests can get very large).
+    pub forest_limit: Option<usize>,
+    /// The most rules which can be matched inside each other (see
+    /// `Stats::peak_depth`), before failing with `Error::RecursionLimit`,
+    /// instead of overflowing the stack (e.g. for untrusted grammars, or
+    /// deeply nested inputs).
+    pub recursion_limit: Option<usize>,
+    /// The most times rules can be matched (see `Stats::calls`), before
+    /// failing with `Error::ResourceExhausted`, bounding the work done on
+    /// inputs which would take too long (e.g. due to backtracking).
+    pub step_limit: Option<usize>,
+    stats: Cell<Stats>,
+}
+
+/// Statistics about the last time the input was matched by an `Interpreter`
+/// (see `Interpreter::stats`), e.g. to pick `memo_limit`/`forest_limit`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// How many times rules were matched (including memoized results).
+    pub calls: usize,
+    /// The peak number of rules being matched at once (nested in each other).
+    pub peak_depth: usize,
+    /// How many results were memoized (see `Interpreter::memo_limit`).
+    pub memo_entries: usize,
+    /// How many nodes the parse forest had (only for `Interpreter::parse`).
+    pub forest_nodes: usize,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// `Call` to a rule not defined in the grammar.
+    Undefined(String),
+    /// `Guard` without a predicate in `Interpreter::guards`.
+    MissingGuard(String),
+    /// `Interpreter::cancellation` was cancelled.
+    Cancelled,
+    /// Some limit (e.g. `Interpreter::forest_limit`) was exceeded.
+    ResourceExhausted {
+        resource: &'static str,
+        limit: usize,
+    },
+    /// `Interpreter::recursion_limit` was exceeded at `at` (in the input),
+    /// while expanding `cycle`, i.e. the rules calling each other (starting
+    /// and ending with the same rule), or just the innermost rule, if none
+    /// of the rules being matched was being matched already.
+    RecursionLimit {
+        limit: usize,
+        cycle: Vec<String>,
+        at: usize,
+    },
+    /// The reserved `rule` (see `rule::Modifiers::reserved`) was derived,
+    /// matching `span` (in the input), with its `message`.
+    Reserved {
+        rule: String,
+        message: String,
+        span: Range<usize>,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Undefined(rule) => write!(f, "no rule named `{}`", rule),
+            Error::MissingGuard(guard) => write!(f, "no predicate for guard `{}`", guard),
+            Error::Cancelled => f.write_str("cancelled"),
+            Error::ResourceExhausted { resource, limit } => {
+                write!(f, "more than {} {}", limit, resource)
+            }
+            Error::RecursionLimit { limit, cycle, at } => {
+                let cycle: Vec<_> = cycle.iter().map(|rule| format!("`{}`", rule)).collect();
+                write!(
+                    f,
+                    "more than {} nested rules, expanding {} at {}",
+                    limit,
+                    cycle.join(" -> "),
+                    at
+                )
+            }
+            Error::Reserved {
+                rule: _,
+                message,
+                span,
+            } => write!(f, "{} (at {}..{})", message, span.start, span.end),
+        }
+    }
+}
+
+impl Localize for Error {
+    /// One of `undefined-rule` (with a `name` argument), `missing-guard`
+    /// (`guard`), `cancelled`, `resource-exhausted` (`resource` and `limit`),
+    /// `recursion-limit` (`limit`, `cycle` and `at`), and `reserved` (`rule`,
+    /// `message`, `start` and `end`).
+    fn message_id(&self) -> &'static str {
+        match self {
+            Error::Undefined(_) => "undefined-rule",
+            Error::MissingGuard(_) => "missing-guard",
+            Error::Cancelled => "cancelled",
+            Error::ResourceExhausted { .. } => "resource-exhausted",
+            Error::RecursionLimit { .. } => "recursion-limit",
+            Error::Reserved { .. } => "reserved",
+        }
+    }
+
+    fn args(&self) -> Args {
+        match self {
+            Error::Undefined(rule) => vec![("name", rule.clone())],
+            Error::MissingGuard(guard) => vec![("guard", guard.clone())],
+            Error::Cancelled => vec![],
+            Error::ResourceExhausted { resource, limit } => vec![
+                ("resource", resource.to_string()),
+                ("limit", limit.to_string()),
+            ],
+            Error::RecursionLimit { limit, cycle, at } => {
+                let cycle: Vec<_> = cycle.iter().map(|rule| format!("`{}`", rule)).collect();
+                vec![
+                    ("limit", limit.to_string()),
+                    ("cycle", cycle.join(" -> ")),
+                    ("at", at.to_string()),
+                ]
+            }
+            Error::Reserved {
+                rule,
+                message,
+                span,
+            } => vec![
+                ("rule", rule.clone()),
+                ("message", message.clone()),
+                ("start", span.start.to_string()),
+                ("end", span.end.to_string()),
+            ],
+        }
+    }
+}
+
+/// The `GrammarReflector` for the parse forests from `Interpreter::parse`,
+/// where every `Rule` is a node kind (with the shape from `node_shape`).
+///
+/// Unlike with generated parsers, `Call`s are `NodeShape::Alias`es of the
+/// rules they call, even for rules without fields, so that every rule
+/// (and any ambiguities in it) can be found in the forest.
+pub struct Reflector<'a, Pat> {
+    pub cx: &'a Context<Pat>,
+    pub grammar: &'a Grammar,
+}
+
+impl<Pat: Eq + Hash + fmt::Debug> GrammarReflector for Reflector<'_, Pat> {
+    type NodeKind = IRule;
+
+    fn node_shape(&self, kind: IRule) -> NodeShape<IRule> {
+        match self.cx[kind] {
+            Rule::Call(name) => match self.grammar.rules().get(&name) {
+                Some(rule) => NodeShape::Alias(rule.rule),
+                None => NodeShape::Opaque,
+            },
+            _ => kind.node_shape(self.cx, None),
+        }
+    }
+
+    fn node_shape_choice_get(&self, kind: IRule, i: usize) -> IRule {
+        match self.cx[kind] {
+            Rule::Or(ref cases) => cases[i],
+            _ => unreachable!(),
+        }
+    }
+
+    fn node_shape_seq_get(&self, kind: IRule, i: usize) -> IRule {
+        kind.node_shape_seq_get(self.cx, i)
+    }
+
+    fn node_desc(&self, kind: IRule) -> String {
+        kind.node_desc(self.cx)
+    }
+}
+
+/// How (part of) the input was matched by a rule (see `Interpreter::explain`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Derivation {
+    pub rule: String,
+    /// The ID of `rule` (see `ids::Ids::rule`), i.e. its index in `Grammar::rules`.
+    pub id: RuleId,
+    /// The part of the input matched by the rule (in bytes, for text).
+    pub span: Range<usize>,
+    /// The index of the case picked by every `Or` in the rule (but not in
+    /// the rules it calls), in the order they were matched in.
+    pub choices: Vec<usize>,
+    /// The derivations of all the rules called by the rule, in order.
+    pub children: Vec<Derivation>,
+    /// The named fields of the rule (but not of the rules it calls), in the
+    /// order they were matched in, e.g. once per repetition, if repeated.
+    pub fields: Vec<FieldMatch>,
+}
+
+/// A named field (see `rule::Field`), and where it matched.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldMatch {
+    pub name: String,
+    /// The part of the input matched by the field (in bytes, for text).
+    pub span: Range<usize>,
+    /// The fields nested in this one (see `rule::Field::sub`).
+    pub fields: Vec<FieldMatch>,
+}
+
+impl FieldMatch {
+    /// The text matched by the field, in `input` (which has to be the text
+    /// it was matched in).
+    pub fn text<'i>(&self, input: &'i str) -> &'i str {
+        &input[self.span.clone()]
+    }
+
+    /// All the matches of the field `name` nested in this one, in order.
+    pub fn field<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a FieldMatch> + 'a {
+        self.fields.iter().filter(move |f| f.name == name)
+    }
+
+    fn to_json(&self) -> String {
+        let fields: Vec<_> = self.fields.iter().map(|f| f.to_json()).collect();
+        format!(
+            "{{\"name\":{},\"span\":[{},{}],\"fields\":[{}]}}",
+            json_str(&self.name),
+            self.span.start,
+            self.span.end,
+            fields.join(",")
+        )
+    }
+}
+
+impl Derivation {
+    /// Serialize to a JSON object, with `rule`, `span` (`[start, end]`),
+    /// `choices`, `children` (an array of the same kind of objects) and
+    /// `fields` (an array of objects with `name`, `span` and `fields`) keys.
+    pub fn to_json(&self) -> String {
+        let choices: Vec<_> = self.choices.iter().map(|i| i.to_string()).collect();
+        let children: Vec<_> = self.children.iter().map(|d| d.to_json()).collect();
+        let fields: Vec<_> = self.fields.iter().map(|f| f.to_json()).collect();
+        format!(
+            "{{\"rule\":{},\"span\":[{},{}],\"choices\":[{}],\"children\":[{}],\"fields\":[{}]}}",
+            json_str(&self.rule),
+            self.span.start,
+            self.span.end,
+            choices.join(","),
+            children.join(","),
+            fields.join(",")
+        )
+    }
+
+    /// The text matched by the rule, in `input` (which has to be the text
+    /// it was matched in).
+    pub fn text<'i>(&self, input: &'i str) -> &'i str {
+        &input[self.span.clone()]
+    }
+
+    /// All the matches of the (outermost) field `name`, in order.
+    pub fn field<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a FieldMatch> + 'a {
+        self.fields.iter().filter(move |f| f.name == name)
+    }
+
+    /// All the matches of the last field in `path`, nested in matches of the
+    /// ones before it, in order, e.g. `["pairs", "pair", "key"]` finds every
+    /// `key` field, even if `pair` is repeated (and so matched many times).
+    pub fn field_path<'a>(&'a self, path: &[&'a str]) -> Vec<&'a FieldMatch> {
+        let (first, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return vec![],
+        };
+        let mut matches: Vec<_> = self.field(first).collect();
+        for name in rest {
+            matches = matches.into_iter().flat_map(|f| f.field(name)).collect();
+        }
+        matches
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        write!(
+            f,
+            "{:indent$}{} @ {:?}",
+            "",
+            self.rule,
+            self.span,
+            indent = indent
+        )?;
+        if !self.choices.is_empty() {
+            let choices: Vec<_> = self.choices.iter().map(|i| i.to_string()).collect();
+            write!(f, " (cases {})", choices.join(", "))?;
+        }
+        for child in &self.children {
+            writeln!(f)?;
+            child.fmt_indented(f, indent + 2)?;
+        }
+        Ok(())
+    }
+}
+
+/// A part of the input, in the result of `Interpreter::explain_tolerant`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Recovered {
+    /// A part of the input matched by the rule.
+    Parsed(Derivation),
+    /// A part of the input skipped to recover from an error (in bytes, for
+    /// text), i.e. which the rule couldn't match any prefix of.
+    Error(Range<usize>),
+}
+
+/// A terminal which failed to match (see `Interpreter::explain_failure`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Failure {
+    /// Where in the input the terminal was expected (in bytes, for text).
+    pub at: usize,
+    /// The terminal (see `IRule::node_desc`), e.g. `"+"`, or `EOI` for
+    /// the end of the input.
+    pub expected: String,
+    /// A human-readable name for what was expected, e.g. "a statement",
+    /// from the outermost rule starting at `at` with one (see
+    /// `Grammar::set_label`), or the terminal (see `Grammar::set_terminal_label`).
+    pub label: Option<String>,
+    /// The partial derivation leading up to the terminal, i.e. the rules
+    /// being matched (outermost first), each with where it started.
+    pub rules: Vec<(String, usize)>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.label {
+            Some(label) => write!(f, "expected {} at {}", label, self.at)?,
+            None => write!(f, "expected `{}` at {}", self.expected, self.at)?,
+        }
+        let rules: Vec<_> = self
+            .rules
+            .iter()
+            .map(|(rule, start)| format!("{} @ {}", rule, start))
+            .collect();
+        if !rules.is_empty() {
+            write!(f, ", in {}", rules.join(" > "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Localize for Failure {
+    /// `expected` (or `expected-label`, if there's a `label`), with `expected`
+    /// (or `label`), `at` and `rules` (as in the English message) arguments.
+    fn message_id(&self) -> &'static str {
+        match self.label {
+            Some(_) => "expected-label",
+            None => "expected",
+        }
+    }
+
+    fn args(&self) -> Args {
+        let expected = match &self.label {
+            Some(label) => ("label", label.clone()),
+            None => ("expected", self.expected.clone()),
+        };
+        let rules: Vec<_> = self
+            .rules
+            .iter()
+            .map(|(rule, start)| format!("{} @ {}", rule, start))
+            .collect();
+        vec![
+            expected,
+            ("at", self.at.to_string()),
+            ("rules", rules.join(" > ")),
+        ]
+    }
+}
+
+/// Something which could come next, after some input (see `Interpreter::completions`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Completion {
+    /// The terminal (e.g. an `Eat`), or `None` for the end of the input.
+    pub terminal: Option<IRule>,
+    /// The rules which would start with the terminal, outermost first.
+    pub rule_starts: IndexSet<IStr>,
+}
+
+/// Something happening while matching (see `Interpreter::observe`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Started matching a `Frame`.
+    Enter(Frame),
+    /// Finished matching `frame`, with all of its possible `ends`.
+    Exit { frame: Frame, ends: Vec<usize> },
+    /// Tried matching `terminal` (an `Eat`) at `at`, which matched up to
+    /// `end`, if at all.
+    Terminal {
+        terminal: IRule,
+        at: usize,
+        end: Option<usize>,
+    },
+}
+
+/// Something being matched, which can contain more `Frame`s.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    /// A rule, being matched from `at`.
+    Rule { rule: IStr, at: usize },
+    /// The case `index` (out of `cases`) of an `Or` (the rule `or`), being
+    /// matched from `at`.
+    Case {
+        or: IRule,
+        index: usize,
+        cases: usize,
+        at: usize,
+    },
+}
+
+impl Frame {
+    pub fn at(self) -> usize {
+        match self {
+            Frame::Rule { at, .. } | Frame::Case { at, .. } => at,
+        }
+    }
+}
+
+/// Indented trace, with one line per rule, e.g. `Expr @ 0..3 (cases 1)`.
+impl fmt::Display for Derivation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+impl<'a, Pat, I: ?Sized + IndexedInputMatch<Pat>> Interpreter<'a, Pat, I> {
+    pub fn new(cx: &'a Context<Pat>, grammar: &'a Grammar) -> Self {
+        Interpreter {
+            cx,
+            grammar,
+            features: BTreeSet::new(),
+            guards: Guards::new(),
+            memo_limit: None,
+            cancellation: None,
+            forest_limit: None,
+            recursion_limit: None,
+            step_limit: None,
+            stats: Cell::new(Stats::default()),
+        }
+    }
+
+    /// Statistics about the last time the input was matched (by any of the
+    /// methods taking an input), even if it failed with an `Error`.
+    pub fn stats(&self) -> Stats {
+        self.stats.get()
+    }
+
+    /// Whether `rule` matches all of `input`.
+    pub fn matches(&self, rule: IStr, input: &I) -> Result<bool, Error> {
+        self.with_normalized(input, |input, _| {
+            let mut matcher = self.matcher(input);
+            let flags = Flags::default();
+            let matched = matcher.call_ends(rule, 0, &flags)?.contains(&input.len());
+            // NOTE only deriving can tell whether reserved rules were used
+            // (see `rule::Modifiers::reserved`), so it's only done if any exist.
+            let any_reserved = self
+                .grammar
+                .modifiers
+                .values()
+                .any(|m| m.reserved.is_some());
+            if matched && any_reserved {
+                matcher.derive_call(rule, 0..input.len(), &flags)?;
+            }
+            Ok(matched)
+        })
+    }
+
+    /// How `rule` matches all of `input` (if it does at all), i.e. which
+    /// rules, and which `Or` cases in them, matched which parts of `input`.
+    ///
+    /// Only one derivation is returned for ambiguous inputs, preferring
+    /// earlier `Or` cases, then shorter matches for earlier elements of
+    /// `Concat`s and repeats.
+    pub fn explain(&self, rule: IStr, input: &I) -> Result<Option<Derivation>, Error> {
+        self.with_normalized(input, |input, normalized| {
+            let mut matcher = self.matcher(input);
+            let derivation = matcher.derive_call(rule, 0..input.len(), &Flags::default())?;
+            Ok(derivation.map(|derivation| original_derivation(normalized, derivation)))
+        })
+    }
+
+    /// Like `matches`, but matching `rule` from `start` (instead of `0`),
+    /// against any prefix of the rest of `input` (instead of all of it),
+    /// returning the length of the longest match (if `rule` matches at all).
+    ///
+    /// The input before `start` is still taken into account (e.g. by
+    /// `Anchor::Column`), so fragments of a larger input (e.g. a snippet
+    /// selected in an editor) match the same as they would in the whole input.
+    pub fn parse_prefix(
+        &self,
+        rule: IStr,
+        input: &I,
+        start: usize,
+    ) -> Result<Option<usize>, Error> {
+        self.with_normalized(input, |input, normalized| {
+            let mut matcher = self.matcher(input);
+            let normalized_start = normalized.map_or(start, |n| n.normalized_start(start));
+            Ok(matcher
+                .call_ends(rule, normalized_start, &Flags::default())?
+                .last()
+                .map(|&end| original_end(normalized, end) - start))
+        })
+    }
+
+    /// Like `explain`, but for the longest match found by `parse_prefix`.
+    pub fn explain_prefix(
+        &self,
+        rule: IStr,
+        input: &I,
+        start: usize,
+    ) -> Result<Option<Derivation>, Error> {
+        self.with_normalized(input, |input, normalized| {
+            let mut matcher = self.matcher(input);
+            let flags = Flags::default();
+            let start = normalized.map_or(start, |n| n.normalized_start(start));
+            let derivation = match matcher.call_ends(rule, start, &flags)?.last() {
+                Some(&end) => matcher.derive_call(rule, start..end, &flags)?,
+                None => None,
+            };
+            Ok(derivation.map(|derivation| original_derivation(normalized, derivation)))
+        })
+    }
+
+    /// Like `explain`, but never failing to match: `rule` matches as much
+    /// of `input` as it can (the furthest it can reach), after which input
+    /// is skipped (as a `Recovered::Error`), until `rule` can match again,
+    /// and so on, until the end of `input` (e.g. for editors, which need a
+    /// tree of the input after every keystroke, even if it's not valid).
+    ///
+    /// Skipping stops right after any of the terminals in `sync` (e.g. `;`
+    /// or `}`, after which the input is likely valid again), or, if `sync`
+    /// is empty, at the first position `rule` can match anything from.
+    ///
+    /// NOTE `rule` is matched from (almost) every position, so this
+    /// is best used with memoization (see `memo_limit`).
+    pub fn explain_tolerant(
+        &self,
+        rule: IStr,
+        input: &I,
+        sync: &[Pat],
+    ) -> Result<Vec<Recovered>, Error> {
+        self.with_normalized(input, |input, normalized| {
+            let parts = self.explain_tolerant_normalized(rule, input, sync)?;
+            Ok(parts
+                .into_iter()
+                .map(|part| match part {
+                    Recovered::Parsed(derivation) => {
+                        Recovered::Parsed(original_derivation(normalized, derivation))
+                    }
+                    Recovered::Error(range) => Recovered::Error(original_range(normalized, range)),
+                })
+                .collect())
+        })
+    }
+
+    fn explain_tolerant_normalized(
+        &self,
+        rule: IStr,
+        input: &I,
+        sync: &[Pat],
+    ) -> Result<Vec<Recovered>, Error> {
+        let mut matcher = self.matcher(input);
+        let flags = Flags::default();
+        let len = input.len();
+        let mut parts = vec![];
+        let mut error_start = None;
+        let mut pos = 0;
+        loop {
+            // The furthest `rule` can reach, but never empty (except for an
+            // empty input), as empty parts wouldn't make progress.
+            let ends = matcher.call_ends(rule, pos, &flags)?;
+            let min_end = if len == 0 { 0 } else { pos + 1 };
+            match ends.range(min_end..).next_back() {
+                Some(&end) => {
+                    if let Some(start) = error_start.take() {
+                        parts.push(Recovered::Error(start..pos));
+                    }
+                    // NOTE `end` is one of the `ends`, so this can't fail.
+                    parts.extend(
+                        matcher
+                            .derive_call(rule, pos..end, &flags)?
+                            .map(Recovered::Parsed),
+                    );
+                    pos = end;
+                }
+                None => {
+                    error_start.get_or_insert(pos);
+                    pos = if sync.is_empty() {
+                        input.next_position(pos)
+                    } else {
+                        Self::skip_past_sync(input, pos, sync)
+                    };
+                }
+            }
+            if pos >= len {
+                break;
+            }
+        }
+        if let Some(start) = error_start {
+            parts.push(Recovered::Error(start..len));
+        }
+        Ok(parts)
+    }
+
+    /// The position right after the first (non-empty) match of any of
+    /// the terminals in `sync` from `pos` onwards (or the end of `input`).
+    fn skip_past_sync(input: &I, mut pos: usize, sync: &[Pat]) -> usize {
+        let len = input.len();
+        while pos < len {
+            let matched = sync.iter().filter_map(|pat| input.match_at(pos, pat));
+            if let Some(n) = matched.filter(|&n| n > 0).max() {
+                return pos + n;
+            }
+            pos = input.next_position(pos);
+        }
+        len
+    }
+
+    /// Why `rule` doesn't match all of `input` (empty if it does): the
+    /// terminals which failed to match, ranked by how far into `input` they
+    /// were expected (furthest first, as the furthest attempts are usually
+    /// the closest to being right), at `max_positions` distinct positions.
+    ///
+    /// Failures with the same `label` (see `Failure::label`) are merged, as
+    /// they only differ in which terminal (of e.g. a labeled rule) failed.
+    pub fn explain_failure(
+        &self,
+        rule: IStr,
+        input: &I,
+        max_positions: usize,
+    ) -> Result<Vec<Failure>, Error>
+    where
+        Pat: fmt::Debug,
+    {
+        self.with_normalized(input, |input, normalized| {
+            let mut failures = self.explain_failure_normalized(rule, input, max_positions)?;
+            for failure in &mut failures {
+                failure.at = original_start(normalized, failure.at);
+                for (_, start) in &mut failure.rules {
+                    *start = original_start(normalized, *start);
+                }
+            }
+            Ok(failures)
+        })
+    }
+
+    fn explain_failure_normalized(
+        &self,
+        rule: IStr,
+        input: &I,
+        max_positions: usize,
+    ) -> Result<Vec<Failure>, Error>
+    where
+        Pat: fmt::Debug,
+    {
+        let cx = self.cx;
+        let grammar = self.grammar;
+        let mut matcher = self.matcher(input);
+        matcher.failures = Some(BTreeMap::new());
+        let ends = matcher.call_ends(rule, 0, &Flags::default())?;
+        if ends.contains(&input.len()) {
+            return Ok(vec![]);
+        }
+        let label = |at, expected: Option<IRule>, rules: &Stack| {
+            let rule_label = rules
+                .iter()
+                .filter(|&&(_, start)| start == at)
+                .find_map(|&(rule, _)| grammar.modifiers(rule).label);
+            rule_label
+                .or_else(|| grammar.terminal_label(expected?))
+                .map(|label| cx[label].to_string())
+        };
+        let mut failures = matcher.failures.take().unwrap();
+        // Ending early means the end of the input was expected instead.
+        for end in ends {
+            failures
+                .entry(end)
+                .or_default()
+                .insert((None, vec![(rule, 0)]));
+        }
+
+        let mut failures: Vec<_> = failures
+            .into_iter()
+            .rev()
+            .take(max_positions)
+            .flat_map(|(at, failures)| {
+                failures.into_iter().map(move |(expected, rules)| Failure {
+                    at,
+                    label: label(at, expected, &rules),
+                    expected: expected.map_or("EOI".to_string(), |rule| rule.node_desc(cx)),
+                    rules: rules
+                        .into_iter()
+                        .map(|(rule, start)| (cx[rule].to_string(), start))
+                        .collect(),
+                })
+            })
+            .collect();
+        // NOTE e.g. every terminal a labeled rule can start with has
+        // the same label, so it only needs to be reported once.
+        let mut labeled: HashSet<_> = HashSet::default();
+        failures.retain(|failure| match &failure.label {
+            Some(label) => labeled.insert((failure.at, label.clone(), failure.rules.clone())),
+            None => true,
+        });
+        Ok(failures)
+    }
+
+    /// What could come next after `input` (e.g. the text before the cursor
+    /// in an editor), for `rule` to match more of it, i.e. every terminal
+    /// which could be matched at the end of `input` (in the order they're
+    /// first tried in), along with the rules which would start there.
+    ///
+    /// Terminals partially matching the end of `input` (e.g. a keyword being
+    /// typed) aren't found, so the end of `input` should be before them.
+    pub fn completions(&self, rule: IStr, input: &I) -> Result<Vec<Completion>, Error> {
+        self.with_normalized(input, |input, _| self.completions_normalized(rule, input))
+    }
+
+    fn completions_normalized(&self, rule: IStr, input: &I) -> Result<Vec<Completion>, Error> {
+        let len = input.len();
+        let mut matcher = self.matcher(input);
+        matcher.failures = Some(BTreeMap::new());
+        let ends = matcher.call_ends(rule, 0, &Flags::default())?;
+        let mut failures = matcher.failures.take().unwrap();
+        if ends.contains(&len) {
+            failures
+                .entry(len)
+                .or_default()
+                .insert((None, vec![(rule, 0)]));
+        }
+
+        let mut completions: IndexMap<Option<IRule>, IndexSet<IStr>> = IndexMap::default();
+        for (terminal, stack) in failures.remove(&len).unwrap_or_default() {
+            completions.entry(terminal).or_default().extend(
+                stack
+                    .into_iter()
+                    .filter(|&(_, start)| start == len)
+                    .map(|(rule, _)| rule),
+            );
+        }
+        Ok(completions
+            .into_iter()
+            .map(|(terminal, rule_starts)| Completion {
+                terminal,
+                rule_starts,
+            })
+            .collect())
+    }
+
+    /// Like `matches`, but also reporting every `Event` to `observer`, as
+    /// it happens (e.g. to trace, or collect statistics about, matching).
+    pub fn observe(
+        &self,
+        rule: IStr,
+        input: &I,
+        mut observer: impl FnMut(&Event),
+    ) -> Result<bool, Error> {
+        self.with_normalized(input, |input, normalized| {
+            let mut observer = |event: &Event| match normalized {
+                Some(normalized) => observer(&original_event(normalized, event.clone())),
+                None => observer(event),
+            };
+            let mut matcher = self.matcher(input);
+            matcher.observer = Some(&mut observer);
+            Ok(matcher
+                .call_ends(rule, 0, &Flags::default())?
+                .contains(&input.len()))
+        })
+    }
+
+    /// Call `f` with `input` normalized as the grammar requires (see
+    /// `Grammar::set_input_normalization`), with how to map positions back.
+    fn with_normalized<R>(&self, input: &I, f: impl FnOnce(&I, Option<&Normalized>) -> R) -> R {
+        input.with_normalized(self.grammar.input_normalization(), f)
+    }
+
+    fn matcher<'i>(&'i self, input: &'i I) -> Matcher<'a, 'i, Pat, I> {
+        Matcher {
+            interpreter: self,
+            input,
+            active: HashMap::default(),
+            seeds: HashMap::default(),
+            used_seeds: BTreeSet::new(),
+            deriving: HashSet::default(),
+            memo: HashMap::default(),
+            stack: vec![],
+            stats: Stats::default(),
+            failures: None,
+            observer: None,
+        }
+    }
+}
+
+impl<'a, Pat: Eq + Hash + fmt::Debug> Interpreter<'a, Pat>
+where
+    str: IndexedInputMatch<Pat>,
+{
+    /// All the ways `rule` matches all of `input` (if it does at all), as a
+    /// parse forest (with `Reflector` node kinds), rooted at a `Call` node.
+    ///
+    /// Only the ends of rules are found while matching (as usual), and the
+    /// forest is built afterwards, from those ends, so setting `memo_limit`
+    /// is strongly recommended (to avoid matching the same rules repeatedly).
+    ///
+    /// NOTE forest nodes don't track flags (see `Rule::WithFlag`), so
+    /// a rule matched with different flags, over the same range, results in
+    /// a single node (with the possibilities from all the flags combined),
+    /// which can be avoided with `Grammar::instantiate_flags`.
+    ///
+    /// NOTE unlike the other methods, this doesn't normalize `input` (see
+    /// `Grammar::set_input_normalization`), as the forest refers to it
+    /// directly, so it has to be normalized first (see `Grammar::normalize_input`).
+    pub fn parse<'i>(
+        &self,
+        rule: IStr,
+        input: &'i str,
+    ) -> Result<Option<OwnedParseForestAndNode<Reflector<'a, Pat>, &'i str>>, Error>
+    where
+        Pat: Eq + Hash + fmt::Debug,
+    {
+        let reflector = Reflector {
+            cx: self.cx,
+            grammar: self.grammar,
+        };
+        let mut error = None;
+        let result = Parser::<_, _, ()>::parse_with(reflector, input, |mut parser| {
+            let mut matcher = self.matcher(input);
+            match matcher.build_forest(&mut parser, rule) {
+                Ok(node) => node,
+                Err(e) => {
+                    error = Some(e);
+                    None
+                }
+            }
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(result.ok()),
+        }
+    }
+}
+
+/// How many rules are matched between checks of `Interpreter::cancellation`
+/// (as checking a deadline requires getting the current time).
+const CANCELLATION_CHECK_PERIOD: usize = 1024;
+
+/// The flags set (see `Rule::WithFlag`), and the ranges of the input
+/// captured (see `Rule::Capture`), at some point during matching.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+struct Flags {
+    set: BTreeSet<IStr>,
+    captures: BTreeMap<IStr, Range<usize>>,
+    /// Whether terminals ignore case, i.e. the rule being matched has
+    /// `rule::Modifiers::ignore_case` (which isn't inherited by callees).
+    ignore_case: bool,
+    /// Whether trivia (see `Grammar::set_trivia`) isn't skipped, i.e. the
+    /// rule being matched is (or is called from) an atomic rule.
+    atomic: bool,
+}
+
+/// The rules being matched at some point, with their starts.
+type Stack = Vec<(IStr, usize)>;
+
+/// Terminals which failed to match (`None` being the end of the input),
+/// by position, each with the `Stack` at the point it was attempted.
+type Failures = BTreeMap<usize, IndexSet<(Option<IRule>, Stack)>>;
+
+struct Matcher<'a, 'i, Pat, I: ?Sized + IndexedInput> {
+    interpreter: &'i Interpreter<'a, Pat, I>,
+    input: &'i I,
+    /// The rules currently being matched (and where, with which flags),
+    /// with their depth in `stack`, used to detect left recursion.
+    active: HashMap<(RuleId, usize, Flags), usize>,
+    /// The ends found so far, for left-recursive rules being matched.
+    seeds: HashMap<(RuleId, usize, Flags), BTreeSet<usize>>,
+    /// The depths (in `stack`) of the left-recursive rules whose seeds were
+    /// used by the rule being matched (so far), which can't be memoized, as
+    /// they could still find more ends, if any of those seeds grow.
+    used_seeds: BTreeSet<usize>,
+    /// The rules (with spans and flags) being derived, to avoid cycles.
+    deriving: HashSet<(RuleId, usize, usize, Flags)>,
+    /// The ends of rules already matched (see `Interpreter::memo_limit`).
+    memo: HashMap<(RuleId, usize, Flags), BTreeSet<usize>>,
+    /// The rules currently being matched, in order.
+    stack: Stack,
+    /// Statistics so far, reported to the interpreter once done.
+    stats: Stats,
+    /// Terminals which failed to match, if being recorded.
+    failures: Option<Failures>,
+    observer: Option<&'i mut dyn FnMut(&Event)>,
+}
+
+impl<Pat, I: ?Sized + IndexedInput> Drop for Matcher<'_, '_, Pat, I> {
+    fn drop(&mut self) {
+        self.interpreter.stats.set(self.stats);
+    }
+}
+
+/// Derivation steps within a rule, being accumulated.
+#[derive(Default)]
+struct Steps {
+    choices: Vec<usize>,
+    children: Vec<Derivation>,
+    fields: Vec<FieldMatch>,
+}
+
+impl Steps {
+    fn mark(&self) -> (usize, usize, usize) {
+        (self.choices.len(), self.children.len(), self.fields.len())
+    }
+
+    fn reset(&mut self, (choices, children, fields): (usize, usize, usize)) {
+        self.choices.truncate(choices);
+        self.children.truncate(children);
+        self.fields.truncate(fields);
+    }
+}
+
+impl<Pat, I: ?Sized + IndexedInputMatch<Pat>> Matcher<'_, '_, Pat, I> {
+    fn at_anchor(&self, anchor: Anchor, pos: usize) -> bool {
+        match anchor {
+            Anchor::Start => pos == 0,
+            Anchor::End => pos == self.input.len(),
+            Anchor::Column(column) => self.input.indentation(pos) == Some(column),
+        }
+    }
+
+    /// The flags the rule `name` is matched with, when called with `flags`.
+    fn call_flags<'f>(&self, name: IStr, flags: &'f Flags) -> Cow<'f, Flags> {
+        let modifiers = self.interpreter.grammar.modifiers(name);
+        let ignore_case = modifiers.ignore_case;
+        let atomic = match modifiers.atomicity {
+            Atomicity::Inherited => flags.atomic,
+            Atomicity::Atomic | Atomicity::CompoundAtomic => true,
+            Atomicity::NonAtomic => false,
+        };
+        if flags.ignore_case == ignore_case && flags.atomic == atomic {
+            Cow::Borrowed(flags)
+        } else {
+            Cow::Owned(Flags {
+                ignore_case,
+                atomic,
+                ..flags.clone()
+            })
+        }
+    }
+
+    fn match_at(&self, pos: usize, pat: &Pat, flags: &Flags) -> Option<usize> {
+        if flags.ignore_case {
+            self.input.match_at_ignore_case(pos, pat)
+        } else {
+            self.input.match_at(pos, pat)
+        }
+    }
+
+    fn call_ends(
+        &mut self,
+        name: IStr,
+        start: usize,
+        flags: &Flags,
+    ) -> Result<BTreeSet<usize>, Error> {
+        let (id, rule) = self.lookup(name)?;
+        let flags = self.call_flags(name, flags);
+        let flags = &*flags;
+        self.stats.calls += 1;
+        if let Some(limit) = self.interpreter.step_limit {
+            if self.stats.calls > limit {
+                return Err(Error::ResourceExhausted {
+                    resource: "rule calls",
+                    limit,
+                });
+            }
+        }
+        if self.stats.calls.is_multiple_of(CANCELLATION_CHECK_PERIOD) {
+            if let Some(cancellation) = &self.interpreter.cancellation {
+                cancellation.check().map_err(|_| Error::Cancelled)?;
+            }
+        }
+
+        let key = (id, start, flags.clone());
+        let frame = Frame::Rule {
+            rule: name,
+            at: start,
+        };
+
+        // NOTE in both of these cases, the rule isn't matched again,
+        // so there are no events (or failures) from inside it.
+        let known = match self.active.get(&key) {
+            Some(&depth) => {
+                self.used_seeds.insert(depth);
+                Some(self.seeds.get(&key).cloned().unwrap_or_default())
+            }
+            None => self.memo.get(&key).cloned(),
+        };
+        if let Some(ends) = known {
+            self.emit(|| Event::Enter(frame));
+            self.emit(|| Event::Exit {
+                frame,
+                ends: ends.iter().copied().collect(),
+            });
+            return Ok(ends);
+        }
+
+        if let Some(limit) = self.interpreter.recursion_limit {
+            if self.stack.len() >= limit {
+                return Err(self.recursion_limit_error(name, start, limit));
+            }
+        }
+
+        trace_span!(TRACE, "rule", rule = &self.interpreter.cx[name], at = start);
+        self.emit(|| Event::Enter(frame));
+        let depth = self.stack.len();
+        self.active.insert(key.clone(), depth);
+        self.stack.push((name, start));
+        self.stats.peak_depth = self.stats.peak_depth.max(self.stack.len());
+        let outer_used_seeds = mem::take(&mut self.used_seeds);
+        let ends = loop {
+            let ends = self.ends(rule.rule, start, flags)?;
+            if !self.used_seeds.remove(&depth) {
+                break ends;
+            }
+            // Left recursion, grow the seed until it stops growing.
+            let seed = self.seeds.entry(key.clone()).or_default();
+            if ends.is_subset(seed) {
+                break ends;
+            }
+            seed.extend(ends);
+            trace_event!(TRACE, seed = ?seed, "growing seed");
+        };
+        self.seeds.remove(&key);
+        self.stack.pop();
+        self.active.remove(&key);
+        if let Some(limit) = self.interpreter.memo_limit {
+            if self.used_seeds.is_empty() && self.memo.len() < limit {
+                self.memo.insert(key, ends.clone());
+                self.stats.memo_entries = self.memo.len();
+            }
+        }
+        self.used_seeds.extend(outer_used_seeds);
+        trace_event!(TRACE, ends = ?ends);
+        self.emit(|| Event::Exit {
+            frame,
+            ends: ends.iter().copied().collect(),
+        });
+        Ok(ends)
+    }
+
+    /// The rule `name`, with its ID (see `ids::Ids::rule`).
+    fn lookup(&self, name: IStr) -> Result<(RuleId, RuleWithFields), Error> {
+        let cx = self.interpreter.cx;
+        let (i, _, &rule) = self
+            .interpreter
+            .grammar
+            .rules()
+            .get_full(&name)
+            .ok_or_else(|| Error::Undefined(cx[name].to_string()))?;
+        Ok((RuleId(ids::id(i)), rule))
+    }
+
+    /// The error for calling `name` at `start` exceeding `limit`, reporting
+    /// the cycle of rules (on `stack`) which led to it.
+    fn recursion_limit_error(&self, name: IStr, start: usize, limit: usize) -> Error {
+        let cx = self.interpreter.cx;
+        let cycle_start = self
+            .stack
+            .iter()
+            .rposition(|&(rule, _)| rule == name)
+            .unwrap_or(self.stack.len());
+        let cycle = self.stack[cycle_start..]
+            .iter()
+            .map(|&(rule, _)| rule)
+            .chain([name])
+            .map(|rule| cx[rule].to_string())
+            .collect();
+        Error::RecursionLimit {
+            limit,
+            cycle,
+            at: start,
+        }
+    }
+
+    /// All the possible ends of `rule` matching from `start`.
+    fn ends(&mut self, rule: IRule, start: usize, flags: &Flags) -> Result<BTreeSet<usize>, Error> {
+        let interpreter = self.interpreter;
+        let cx = interpreter.cx;
+        let empty_if = |cond: bool| -> BTreeSet<usize> {
+            if cond {
+                [start].into_iter().collect()
+            } else {
+                BTreeSet::new()
+            }
+        };
+        Ok(match cx[rule] {
+            Rule::Empty => empty_if(true),
+            Rule::Anchor(anchor) => {
+                let at_anchor = self.at_anchor(anchor, start);
+                if !at_anchor && anchor == Anchor::End {
+                    self.record_failure(rule, start);
+                }
+                empty_if(at_anchor)
+            }
+            Rule::Eat(ref pat) => {
+                let end = self.match_at(start, pat, flags).map(|n| start + n);
+                self.emit(|| Event::Terminal {
+                    terminal: rule,
+                    at: start,
+                    end,
+                });
+                if end.is_none() {
+                    self.record_failure(rule, start);
+                }
+                end.into_iter().collect()
+            }
+            Rule::EatUntil(ref pat) => {
+                let mut end = start;
+                while end < self.input.len() && self.match_at(end, pat, flags).is_none() {
+                    end = self.input.next_position(end);
+                }
+                self.emit(|| Event::Terminal {
+                    terminal: rule,
+                    at: start,
+                    end: Some(end),
+                });
+                [end].into_iter().collect()
+            }
+            Rule::Call(name) => self.call_ends(name, start, flags)?,
+            Rule::FlagGuard(flag, value) => empty_if(flags.set.contains(&flag) == value),
+            Rule::Guard(name) => {
+                let guard = interpreter
+                    .guards
+                    .get(name)
+                    .ok_or_else(|| Error::MissingGuard(cx[name].to_string()))?;
+                let (before, after) = self.input.split_at(start);
+                empty_if(guard(&before, &after))
+            }
+            Rule::Feature(feature, value) => {
+                empty_if(interpreter.features.contains(&feature) == value)
+            }
+            Rule::WithFlag(flag, value, rule) => {
+                self.ends(rule, start, &with_flag(flags, flag, value))?
+            }
+            Rule::Silent(rule) => self.ends(rule, start, flags)?,
+            Rule::Capture(name, delim, body) => {
+                let mut ends = BTreeSet::new();
+                for mid in self.ends(delim, start, flags)? {
+                    ends.extend(self.ends(body, mid, &with_capture(flags, name, start..mid))?);
+                }
+                ends
+            }
+            Rule::Backref(name) => {
+                let end = flags
+                    .captures
+                    .get(&name)
+                    .filter(|captured| self.input.repeats_at(start, (*captured).clone()))
+                    .map(|captured| start + captured.len());
+                self.emit(|| Event::Terminal {
+                    terminal: rule,
+                    at: start,
+                    end,
+                });
+                if end.is_none() {
+                    self.record_failure(rule, start);
+                }
+                end.into_iter().collect()
+            }
+            Rule::Concat(ref rules) => self.seq_ends(rules, start, start, flags)?,
+            Rule::Or(ref cases) => {
+                let mut ends = BTreeSet::new();
+                for (index, &case) in cases.iter().enumerate() {
+                    let frame = Frame::Case {
+                        or: rule,
+                        index,
+                        cases: cases.len(),
+                        at: start,
+                    };
+                    self.emit(|| Event::Enter(frame));
+                    let case_ends = self.ends(case, start, flags)?;
+                    self.emit(|| Event::Exit {
+                        frame,
+                        ends: case_ends.iter().copied().collect(),
+                    });
+                    ends.extend(case_ends);
+                }
+                ends
+            }
+            Rule::Opt(rule) => {
+                let mut ends = self.ends(rule, start, flags)?;
+                ends.insert(start);
+                ends
+            }
+            Rule::RepeatMany(elem, sep) => {
+                let mut ends = self.repeat_ends(elem, sep, start, flags)?;
+                ends.insert(start);
+                ends
+            }
+            Rule::RepeatMore(elem, sep) => self.repeat_ends(elem, sep, start, flags)?,
+        })
+    }
+
+    fn emit(&mut self, event: impl FnOnce() -> Event) {
+        if let Some(observer) = &mut self.observer {
+            observer(&event());
+        }
+    }
+
+    fn record_failure(&mut self, rule: IRule, at: usize) {
+        if let Some(failures) = &mut self.failures {
+            failures
+                .entry(at)
+                .or_default()
+                .insert((Some(rule), self.stack.clone()));
+        }
+    }
+
+    /// The positions reachable from `pos` by skipping trivia (see
+    /// `Grammar::set_trivia`) one or more times, i.e. none if there is
+    /// no trivia, or the rule being matched is atomic.
+    fn trivia_ends(&mut self, pos: usize, flags: &Flags) -> Result<BTreeSet<usize>, Error> {
+        let mut ends = BTreeSet::new();
+        let trivia = match self.interpreter.grammar.trivia() {
+            Some(trivia) if !flags.atomic => trivia.rule,
+            _ => return Ok(ends),
+        };
+        let trivia_flags = Flags {
+            atomic: true,
+            ..Flags::default()
+        };
+        // NOTE trivia is never what's expected, so failing to match it
+        // isn't recorded (see `Interpreter::expected`).
+        let failures = self.failures.take();
+        let mut queue = vec![pos];
+        let mut result = Ok(());
+        while let Some(pos) = queue.pop() {
+            match self.ends(trivia, pos, &trivia_flags) {
+                Ok(trivia_ends) => queue.extend(
+                    trivia_ends
+                        .into_iter()
+                        .filter(|&end| end > pos && ends.insert(end)),
+                ),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.failures = failures;
+        result.map(|()| ends)
+    }
+
+    /// Like `ends`, but if `skip` is set, also after skipping trivia from
+    /// `start` (see `trivia_ends`), only if `rule` then matches something,
+    /// as trivia is only skipped between two terminals.
+    fn ends_after_trivia(
+        &mut self,
+        skip: bool,
+        rule: IRule,
+        start: usize,
+        flags: &Flags,
+    ) -> Result<BTreeSet<usize>, Error> {
+        let mut ends = self.ends(rule, start, flags)?;
+        if skip {
+            for after in self.trivia_ends(start, flags)? {
+                ends.extend(
+                    self.ends(rule, after, flags)?
+                        .into_iter()
+                        .filter(|&end| end > after),
+                );
+            }
+        }
+        Ok(ends)
+    }
+
+    /// All the possible ends of the elements of a `Concat` (`rules`), which
+    /// started at `seq_start`, matching from `start` (as trivia can only be
+    /// skipped after some of the `Concat` matched something).
+    fn seq_ends(
+        &mut self,
+        rules: &[IRule],
+        start: usize,
+        seq_start: usize,
+        flags: &Flags,
+    ) -> Result<BTreeSet<usize>, Error> {
+        let mut ends: BTreeSet<_> = [start].into_iter().collect();
+        for &rule in rules {
+            let mut next = BTreeSet::new();
+            for end in ends {
+                next.extend(self.ends_after_trivia(end > seq_start, rule, end, flags)?);
+            }
+            ends = next;
+        }
+        Ok(ends)
+    }
+
+    /// All the possible ends of `elem (sep elem)*` (plus a leading and/or
+    /// trailing `sep`, depending on its `SepKind`) matching from `start`.
+    fn repeat_ends(
+        &mut self,
+        elem: IRule,
+        sep: Option<(IRule, SepKind)>,
+        start: usize,
+        flags: &Flags,
+    ) -> Result<BTreeSet<usize>, Error> {
+        let mut ends = BTreeSet::new();
+        let mut trailing = BTreeSet::new();
+        // NOTE each end is paired with the end of whatever came before the
+        // element, as trivia can only be skipped after an element (or a
+        // separator) which matched something (and all do, after trivia).
+        let mut queue: Vec<_> = self
+            .ends(elem, start, flags)?
+            .into_iter()
+            .map(|end| (start, end))
+            .collect();
+        if let Some((sep, kind)) = sep {
+            if kind.allows_leading() {
+                for sep_end in self.ends(sep, start, flags)? {
+                    let elem_ends =
+                        self.ends_after_trivia(sep_end > start, elem, sep_end, flags)?;
+                    queue.extend(elem_ends.into_iter().map(|end| (sep_end, end)));
+                }
+            }
+        }
+        let mut seen = BTreeSet::new();
+        while let Some((before, end)) = queue.pop() {
+            let progress = end > before;
+            if !seen.insert((end, progress)) {
+                continue;
+            }
+            ends.insert(end);
+            match sep {
+                None => {
+                    let elem_ends = self.ends_after_trivia(progress, elem, end, flags)?;
+                    queue.extend(elem_ends.into_iter().map(|next| (end, next)));
+                }
+                Some((sep, kind)) => {
+                    for sep_end in self.ends_after_trivia(progress, sep, end, flags)? {
+                        if kind.allows_trailing() {
+                            trailing.insert(sep_end);
+                        }
+                        let elem_ends =
+                            self.ends_after_trivia(sep_end > end, elem, sep_end, flags)?;
+                        queue.extend(elem_ends.into_iter().map(|next| (sep_end, next)));
+                    }
+                }
+            }
+        }
+        ends.extend(trailing);
+        Ok(ends)
+    }
+
+    fn derive_call(
+        &mut self,
+        name: IStr,
+        span: Range<usize>,
+        flags: &Flags,
+    ) -> Result<Option<Derivation>, Error> {
+        if !self.call_ends(name, span.start, flags)?.contains(&span.end) {
+            return Ok(None);
+        }
+        let flags = self.call_flags(name, flags);
+        let flags = &*flags;
+        // NOTE a derivation of a rule matching `span`, which contains
+        // another one of the same rule matching `span`, can always be replaced
+        // with the inner one, so those are skipped (as they'd never end).
+        let (id, rule) = self.lookup(name)?;
+        let key = (id, span.start, span.end, flags.clone());
+        if !self.deriving.insert(key.clone()) {
+            return Ok(None);
+        }
+        let mut steps = Steps::default();
+        let found = self.derive(
+            rule.rule,
+            Some(rule.fields),
+            span.clone(),
+            flags,
+            &mut steps,
+        );
+        self.deriving.remove(&key);
+        if !found? {
+            return Ok(None);
+        }
+        // NOTE this is reported as soon as a derivation is found, even
+        // if e.g. a later `Or` case could have derived the rest without it,
+        // as reserved syntax is meant to be claimed before any other use.
+        if let Some(message) = self.interpreter.grammar.modifiers(name).reserved {
+            let cx = self.interpreter.cx;
+            return Err(Error::Reserved {
+                rule: cx[name].to_string(),
+                message: cx[message].to_string(),
+                span,
+            });
+        }
+        // NOTE atomic rules hide the rules they call (like pest's `@`).
+        if self.interpreter.grammar.modifiers(name).atomicity == Atomicity::Atomic {
+            steps.children.clear();
+        }
+        Ok(Some(Derivation {
+            rule: self.interpreter.cx[name].to_string(),
+            id,
+            span,
+            choices: steps.choices,
+            children: steps.children,
+            fields: steps.fields,
+        }))
+    }
+
+    /// The fields of the `i`-th child of a rule with `fields` (if any).
+    fn child_fields(&self, fields: Option<IFields>, i: usize) -> Option<IFields> {
+        match self.interpreter.cx[fields?] {
+            Fields::Aggregate(ref children) => children.get(i).copied(),
+            Fields::Leaf(_) => None,
+        }
+    }
+
+    /// Find one way for `rule` (with `fields`, if any) to match exactly
+    /// `span`, recording it in `out`.
+    fn derive(
+        &mut self,
+        rule: IRule,
+        fields: Option<IFields>,
+        span: Range<usize>,
+        flags: &Flags,
+        out: &mut Steps,
+    ) -> Result<bool, Error> {
+        if !self.ends(rule, span.start, flags)?.contains(&span.end) {
+            return Ok(false);
+        }
+        if let Some(field) = fields.and_then(|fields| match self.interpreter.cx[fields] {
+            Fields::Leaf(field) => field,
+            Fields::Aggregate(_) => None,
+        }) {
+            // NOTE the fields nested in `field` are collected on their
+            // own, to be placed inside its `FieldMatch`, instead of `out`.
+            let outer = mem::take(&mut out.fields);
+            let found = self.derive(rule, Some(field.sub), span.clone(), flags, out);
+            let mut nested = mem::replace(&mut out.fields, outer);
+            if found? {
+                match self.interpreter.grammar.field_shape(field.name) {
+                    Shape::Skip => {}
+                    Shape::Flatten => out.fields.append(&mut nested),
+                    Shape::Lift if nested.len() == 1 => out.fields.append(&mut nested),
+                    Shape::Keep | Shape::Lift => out.fields.push(FieldMatch {
+                        name: self.interpreter.cx[field.name].to_string(),
+                        span,
+                        fields: nested,
+                    }),
+                }
+                return Ok(true);
+            }
+            return Ok(false);
+        }
+        // NOTE `rule` is known to match `span` from here on, so only
+        // its components need to be searched (to find how it matches).
+        Ok(match self.interpreter.cx[rule] {
+            Rule::Empty
+            | Rule::Anchor(_)
+            | Rule::Eat(_)
+            | Rule::EatUntil(_)
+            | Rule::Backref(_)
+            | Rule::FlagGuard(..)
+            | Rule::Guard(_)
+            | Rule::Feature(..) => true,
+            Rule::Call(name) => match self.derive_call(name, span, flags)? {
+                Some(mut derivation) => {
+                    let modifiers = self.interpreter.grammar.modifiers(name);
+                    match modifiers.shape {
+                        Shape::Skip => {}
+                        // Silent rules are replaced by their children (and fields).
+                        _ if modifiers.silent => {
+                            out.children.extend(derivation.children);
+                            out.fields.extend(derivation.fields);
+                        }
+                        Shape::Lift
+                            if derivation.children.len() == 1 && derivation.fields.is_empty() =>
+                        {
+                            out.children.push(derivation.children.pop().unwrap());
+                        }
+                        Shape::Keep | Shape::Flatten | Shape::Lift => out.children.push(derivation),
+                    }
+                    true
+                }
+                None => false,
+            },
+            Rule::WithFlag(flag, value, rule) => self.derive(
+                rule,
+                self.child_fields(fields, 0),
+                span,
+                &with_flag(flags, flag, value),
+                out,
+            )?,
+            Rule::Silent(rule) => {
+                self.derive(rule, self.child_fields(fields, 0), span, flags, out)?
+            }
+            Rule::Capture(name, delim, body) => {
+                let mut found = false;
+                for mid in self.ends(delim, span.start, flags)?.range(..=span.end) {
+                    let body_flags = with_capture(flags, name, span.start..*mid);
+                    let mark = out.mark();
+                    if self.derive(
+                        delim,
+                        self.child_fields(fields, 0),
+                        span.start..*mid,
+                        flags,
+                        out,
+                    )? && self.derive(
+                        body,
+                        self.child_fields(fields, 1),
+                        *mid..span.end,
+                        &body_flags,
+                        out,
+                    )? {
+                        found = true;
+                        break;
+                    }
+                    out.reset(mark);
+                }
+                found
+            }
+            Rule::Concat(ref rules) => {
+                let fields: Vec<_> = (0..rules.len())
+                    .map(|i| self.child_fields(fields, i))
+                    .collect();
+                let seq_start = span.start;
+                self.derive_seq(rules, &fields, span, seq_start, flags, out)?
+            }
+            Rule::Or(ref cases) => {
+                let mut found = false;
+                for (i, &case) in cases.iter().enumerate() {
+                    let mark = out.mark();
+                    out.choices.push(i);
+                    if self.derive(case, self.child_fields(fields, i), span.clone(), flags, out)? {
+                        found = true;
+                        break;
+                    }
+                    out.reset(mark);
+                }
+                found
+            }
+            Rule::Opt(rule) => {
+                span.is_empty()
+                    || self.derive(rule, self.child_fields(fields, 0), span, flags, out)?
+            }
+            Rule::RepeatMany(elem, sep) => {
+                span.is_empty()
+                    || self.derive_repeat_leading(elem, sep, fields, span, flags, out)?
+            }
+            Rule::RepeatMore(elem, sep) => {
+                self.derive_repeat_leading(elem, sep, fields, span, flags, out)?
+            }
+        })
+    }
+
+    /// The positions the next element (or separator) can start at, after
+    /// `pos`: `pos` itself, and (if `skip` is set) after trivia up to `end`.
+    fn starts_after_trivia(
+        &mut self,
+        skip: bool,
+        pos: usize,
+        end: usize,
+        flags: &Flags,
+    ) -> Result<Vec<usize>, Error> {
+        let mut starts = vec![pos];
+        if skip {
+            starts.extend(self.trivia_ends(pos, flags)?.range(..=end));
+        }
+        Ok(starts)
+    }
+
+    /// Like `derive`, but for the elements of a `Concat` (`rules`) which
+    /// started at `seq_start` (see `seq_ends`), each with its fields (in
+    /// `fields`, which is the same length as `rules`).
+    fn derive_seq(
+        &mut self,
+        rules: &[IRule],
+        fields: &[Option<IFields>],
+        span: Range<usize>,
+        seq_start: usize,
+        flags: &Flags,
+        out: &mut Steps,
+    ) -> Result<bool, Error> {
+        let (&first, rest) = match rules.split_first() {
+            Some(split) => split,
+            None => return Ok(span.is_empty()),
+        };
+        let (&first_fields, rest_fields) = fields.split_first().unwrap();
+        let skip = span.start > seq_start;
+        for first_start in self.starts_after_trivia(skip, span.start, span.end, flags)? {
+            for mid in self.ends(first, first_start, flags)? {
+                if mid > span.end {
+                    break;
+                }
+                // NOTE only non-empty matches can follow trivia.
+                if first_start > span.start && mid == first_start {
+                    continue;
+                }
+                if !self
+                    .seq_ends(rest, mid, seq_start, flags)?
+                    .contains(&span.end)
+                {
+                    continue;
+                }
+                let mark = out.mark();
+                if self.derive(first, first_fields, first_start..mid, flags, out)?
+                    && self.derive_seq(rest, rest_fields, mid..span.end, seq_start, flags, out)?
+                {
+                    return Ok(true);
+                }
+                out.reset(mark);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Like `derive_repeat`, but also trying a leading `sep` first (if its
+    /// `SepKind` allows one).
+    fn derive_repeat_leading(
+        &mut self,
+        elem: IRule,
+        sep: Option<(IRule, SepKind)>,
+        fields: Option<IFields>,
+        span: Range<usize>,
+        flags: &Flags,
+        out: &mut Steps,
+    ) -> Result<bool, Error> {
+        if let Some((sep_rule, kind)) = sep {
+            if kind.allows_leading() {
+                let sep_fields = self.child_fields(fields, 1);
+                for sep_end in self.ends(sep_rule, span.start, flags)? {
+                    if sep_end > span.end {
+                        break;
+                    }
+                    let mark = out.mark();
+                    if self.derive(sep_rule, sep_fields, span.start..sep_end, flags, out)?
+                        && self.derive_repeat(
+                            elem,
+                            sep,
+                            fields,
+                            sep_end..span.end,
+                            sep_end > span.start,
+                            flags,
+                            out,
+                        )?
+                    {
+                        return Ok(true);
+                    }
+                    out.reset(mark);
+                }
+            }
+        }
+        self.derive_repeat(elem, sep, fields, span, false, flags, out)
+    }
+
+    /// Like `derive`, but for a repetition (with `fields`) of `elem`, with
+    /// no leading separator (see `derive_repeat_leading`), and with trivia
+    /// skipped before the first element if `skip` is set.
+    #[allow(clippy::too_many_arguments)]
+    fn derive_repeat(
+        &mut self,
+        elem: IRule,
+        sep: Option<(IRule, SepKind)>,
+        fields: Option<IFields>,
+        span: Range<usize>,
+        skip: bool,
+        flags: &Flags,
+        out: &mut Steps,
+    ) -> Result<bool, Error> {
+        let (elem_fields, sep_fields) =
+            (self.child_fields(fields, 0), self.child_fields(fields, 1));
+        for elem_start in self.starts_after_trivia(skip, span.start, span.end, flags)? {
+            for mid in self.ends(elem, elem_start, flags)? {
+                if mid > span.end {
+                    break;
+                }
+                if elem_start > span.start && mid == elem_start {
+                    continue;
+                }
+                let mark = out.mark();
+                if !self.derive(elem, elem_fields, elem_start..mid, flags, out)? {
+                    continue;
+                }
+                if mid == span.end {
+                    return Ok(true);
+                }
+                let progress = mid > elem_start;
+                // NOTE only repeating after making progress, to avoid
+                // looping forever on elements (and separators) matching empty.
+                let found = match sep {
+                    None => {
+                        mid > span.start
+                            && self.derive_repeat(
+                                elem,
+                                None,
+                                fields,
+                                mid..span.end,
+                                progress,
+                                flags,
+                                out,
+                            )?
+                    }
+                    Some((sep, kind)) => {
+                        let mut found = false;
+                        for sep_start in self.starts_after_trivia(progress, mid, span.end, flags)? {
+                            for sep_end in self.ends(sep, sep_start, flags)? {
+                                if sep_end > span.end {
+                                    break;
+                                }
+                                if sep_start > mid && sep_end == sep_start {
+                                    continue;
+                                }
+                                let sep_mark = out.mark();
+                                if self.derive(sep, sep_fields, sep_start..sep_end, flags, out)? {
+                                    if sep_end == span.end && kind.allows_trailing() {
+                                        found = true;
+                                    } else if sep_end > span.start {
+                                        found = self.derive_repeat(
+                                            elem,
+                                            Some((sep, kind)),
+                                            fields,
+                                            sep_end..span.end,
+                                            sep_end > sep_start,
+                                            flags,
+                                            out,
+                                        )?;
+                                    }
+                                }
+                                if found {
+                                    break;
+                                }
+                                out.reset(sep_mark);
+                            }
+                            if found {
+                                break;
+                            }
+                        }
+                        found
+                    }
+                };
+                if found {
+                    return Ok(true);
+                }
+                out.reset(mark);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl<'a, Pat: Eq + Hash + fmt::Debug> Matcher<'a, '_, Pat, str>
+where
+    str: IndexedInputMatch<Pat>,
+{
+    /// Add all the derivations of `Call(rule)` matching the whole input to
+    /// the forest being built by `parser`, starting from the root, and going
+    /// through every node (kind, range and flags) reachable from it.
+    fn build_forest<'i>(
+        &mut self,
+        parser: &mut Parser<'_, 'i, Reflector<'a, Pat>, &str, ()>,
+        rule: IStr,
+    ) -> Result<Option<Node<'i, Reflector<'a, Pat>>>, Error> {
+        let cx = self.interpreter.cx;
+        let len = self.input.len();
+        let full = parser.remaining();
+        let sub = |start: usize, end: usize| {
+            let (_, after, _) = full.split_at(start);
+            input::Range(input::Range(after).split_at(end - start).0)
+        };
+
+        // The ends of every rule matched so far, as the same rule is often
+        // needed at the same position (e.g. by both cases of an `Or`).
+        let mut all_ends: HashMap<_, _> = HashMap::default();
+        let mut ends = |this: &mut Self, rule: IRule, start: usize, flags: &Flags| {
+            let key = (rule, start, flags.clone());
+            if let Some(ends) = all_ends.get(&key) {
+                return Ok(BTreeSet::clone(ends));
+            }
+            let ends = this.ends(rule, start, flags)?;
+            all_ends.insert(key, ends.clone());
+            Ok::<_, Error>(ends)
+        };
+
+        let root = cx.intern(Rule::Call(rule));
+        if !ends(self, root, 0, &Flags::default())?.contains(&len) {
+            return Ok(None);
+        }
+
+        let reflector = Reflector {
+            cx,
+            grammar: self.interpreter.grammar,
+        };
+        let mut queue = vec![(root, 0, len, Flags::default())];
+        let mut seen: HashSet<_> = queue.iter().cloned().collect();
+        while let Some((kind, start, end, flags)) = queue.pop() {
+            let mut children = vec![];
+            match reflector.node_shape(kind) {
+                NodeShape::Opaque => {}
+                NodeShape::Alias(inner) => {
+                    let flags = match cx[kind] {
+                        Rule::WithFlag(flag, value, _) => with_flag(&flags, flag, value),
+                        Rule::Call(name) => self.call_flags(name, &flags).into_owned(),
+                        _ => flags,
+                    };
+                    children.push((inner, start, end, flags));
+                }
+                NodeShape::Opt(inner) => {
+                    if start < end {
+                        children.push((inner, start, end, flags));
+                    }
+                }
+                NodeShape::Choice(count) => {
+                    for i in 0..count {
+                        let case = reflector.node_shape_choice_get(kind, i);
+                        if ends(self, case, start, &flags)?.contains(&end) {
+                            parser
+                                .with_result_and_remaining(sub(start, end), sub(end, len))
+                                .forest_add_choice(kind, i);
+                            children.push((case, start, end, flags.clone()));
+                        }
+                    }
+                }
+                NodeShape::Split(left, right) => {
+                    for mid in ends(self, left, start, &flags)?.range(..=end) {
+                        let right_flags = match cx[kind] {
+                            Rule::Capture(name, ..) => with_capture(&flags, name, start..*mid),
+                            _ => flags.clone(),
+                        };
+                        if ends(self, right, *mid, &right_flags)?.contains(&end) {
+                            parser
+                                .with_result_and_remaining(sub(*mid, end), sub(end, len))
+                                .forest_add_split(
+                                    kind,
+                                    Node {
+                                        kind: left,
+                                        range: sub(start, *mid),
+                                    },
+                                );
+                            children.push((left, start, *mid, flags.clone()));
+                            children.push((right, *mid, end, right_flags));
+                        }
+                    }
+                }
+                NodeShape::Seq(count) => {
+                    let elems: Vec<_> = (0..count)
+                        .map(|i| reflector.node_shape_seq_get(kind, i))
+                        .collect();
+                    // Every way of splitting `start..end` between the elements,
+                    // found by extending partial splits one element at a time
+                    // (each with the ranges of its elements, and where it ends),
+                    // with trivia (see `seq_ends`) left outside of those ranges.
+                    let mut splits = vec![(vec![], start)];
+                    for (i, &elem) in elems.iter().enumerate() {
+                        let mut next = vec![];
+                        for (ranges, pos) in splits {
+                            for elem_start in
+                                self.starts_after_trivia(pos > start, pos, end, &flags)?
+                            {
+                                for &mid in ends(self, elem, elem_start, &flags)?.range(..=end) {
+                                    if elem_start > pos && mid == elem_start {
+                                        continue;
+                                    }
+                                    if self
+                                        .seq_ends(&elems[i + 1..], mid, start, &flags)?
+                                        .contains(&end)
+                                    {
+                                        let mut ranges = ranges.clone();
+                                        ranges.push((elem_start, mid));
+                                        next.push((ranges, mid));
+                                    }
+                                }
+                            }
+                        }
+                        splits = next;
+                    }
+                    for (ranges, _) in splits {
+                        let subs: Vec<_> = ranges.iter().map(|&(s, e)| sub(s, e)).collect();
+                        parser
+                            .with_result_and_remaining(sub(start, end), sub(end, len))
+                            .forest_add_seq(kind, &subs);
+                        children.extend(
+                            elems
+                                .iter()
+                                .zip(ranges)
+                                .map(|(&elem, (s, e))| (elem, s, e, flags.clone())),
+                        );
+                    }
+                }
+            }
+            for child in children {
+                if seen.insert(child.clone()) {
+                    self.stats.forest_nodes = seen.len();
+                    if let Some(limit) = self.interpreter.forest_limit {
+                        if seen.len() > limit {
+                            return Err(Error::ResourceExhausted {
+                                resource: "forest nodes",
+                                limit,
+                            });
+                        }
+                    }
+                    queue.push(child);
+                }
+            }
+        }
+
+        Ok(Some(Node {
+            kind: root,
+            range: full,
+        }))
+    }
+}
+
+/// Map `pos`, in a normalized input, back to the original input (see
+/// `Normalized::original_start`), if the input was normalized at all.
+fn original_start(normalized: Option<&Normalized>, pos: usize) -> usize {
+    normalized.map_or(pos, |normalized| normalized.original_start(pos))
+}
+
+/// Like `original_start`, but for an end (see `Normalized::original_end`).
+fn original_end(normalized: Option<&Normalized>, pos: usize) -> usize {
+    normalized.map_or(pos, |normalized| normalized.original_end(pos))
+}
+
+fn original_range(normalized: Option<&Normalized>, range: Range<usize>) -> Range<usize> {
+    original_start(normalized, range.start)..original_end(normalized, range.end)
+}
+
+fn original_derivation(normalized: Option<&Normalized>, derivation: Derivation) -> Derivation {
+    match normalized {
+        Some(normalized) => normalized.original_derivation(derivation),
+        None => derivation,
+    }
+}
+
+fn original_event(normalized: &Normalized, event: Event) -> Event {
+    let frame = |frame| match frame {
+        Frame::Rule { rule, at } => Frame::Rule {
+            rule,
+            at: normalized.original_start(at),
+        },
+        Frame::Case {
+            or,
+            index,
+            cases,
+            at,
+        } => Frame::Case {
+            or,
+            index,
+            cases,
+            at: normalized.original_start(at),
+        },
+    };
+    match event {
+        Event::Enter(f) => Event::Enter(frame(f)),
+        Event::Exit { frame: f, ends } => Event::Exit {
+            frame: frame(f),
+            ends: ends
+                .into_iter()
+                .map(|end| normalized.original_end(end))
+                .collect(),
+        },
+        Event::Terminal { terminal, at, end } => Event::Terminal {
+            terminal,
+            at: normalized.original_start(at),
+            end: end.map(|end| normalized.original_end(end)),
+        },
+    }
+}
+
+fn with_flag(flags: &Flags, flag: IStr, value: bool) -> Flags {
+    let mut flags = flags.clone();
+    if value {
+        flags.set.insert(flag);
+    } else {
+        flags.set.remove(&flag);
+    }
+    flags
+}
+
+fn with_capture(flags: &Flags, name: IStr, captured: Range<usize>) -> Flags {
+    let mut flags = flags.clone();
+    flags.captures.insert(name, captured);
+    flags
+}
+
+/// A step-through debugger for the interpreter (e.g. for a TUI to drive),
+/// going through the `Event`s of matching a rule, one at a time.
+///
+/// All the matching happens upfront (in `new`), and is only replayed by
+/// stepping, which is indistinguishable, as matching has no side-effects.
+pub struct ParserDebugger {
+    events: Vec<Event>,
+    /// How many `events` were stepped through so far.
+    next: usize,
+    stack: Vec<Frame>,
+    breakpoints: BTreeSet<IStr>,
+    matched: bool,
+}
+
+impl ParserDebugger {
+    pub fn new<Pat, I: ?Sized + IndexedInputMatch<Pat>>(
+        interpreter: &Interpreter<'_, Pat, I>,
+        rule: IStr,
+        input: &I,
+    ) -> Result<Self, Error> {
+        let mut events = vec![];
+        let matched = interpreter.observe(rule, input, |event| events.push(event.clone()))?;
+        Ok(ParserDebugger {
+            events,
+            next: 0,
+            stack: vec![],
+            breakpoints: BTreeSet::new(),
+            matched,
+        })
+    }
+
+    /// Go to the next event, returning it (or `None` if there are none left).
+    pub fn step(&mut self) -> Option<&Event> {
+        let event = self.events.get(self.next)?;
+        self.next += 1;
+        match *event {
+            Event::Enter(frame) => self.stack.push(frame),
+            Event::Exit { .. } => {
+                self.stack.pop();
+            }
+            Event::Terminal { .. } => {}
+        }
+        Some(event)
+    }
+
+    /// Step until entering a rule with a breakpoint, returning that event
+    /// (or `None` if no more breakpoints are hit).
+    pub fn resume(&mut self) -> Option<&Event> {
+        loop {
+            let i = self.next;
+            let rule = match *self.step()? {
+                Event::Enter(Frame::Rule { rule, .. }) => rule,
+                _ => continue,
+            };
+            if self.breakpoints.contains(&rule) {
+                return Some(&self.events[i]);
+            }
+        }
+    }
+
+    pub fn set_breakpoint(&mut self, rule: IStr) {
+        self.breakpoints.insert(rule);
+    }
+
+    pub fn clear_breakpoint(&mut self, rule: IStr) {
+        self.breakpoints.remove(&rule);
+    }
+
+    /// The last event stepped through, if any.
+    pub fn current(&self) -> Option<&Event> {
+        self.events[..self.next].last()
+    }
+
+    /// The rules and `Or` cases being matched (outermost first), which
+    /// also indicates which alternatives are being tried, and where.
+    pub fn stack(&self) -> &[Frame] {
+        &self.stack
+    }
+
+    /// The position in the input the last event happened at.
+    pub fn position(&self) -> usize {
+        match self.current() {
+            None => 0,
+            Some(&Event::Enter(frame)) | Some(&Event::Exit { frame, .. }) => frame.at(),
+            Some(&Event::Terminal { at, .. }) => at,
+        }
+    }
+
+    /// All the events, including ones not stepped through yet.
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Whether the rule matched all of the input, in the end.
+    pub fn matched(&self) -> bool {
+        self.matched
+    }
+}
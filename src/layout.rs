@@ -0,0 +1,117 @@
+//! Layout-sensitive ("off-side rule") preprocessing for scannerless input,
+//! making indentation changes explicit, as `INDENT` and `DEDENT` characters
+//! which grammars can then `eat` like any other terminal.
+
+use crate::compat::prelude::*;
+use crate::input::{self, LineColumn};
+use core::fmt;
+
+/// Inserted before the first token of a line more indented than the last.
+pub const INDENT: &str = "\u{E000}";
+/// Inserted (once per closed indentation level) before the first token of
+/// a line less indented than the last, and at the end of the input.
+pub const DEDENT: &str = "\u{E001}";
+
+/// Configuration for computing `INDENT` / `DEDENT` from indentation.
+#[derive(Clone, Debug)]
+pub struct OffsideRule {
+    /// Tabs advance the indentation to the next multiple of `tab_width`.
+    pub tab_width: usize,
+    /// Lines starting with this (after indentation) don't affect layout,
+    /// just like lines containing only whitespace.
+    pub line_comment: Option<String>,
+}
+
+impl Default for OffsideRule {
+    fn default() -> Self {
+        OffsideRule {
+            tab_width: input::TAB_WIDTH,
+            line_comment: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InconsistentDedent {
+    /// Where the offending line's first token is, in the original text.
+    pub at: LineColumn,
+}
+
+impl fmt::Display for InconsistentDedent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}: dedent doesn't match any outer indentation level",
+            self.at
+        )
+    }
+}
+
+/// The result of applying an `OffsideRule`: the `text` to parse, and a
+/// mapping back to positions in the original text.
+pub struct Layout {
+    pub text: String,
+    /// Byte offsets (in `text`) of all the inserted characters, ascending.
+    inserted: Vec<usize>,
+}
+
+impl Layout {
+    /// Map a byte offset in `text` to one in the original text
+    /// (inserted characters map to the position they were inserted at).
+    pub fn original_offset(&self, offset: usize) -> usize {
+        let before = self.inserted.partition_point(|&i| i < offset);
+        offset - before * INDENT.len()
+    }
+}
+
+impl OffsideRule {
+    pub fn apply(&self, text: &str) -> Result<Layout, InconsistentDedent> {
+        let mut layout = Layout {
+            text: String::with_capacity(text.len()),
+            inserted: vec![],
+        };
+        let insert = |layout: &mut Layout, s: &str| {
+            layout.inserted.push(layout.text.len());
+            layout.text += s;
+        };
+
+        // FIXME support implicit line joining (inside brackets).
+        let mut levels = vec![0];
+        for (line_idx, line) in text.split_inclusive('\n').enumerate() {
+            let content = line.trim_start_matches(|c: char| c.is_whitespace() && c != '\n');
+            let (indentation, content) = line.split_at(line.len() - content.len());
+
+            let ignored = content.trim().is_empty()
+                || self
+                    .line_comment
+                    .as_deref()
+                    .is_some_and(|comment| content.starts_with(comment));
+            layout.text += indentation;
+            if !ignored {
+                let width = input::indentation_width(indentation, self.tab_width);
+                if width > *levels.last().unwrap() {
+                    levels.push(width);
+                    insert(&mut layout, INDENT);
+                }
+                while width < *levels.last().unwrap() {
+                    levels.pop();
+                    insert(&mut layout, DEDENT);
+                }
+                if width != *levels.last().unwrap() {
+                    return Err(InconsistentDedent {
+                        at: LineColumn {
+                            line: line_idx,
+                            column: indentation.chars().count(),
+                        },
+                    });
+                }
+            }
+            layout.text += content;
+        }
+        for _ in 1..levels.len() {
+            insert(&mut layout, DEDENT);
+        }
+
+        Ok(layout)
+    }
+}
@@ -1,81 +1,1542 @@
+//! Grammar framework.
+//!
+//! # Determinism
+//!
+//! Everything this crate produces (exports, generated code, diagnostics,
+//! analysis results, and even `ParseForest::dump_graphviz`) only depends
+//! on its inputs, and so is byte-for-byte identical across runs (and
+//! targets). All collections whose order can be observed are either
+//! ordered by insertion (`IndexMap`/`IndexSet`) or sorted, and never
+//! hash-ordered (`HashMap`/`HashSet` are only used for lookups).
+//!
+//! Note that the order of `Grammar::rules` is part of the input, e.g.
+//! defining the same rules in another order will result in another order
+//! in the output, too (but not a different `Grammar::fingerprint`).
+//!
+//! # `no_std`
+//!
+//! Without the (default) `std` feature, the crate only needs `alloc`, e.g.
+//! for building grammars (see `build::CodegenOptions::no_std`), analyzing
+//! them, and interpreting them, on targets without an OS. Everything doing
+//! I/O (`build`, `pest`, `proc_macro`, `profile`, `reload`, etc.) is left
+//! out, and hashing uses `Fnv1a`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
 #![deny(unsafe_code)]
 #![deny(rust_2018_idioms)]
 
-// NOTE only these two modules can and do contain unsafe code.
+extern crate alloc;
+
+// NOTE these expand to nothing without the `tracing` feature, to
+// keep the instrumentation zero-cost when it's not being used.
+macro_rules! trace_span {
+    ($level:ident, $name:expr $(, $($fields:tt)*)?) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::span!(tracing::Level::$level, $name $(, $($fields)*)?).entered();
+    };
+}
+macro_rules! trace_event {
+    ($level:ident, $($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::event!(tracing::Level::$level, $($args)*);
+    };
+}
+
+// NOTE only these modules can and do contain unsafe code.
+#[cfg(feature = "capi")]
+#[allow(unsafe_code)]
+pub mod capi;
 #[allow(unsafe_code)]
 mod high;
 #[allow(unsafe_code)]
 mod indexing_str;
 
+#[forbid(unsafe_code)]
+pub mod backend;
+#[cfg(feature = "std")]
+#[forbid(unsafe_code)]
+pub mod build;
+#[forbid(unsafe_code)]
+pub mod cancel;
+#[forbid(unsafe_code)]
+mod compat;
 #[forbid(unsafe_code)]
 pub mod context;
 #[forbid(unsafe_code)]
+pub mod cse;
+#[forbid(unsafe_code)]
+pub mod diagnostics;
+#[forbid(unsafe_code)]
+pub mod dispatch;
+#[forbid(unsafe_code)]
+pub mod docs;
+#[forbid(unsafe_code)]
+pub mod ebnf;
+#[forbid(unsafe_code)]
+pub mod enumerate;
+#[forbid(unsafe_code)]
 pub mod forest;
+#[cfg(feature = "std")]
+#[forbid(unsafe_code)]
+pub mod fuzz;
+#[forbid(unsafe_code)]
+pub mod ids;
 #[forbid(unsafe_code)]
 pub mod input;
 #[forbid(unsafe_code)]
+pub mod interpreter;
+#[forbid(unsafe_code)]
+pub mod layout;
+#[forbid(unsafe_code)]
+pub mod lift;
+#[forbid(unsafe_code)]
+pub mod limits;
+#[forbid(unsafe_code)]
+pub mod normalize;
+#[forbid(unsafe_code)]
 pub mod parser;
+#[cfg(feature = "std")]
+#[forbid(unsafe_code)]
+pub mod pest;
+#[forbid(unsafe_code)]
+pub mod precedence;
+#[forbid(unsafe_code)]
+pub mod pretty;
+#[cfg(feature = "std")]
 #[forbid(unsafe_code)]
 pub mod proc_macro;
+#[cfg(feature = "std")]
+#[forbid(unsafe_code)]
+pub mod profile;
+#[forbid(unsafe_code)]
+pub mod regex;
+#[cfg(feature = "std")]
+#[forbid(unsafe_code)]
+pub mod reload;
+#[forbid(unsafe_code)]
+pub mod remap;
+#[forbid(unsafe_code)]
+pub mod rewrite;
+#[cfg(feature = "std")]
+#[forbid(unsafe_code)]
+pub mod roundtrip;
 #[forbid(unsafe_code)]
 pub mod rule;
 #[forbid(unsafe_code)]
 pub mod scannerless;
+#[forbid(unsafe_code)]
+pub mod source_map;
+#[forbid(unsafe_code)]
+pub mod synthetic;
+#[forbid(unsafe_code)]
+pub mod textmate;
+#[forbid(unsafe_code)]
+pub mod token_tree;
+#[forbid(unsafe_code)]
+pub mod tokens;
+#[cfg(feature = "tree-sitter")]
+#[forbid(unsafe_code)]
+pub mod tree_sitter;
+#[forbid(unsafe_code)]
+pub mod versions;
 
 // HACK(eddyb) this contains impls for types in `proc_macro`, which depend on
 // `input`, collapse this back into `proc_macro`.
+#[cfg(feature = "std")]
 #[forbid(unsafe_code)]
 mod proc_macro_input;
 
+#[forbid(unsafe_code)]
+mod minimize;
+#[rustfmt::skip]
+mod unicode_tables;
+
 // FIXME(eddyb) maybe put the rest of this file into submodules?
 
-use crate::context::{Context, IStr};
-use indexmap::IndexMap;
-use std::collections::HashMap;
-use std::hash::Hash;
+use crate::compat::prelude::*;
+use crate::compat::{HashMap, HashSet, IndexMap, IndexSet, OnceLock};
+use crate::context::{Context, IRule, IStr};
+use crate::diagnostics::{Args, Diagnostic, Localize};
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use core::fmt;
+use core::hash::{Hash, Hasher};
 
 pub struct Grammar {
+    /// The rules, in the order they were defined in (see `rules`).
+    ///
+    /// Deprecated, as mutating this directly (instead of through e.g. `define`)
+    /// keeps stale analyses (e.g. `can_be_empty`), so it's only public for
+    /// compatibility (see `rules` instead).
+    #[deprecated(note = "use `rules()` to read, and e.g. `define` or `remove_rule` to mutate")]
     pub rules: IndexMap<IStr, rule::RuleWithFields>,
+
+    /// What can appear between any two terminals (see `set_trivia`).
+    trivia: Option<rule::RuleWithFields>,
+
+    /// How to normalize inputs (see `set_input_normalization`).
+    input_normalization: normalize::Normalization,
+
+    /// The modifiers of the rules which have any (see `set_modifiers`).
+    modifiers: IndexMap<IStr, rule::Modifiers>,
+
+    /// Where the rules invented by transformations came from (see
+    /// `synthetic_origin`).
+    synthetic: IndexMap<IStr, synthetic::Origin>,
+
+    /// The shapes of the fields which have any (see `set_field_shape`).
+    field_shapes: IndexMap<IStr, rule::Shape>,
+
+    /// The labels of the terminals which have any (see `set_terminal_label`).
+    terminal_labels: IndexMap<IRule, IStr>,
+
+    analyses: Analyses,
+}
+
+#[derive(Debug)]
+pub enum RuleError {
+    AlreadyDefined(String),
+    Undefined(String),
+    /// The rule can't be extended, as it's not open (see `rule::Modifiers::open`).
+    NotOpen(String),
+    /// The rule can't be removed without breaking `Call`s to it, from
+    /// other rules, and/or from the trivia (see `Grammar::set_trivia`).
+    StillCalled {
+        rule: String,
+        callers: Vec<String>,
+        trivia: bool,
+    },
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleError::AlreadyDefined(rule) => write!(f, "rule `{}` is already defined", rule),
+            RuleError::Undefined(rule) => write!(f, "no rule named `{}`", rule),
+            RuleError::NotOpen(rule) => write!(f, "rule `{}` is not open to extension", rule),
+            RuleError::StillCalled {
+                rule,
+                callers,
+                trivia,
+            } => {
+                let mut callers: Vec<_> = callers
+                    .iter()
+                    .map(|caller| format!("`{}`", caller))
+                    .collect();
+                if *trivia {
+                    callers.push("the trivia".to_string());
+                }
+                write!(
+                    f,
+                    "rule `{}` is still called from {}",
+                    rule,
+                    callers.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl Localize for RuleError {
+    /// One of `rule-already-defined`, `undefined-rule` and `rule-not-open` (with
+    /// a `name` argument), and `rule-still-called` (`name`, `callers`, and
+    /// `trivia`, i.e. `true` if it's also called from the trivia).
+    fn message_id(&self) -> &'static str {
+        match self {
+            RuleError::AlreadyDefined(_) => "rule-already-defined",
+            RuleError::Undefined(_) => "undefined-rule",
+            RuleError::NotOpen(_) => "rule-not-open",
+            RuleError::StillCalled { .. } => "rule-still-called",
+        }
+    }
+
+    fn args(&self) -> Args {
+        match self {
+            RuleError::AlreadyDefined(rule)
+            | RuleError::Undefined(rule)
+            | RuleError::NotOpen(rule) => vec![("name", rule.clone())],
+            RuleError::StillCalled {
+                rule,
+                callers,
+                trivia,
+            } => {
+                let callers: Vec<_> = callers
+                    .iter()
+                    .map(|caller| format!("`{}`", caller))
+                    .collect();
+                vec![
+                    ("name", rule.clone()),
+                    ("callers", callers.join(", ")),
+                    ("trivia", trivia.to_string()),
+                ]
+            }
+        }
+    }
+}
+
+/// Whole-grammar analyses, computed on demand and kept until the grammar
+/// is mutated (as they only depend on the rules, not on their users).
+#[derive(Default)]
+struct Analyses {
+    sccs: OnceLock<Vec<Vec<IStr>>>,
+    can_be_empty: OnceLock<IndexMap<IStr, rule::MaybeKnown<bool>>>,
+    reachable: OnceLock<IndexMap<IStr, IndexSet<IStr>>>,
+    first_sets: OnceLock<IndexMap<IStr, dispatch::FirstSet>>,
 }
 
 impl Grammar {
+    #[allow(deprecated)]
     pub fn new() -> Self {
         Grammar {
-            rules: IndexMap::new(),
+            rules: IndexMap::default(),
+            trivia: None,
+            input_normalization: normalize::Normalization::default(),
+            modifiers: IndexMap::default(),
+            synthetic: IndexMap::default(),
+            field_shapes: IndexMap::default(),
+            terminal_labels: IndexMap::default(),
+            analyses: Analyses::default(),
         }
     }
+
+    /// All the rules, in the order they were defined in (and only mutable
+    /// through e.g. `define`, `insert_rule` and `remove_rule`).
+    #[allow(deprecated)]
+    pub fn rules(&self) -> &IndexMap<IStr, rule::RuleWithFields> {
+        &self.rules
+    }
+
+    /// All the rules, for mutating them (which requires calling
+    /// `invalidate_analyses` afterwards).
+    #[allow(deprecated)]
+    fn rules_mut(&mut self) -> &mut IndexMap<IStr, rule::RuleWithFields> {
+        &mut self.rules
+    }
+
+    /// The rule named `name`, if it's defined.
+    pub fn rule(&self, name: IStr) -> Option<rule::RuleWithFields> {
+        self.rules().get(&name).copied()
+    }
+
     pub fn define(&mut self, name: IStr, rule: rule::RuleWithFields) {
-        self.rules.insert(name, rule);
+        self.rules_mut().insert(name, rule);
+        self.invalidate_analyses();
     }
     pub fn extend(&mut self, other: Self) {
-        self.rules.extend(other.rules);
+        self.rules_mut().extend(other.rules());
+        if other.trivia.is_some() {
+            self.trivia = other.trivia;
+        }
+        if !other.input_normalization.is_identity() {
+            self.input_normalization = other.input_normalization;
+        }
+        self.modifiers.extend(other.modifiers);
+        self.synthetic.extend(other.synthetic);
+        self.field_shapes.extend(other.field_shapes);
+        self.terminal_labels.extend(other.terminal_labels);
+        self.invalidate_analyses();
     }
+
+    /// Set the modifiers of the rule `name` (e.g. making it atomic, see
+    /// `rule::Modifiers`), which are kept if the rule is redefined, and by
+    /// transformations (e.g. `map_rules`, or `instantiate_flags`, for which
+    /// every instance gets the modifiers of the rule it came from).
+    ///
+    /// A `shape` of `Flatten` is the same as (and is replaced by) `silent`.
+    pub fn set_modifiers(&mut self, name: IStr, mut modifiers: rule::Modifiers) {
+        if modifiers.shape == rule::Shape::Flatten {
+            modifiers.shape = rule::Shape::Keep;
+            modifiers.silent = true;
+        }
+        if modifiers.is_default() {
+            self.modifiers.shift_remove(&name);
+        } else {
+            self.modifiers.insert(name, modifiers);
+        }
+    }
+
+    pub fn modifiers(&self, name: IStr) -> rule::Modifiers {
+        self.modifiers.get(&name).copied().unwrap_or_default()
+    }
+
+    /// Mark the rule `name` as reserved syntax (see `rule::Modifiers::reserved`),
+    /// e.g. an alternative for `=>`, reserved for future use, with `message`
+    /// being the error reported when it's matched, instead of a parse error.
+    pub fn reserve<Pat: Eq + Hash>(&mut self, cx: &Context<Pat>, name: IStr, message: &str) {
+        let modifiers = self.modifiers(name);
+        self.set_modifiers(
+            name,
+            rule::Modifiers {
+                reserved: Some(cx.intern(message)),
+                ..modifiers
+            },
+        );
+    }
+
+    /// Set the human-readable name of the rule `name` (see `rule::Modifiers::label`),
+    /// e.g. "a statement", for `interpreter::Interpreter::explain_failure`
+    /// to report what was expected, instead of the terminals it starts with.
+    pub fn set_label<Pat: Eq + Hash>(&mut self, cx: &Context<Pat>, name: IStr, label: &str) {
+        let modifiers = self.modifiers(name);
+        self.set_modifiers(
+            name,
+            rule::Modifiers {
+                label: Some(cx.intern(label)),
+                ..modifiers
+            },
+        );
+    }
+
+    /// Set the human-readable name of `terminal` (e.g. `eat(";").finish(cx).rule`),
+    /// e.g. "a semicolon", like `set_label`, but for terminals (wherever they
+    /// appear), if no rule around them has a label.
+    pub fn set_terminal_label<Pat: Eq + Hash>(
+        &mut self,
+        cx: &Context<Pat>,
+        terminal: IRule,
+        label: &str,
+    ) {
+        self.terminal_labels.insert(terminal, cx.intern(label));
+    }
+
+    pub fn terminal_label(&self, terminal: IRule) -> Option<IStr> {
+        self.terminal_labels.get(&terminal).copied()
+    }
+
+    /// Set how the field `name` is shaped in derivations (see `rule::Shape`),
+    /// in every rule it appears in, e.g. `Flatten` for a field only grouping
+    /// others, while rules are shaped by their modifiers (see `set_modifiers`).
+    ///
+    /// Transformations keep the field shapes as-is (like the trivia).
+    pub fn set_field_shape(&mut self, name: IStr, shape: rule::Shape) {
+        if shape == rule::Shape::Keep {
+            self.field_shapes.shift_remove(&name);
+        } else {
+            self.field_shapes.insert(name, shape);
+        }
+    }
+
+    pub fn field_shape(&self, name: IStr) -> rule::Shape {
+        self.field_shapes.get(&name).copied().unwrap_or_default()
+    }
+
+    /// Where the rule `name` came from, if it was invented by a transformation
+    /// (e.g. `lift_inline`, or `extract_common`), instead of being defined by
+    /// the user (see `synthetic` for how such rules are named).
+    pub fn synthetic_origin(&self, name: IStr) -> Option<synthetic::Origin> {
+        self.synthetic.get(&name).copied()
+    }
+
+    /// All the rules invented by transformations, and where they came from.
+    pub fn synthetic_rules(&self) -> impl Iterator<Item = (IStr, synthetic::Origin)> + '_ {
+        self.synthetic.iter().map(|(&name, &origin)| (name, origin))
+    }
+
+    /// A grammar with no rules, but with everything else (e.g. the trivia,
+    /// the modifiers, or the field shapes) copied from this one, for
+    /// transformations to define their results in, so that they keep all
+    /// of it, without having to know about every part of a grammar.
+    ///
+    /// Transformations which can remove (or rename) rules should also call
+    /// `forget_undefined` once they're done defining rules.
+    #[allow(deprecated)]
+    pub fn empty_like(&self) -> Self {
+        Grammar {
+            rules: IndexMap::default(),
+            trivia: self.trivia,
+            input_normalization: self.input_normalization,
+            modifiers: self.modifiers.clone(),
+            synthetic: self.synthetic.clone(),
+            field_shapes: self.field_shapes.clone(),
+            terminal_labels: self.terminal_labels.clone(),
+            analyses: Analyses::default(),
+        }
+    }
+
+    /// Forget the modifiers and origins (see `synthetic_origin`) of the
+    /// rules which aren't defined, e.g. after a transformation removed them.
+    #[allow(deprecated)]
+    pub fn forget_undefined(&mut self) {
+        let rules = &self.rules;
+        self.modifiers.retain(|name, _| rules.contains_key(name));
+        self.synthetic.retain(|name, _| rules.contains_key(name));
+    }
+
+    /// Declare the trivia (e.g. `WS | COMMENT`), i.e. what can appear (any
+    /// number of times) between any two terminals, without being part of
+    /// the rules themselves, for backends which can skip it (e.g. tree-sitter
+    /// `extras`, or pest's implicit `WHITESPACE`), instead of having to use
+    /// `insert_whitespace`, which distorts the rules (see `lower_trivia`).
+    ///
+    /// The interpreter skips it between the elements of sequences and
+    /// repetitions, once they've matched something, except in atomic rules
+    /// (see `rule::Atomicity`), and never before or after a whole input.
+    ///
+    /// Transformations (e.g. `map_rules`) keep the trivia as-is, except for
+    /// `configure`, which also resolves the `Feature`s in it.
+    pub fn set_trivia(&mut self, trivia: rule::RuleWithFields) {
+        self.trivia = Some(trivia);
+    }
+
+    pub fn trivia(&self) -> Option<rule::RuleWithFields> {
+        self.trivia
+    }
+
+    /// Declare how inputs should be normalized before being matched (e.g. to
+    /// match case-insensitively, see `normalize::Normalization`), which the
+    /// interpreter does (reporting positions in the original input), while
+    /// other users of the grammar have to do it themselves (see
+    /// `normalize_input`), as well as normalizing the terminals (see
+    /// `normalize_terminals`).
+    ///
+    /// Transformations keep the normalization as-is (like the trivia).
+    pub fn set_input_normalization(&mut self, normalization: normalize::Normalization) {
+        self.input_normalization = normalization;
+    }
+
+    pub fn input_normalization(&self) -> normalize::Normalization {
+        self.input_normalization
+    }
+
+    /// Normalize `input` for matching (see `set_input_normalization`), e.g.
+    /// with `Interpreter::parse`, mapping positions in the result back to `input`
+    /// with `normalize::Normalized::original_range` (or `original_derivation`).
+    pub fn normalize_input(&self, input: &str) -> normalize::Normalized {
+        normalize::Normalized::new(input, self.input_normalization)
+    }
+
+    /// Normalize the string terminals (see `set_input_normalization`), so they
+    /// can match normalized inputs, e.g. `"SELECT"` becomes `"select"` when
+    /// case folding (character ranges are only lowercased if they're
+    /// entirely made of ASCII uppercase letters, e.g. `'A'..='F'`).
+    ///
+    /// FIXME other ranges would need to be split into several ones.
+    pub fn normalize_terminals<S>(self, cx: &Context<scannerless::Pat<S>>) -> Self
+    where
+        S: AsRef<str> + From<String> + Eq + Hash,
+    {
+        trace_span!(DEBUG, "normalize_terminals");
+        let normalization = self.input_normalization;
+        self.map_rules(&mut normalize::TerminalNormalizer { cx, normalization })
+    }
+
+    /// Make the trivia (see `set_trivia`) explicit, for backends which can't
+    /// skip it, by inserting repetitions of it between the elements of all
+    /// the rules for which `filter` returns `true` (see `insert_whitespace`),
+    /// except for atomic ones (see `atomic_rules`).
+    pub fn lower_trivia<Pat>(
+        mut self,
+        cx: &Context<Pat>,
+        mut filter: impl FnMut(IStr) -> bool,
+    ) -> Self
+    where
+        Pat: Eq + Hash + rule::MatchesEmpty + fmt::Debug,
+    {
+        trace_span!(DEBUG, "lower_trivia");
+        let atomic = self.atomic_rules(cx);
+        match self.trivia.take() {
+            Some(trivia) => self
+                .insert_trivia_where(cx, [trivia], |name| !atomic.contains(&name) && filter(name)),
+            None => self,
+        }
+    }
+
+    /// The rules which don't allow trivia (see `rule::Atomicity`), i.e. the
+    /// atomic rules, and the rules only ever called from those (directly, or
+    /// through other such rules), but not `NonAtomic` rules.
+    ///
+    /// FIXME rules called from both atomic and non-atomic rules allow
+    /// trivia, but should be split into an atomic and a non-atomic copy.
+    pub fn atomic_rules<Pat>(&self, cx: &Context<Pat>) -> IndexSet<IStr> {
+        use rule::Atomicity;
+
+        // Whether every rule is called from non-atomic and/or atomic rules.
+        let mut contexts: IndexMap<IStr, (bool, bool)> = IndexMap::default();
+        let mut called: HashSet<_> = HashSet::default();
+        for rule in self.rules().values() {
+            called.extend(rule.rule.call_names(cx));
+        }
+        // NOTE rules never called by any others are entry points (as
+        // are recursive rules only reachable from themselves, added later).
+        let mut queue: VecDeque<_> = self
+            .rules()
+            .keys()
+            .filter(|name| !called.contains(*name))
+            .map(|&name| (name, false))
+            .collect();
+        loop {
+            while let Some((name, atomic)) = queue.pop_front() {
+                let rule = match self.rules().get(&name) {
+                    Some(rule) => rule,
+                    None => continue,
+                };
+                let context = contexts.entry(name).or_default();
+                let seen = if atomic {
+                    &mut context.1
+                } else {
+                    &mut context.0
+                };
+                if core::mem::replace(seen, true) {
+                    continue;
+                }
+                let inner = match self.modifiers(name).atomicity {
+                    Atomicity::Inherited => atomic,
+                    Atomicity::Atomic | Atomicity::CompoundAtomic => true,
+                    Atomicity::NonAtomic => false,
+                };
+                queue.extend(
+                    rule.rule
+                        .call_names(cx)
+                        .into_iter()
+                        .map(|callee| (callee, inner)),
+                );
+            }
+            match self
+                .rules()
+                .keys()
+                .find(|name| !contexts.contains_key(*name))
+            {
+                Some(&name) => queue.push_back((name, false)),
+                None => break,
+            }
+        }
+
+        self.rules()
+            .keys()
+            .copied()
+            .filter(|name| match self.modifiers(*name).atomicity {
+                Atomicity::Atomic | Atomicity::CompoundAtomic => true,
+                Atomicity::NonAtomic => false,
+                Atomicity::Inherited => contexts[name] == (false, true),
+            })
+            .collect()
+    }
+
+    /// Discard the results of all the analyses (e.g. `sccs`), which is only
+    /// needed after mutating `rules` directly.
+    fn invalidate_analyses(&mut self) {
+        self.analyses = Analyses::default();
+    }
+
+    /// Assign IDs to all the rules, alternatives and field names (see
+    /// `ids::Ids`), once the grammar is done being built, which prevents it
+    /// from being mutated further (until `ids::FrozenGrammar::thaw`).
+    pub fn freeze<Pat: Eq + Hash>(self, cx: &Context<Pat>) -> ids::FrozenGrammar {
+        ids::FrozenGrammar::new(cx, self)
+    }
+
+    /// Like `define`, but refusing to overwrite an existing rule.
+    pub fn insert_rule<Pat>(
+        &mut self,
+        cx: &Context<Pat>,
+        name: IStr,
+        rule: rule::RuleWithFields,
+    ) -> Result<(), RuleError> {
+        if self.rules().contains_key(&name) {
+            return Err(RuleError::AlreadyDefined(cx[name].to_string()));
+        }
+        self.define(name, rule);
+        Ok(())
+    }
+
+    /// Replace an existing rule (keeping its position), returning the old one.
+    pub fn replace_rule<Pat>(
+        &mut self,
+        cx: &Context<Pat>,
+        name: IStr,
+        rule: rule::RuleWithFields,
+    ) -> Result<rule::RuleWithFields, RuleError> {
+        let old = self
+            .rules_mut()
+            .get_mut(&name)
+            .ok_or_else(|| RuleError::Undefined(cx[name].to_string()))?;
+        let old = core::mem::replace(old, rule);
+        self.invalidate_analyses();
+        Ok(old)
+    }
+
+    /// Add `alternative` as a new case of the rule `name` (after all of its
+    /// existing cases), which has to be open (see `rule::Modifiers::open`),
+    /// e.g. to add new kinds of statements to a base grammar, without the
+    /// base grammar having to know about them.
+    pub fn extend_rule<Pat: Eq + Hash>(
+        &mut self,
+        cx: &Context<Pat>,
+        name: IStr,
+        alternative: rule::RuleWithFields,
+    ) -> Result<(), RuleError> {
+        let rule = *self
+            .rules()
+            .get(&name)
+            .ok_or_else(|| RuleError::Undefined(cx[name].to_string()))?;
+        if !self.modifiers(name).open {
+            return Err(RuleError::NotOpen(cx[name].to_string()));
+        }
+        // NOTE `alternative` can itself have several cases, which are
+        // all added to the rule (instead of being nested in one new case).
+        let cases = match (&cx[alternative.rule], &cx[alternative.fields]) {
+            (rule::Rule::Or(cases), rule::Fields::Leaf(None))
+            | (rule::Rule::Or(cases), rule::Fields::Aggregate(_)) => cases
+                .iter()
+                .enumerate()
+                .map(|(i, &rule)| rule::RuleWithFields {
+                    rule,
+                    fields: alternative.fields.child(cx, i),
+                })
+                .collect(),
+            _ => vec![alternative],
+        };
+        let rule = rule::any_of(core::iter::once(rule).chain(cases)).finish(cx);
+        self.replace_rule(cx, name, rule)?;
+        Ok(())
+    }
+
+    /// Remove a rule, refusing to do so while other rules (or the trivia)
+    /// still call it, unless `cascade` is set, in which case all the rules
+    /// that (directly or indirectly) call it are removed as well, and so is
+    /// the trivia, if it calls any of them.
+    ///
+    /// Returns the names of all the removed rules (starting with `name`).
+    pub fn remove_rule<Pat>(
+        &mut self,
+        cx: &Context<Pat>,
+        name: IStr,
+        cascade: bool,
+    ) -> Result<Vec<IStr>, RuleError> {
+        if !self.rules().contains_key(&name) {
+            return Err(RuleError::Undefined(cx[name].to_string()));
+        }
+
+        let trivia = self.trivia;
+        let trivia_calls = |removed: &IndexSet<IStr>| {
+            trivia.is_some_and(|trivia| {
+                let calls = trivia.rule.call_names(cx);
+                removed.iter().any(|removed| calls.contains(removed))
+            })
+        };
+
+        let mut removed = IndexSet::default();
+        removed.insert(name);
+        loop {
+            let callers: Vec<_> = self
+                .rules()
+                .iter()
+                .filter(|(caller, _)| !removed.contains(*caller))
+                .filter(|(_, rule)| {
+                    let calls = rule.rule.call_names(cx);
+                    removed.iter().any(|removed| calls.contains(removed))
+                })
+                .map(|(&caller, _)| caller)
+                .collect();
+            if !cascade && (!callers.is_empty() || trivia_calls(&removed)) {
+                return Err(RuleError::StillCalled {
+                    rule: cx[name].to_string(),
+                    callers: callers
+                        .iter()
+                        .map(|&caller| cx[caller].to_string())
+                        .collect(),
+                    trivia: trivia_calls(&removed),
+                });
+            }
+            if callers.is_empty() {
+                break;
+            }
+            removed.extend(callers);
+        }
+
+        if trivia_calls(&removed) {
+            self.trivia = None;
+        }
+        for removed in &removed {
+            self.rules_mut().shift_remove(removed);
+            self.modifiers.shift_remove(removed);
+            self.synthetic.shift_remove(removed);
+        }
+        self.invalidate_analyses();
+        Ok(removed.into_iter().collect())
+    }
+
+    /// Apply `transform` (e.g. `|g| g.insert_whitespace(cx, ws)`), which has
+    /// to keep the rule names, also returning where every field ended up
+    /// (e.g. after `Concat` elements were inserted before it), by matching
+    /// up fields by name (see `remap::PathRemap::by_name`).
+    ///
+    /// See also `instantiate_flags_remapped` and `lift_inline_remapped`,
+    /// for the transformations which create new rules.
+    pub fn remapped<Pat: Eq + Hash>(
+        self,
+        cx: &Context<Pat>,
+        transform: impl FnOnce(Self) -> Self,
+    ) -> (Self, remap::PathRemap) {
+        let old = remap::FieldPaths::of(cx, &self);
+        let grammar = transform(self);
+        let remap = remap::PathRemap::by_name(&old, &remap::FieldPaths::of(cx, &grammar), Some);
+        (grammar, remap)
+    }
+
+    /// Apply `folder` to every rule, rebuilding the grammar.
+    ///
+    /// The rules keep their names (and order), so `Call`s keep referring
+    /// to the same rules, and any new `Call`s introduced by `folder` are
+    /// checked to refer to rules defined in the grammar.
+    pub fn map_rules<'cx, Pat: 'cx + Eq + Hash>(
+        self,
+        folder: &mut impl rule::Folder<'cx, Pat>,
+    ) -> Self {
+        self.map_rules_where(|_| true, folder)
+    }
+
+    /// Like `map_rules`, but only applying `folder` to the rules for which
+    /// `filter` returns `true`, leaving the rest (e.g. lexical rules) as-is.
+    pub fn map_rules_where<'cx, Pat: 'cx + Eq + Hash>(
+        self,
+        mut filter: impl FnMut(IStr) -> bool,
+        folder: &mut impl rule::Folder<'cx, Pat>,
+    ) -> Self {
+        trace_span!(DEBUG, "map_rules", rules = self.rules().len());
+        let cx = folder.cx();
+        let mut grammar = self.empty_like();
+        let mut new_call_names = vec![];
+        for (&name, &rule) in self.rules() {
+            if filter(name) {
+                trace_event!(TRACE, rule = &cx[name], "folding");
+                let old_call_names = rule.rule.call_names(cx);
+                let rule = rule.fold(folder);
+                new_call_names.extend(
+                    rule.rule
+                        .call_names(cx)
+                        .into_iter()
+                        .filter(|name| !old_call_names.contains(name)),
+                );
+                grammar.define(name, rule);
+            } else {
+                grammar.define(name, rule);
+            }
+        }
+        for name in new_call_names {
+            assert!(
+                grammar.rules().contains_key(&name),
+                "no rule named `{}`",
+                &cx[name]
+            );
+        }
+        grammar
+    }
+
+    /// Apply `rewrite` everywhere in every rule (see `rewrite::Rewrite`).
+    pub fn rewrite<Pat: Eq + Hash>(self, cx: &Context<Pat>, rewrite: &rewrite::Rewrite) -> Self {
+        trace_span!(DEBUG, "rewrite");
+        self.map_rules(&mut rewrite::Rewriter { cx, rewrite })
+    }
+
+    /// Instantiate rules for every combination of (relevant) flags they can
+    /// be called with, e.g. `Expr<+NoStruct>` for `Expr` called with the
+    /// `NoStruct` flag set, resolving all `FlagGuard`s and `WithFlag`s.
+    ///
+    /// Every rule is also instantiated with no flags set, keeping its name.
+    /// Instances which can never match (due to `FlagGuard`s) are left out,
+    /// and so `check` will report any `Call`s to them.
+    pub fn instantiate_flags<Pat: Eq + Hash>(self, cx: &Context<Pat>) -> Self {
+        self.instantiate_flags_with_origins(cx).0
+    }
+
+    /// Like `instantiate_flags`, but also returning where every field ended
+    /// up (in each instance of its rule), see `remap::PathRemap`.
+    pub fn instantiate_flags_remapped<Pat: Eq + Hash>(
+        self,
+        cx: &Context<Pat>,
+    ) -> (Self, remap::PathRemap) {
+        let old = remap::FieldPaths::of(cx, &self);
+        let (grammar, origins) = self.instantiate_flags_with_origins(cx);
+        let remap = remap::PathRemap::by_name(&old, &remap::FieldPaths::of(cx, &grammar), |name| {
+            origins.get(&name).copied()
+        });
+        (grammar, remap)
+    }
+
+    /// `instantiate_flags`, also returning the rule each instance came from.
+    fn instantiate_flags_with_origins<Pat: Eq + Hash>(
+        self,
+        cx: &Context<Pat>,
+    ) -> (Self, IndexMap<IStr, IStr>) {
+        trace_span!(DEBUG, "instantiate_flags");
+        // FIXME this is conservative, e.g. flags set by a `WithFlag`
+        // are still considered relevant to the rule containing it.
+        let mut relevant_flags: BTreeMap<_, _> = self
+            .rules()
+            .iter()
+            .map(|(&name, rule)| (name, rule.rule.guard_flags(cx)))
+            .collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (name, rule) in self.rules() {
+                for callee in rule.rule.call_names(cx) {
+                    let callee_flags = relevant_flags.get(&callee).cloned().unwrap_or_default();
+                    let flags = relevant_flags.get_mut(name).unwrap();
+                    for flag in callee_flags {
+                        changed |= flags.insert(flag);
+                    }
+                }
+            }
+        }
+
+        let mut grammar = self.empty_like();
+        let mut queue: VecDeque<_> = self
+            .rules()
+            .keys()
+            .map(|&name| (name, BTreeSet::new()))
+            .collect();
+        let mut seen = BTreeSet::new();
+        let mut origins = IndexMap::default();
+        while let Some((name, flags)) = queue.pop_front() {
+            let instance = rule::FlagInstantiator::instance_name(cx, name, &flags);
+            if !seen.insert(instance) {
+                continue;
+            }
+            let mut instantiator = rule::FlagInstantiator {
+                cx,
+                flags,
+                relevant_flags: &relevant_flags,
+                calls: vec![],
+            };
+            if let Some(rule) = self.rules()[&name].prune(&mut instantiator) {
+                grammar.define(instance, rule);
+                grammar.set_modifiers(instance, self.modifiers(name));
+                origins.insert(instance, name);
+            }
+            queue.extend(instantiator.calls);
+        }
+        grammar.forget_undefined();
+        (grammar, origins)
+    }
+
+    /// Lift every `Or` nested inside a rule (i.e. not its whole body), and
+    /// every `Opt`/repeat operand more complex than e.g. a `Call`, into a
+    /// rule of its own, for backends which need all choices and repetitions
+    /// to be between named rules (e.g. when exporting to BNF-like formats).
+    ///
+    /// Lifted rules are named after the rule they're lifted out of, and
+    /// numbered in order of appearance, e.g. `Expr__alt_1` or `Expr__group_2`
+    /// (see `synthetic`), and are mapped back to their original location
+    /// (see `lift::Origin`).
+    /// Fields on a lifted part stay on the `Call` replacing it, while the
+    /// fields inside it are moved into the lifted rule.
+    pub fn lift_inline<Pat: Eq + Hash>(
+        self,
+        cx: &Context<Pat>,
+    ) -> (Self, IndexMap<IStr, lift::Origin>) {
+        trace_span!(DEBUG, "lift_inline");
+        lift::lift_inline(cx, self)
+    }
+
+    /// Like `lift_inline`, but also returning where every field ended up
+    /// (which is in a lifted rule, for fields inside lifted parts).
+    pub fn lift_inline_remapped<Pat: Eq + Hash>(
+        self,
+        cx: &Context<Pat>,
+    ) -> (Self, IndexMap<IStr, lift::Origin>, remap::PathRemap) {
+        let old = remap::FieldPaths::of(cx, &self);
+        let (grammar, origins) = self.lift_inline(cx);
+        let remap = remap::PathRemap::lifted(&old, &remap::FieldPaths::of(cx, &grammar), &origins);
+        (grammar, origins, remap)
+    }
+
+    /// Replace every terminal (`Eat` or `EatUntil`) with a `Call` to a token,
+    /// named after its pattern (e.g. `PLUS` for `"+"`, or `WHILE` for `"while"`),
+    /// to get a token-level grammar (e.g. for LR tools, with the lexical layer
+    /// specified separately), and the `tokens::TokenTable` of what they stand for.
+    ///
+    /// Identical terminals share one token, and token names which would be
+    /// taken (by rules, or other tokens) get numbered suffixes, e.g. `PLUS_2`.
+    /// Tokens aren't defined as rules, so `check` reports `Call`s to them,
+    /// unless the grammar is `extend`ed with `TokenTable::lexical_grammar`.
+    /// The trivia (usually left to the lexer, in token-level grammars) is kept.
+    pub fn anonymize_terminals<S: AsRef<str> + Eq + Hash>(
+        self,
+        cx: &Context<scannerless::Pat<S>>,
+    ) -> (Self, tokens::TokenTable) {
+        trace_span!(DEBUG, "anonymize_terminals");
+        tokens::anonymize_terminals(cx, self)
+    }
+
+    /// Split a scannerless grammar into a lexer, for the rules for which
+    /// `lexical` returns `true` (e.g. the ones in `tokens::lexical_rules`),
+    /// and the terminals in the other rules (see `anonymize_terminals`), and
+    /// a grammar over the tokens it produces, with both skipping the trivia.
+    ///
+    /// The lexer takes the longest match (i.e. maximal munch), so wherever
+    /// the original grammar allows two tokens to be adjacent, but their
+    /// concatenation would be lexed differently (e.g. `>` followed by `=`,
+    /// lexed as `>=`), a `tokens::MunchConflict` is also reported.
+    pub fn tokenize<S: AsRef<str> + Eq + Hash>(
+        self,
+        cx: &Context<scannerless::Pat<S>>,
+        lexical: impl FnMut(IStr) -> bool,
+    ) -> tokens::Tokenized {
+        trace_span!(DEBUG, "tokenize");
+        tokens::tokenize(cx, self, lexical)
+    }
+
+    /// Factor parts of rules (of at least `min_size` nodes, see `cse::size`)
+    /// repeated across the grammar into shared helper rules, largest savings
+    /// first, e.g. the same `("," Item)*` written out in many rules, with a
+    /// report of the helpers created, and how much smaller the grammar got.
+    ///
+    /// Helpers are named after the first rule they're found in, and numbered,
+    /// e.g. `List__shared_1` (see `synthetic`), and are silent (see `rule::Modifiers`), so they
+    /// don't change derivations, while parts with fields inside them are
+    /// never extracted (though fields around them are kept on the `Call`).
+    pub fn extract_common<Pat: Eq + Hash>(
+        self,
+        cx: &Context<Pat>,
+        min_size: usize,
+    ) -> (Self, cse::Report) {
+        trace_span!(DEBUG, "extract_common");
+        cse::extract_common(cx, self, min_size)
+    }
+
+    /// Reorder the cases of `Or`s by how often they match (most often first),
+    /// for backends trying them in order, but only where that can't change
+    /// which case ordered choice picks, unless `allow_overlap` is set, also
+    /// returning the `Or`s left as-is (see `dispatch::reorder_alternatives`).
+    pub fn reorder_alternatives<Pat: rule::Pattern + Eq + Hash>(
+        self,
+        cx: &Context<Pat>,
+        frequencies: &IndexMap<IRule, Vec<usize>>,
+        allow_overlap: bool,
+    ) -> (Self, Vec<dispatch::Overlap>) {
+        trace_span!(DEBUG, "reorder_alternatives");
+        dispatch::reorder_alternatives(cx, self, frequencies, allow_overlap)
+    }
+
+    /// Strip the parts of the grammar only relevant to features other than
+    /// `features`, resolving all `Feature`s, e.g. to get a specific edition
+    /// of a language out of a grammar describing all of them.
+    ///
+    /// Rules which can never match (due to `Feature`s) are left out,
+    /// and so `check` will report any `Call`s to them.
+    pub fn configure<Pat: Eq + Hash>(self, cx: &Context<Pat>, features: &[&str]) -> Self {
+        trace_span!(DEBUG, "configure", ?features);
+        let features = features.iter().map(|&feature| cx.intern(feature)).collect();
+        let mut configurer = rule::FeatureConfigurer {
+            cx,
+            features: &features,
+        };
+        let mut grammar = self.empty_like();
+        grammar.trivia = self.trivia.and_then(|trivia| trivia.prune(&mut configurer));
+        for (&name, &rule) in self.rules() {
+            if let Some(rule) = rule.prune(&mut configurer) {
+                grammar.define(name, rule);
+            }
+        }
+        grammar.forget_undefined();
+        grammar
+    }
+
     pub fn insert_whitespace<Pat: Eq + Hash>(
         self,
         cx: &Context<Pat>,
         whitespace: rule::RuleWithFields,
     ) -> Self {
-        Grammar {
-            rules: self
-                .rules
-                .into_iter()
-                .map(|(name, rule)| (name, rule.insert_whitespace(cx, whitespace)))
-                .collect(),
+        self.insert_whitespace_where(cx, whitespace, |_| true)
+    }
+
+    /// Like `insert_whitespace`, but only for the rules for which `filter`
+    /// returns `true`, e.g. to skip the lexical rules of a grammar.
+    pub fn insert_whitespace_where<Pat: Eq + Hash>(
+        self,
+        cx: &Context<Pat>,
+        whitespace: rule::RuleWithFields,
+        filter: impl FnMut(IStr) -> bool,
+    ) -> Self {
+        trace_span!(DEBUG, "insert_whitespace");
+        self.map_rules_where(filter, &mut rule::WhitespaceInserter::new(cx, whitespace))
+    }
+
+    /// Like `insert_whitespace`, but for several kinds of trivia (e.g.
+    /// whitespace, line comments and block comments), any number of which
+    /// can appear, in any order, i.e. inserting `(A | B | ...)*`.
+    ///
+    /// Panics if any of `trivia` can match empty (making the repetition
+    /// invalid), instead of leaving it to be found by `check` later.
+    pub fn insert_trivia<Pat>(
+        self,
+        cx: &Context<Pat>,
+        trivia: impl IntoIterator<Item = rule::RuleWithFields>,
+    ) -> Self
+    where
+        Pat: Eq + Hash + rule::MatchesEmpty + fmt::Debug,
+    {
+        self.insert_trivia_where(cx, trivia, |_| true)
+    }
+
+    /// Like `insert_trivia`, but only for the rules for which `filter`
+    /// returns `true` (see `insert_whitespace_where`).
+    pub fn insert_trivia_where<Pat>(
+        self,
+        cx: &Context<Pat>,
+        trivia: impl IntoIterator<Item = rule::RuleWithFields>,
+        filter: impl FnMut(IStr) -> bool,
+    ) -> Self
+    where
+        Pat: Eq + Hash + rule::MatchesEmpty + fmt::Debug,
+    {
+        trace_span!(DEBUG, "insert_trivia");
+        let trivia: Vec<_> = trivia.into_iter().collect();
+        assert!(!trivia.is_empty(), "no trivia to insert");
+        let mut cache = BTreeMap::new();
+        for trivia in &trivia {
+            let can_be_empty = trivia.rule.can_be_empty(&mut cache, cx, &self);
+            assert_eq!(
+                can_be_empty,
+                rule::MaybeKnown::Known(false),
+                "trivia `{}` can't be repeated, as it can (or might) match empty",
+                trivia.rule.node_desc(cx)
+            );
         }
+        let trivia = rule::any_of(trivia).repeat_many().finish(cx);
+        self.insert_whitespace_where(cx, trivia, filter)
     }
 }
 
 impl Grammar {
-    pub fn check<Pat: rule::MatchesEmpty>(&self, cx: &Context<Pat>) {
-        for rule in self.rules.values() {
-            rule.rule.check_call_names(cx, self);
+    /// Check the grammar for mistakes, e.g. calling undefined rules,
+    /// returning all of them (see `diagnostics::Code` for a full list).
+    pub fn validate<Pat>(&self, cx: &Context<Pat>) -> Vec<Diagnostic>
+    where
+        Pat: rule::MatchesEmpty + fmt::Debug,
+    {
+        let mut diagnostics = vec![];
+        for (&name, rule) in self.rules() {
+            rule.rule.check_call_names(cx, self, name, &mut diagnostics);
+        }
+
+        // NOTE can't compute emptiness with undefined rules.
+        if !diagnostics.is_empty() {
+            return diagnostics;
         }
 
-        let mut can_be_empty_cache = HashMap::new();
-        for rule in self.rules.values() {
-            rule.rule
-                .check_non_empty_opt(&mut can_be_empty_cache, cx, self);
+        let mut can_be_empty_cache = BTreeMap::new();
+        for (&name, rule) in self.rules() {
+            rule.rule.check_non_empty_opt(
+                &mut can_be_empty_cache,
+                cx,
+                self,
+                name,
+                &mut diagnostics,
+            );
         }
+        diagnostics
+    }
+
+    /// Like `validate`, but panicking on the first error.
+    pub fn check<Pat>(&self, cx: &Context<Pat>)
+    where
+        Pat: rule::MatchesEmpty + fmt::Debug,
+    {
+        let diagnostics = self.validate(cx);
+        if let Some(error) = diagnostics
+            .iter()
+            .find(|d| d.severity() == diagnostics::Severity::Error)
+        {
+            panic!("{}", error);
+        }
+    }
+
+    /// How `rule` matches all of `input` (if it does at all), with no features
+    /// enabled and no guards (see `interpreter::Interpreter::explain`).
+    pub fn explain<Pat>(
+        &self,
+        cx: &Context<Pat>,
+        rule: IStr,
+        input: &str,
+    ) -> Result<Option<interpreter::Derivation>, interpreter::Error>
+    where
+        str: input::InputMatch<Pat>,
+    {
+        interpreter::Interpreter::new(cx, self).explain(rule, input)
+    }
+
+    /// Why `rule` doesn't match all of `input`, with no features enabled
+    /// and no guards (see `interpreter::Interpreter::explain_failure`).
+    pub fn explain_failure<Pat: fmt::Debug>(
+        &self,
+        cx: &Context<Pat>,
+        rule: IStr,
+        input: &str,
+        max_positions: usize,
+    ) -> Result<Vec<interpreter::Failure>, interpreter::Error>
+    where
+        str: input::InputMatch<Pat>,
+    {
+        interpreter::Interpreter::new(cx, self).explain_failure(rule, input, max_positions)
+    }
+
+    /// Parse all of `input` with `rule`, using the backend `B` (see
+    /// `backend::ParserBackend`), after checking that `B` can handle this
+    /// grammar (e.g. its left recursion, see `backend::left_recursion`).
+    pub fn parse_with<'a, 'i, B, Pat>(
+        &'a self,
+        cx: &'a Context<Pat>,
+        rule: IStr,
+        input: &'i str,
+    ) -> Result<Option<B::Output<'i>>, backend::Error<B::Error>>
+    where
+        B: backend::ParserBackend<'a, Pat>,
+        Pat: rule::MatchesEmpty,
+    {
+        if !B::CAPABILITIES.left_recursion {
+            if let Some(name) = backend::left_recursion(cx, self) {
+                return Err(backend::Error::Unsupported {
+                    rule: cx[name].to_string(),
+                    reason: "left recursion",
+                });
+            }
+        }
+        B::new(cx, self)
+            .parse(rule, input)
+            .map_err(backend::Error::Backend)
+    }
+
+    /// A regex matching (at least) everything `rule` can match, and whether
+    /// it's exact, i.e. `rule` is regular (see `regex::approx`).
+    pub fn to_regex_approx<Pat: regex::RegexPat>(
+        &self,
+        cx: &Context<Pat>,
+        rule: IStr,
+    ) -> Result<regex::Regex, RuleError> {
+        regex::approx(cx, self, rule)
+    }
+
+    /// Reduce the grammar as much as possible while `predicate` (e.g. "still
+    /// crashes my backend") keeps holding, e.g. to turn a large grammar into
+    /// a small test case for a bug report. `predicate` must hold initially.
+    ///
+    /// Rules are removed with delta debugging, followed by simplifying all
+    /// the rules (replacing parts of them with `Empty`, removing `Or` cases
+    /// or `Concat` elements, unwrapping `Opt`s, etc.) one step at a time,
+    /// until no single removal or simplification keeps `predicate` holding.
+    ///
+    /// Note that `predicate` will see grammars with `Call`s to removed rules
+    /// (see `validate`), which it should reject, if that's not desired.
+    pub fn minimize<Pat: Eq + Hash>(
+        self,
+        cx: &Context<Pat>,
+        predicate: impl FnMut(&Grammar) -> bool,
+    ) -> Self {
+        minimize::minimize(cx, self, predicate)
+    }
+
+    /// Hash the grammar's structure, independently of rule order and of
+    /// the `Context` it was built in, e.g. to detect when it changed (in
+    /// a build script), and skip regenerating anything derived from it.
+    ///
+    /// The result is stable across runs and targets (integers are always
+    /// hashed as little-endian `u64`s), as long as `Pat`'s `Hash` impl is.
+    pub fn fingerprint<Pat: Hash>(&self, cx: &Context<Pat>) -> u64 {
+        let mut rules: Vec<_> = self.rules().iter().collect();
+        rules.sort_by_key(|&(&name, _)| &cx[name]);
+
+        let mut state = Fnv1a::default();
+        rules.len().hash(&mut state);
+        for (&name, rule) in rules {
+            cx[name].hash(&mut state);
+            rule.rule.hash_structure(cx, &mut state);
+            rule.fields.hash_structure(cx, &mut state);
+        }
+        self.trivia.is_some().hash(&mut state);
+        if let Some(trivia) = self.trivia {
+            trivia.rule.hash_structure(cx, &mut state);
+            trivia.fields.hash_structure(cx, &mut state);
+        }
+        self.input_normalization.hash(&mut state);
+        // NOTE strings in modifiers are hashed by contents, instead of
+        // by `IStr` (which depends on the order they were interned in).
+        let mut modifiers: Vec<_> = self
+            .modifiers
+            .iter()
+            .filter(|(name, _)| self.rules().contains_key(*name))
+            .map(|(&name, &modifiers)| {
+                let strings = (
+                    modifiers.reserved.map(|s| &cx[s]),
+                    modifiers.label.map(|s| &cx[s]),
+                );
+                let modifiers = rule::Modifiers {
+                    reserved: None,
+                    label: None,
+                    ..modifiers
+                };
+                (&cx[name], modifiers, strings)
+            })
+            .collect();
+        modifiers.sort();
+        modifiers.hash(&mut state);
+        let mut field_shapes: Vec<_> = self
+            .field_shapes
+            .iter()
+            .map(|(&name, shape)| (&cx[name], shape))
+            .collect();
+        field_shapes.sort();
+        field_shapes.hash(&mut state);
+        let mut terminal_labels: Vec<_> = self
+            .terminal_labels
+            .iter()
+            .map(|(&terminal, &label)| {
+                let mut hasher = Fnv1a::default();
+                terminal.hash_structure(cx, &mut hasher);
+                (hasher.finish(), &cx[label])
+            })
+            .collect();
+        terminal_labels.sort();
+        terminal_labels.hash(&mut state);
+        state.finish()
+    }
+
+    /// Group the rules into the strongly connected components of the call
+    /// graph (i.e. sets of mutually recursive rules), with every component
+    /// after all the components it calls into, e.g. to analyze the rules
+    /// bottom-up, with only the rules in the same component needing to
+    /// reach a fixpoint together (and unrelated components being independent).
+    ///
+    /// `Call`s to undefined rules are ignored.
+    pub fn sccs<Pat>(&self, cx: &Context<Pat>) -> &[Vec<IStr>] {
+        self.analyses.sccs.get_or_init(|| self.compute_sccs(cx))
+    }
+
+    /// Group the components of `sccs` (by their indices) into levels, with
+    /// every component only calling into components in earlier levels, i.e.
+    /// the components in the same level are independent of each other (and
+    /// can be analyzed in parallel, once all the earlier levels are).
+    pub fn scc_levels<Pat>(&self, cx: &Context<Pat>) -> Vec<Vec<usize>> {
+        let sccs = self.sccs(cx);
+        let scc_of: HashMap<IStr, usize> = sccs
+            .iter()
+            .enumerate()
+            .flat_map(|(i, scc)| scc.iter().map(move |&name| (name, i)))
+            .collect();
+        let mut levels: Vec<Vec<usize>> = vec![];
+        let mut level_of = Vec::with_capacity(sccs.len());
+        // NOTE `sccs` has callees first, so their levels are already known.
+        for (i, scc) in sccs.iter().enumerate() {
+            let level = scc
+                .iter()
+                .flat_map(|name| self.rules()[name].rule.call_names(cx))
+                .filter_map(|callee| scc_of.get(&callee))
+                .filter(|&&callee_scc| callee_scc != i)
+                .map(|&callee_scc| level_of[callee_scc] + 1)
+                .max()
+                .unwrap_or(0);
+            level_of.push(level);
+            if level == levels.len() {
+                levels.push(vec![]);
+            }
+            levels[level].push(i);
+        }
+        levels
+    }
+
+    fn compute_sccs<Pat>(&self, cx: &Context<Pat>) -> Vec<Vec<IStr>> {
+        struct Tarjan<'a> {
+            calls: IndexMap<IStr, Vec<IStr>>,
+            index: HashMap<IStr, usize>,
+            low_link: HashMap<IStr, usize>,
+            stack: Vec<IStr>,
+            on_stack: HashSet<IStr>,
+            sccs: &'a mut Vec<Vec<IStr>>,
+        }
+
+        impl Tarjan<'_> {
+            fn enter(&mut self, name: IStr) {
+                let index = self.index.len();
+                self.index.insert(name, index);
+                self.low_link.insert(name, index);
+                self.stack.push(name);
+                self.on_stack.insert(name);
+            }
+
+            // NOTE this is iterative (with an explicit stack of the rules
+            // being visited, each with the index of its next callee), as
+            // long chains of calls could otherwise overflow the stack.
+            fn visit(&mut self, root: IStr) {
+                self.enter(root);
+                let mut visiting = vec![(root, 0)];
+                while let Some(&(name, next)) = visiting.last() {
+                    if let Some(&callee) = self.calls[&name].get(next) {
+                        visiting.last_mut().unwrap().1 += 1;
+                        if !self.index.contains_key(&callee) {
+                            self.enter(callee);
+                            visiting.push((callee, 0));
+                        } else if self.on_stack.contains(&callee) {
+                            let low_link = self.low_link[&name].min(self.index[&callee]);
+                            self.low_link.insert(name, low_link);
+                        }
+                        continue;
+                    }
+
+                    visiting.pop();
+                    if let Some(&(caller, _)) = visiting.last() {
+                        let low_link = self.low_link[&caller].min(self.low_link[&name]);
+                        self.low_link.insert(caller, low_link);
+                    }
+                    if self.low_link[&name] == self.index[&name] {
+                        let mut scc = vec![];
+                        loop {
+                            let member = self.stack.pop().unwrap();
+                            self.on_stack.remove(&member);
+                            scc.push(member);
+                            if member == name {
+                                break;
+                            }
+                        }
+                        scc.reverse();
+                        self.sccs.push(scc);
+                    }
+                }
+            }
+        }
+
+        let mut sccs = vec![];
+        let mut tarjan = Tarjan {
+            calls: self
+                .rules()
+                .iter()
+                .map(|(&name, rule)| {
+                    let calls = rule.rule.call_names(cx).into_iter();
+                    (
+                        name,
+                        calls
+                            .filter(|callee| self.rules().contains_key(callee))
+                            .collect(),
+                    )
+                })
+                .collect(),
+            index: HashMap::default(),
+            low_link: HashMap::default(),
+            stack: vec![],
+            on_stack: HashSet::default(),
+            sccs: &mut sccs,
+        };
+        for &name in self.rules().keys() {
+            if !tarjan.index.contains_key(&name) {
+                tarjan.visit(name);
+            }
+        }
+        sccs
+    }
+
+    /// Whether each rule can match the empty string (`Unknown` only when
+    /// that depends on patterns, e.g. proc macro ones, that can't tell).
+    pub fn can_be_empty<Pat: rule::MatchesEmpty>(
+        &self,
+        cx: &Context<Pat>,
+    ) -> &IndexMap<IStr, rule::MaybeKnown<bool>> {
+        self.analyses.can_be_empty.get_or_init(|| {
+            let mut cache = BTreeMap::new();
+            self.rules()
+                .iter()
+                .map(|(&name, rule)| (name, rule.rule.can_be_empty(&mut cache, cx, self)))
+                .collect()
+        })
+    }
+
+    /// The FIRST set of each rule, i.e. the characters it can start with.
+    pub fn first_sets<Pat: rule::Pattern + Eq + Hash>(
+        &self,
+        cx: &Context<Pat>,
+    ) -> &IndexMap<IStr, dispatch::FirstSet> {
+        self.analyses
+            .first_sets
+            .get_or_init(|| dispatch::first_sets(cx, self))
+    }
+
+    /// The rules reachable from each rule (including itself), through `Call`s.
+    ///
+    /// With the `rayon` feature, the independent components of the call
+    /// graph (see `scc_levels`) are handled in parallel.
+    pub fn reachable<Pat>(&self, cx: &Context<Pat>) -> &IndexMap<IStr, IndexSet<IStr>> {
+        self.analyses.reachable.get_or_init(|| {
+            let sccs = self.sccs(cx);
+            // NOTE the calls are collected upfront, so that only `IStr`s
+            // are shared between threads (and not the `Context`).
+            let calls: Vec<Vec<IStr>> = sccs
+                .iter()
+                .map(|scc| {
+                    scc.iter()
+                        .flat_map(|name| self.rules()[name].rule.call_names(cx))
+                        .collect()
+                })
+                .collect();
+            let mut reachable: HashMap<_, _> = HashMap::default();
+            for level in self.scc_levels(cx) {
+                // NOTE every component outside of the current level
+                // that's called from it already has its reachable set.
+                let scc_reachable = |&i: &usize| {
+                    let mut scc_reachable: IndexSet<_> = sccs[i].iter().copied().collect();
+                    for callee in &calls[i] {
+                        if let Some(callee_reachable) = reachable.get(callee) {
+                            scc_reachable.extend(callee_reachable);
+                        }
+                    }
+                    (i, scc_reachable)
+                };
+                #[cfg(feature = "rayon")]
+                let level_reachable: Vec<_> = {
+                    use rayon::prelude::*;
+                    level.par_iter().map(scc_reachable).collect()
+                };
+                #[cfg(not(feature = "rayon"))]
+                let level_reachable: Vec<_> = level.iter().map(scc_reachable).collect();
+                for (i, scc_reachable) in level_reachable {
+                    for &name in &sccs[i] {
+                        reachable.insert(name, scc_reachable.clone());
+                    }
+                }
+            }
+            self.rules()
+                .keys()
+                .map(|name| (*name, reachable[name].clone()))
+                .collect()
+        })
+    }
+}
+
+/// The 64-bit FNV-1a hash, used for `Grammar::fingerprint`, as its
+/// results (unlike those of `DefaultHasher`) are guaranteed not to change.
+///
+/// Without the `std` feature, all the maps and sets in the API (e.g.
+/// `Grammar::rules`) also hash with it, i.e. use `BuildHasherDefault<Fnv1a>`.
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    // NOTE the default methods use native endianness (and `usize`
+    // has a target-dependent size), which would make fingerprints differ
+    // between targets, so all integers are widened to `u64` instead.
+    fn write_u16(&mut self, x: u16) {
+        self.write_u64(x.into());
+    }
+    fn write_u32(&mut self, x: u32) {
+        self.write_u64(x.into());
+    }
+    fn write_u64(&mut self, x: u64) {
+        self.write(&x.to_le_bytes());
+    }
+    fn write_usize(&mut self, x: usize) {
+        self.write_u64(x as u64);
     }
 }
 
@@ -0,0 +1,233 @@
+//! Lifting nested alternations and groups out of rules, into rules of their
+//! own, with deterministic names (see `Grammar::lift_inline`).
+
+use crate::compat::prelude::*;
+use crate::compat::IndexMap;
+use crate::context::{Context, IStr};
+use crate::rule::{any_of, call, capture, seq, Field, Fields, Modifiers, Rule, RuleWithFields};
+use crate::synthetic::{self, Kind, Namer};
+use crate::Grammar;
+use core::hash::Hash;
+
+/// Where a lifted rule was originally nested: in `rule` (in the grammar
+/// before lifting), found by taking the child at every index in `path`, in
+/// turn, from the top of `rule` (numbered like `Fields` children, e.g. `[1, 0]`
+/// is the operand of an `Opt` which is the second element of a `Concat`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub rule: IStr,
+    pub path: Vec<usize>,
+}
+
+pub(crate) fn lift_inline<Pat: Eq + Hash>(
+    cx: &Context<Pat>,
+    grammar: Grammar,
+) -> (Grammar, IndexMap<IStr, Origin>) {
+    let mut lifted = grammar.empty_like();
+    let mut origins = IndexMap::default();
+    let mut namer = Namer::new(&grammar);
+    for (&name, &rule) in grammar.rules() {
+        let mut lifter = Lifter {
+            cx,
+            namer: &mut namer,
+            parent: name,
+            lifted: vec![],
+        };
+        let rule = lifter.lift(rule, &mut vec![], true);
+        let lifter_lifted = lifter.lifted;
+
+        lifted.define(name, rule);
+        let modifiers = grammar.modifiers(name);
+        for (lifted_name, rule, path) in lifter_lifted {
+            lifted.define(lifted_name, rule);
+            // NOTE lifted rules are only called from the rule they're
+            // lifted out of, so they already inherit its atomicity, but not
+            // the case-insensitivity of its terminals.
+            lifted.set_modifiers(
+                lifted_name,
+                Modifiers {
+                    ignore_case: modifiers.ignore_case,
+                    ..Modifiers::default()
+                },
+            );
+            origins.insert(lifted_name, Origin { rule: name, path });
+        }
+    }
+    lifted.synthetic.extend(namer.picked);
+    (lifted, origins)
+}
+
+struct Lifter<'a, Pat> {
+    cx: &'a Context<Pat>,
+    namer: &'a mut Namer,
+    /// The rule being lifted out of.
+    parent: IStr,
+    /// The lifted rules, in the order they're found in `parent`.
+    lifted: Vec<(IStr, RuleWithFields, Vec<usize>)>,
+}
+
+impl<Pat: Eq + Hash> Lifter<'_, Pat> {
+    /// Lift all the `Or`s in `rule` (except for `rule` itself, if `top`, i.e.
+    /// it's the whole body of a rule), and all the non-leaf `Opt`/repeat
+    /// operands, with `path` leading to `rule`.
+    fn lift(&mut self, rule: RuleWithFields, path: &mut Vec<usize>, top: bool) -> RuleWithFields {
+        let cx = self.cx;
+        if let Fields::Leaf(Some(field)) = cx[rule.fields] {
+            let inner = self.lift(
+                RuleWithFields {
+                    rule: rule.rule,
+                    fields: field.sub,
+                },
+                path,
+                top,
+            );
+            return RuleWithFields {
+                rule: inner.rule,
+                fields: cx.intern(Fields::Leaf(Some(Field {
+                    name: field.name,
+                    sub: inner.fields,
+                }))),
+            };
+        }
+
+        let child = |i, child| RuleWithFields {
+            rule: child,
+            fields: rule.fields.child(cx, i),
+        };
+        match cx[rule.rule] {
+            Rule::Empty
+            | Rule::Anchor(_)
+            | Rule::Eat(_)
+            | Rule::EatUntil(_)
+            | Rule::Backref(_)
+            | Rule::Call(_)
+            | Rule::FlagGuard(..)
+            | Rule::Guard(_)
+            | Rule::Feature(..) => rule,
+            // NOTE lifting `body` out would be fine, as captures
+            // are inherited through `Call`s, but it's treated like a
+            // `Concat`, i.e. only the `Or`s inside are lifted.
+            Rule::Capture(name, delim, body) => capture(
+                &cx[name],
+                self.lift_child(child(0, delim), path, 0, false),
+                self.lift_child(child(1, body), path, 1, false),
+            )
+            .finish(cx),
+            Rule::Or(_) if !top => self.extract(rule, path, false),
+            Rule::Or(ref cases) => any_of(
+                cases
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &case)| self.lift_child(child(i, case), path, i, false)),
+            )
+            .finish(cx),
+            Rule::Concat(ref elems) => seq(elems
+                .iter()
+                .enumerate()
+                .map(|(i, &elem)| self.lift_child(child(i, elem), path, i, false)))
+            .finish(cx),
+            // NOTE these don't affect the structure, so an `Or` right
+            // inside them is still at the top of the rule.
+            Rule::WithFlag(flag, value, inner) => {
+                let inner = self.lift_child(child(0, inner), path, 0, top);
+                if value {
+                    inner.with_flag(&cx[flag]).finish(cx)
+                } else {
+                    inner.without_flag(&cx[flag]).finish(cx)
+                }
+            }
+            Rule::Silent(inner) => self
+                .lift_child(child(0, inner), path, 0, top)
+                .silent()
+                .finish(cx),
+            Rule::Opt(inner) => self.group(child(0, inner), path, 0).opt().finish(cx),
+            Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => {
+                let elem = self.group(child(0, elem), path, 0);
+                let sep = sep.map(|(sep, kind)| (self.group(child(1, sep), path, 1), kind));
+                match (&cx[rule.rule], sep) {
+                    (Rule::RepeatMany(..), None) => elem.repeat_many().finish(cx),
+                    (Rule::RepeatMany(..), Some((sep, kind))) => {
+                        elem.repeat_many_sep(sep, kind).finish(cx)
+                    }
+                    (_, None) => elem.repeat_more().finish(cx),
+                    (_, Some((sep, kind))) => elem.repeat_more_sep(sep, kind).finish(cx),
+                }
+            }
+        }
+    }
+
+    /// Lift the child `i` of `path`, `rule`.
+    fn lift_child(
+        &mut self,
+        rule: RuleWithFields,
+        path: &mut Vec<usize>,
+        i: usize,
+        top: bool,
+    ) -> RuleWithFields {
+        path.push(i);
+        let rule = self.lift(rule, path, top);
+        path.pop();
+        rule
+    }
+
+    /// Lift the `Opt`/repeat operand `rule` (the child `i` of `path`),
+    /// unless it's a leaf (e.g. a `Call` or an `Eat`).
+    fn group(&mut self, rule: RuleWithFields, path: &mut Vec<usize>, i: usize) -> RuleWithFields {
+        path.push(i);
+        let is_leaf = matches!(
+            self.cx[rule.rule],
+            Rule::Empty
+                | Rule::Anchor(_)
+                | Rule::Eat(_)
+                | Rule::EatUntil(_)
+                | Rule::Backref(_)
+                | Rule::Call(_)
+                | Rule::FlagGuard(..)
+                | Rule::Guard(_)
+                | Rule::Feature(..)
+        );
+        let rule = if is_leaf {
+            rule
+        } else {
+            self.extract(rule, path, true)
+        };
+        path.pop();
+        rule
+    }
+
+    /// Move `rule` into a new rule, returning a `Call` to it (with any fields
+    /// wrapping `rule` left around the `Call`, and the rest moved with it).
+    fn extract(
+        &mut self,
+        rule: RuleWithFields,
+        path: &mut Vec<usize>,
+        group: bool,
+    ) -> RuleWithFields {
+        let cx = self.cx;
+        if let Fields::Leaf(Some(field)) = cx[rule.fields] {
+            let call = self.extract(
+                RuleWithFields {
+                    rule: rule.rule,
+                    fields: field.sub,
+                },
+                path,
+                group,
+            );
+            return call.field(&cx[field.name]).finish(cx);
+        }
+
+        let name = self.namer.fresh(
+            cx,
+            synthetic::Origin {
+                parent: self.parent,
+                kind: if group { Kind::Group } else { Kind::Alt },
+            },
+        );
+        // NOTE this is pushed first (and replaced later) so that rules
+        // lifted out of this one come after it, instead of before it.
+        let i = self.lifted.len();
+        self.lifted.push((name, rule, path.clone()));
+        self.lifted[i].1 = self.lift(rule, path, true);
+        call(&cx[name]).finish(cx)
+    }
+}
@@ -0,0 +1,114 @@
+//! Limits on the size of grammars, to bound the resources (memory, and
+//! stack space, as most analyses are recursive) used on untrusted input.
+
+use crate::compat::prelude::*;
+use crate::compat::HashMap;
+use crate::context::{Context, IRule};
+use crate::diagnostics::{Args, Localize};
+use crate::rule::Rule;
+use crate::Grammar;
+use core::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// How deeply rules can be nested, e.g. `((A B)?)*` has a depth of 4.
+    pub max_depth: usize,
+    /// How many cases a single `Or` can have.
+    pub max_alternatives: usize,
+    /// How many distinct rules (including all nested ones) can be
+    /// created, in total, across the whole grammar.
+    pub max_nodes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_depth: 256,
+            max_alternatives: 4096,
+            max_nodes: 1 << 20,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LimitError {
+    /// The name of the rule exceeding the limit.
+    pub rule: String,
+    /// Which limit was exceeded, e.g. `max_depth`.
+    pub limit: &'static str,
+    pub max: usize,
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rule `{}` exceeds `{}` (of {})",
+            self.rule, self.limit, self.max
+        )
+    }
+}
+
+impl Localize for LimitError {
+    /// `limit-exceeded`, with `rule`, `limit` and `max` arguments.
+    fn message_id(&self) -> &'static str {
+        "limit-exceeded"
+    }
+
+    fn args(&self) -> Args {
+        vec![
+            ("rule", self.rule.clone()),
+            ("limit", self.limit.to_string()),
+            ("max", self.max.to_string()),
+        ]
+    }
+}
+
+impl Limits {
+    /// Check that `grammar` is within these limits.
+    ///
+    /// Unlike most analyses, this doesn't recurse, so it's safe to use even
+    /// on grammars nested deeply enough to exhaust the stack otherwise.
+    pub fn check<Pat>(&self, cx: &Context<Pat>, grammar: &Grammar) -> Result<(), LimitError> {
+        // NOTE rules are shared (by interning), so this computes the
+        // depth of each distinct rule only once, in post-order.
+        let mut depths: HashMap<IRule, usize> = HashMap::default();
+        for (&name, rule) in grammar.rules() {
+            let error = |limit, max| LimitError {
+                rule: cx[name].to_string(),
+                limit,
+                max,
+            };
+            let mut stack = vec![(rule.rule, false)];
+            while let Some((rule, children_done)) = stack.pop() {
+                if depths.contains_key(&rule) {
+                    continue;
+                }
+                let children = rule.children(cx);
+                if !children_done {
+                    if let Rule::Or(ref cases) = cx[rule] {
+                        if cases.len() > self.max_alternatives {
+                            return Err(error("max_alternatives", self.max_alternatives));
+                        }
+                    }
+                    stack.push((rule, true));
+                    stack.extend(children.into_iter().map(|child| (child, false)));
+                    continue;
+                }
+                let depth = 1 + children
+                    .iter()
+                    .map(|child| depths[child])
+                    .max()
+                    .unwrap_or(0);
+                if depth > self.max_depth {
+                    return Err(error("max_depth", self.max_depth));
+                }
+                depths.insert(rule, depth);
+                if depths.len() > self.max_nodes {
+                    return Err(error("max_nodes", self.max_nodes));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+//! Test case reduction for grammars (see `Grammar::minimize`).
+
+use crate::compat::prelude::*;
+use crate::compat::IndexMap;
+use crate::context::{Context, IStr};
+use crate::rule::{any_of, capture, empty, seq, Fields, Rule, RuleWithFields};
+use crate::Grammar;
+use core::hash::Hash;
+
+pub(crate) fn minimize<Pat: Eq + Hash>(
+    cx: &Context<Pat>,
+    grammar: Grammar,
+    mut predicate: impl FnMut(&Grammar) -> bool,
+) -> Grammar {
+    let template = grammar.empty_like();
+    let build = |rules: &IndexMap<IStr, RuleWithFields>, trivia: Option<RuleWithFields>| {
+        let mut grammar = template.empty_like();
+        grammar.trivia = trivia;
+        for (&name, &rule) in rules {
+            grammar.define(name, rule);
+        }
+        grammar.forget_undefined();
+        grammar
+    };
+
+    trace_span!(DEBUG, "minimize", rules = grammar.rules().len());
+    let mut rules = grammar.rules().clone();
+    let mut trivia = grammar.trivia;
+
+    // NOTE simplifications can enable more simplifications (even in
+    // other rules), or the removal of more rules (which aren't called from
+    // anywhere anymore), so this keeps going until nothing changes anymore.
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        let names: Vec<_> = rules.keys().copied().collect();
+        let names = ddmin(names, |names| {
+            let rules = names.iter().map(|&name| (name, rules[&name])).collect();
+            predicate(&build(&rules, trivia))
+        });
+        if names.len() < rules.len() {
+            trace_event!(DEBUG, rules = names.len(), "removed rules");
+            rules = names.into_iter().map(|name| (name, rules[&name])).collect();
+            changed = true;
+        }
+
+        if trivia.is_some() && predicate(&build(&rules, None)) {
+            trivia = None;
+            changed = true;
+        }
+
+        for i in 0..rules.len() {
+            while let Some(simpler) = simplifications(cx, rules[i]).into_iter().find(|&simpler| {
+                // NOTE rebuilding can sometimes result in the same
+                // rule (e.g. due to flattening), which would never end.
+                if simpler == rules[i] {
+                    return false;
+                }
+                let mut rules = rules.clone();
+                rules[i] = simpler;
+                predicate(&build(&rules, trivia))
+            }) {
+                trace_event!(
+                    DEBUG,
+                    rule = &cx[*rules.get_index(i).unwrap().0],
+                    "simplified rule"
+                );
+                rules[i] = simpler;
+                changed = true;
+            }
+        }
+        if let Some(old) = trivia {
+            if let Some(simpler) = simplifications(cx, old)
+                .into_iter()
+                .find(|&simpler| simpler != old && predicate(&build(&rules, Some(simpler))))
+            {
+                trivia = Some(simpler);
+                changed = true;
+            }
+        }
+    }
+
+    build(&rules, trivia)
+}
+
+/// Delta debugging ("ddmin"), i.e. finding a subset of `items` for which
+/// `holds` (assumed to hold for all of `items`) still holds, and which is
+/// 1-minimal (removing any single item makes `holds` fail), by removing
+/// progressively smaller chunks of `items`.
+fn ddmin<T: Copy>(mut items: Vec<T>, mut holds: impl FnMut(&[T]) -> bool) -> Vec<T> {
+    let mut chunks = 2;
+    while !items.is_empty() {
+        let chunk_len = items.len().div_ceil(chunks);
+        let reduced = (0..items.len()).step_by(chunk_len).find_map(|start| {
+            let end = (start + chunk_len).min(items.len());
+            let rest: Vec<_> = items[..start]
+                .iter()
+                .chain(&items[end..])
+                .copied()
+                .collect();
+            if holds(&rest) {
+                Some(rest)
+            } else {
+                None
+            }
+        });
+        match reduced {
+            Some(rest) => {
+                items = rest;
+                chunks = (chunks - 1).max(2);
+            }
+            None if chunk_len == 1 => break,
+            None => chunks = (chunks * 2).min(items.len()),
+        }
+    }
+    items
+}
+
+/// All the ways to make `rule` simpler in one step, most drastic first,
+/// e.g. replacing it with `Empty`, removing a case from an `Or`, or
+/// unwrapping an `Opt` (anywhere inside `rule`).
+fn simplifications<Pat: Eq + Hash>(cx: &Context<Pat>, rule: RuleWithFields) -> Vec<RuleWithFields> {
+    if let Fields::Leaf(Some(field)) = cx[rule.fields] {
+        let sub = RuleWithFields {
+            rule: rule.rule,
+            fields: field.sub,
+        };
+        let mut simpler = simplifications(cx, sub);
+        for rule in &mut simpler {
+            *rule = rule.field(&cx[field.name]).finish(cx);
+        }
+        simpler.insert(0, sub);
+        return simpler;
+    }
+
+    let child = |child, i| RuleWithFields {
+        rule: child,
+        fields: rule.fields.child(cx, i),
+    };
+    // Simplify `rule`'s child, rebuilding `rule` around it with `rebuild`.
+    let simplify_child = |child, rebuild: &dyn Fn(RuleWithFields) -> RuleWithFields| {
+        simplifications(cx, child)
+            .into_iter()
+            .map(rebuild)
+            .collect::<Vec<_>>()
+    };
+
+    let mut simpler = vec![];
+    if let Rule::Empty = cx[rule.rule] {
+        return simpler;
+    }
+    simpler.push(empty().finish(cx));
+    match cx[rule.rule] {
+        Rule::Empty
+        | Rule::Anchor(_)
+        | Rule::Eat(_)
+        | Rule::EatUntil(_)
+        | Rule::Backref(_)
+        | Rule::Call(_)
+        | Rule::FlagGuard(..)
+        | Rule::Guard(_)
+        | Rule::Feature(..) => {}
+        Rule::WithFlag(flag, value, inner) => {
+            let inner = child(inner, 0);
+            simpler.push(inner);
+            simpler.extend(simplify_child(inner, &|inner| {
+                if value {
+                    inner.with_flag(&cx[flag]).finish(cx)
+                } else {
+                    inner.without_flag(&cx[flag]).finish(cx)
+                }
+            }));
+        }
+        Rule::Silent(inner) => {
+            let inner = child(inner, 0);
+            simpler.push(inner);
+            simpler.extend(simplify_child(inner, &|inner| inner.silent().finish(cx)));
+        }
+        Rule::Capture(name, delim, body) => {
+            let (delim, body) = (child(delim, 0), child(body, 1));
+            simpler.push(body);
+            simpler.extend(simplify_child(delim, &|delim| {
+                capture(&cx[name], delim, body).finish(cx)
+            }));
+            simpler.extend(simplify_child(body, &|body| {
+                capture(&cx[name], delim, body).finish(cx)
+            }));
+        }
+        Rule::Opt(inner) => {
+            let inner = child(inner, 0);
+            simpler.push(inner);
+            simpler.extend(simplify_child(inner, &|inner| inner.opt().finish(cx)));
+        }
+        Rule::Concat(ref rules) | Rule::Or(ref rules) => {
+            let is_or = matches!(cx[rule.rule], Rule::Or(_));
+            let rules: Vec<_> = rules
+                .iter()
+                .enumerate()
+                .map(|(i, &rule)| child(rule, i))
+                .collect();
+            let rebuild = |rules: Vec<RuleWithFields>| {
+                if is_or {
+                    any_of(rules).finish(cx)
+                } else {
+                    seq(rules).finish(cx)
+                }
+            };
+            for i in 0..rules.len() {
+                let mut rules = rules.clone();
+                rules.remove(i);
+                simpler.push(rebuild(rules));
+            }
+            for (i, &rule) in rules.iter().enumerate() {
+                simpler.extend(simplify_child(rule, &|rule| {
+                    let mut rules = rules.clone();
+                    rules[i] = rule;
+                    rebuild(rules)
+                }));
+            }
+        }
+        Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => {
+            let many = matches!(cx[rule.rule], Rule::RepeatMany(..));
+            let elem = child(elem, 0);
+            let sep = sep.map(|(sep, kind)| (child(sep, 1), kind));
+            let rebuild = |elem: RuleWithFields, sep: Option<(RuleWithFields, _)>| match sep {
+                None if many => elem.repeat_many().finish(cx),
+                None => elem.repeat_more().finish(cx),
+                Some((sep, kind)) if many => elem.repeat_many_sep(sep, kind).finish(cx),
+                Some((sep, kind)) => elem.repeat_more_sep(sep, kind).finish(cx),
+            };
+            simpler.push(elem);
+            if sep.is_some() {
+                simpler.push(rebuild(elem, None));
+            }
+            simpler.extend(simplify_child(elem, &|elem| rebuild(elem, sep)));
+            if let Some((sep, kind)) = sep {
+                simpler.extend(simplify_child(sep, &|sep| rebuild(elem, Some((sep, kind)))));
+            }
+        }
+    }
+    simpler
+}
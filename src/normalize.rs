@@ -0,0 +1,355 @@
+//! Normalizing text inputs before matching them (see `Normalization`), so
+//! that e.g. precomposed and decomposed accented letters (with NFC), or upper
+//! and lower case letters (with case folding) match the same, while still
+//! reporting positions in the original input (see `Normalized`).
+
+use crate::compat::prelude::*;
+use crate::context::Context;
+use crate::interpreter::{Derivation, FieldMatch};
+use crate::rule::{eat, until, Folder, Rule, RuleWithFields};
+use crate::scannerless::Pat as SPat;
+use crate::unicode_tables::{CASE_FOLDINGS, COMBINING_CLASSES, COMPOSITIONS, DECOMPOSITIONS};
+use core::hash::Hash;
+use core::ops::Range;
+
+/// How to normalize inputs before matching them (and the terminals they're
+/// matched against, see `Grammar::set_input_normalization`).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Normalization {
+    /// Normalize to Unicode Normalization Form C (canonical composition).
+    pub nfc: bool,
+    /// Apply (full) Unicode case folding, e.g. for case-insensitive languages,
+    /// also normalizing to NFC (as case folding can break it).
+    pub case_fold: bool,
+}
+
+impl Normalization {
+    /// Whether this leaves all text as-is (the default).
+    pub fn is_identity(&self) -> bool {
+        !self.nfc && !self.case_fold
+    }
+
+    /// Normalize `text` (e.g. a terminal), without keeping track of positions.
+    pub fn apply(&self, text: &str) -> String {
+        Normalized::new(text, *self).text
+    }
+}
+
+/// A normalized copy of some text, which maps positions in it (e.g. spans
+/// from matching against it) back to the original text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Normalized {
+    text: String,
+    /// The start of every segment (a character which can't combine with
+    /// the ones before it, followed by all those which can), both in `text`
+    /// and in the original text, or `None` if nothing was normalized.
+    segments: Option<Vec<(usize, usize)>>,
+    original_len: usize,
+}
+
+impl Normalized {
+    pub fn new(original: &str, normalization: Normalization) -> Self {
+        if normalization.is_identity() {
+            return Normalized {
+                text: original.to_string(),
+                segments: None,
+                original_len: original.len(),
+            };
+        }
+
+        let mut text = String::with_capacity(original.len());
+        let mut segments = vec![];
+        let mut chars = original.char_indices().peekable();
+        let mut decomposed = vec![];
+        while let Some((start, c)) = chars.next() {
+            decomposed.clear();
+            decompose(c, &mut decomposed);
+            while let Some(&(_, next)) = chars.peek() {
+                if combining_class(next) == 0 && !composes_with_previous(next) {
+                    break;
+                }
+                decompose(next, &mut decomposed);
+                chars.next();
+            }
+            reorder(&mut decomposed);
+            // NOTE this follows the definition of "canonical caseless
+            // matching", i.e. `NFC(toCasefold(NFD(X)))` (with `NFC` instead
+            // of `NFD`, on the outside), in the Unicode Standard.
+            if normalization.case_fold {
+                decomposed = case_fold(&decomposed);
+                reorder(&mut decomposed);
+            }
+            segments.push((text.len(), start));
+            text.extend(compose(&decomposed));
+        }
+        Normalized {
+            text,
+            segments: Some(segments),
+            original_len: original.len(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// The position in the original text of `pos` (in the normalized text),
+    /// or of the start of its segment, if `pos` is in the middle of one.
+    pub fn original_start(&self, pos: usize) -> usize {
+        match &self.segments {
+            None => pos,
+            Some(segments) => match segments.partition_point(|&(start, _)| start <= pos) {
+                0 => 0,
+                i => segments[i - 1].1,
+            },
+        }
+    }
+
+    /// The position in the normalized text of `pos` (in the original text),
+    /// or of the start of its segment, if `pos` is in the middle of one.
+    pub fn normalized_start(&self, pos: usize) -> usize {
+        match &self.segments {
+            None => pos,
+            Some(segments) => match segments.partition_point(|&(_, start)| start <= pos) {
+                0 => 0,
+                i => segments[i - 1].0,
+            },
+        }
+    }
+
+    /// Like `original_start`, but for the end of its segment, if `pos`
+    /// is in the middle of one.
+    pub fn original_end(&self, pos: usize) -> usize {
+        match &self.segments {
+            None => pos,
+            Some(segments) => {
+                let i = segments.partition_point(|&(start, _)| start < pos);
+                segments
+                    .get(i)
+                    .map_or(self.original_len, |&(_, start)| start)
+            }
+        }
+    }
+
+    /// The range in the original text matching (at least) `range`, in the
+    /// normalized text, e.g. for reporting a span from matching against it.
+    pub fn original_range(&self, range: Range<usize>) -> Range<usize> {
+        self.original_start(range.start)..self.original_end(range.end)
+    }
+
+    /// Map all the spans in `derivation` (of the normalized text) back to
+    /// the original text (see `original_range`).
+    pub fn original_derivation(&self, derivation: Derivation) -> Derivation {
+        Derivation {
+            span: self.original_range(derivation.span),
+            children: derivation
+                .children
+                .into_iter()
+                .map(|child| self.original_derivation(child))
+                .collect(),
+            fields: derivation
+                .fields
+                .into_iter()
+                .map(|field| self.original_field(field))
+                .collect(),
+            ..derivation
+        }
+    }
+
+    fn original_field(&self, field: FieldMatch) -> FieldMatch {
+        FieldMatch {
+            span: self.original_range(field.span),
+            fields: field
+                .fields
+                .into_iter()
+                .map(|field| self.original_field(field))
+                .collect(),
+            ..field
+        }
+    }
+}
+
+/// Normalizes terminals (see `Grammar::normalize_terminals`).
+pub(crate) struct TerminalNormalizer<'cx, S> {
+    pub(crate) cx: &'cx Context<SPat<S>>,
+    pub(crate) normalization: Normalization,
+}
+
+impl<'cx, S> Folder<'cx, SPat<S>> for TerminalNormalizer<'cx, S>
+where
+    S: AsRef<str> + From<String> + Eq + Hash,
+{
+    fn cx(&self) -> &'cx Context<SPat<S>> {
+        self.cx
+    }
+    fn fold_leaf(&mut self, rule: RuleWithFields) -> RuleWithFields {
+        let normalize = |pat: &SPat<S>| match *pat {
+            SPat::String(ref s) => {
+                Some(SPat::String(S::from(self.normalization.apply(s.as_ref()))))
+            }
+            SPat::Range(start, end)
+                if self.normalization.case_fold
+                    && start.is_ascii_uppercase()
+                    && end.is_ascii_uppercase() =>
+            {
+                Some(SPat::Range(
+                    start.to_ascii_lowercase(),
+                    end.to_ascii_lowercase(),
+                ))
+            }
+            SPat::Range(..) => None,
+        };
+        match self.cx[rule.rule] {
+            Rule::Eat(ref pat) => normalize(pat).map_or(rule, |pat| eat(pat).finish(self.cx)),
+            Rule::EatUntil(ref pat) => {
+                normalize(pat).map_or(rule, |pat| until(pat).finish(self.cx))
+            }
+            _ => rule,
+        }
+    }
+}
+
+// NOTE Hangul syllables are (de)composed algorithmically, as per
+// "Hangul Syllable Decomposition" in the Unicode Standard.
+const HANGUL_S_BASE: u32 = 0xAC00;
+const HANGUL_L_BASE: u32 = 0x1100;
+const HANGUL_V_BASE: u32 = 0x1161;
+const HANGUL_T_BASE: u32 = 0x11A7;
+const HANGUL_L_COUNT: u32 = 19;
+const HANGUL_V_COUNT: u32 = 21;
+const HANGUL_T_COUNT: u32 = 28;
+const HANGUL_N_COUNT: u32 = HANGUL_V_COUNT * HANGUL_T_COUNT;
+const HANGUL_S_COUNT: u32 = HANGUL_L_COUNT * HANGUL_N_COUNT;
+
+fn combining_class(c: char) -> u8 {
+    let i = COMBINING_CLASSES.partition_point(|&(_, end, _)| end < c);
+    match COMBINING_CLASSES.get(i) {
+        Some(&(start, _, class)) if start <= c => class,
+        _ => 0,
+    }
+}
+
+/// Whether `c` can be composed with a character before it, even if it has
+/// a combining class of `0` (e.g. Hangul vowels, or some vowel signs).
+fn composes_with_previous(c: char) -> bool {
+    #[cfg(feature = "std")]
+    let is_second = {
+        static SECOND: std::sync::OnceLock<Vec<char>> = std::sync::OnceLock::new();
+        let second = SECOND.get_or_init(|| {
+            let mut second: Vec<_> = COMPOSITIONS.iter().map(|&(_, b, _)| b).collect();
+            second.sort();
+            second.dedup();
+            second
+        });
+        second.binary_search(&c).is_ok()
+    };
+    // NOTE without `std`, there's nowhere to lazily cache the sorted
+    // characters in (as that needs to be `Sync`), so they're searched for.
+    #[cfg(not(feature = "std"))]
+    let is_second = COMPOSITIONS.iter().any(|&(_, b, _)| b == c);
+    let c32 = c as u32;
+    (HANGUL_V_BASE..HANGUL_V_BASE + HANGUL_V_COUNT).contains(&c32)
+        || (HANGUL_T_BASE + 1..HANGUL_T_BASE + HANGUL_T_COUNT).contains(&c32)
+        || is_second
+}
+
+/// Push the full canonical decomposition of `c` to `out`.
+fn decompose(c: char, out: &mut Vec<char>) {
+    let c32 = c as u32;
+    if (HANGUL_S_BASE..HANGUL_S_BASE + HANGUL_S_COUNT).contains(&c32) {
+        let s = c32 - HANGUL_S_BASE;
+        let jamo = [
+            HANGUL_L_BASE + s / HANGUL_N_COUNT,
+            HANGUL_V_BASE + (s % HANGUL_N_COUNT) / HANGUL_T_COUNT,
+            HANGUL_T_BASE + s % HANGUL_T_COUNT,
+        ];
+        out.extend(
+            jamo.into_iter()
+                .filter(|&j| j != HANGUL_T_BASE)
+                .filter_map(char::from_u32),
+        );
+        return;
+    }
+    match DECOMPOSITIONS.binary_search_by_key(&c, |&(c, _, _)| c) {
+        Ok(i) => {
+            let (_, a, b) = DECOMPOSITIONS[i];
+            decompose(a, out);
+            if b != '\0' {
+                decompose(b, out);
+            }
+        }
+        Err(_) => out.push(c),
+    }
+}
+
+/// Case fold (fully decomposed) `chars`, decomposing the result again.
+fn case_fold(chars: &[char]) -> Vec<char> {
+    let mut out = Vec::with_capacity(chars.len());
+    for &c in chars {
+        match CASE_FOLDINGS.binary_search_by_key(&c, |&(c, _)| c) {
+            Ok(i) => CASE_FOLDINGS[i]
+                .1
+                .chars()
+                .for_each(|c| decompose(c, &mut out)),
+            Err(_) => c.to_lowercase().for_each(|c| decompose(c, &mut out)),
+        }
+    }
+    out
+}
+
+/// Put `chars` in canonical order, i.e. sort every run of characters with
+/// non-zero combining classes by their combining class (stably).
+fn reorder(chars: &mut [char]) {
+    for run in chars.split_mut(|&c| combining_class(c) == 0) {
+        run.sort_by_key(|&c| combining_class(c));
+    }
+}
+
+/// Canonically compose (canonically ordered) `chars`.
+fn compose(chars: &[char]) -> Vec<char> {
+    let mut out: Vec<char> = Vec::with_capacity(chars.len());
+    let mut starter = None;
+    // The combining class of the last character since `starter`, if any.
+    let mut last_class = None;
+    for &c in chars {
+        let class = combining_class(c);
+        if let Some(starter) = starter {
+            let blocked = last_class.is_some_and(|last| last >= class);
+            if !blocked {
+                if let Some(composed) = compose_pair(out[starter], c) {
+                    out[starter] = composed;
+                    continue;
+                }
+            }
+        }
+        if class == 0 {
+            starter = Some(out.len());
+            last_class = None;
+        } else {
+            last_class = Some(class);
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn compose_pair(a: char, b: char) -> Option<char> {
+    let (a32, b32) = (a as u32, b as u32);
+    if (HANGUL_L_BASE..HANGUL_L_BASE + HANGUL_L_COUNT).contains(&a32)
+        && (HANGUL_V_BASE..HANGUL_V_BASE + HANGUL_V_COUNT).contains(&b32)
+    {
+        let l = a32 - HANGUL_L_BASE;
+        let v = b32 - HANGUL_V_BASE;
+        return char::from_u32(HANGUL_S_BASE + (l * HANGUL_V_COUNT + v) * HANGUL_T_COUNT);
+    }
+    if (HANGUL_S_BASE..HANGUL_S_BASE + HANGUL_S_COUNT).contains(&a32)
+        && (a32 - HANGUL_S_BASE).is_multiple_of(HANGUL_T_COUNT)
+        && (HANGUL_T_BASE + 1..HANGUL_T_BASE + HANGUL_T_COUNT).contains(&b32)
+    {
+        return char::from_u32(a32 + (b32 - HANGUL_T_BASE));
+    }
+    COMPOSITIONS
+        .binary_search_by_key(&(a, b), |&(a, b, _)| (a, b))
+        .ok()
+        .map(|i| COMPOSITIONS[i].2)
+}
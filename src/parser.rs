@@ -1,8 +1,11 @@
+use crate::compat::prelude::*;
+use crate::compat::{HashMap, IndexMap, IndexSet};
+use crate::context::IStr;
 use crate::forest::{GrammarReflector, Node, OwnedParseForestAndNode, ParseForest};
 use crate::high::ErasableL;
 use crate::input::{Input, InputMatch, Range};
+use crate::rule::Anchor;
 use indexing::{self, Index, Unknown};
-use std::collections::HashMap;
 
 pub struct Parser<'a, 'i, G: GrammarReflector, I: Input, Pat> {
     state: &'a mut ParserState<'i, G, I, Pat>,
@@ -24,6 +27,38 @@ pub struct ParseError<A, Pat> {
 
 pub type ParseResult<A, Pat, T> = Result<T, ParseError<A, Pat>>;
 
+/// A predicate for a `Rule::Guard`, given the input before and after
+/// the position being checked.
+pub type GuardFn<'a, S> = dyn Fn(&S, &S) -> bool + 'a;
+
+/// User-supplied predicates for `Rule::Guard`s, by name, taking slices
+/// of the input of type `S` (e.g. `str`).
+pub struct Guards<'a, S: ?Sized> {
+    guards: HashMap<IStr, Box<GuardFn<'a, S>>>,
+}
+
+impl<S: ?Sized> Default for Guards<'_, S> {
+    fn default() -> Self {
+        Guards {
+            guards: HashMap::default(),
+        }
+    }
+}
+
+impl<'a, S: ?Sized> Guards<'a, S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, name: IStr, guard: impl Fn(&S, &S) -> bool + 'a) {
+        self.guards.insert(name, Box::new(guard));
+    }
+
+    pub fn get(&self, name: IStr) -> Option<&GuardFn<'a, S>> {
+        self.guards.get(&name).map(|guard| &**guard)
+    }
+}
+
 impl<'i, G: GrammarReflector, I: Input, Pat: Ord> Parser<'_, 'i, G, I, Pat> {
     pub fn parse_with(
         grammar: G,
@@ -36,7 +71,8 @@ impl<'i, G: GrammarReflector, I: Input, Pat: Ord> Parser<'_, 'i, G, I, Pat> {
                 forest: ParseForest {
                     grammar,
                     input,
-                    possibilities: HashMap::new(),
+                    possibilities: IndexMap::default(),
+                    seqs: IndexSet::default(),
                 },
                 last_input_pos: range.first(),
                 expected_pats: vec![],
@@ -162,6 +198,30 @@ impl<'i, G: GrammarReflector, I: Input, Pat: Ord> Parser<'_, 'i, G, I, Pat> {
         }
     }
 
+    /// Check whether the current position (i.e. the start of the
+    /// remaining input) satisfies `anchor`, without consuming anything.
+    pub fn input_at_anchor(&self, anchor: Anchor) -> bool {
+        let input = self.state.forest.input.range();
+        match anchor {
+            Anchor::Start => self.remaining.start() == input.start(),
+            Anchor::End => self.remaining.start() == input.end(),
+            Anchor::Column(column) => {
+                I::indentation(&self.state.forest.input, self.remaining.first()) == Some(column)
+            }
+        }
+    }
+
+    /// Check whether `guard` holds at the current position,
+    /// without consuming anything.
+    pub fn input_satisfies(&self, guard: &GuardFn<'_, I::Slice>) -> bool {
+        let input = &self.state.forest.input;
+        let (before, after) = input.split_at(self.remaining.first());
+        guard(
+            I::slice(input, Range(before)),
+            I::slice(input, Range(after)),
+        )
+    }
+
     // FIXME(eddyb) safeguard this against misuse.
     pub fn forest_add_choice(&mut self, kind: G::NodeKind, choice: usize) {
         self.state
@@ -175,6 +235,25 @@ impl<'i, G: GrammarReflector, I: Input, Pat: Ord> Parser<'_, 'i, G, I, Pat> {
             .insert(choice);
     }
 
+    // FIXME safeguard this against misuse.
+    pub fn forest_add_seq(&mut self, kind: G::NodeKind, children: &[Range<'i>]) {
+        let start = self.result.start();
+        let children = children
+            .iter()
+            .map(|child| (child.start() - start, child.end() - start))
+            .collect();
+        let (seq, _) = self.state.forest.seqs.insert_full(children);
+        self.state
+            .forest
+            .possibilities
+            .entry(Node {
+                kind,
+                range: self.result,
+            })
+            .or_default()
+            .insert(seq);
+    }
+
     // FIXME(eddyb) safeguard this against misuse.
     pub fn forest_add_split(&mut self, kind: G::NodeKind, left: Node<'i, G>) {
         self.result = Range(left.range.join(self.result.0).unwrap());
@@ -0,0 +1,972 @@
+//! Conversion from (and to) pest grammars, in their `.pest` PEG syntax.
+//!
+//! PEG semantics (ordered choice, greedy repetition) aren't preserved, i.e.
+//! `a | ab` can match `ab` as a `Grammar`, but not as a pest grammar.
+//!
+//! Grammars can also be split across files (see `import_file`), with two
+//! extensions to the pest syntax: `include "lexical.pest"`, which defines
+//! all the rules in another file (as if they were written in its place,
+//! and at most once per file), and `override Expr = { ... }`, which replaces
+//! a rule already defined (e.g. by an included file). Defining the same
+//! rule twice without `override` is an error.
+//!
+//! Rules defined with `open Stmt = { ... }` can also be extended with more
+//! alternatives, by `extend Stmt |= { ... }` (see `Grammar::extend_rule`),
+//! e.g. from a grammar for a language extension, including the base grammar.
+
+use crate::context::{Context, IRule, IStr};
+use crate::diagnostics::{Args, Localize, Spans};
+use crate::input::LineColumn;
+use crate::limits::Limits;
+use crate::rule::{
+    any_of, call, eat, empty, eoi, guard, seq, soi, Atomicity, Fields, Modifiers, Rule,
+    RuleWithFields, Shape,
+};
+use crate::scannerless;
+use crate::source_map::line_column;
+use crate::Grammar;
+use indexmap::{IndexMap, IndexSet};
+use std::char;
+use std::fmt;
+use std::fs;
+use std::hash::Hash;
+use std::mem;
+use std::ops::{Range, RangeInclusive};
+use std::path::{Path, PathBuf};
+
+/// Rule modifiers (other than the default, normal rules).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Modifier {
+    /// `_{ ... }`, not producing a token pair.
+    Silent,
+    /// `@{ ... }`, no implicit whitespace, and inner rules are silent.
+    Atomic,
+    /// `${ ... }`, no implicit whitespace.
+    CompoundAtomic,
+    /// `!{ ... }`, undoing the effect of an enclosing atomic rule.
+    NonAtomic,
+}
+
+impl Modifier {
+    fn prefix(self) -> &'static str {
+        match self {
+            Modifier::Silent => "_",
+            Modifier::Atomic => "@",
+            Modifier::CompoundAtomic => "$",
+            Modifier::NonAtomic => "!",
+        }
+    }
+
+    /// The equivalent rule modifiers (see `Grammar::set_modifiers`).
+    pub fn to_modifiers(self) -> Modifiers {
+        let atomicity = match self {
+            Modifier::Silent => Atomicity::Inherited,
+            Modifier::Atomic => Atomicity::Atomic,
+            Modifier::CompoundAtomic => Atomicity::CompoundAtomic,
+            Modifier::NonAtomic => Atomicity::NonAtomic,
+        };
+        Modifiers {
+            atomicity,
+            silent: self == Modifier::Silent,
+            ignore_case: false,
+            exhaustive: false,
+            shape: Shape::Keep,
+            open: false,
+            reserved: None,
+            label: None,
+        }
+    }
+
+    /// The pest modifier for `modifiers`, if any (and if there's one, as
+    /// pest rules can't be e.g. both silent and atomic), ignoring
+    /// `ignore_case` (which applies to string terminals instead), and
+    /// `exhaustive`, `shape`, `open`, `reserved` and `label` (which pest has
+    /// no equivalents for).
+    fn from_modifiers(modifiers: Modifiers) -> Result<Option<Self>, ()> {
+        Ok(match (modifiers.silent, modifiers.atomicity) {
+            (false, Atomicity::Inherited) => None,
+            (true, Atomicity::Inherited) => Some(Modifier::Silent),
+            (false, Atomicity::Atomic) => Some(Modifier::Atomic),
+            (false, Atomicity::CompoundAtomic) => Some(Modifier::CompoundAtomic),
+            (false, Atomicity::NonAtomic) => Some(Modifier::NonAtomic),
+            (true, _) => return Err(()),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ImportError {
+    /// The file the error is in, if known (see `import_file`).
+    pub file: Option<PathBuf>,
+    pub at: LineColumn,
+    pub message: String,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        write!(f, "{:?}: {}", self.at, self.message)
+    }
+}
+
+impl Localize for ImportError {
+    /// `pest-import-error` (or `pest-import-error-in-file`, if there's a
+    /// `file`), with `at`, `message` (which is in English) and `file` arguments.
+    fn message_id(&self) -> &'static str {
+        match self.file {
+            Some(_) => "pest-import-error-in-file",
+            None => "pest-import-error",
+        }
+    }
+
+    fn args(&self) -> Args {
+        let mut args = vec![
+            ("at", format!("{:?}", self.at)),
+            ("message", self.message.clone()),
+        ];
+        if let Some(file) = &self.file {
+            args.push(("file", file.display().to_string()));
+        }
+        args
+    }
+}
+
+/// The result of importing a pest grammar, with what can't be represented
+/// as a `Grammar` recorded alongside it.
+///
+/// Note that pest implicitly allows `WHITESPACE` and `COMMENT` between the
+/// elements of sequences and repetitions (in non-atomic rules), so they're
+/// used as the grammar's trivia (see `Grammar::set_trivia`), if defined,
+/// and rule modifiers are kept in the grammar (see `Modifier::to_modifiers`).
+pub struct Imported {
+    pub grammar: Grammar,
+    /// Lookahead predicates (`!x` and `&x`), which have no equivalent, each
+    /// replaced by a `Guard` named after the predicate's source text.
+    pub predicates: IndexSet<IStr>,
+    /// Where every rule (and every call in it) is in the source.
+    pub spans: Spans,
+    /// The file every rule was defined in, if known (see `import_file`),
+    /// which its `spans` are relative to.
+    pub files: IndexMap<String, PathBuf>,
+}
+
+/// Import a pest grammar, from the contents of a `.pest` file.
+///
+/// Tags (`#tag = x`) become fields, and builtin rules (e.g. `ASCII_DIGIT`)
+/// are replaced with their definitions, except for the stack-manipulating
+/// ones (e.g. `PUSH`), and the Unicode properties, which are left as calls.
+pub fn import<Pat>(cx: &Context<Pat>, src: &str) -> Result<Imported, ImportError>
+where
+    Pat: Eq + Hash + for<'s> From<&'s str> + From<RangeInclusive<char>>,
+{
+    import_with_limits(cx, src, &Limits::default())
+}
+
+/// Like `import`, but with non-default `limits` (e.g. for untrusted input),
+/// enforced while parsing (instead of only on the result).
+pub fn import_with_limits<Pat>(
+    cx: &Context<Pat>,
+    src: &str,
+    limits: &Limits,
+) -> Result<Imported, ImportError>
+where
+    Pat: Eq + Hash + for<'s> From<&'s str> + From<RangeInclusive<char>>,
+{
+    Importer::new(cx, limits).import(src, None)
+}
+
+/// Like `import`, but reading the grammar from the file at `path`, which
+/// can also `include` other files (relative to the file including them).
+pub fn import_file<Pat>(cx: &Context<Pat>, path: impl AsRef<Path>) -> Result<Imported, ImportError>
+where
+    Pat: Eq + Hash + for<'s> From<&'s str> + From<RangeInclusive<char>>,
+{
+    let path = path.as_ref();
+    let src = fs::read_to_string(path).map_err(|error| ImportError {
+        file: Some(path.to_path_buf()),
+        at: line_column("", 0),
+        message: error.to_string(),
+    })?;
+    import_from(cx, &src, path, &Limits::default())
+}
+
+/// Like `import_with_limits`, but with `src` being the contents of the file
+/// at `path` (which `include`s are resolved relative to).
+pub fn import_from<Pat>(
+    cx: &Context<Pat>,
+    src: &str,
+    path: &Path,
+    limits: &Limits,
+) -> Result<Imported, ImportError>
+where
+    Pat: Eq + Hash + for<'s> From<&'s str> + From<RangeInclusive<char>>,
+{
+    Importer::new(cx, limits).import(src, Some(path))
+}
+
+/// The state of an import, shared between all the files `include`d by it.
+struct Importer<'a, 'cx, Pat> {
+    cx: &'cx Context<Pat>,
+    limits: &'a Limits,
+    grammar: Grammar,
+    predicates: IndexSet<IStr>,
+    spans: Spans,
+    files: IndexMap<String, PathBuf>,
+    /// The contents of every file included so far, by (canonical) path.
+    included: IndexMap<PathBuf, String>,
+    /// The (canonical) paths of the files being imported, outermost first,
+    /// to detect `include` cycles.
+    stack: Vec<PathBuf>,
+}
+
+impl<'a, 'cx, Pat> Importer<'a, 'cx, Pat>
+where
+    Pat: Eq + Hash + for<'s> From<&'s str> + From<RangeInclusive<char>>,
+{
+    fn new(cx: &'cx Context<Pat>, limits: &'a Limits) -> Self {
+        Importer {
+            cx,
+            limits,
+            grammar: Grammar::new(),
+            predicates: IndexSet::new(),
+            spans: Spans::default(),
+            files: IndexMap::new(),
+            included: IndexMap::new(),
+            stack: vec![],
+        }
+    }
+
+    fn import(mut self, src: &str, file: Option<&Path>) -> Result<Imported, ImportError> {
+        let cx = self.cx;
+        if let Some(file) = file {
+            self.stack.push(canonicalize(file));
+        }
+        self.import_source(src, file)?;
+
+        let mut grammar = self.grammar;
+        let trivia: Vec<_> = ["WHITESPACE", "COMMENT"]
+            .into_iter()
+            .filter(|&name| grammar.rules().contains_key(&cx.intern(name)))
+            .map(|name| call(name).finish(cx))
+            .collect();
+        if !trivia.is_empty() {
+            grammar.set_trivia(any_of(trivia).finish(cx));
+        }
+
+        let (spans, files, included) = (&self.spans, &self.files, &self.included);
+        self.limits.check(cx, &grammar).map_err(|error| {
+            let file = files.get(&error.rule);
+            let src = file
+                .and_then(|file| included.get(&canonicalize(file)))
+                .map_or(src, |src| &src[..]);
+            ImportError {
+                file: file.cloned(),
+                at: line_column(
+                    src,
+                    spans.rules.get(&error.rule).map_or(0, |span| span.start),
+                ),
+                message: error.to_string(),
+            }
+        })?;
+
+        Ok(Imported {
+            grammar,
+            predicates: self.predicates,
+            spans: self.spans,
+            files: self.files,
+        })
+    }
+
+    /// Import all the rules in `src`, the contents of `file` (if known).
+    fn import_source(&mut self, src: &str, file: Option<&Path>) -> Result<(), ImportError> {
+        let cx = self.cx;
+        let mut parser = Parser {
+            cx,
+            src,
+            file,
+            pos: 0,
+            limits: self.limits,
+            depth: 0,
+            predicates: IndexSet::new(),
+            calls: vec![],
+        };
+        loop {
+            parser.skip_trivia();
+            if parser.pos == src.len() {
+                break;
+            }
+            let start = parser.pos;
+            let mut name = parser.ident()?;
+            parser.skip_trivia();
+            if name == "include" && parser.rest().starts_with('"') {
+                let path = parser.string('"')?;
+                self.include(&parser, start, &path)?;
+                continue;
+            }
+            if parser.keyword(&mut name, "extend")? {
+                let name = cx.intern(name);
+                parser.expect("|=")?;
+                let braced = parser.eat("{");
+                let alternative = parser.choice()?;
+                if braced {
+                    parser.expect("}")?;
+                }
+                self.grammar
+                    .extend_rule(cx, name, alternative)
+                    .map_err(|error| parser.error_at(start, error.to_string()))?;
+                // NOTE `spans` can only refer to one file per rule,
+                // so calls in extensions from other files are left out.
+                let calls = mem::take(&mut parser.calls);
+                if self.files.get(&cx[name]).map(PathBuf::as_path) == file {
+                    self.spans
+                        .calls
+                        .entry(cx[name].to_string())
+                        .or_default()
+                        .extend(calls);
+                }
+                continue;
+            }
+            let overriding = parser.keyword(&mut name, "override")?;
+            let open = parser.keyword(&mut name, "open")?;
+            let name = cx.intern(name);
+            let defined = self.grammar.rules().contains_key(&name);
+            if defined && !overriding {
+                let defined_in = match self.files.get(&cx[name]) {
+                    Some(file) if Some(file.as_path()) != parser.file => {
+                        format!(" (in `{}`)", file.display())
+                    }
+                    _ => String::new(),
+                };
+                return Err(parser.error_at(
+                    start,
+                    format!(
+                        "rule `{0}` is already defined{1}, \
+                         use `override {0} = ...` to replace it",
+                        &cx[name], defined_in
+                    ),
+                ));
+            }
+            if overriding && !defined {
+                return Err(parser.error_at(
+                    start,
+                    format!(
+                        "cannot override undefined rule `{}` \
+                         (it must be defined, or included, before this)",
+                        &cx[name]
+                    ),
+                ));
+            }
+            parser.expect("=")?;
+            let modifier = [
+                Modifier::Silent,
+                Modifier::Atomic,
+                Modifier::CompoundAtomic,
+                Modifier::NonAtomic,
+            ]
+            .into_iter()
+            .find(|modifier| parser.eat(modifier.prefix()));
+            parser.expect("{")?;
+            let rule = parser.choice()?;
+            parser.expect("}")?;
+            self.grammar.define(name, rule);
+            // NOTE this also resets the modifiers of overridden rules.
+            self.grammar.set_modifiers(
+                name,
+                Modifiers {
+                    open,
+                    ..modifier.map_or_else(Modifiers::default, Modifier::to_modifiers)
+                },
+            );
+
+            self.spans
+                .rules
+                .insert(cx[name].to_string(), start..parser.pos);
+            self.spans
+                .calls
+                .insert(cx[name].to_string(), mem::take(&mut parser.calls));
+            if let Some(file) = file {
+                self.files.insert(cx[name].to_string(), file.to_path_buf());
+            }
+        }
+        self.predicates.extend(parser.predicates);
+        Ok(())
+    }
+
+    /// Import the file at `path` (relative to the file `parser` is parsing),
+    /// for an `include` at `start`, unless it was already included.
+    fn include(
+        &mut self,
+        parser: &Parser<'_, '_, Pat>,
+        start: usize,
+        path: &str,
+    ) -> Result<(), ImportError> {
+        let base = parser.file.ok_or_else(|| {
+            parser.error_at(
+                start,
+                "`include` requires the path of the grammar being imported (see `import_file`)",
+            )
+        })?;
+        let path = base.parent().unwrap_or(Path::new("")).join(path);
+        let canonical = canonicalize(&path);
+        if let Some(i) = self.stack.iter().position(|file| *file == canonical) {
+            let cycle: Vec<_> = self.stack[i..]
+                .iter()
+                .chain([&canonical])
+                .map(|file| format!("`{}`", file.display()))
+                .collect();
+            return Err(parser.error_at(start, format!("`include` cycle: {}", cycle.join(" -> "))));
+        }
+        if self.included.contains_key(&canonical) {
+            return Ok(());
+        }
+        let src = fs::read_to_string(&path).map_err(|error| {
+            parser.error_at(
+                start,
+                format!("failed to include `{}`: {}", path.display(), error),
+            )
+        })?;
+        self.stack.push(canonical.clone());
+        self.import_source(&src, Some(&path))?;
+        self.stack.pop();
+        self.included.insert(canonical, src);
+        Ok(())
+    }
+}
+
+/// The canonical form of `path`, used to tell if two paths refer to the
+/// same file (falling back to `path` itself, e.g. if it doesn't exist).
+fn canonicalize(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+struct Parser<'a, 'cx, Pat> {
+    cx: &'cx Context<Pat>,
+    src: &'a str,
+    /// The file `src` was read from, if known.
+    file: Option<&'a Path>,
+    pos: usize,
+    limits: &'a Limits,
+    /// How many `term`s are being parsed (i.e. how deep the recursion is).
+    depth: usize,
+    predicates: IndexSet<IStr>,
+    /// The `Call`s (and their spans) in the rule being parsed.
+    calls: Vec<(String, Range<usize>)>,
+}
+
+impl<'a, Pat> Parser<'a, '_, Pat>
+where
+    Pat: Eq + Hash + for<'s> From<&'s str> + From<RangeInclusive<char>>,
+{
+    fn error(&self, message: impl Into<String>) -> ImportError {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at(&self, pos: usize, message: impl Into<String>) -> ImportError {
+        ImportError {
+            file: self.file.map(Path::to_path_buf),
+            at: line_column(self.src, pos),
+            message: message.into(),
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn skip_trivia(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else if trimmed.starts_with("/*") {
+                self.pos += trimmed.find("*/").map_or(trimmed.len(), |i| i + 2);
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        self.skip_trivia();
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), ImportError> {
+        if self.eat(s) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{}`", s)))
+        }
+    }
+
+    fn ident(&mut self) -> Result<&'a str, ImportError> {
+        self.skip_trivia();
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        if len == 0 || rest.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(self.error("expected identifier"));
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    /// Check if `name` (the identifier just parsed) is `keyword`, and if so,
+    /// replace it with the identifier following it (e.g. the rule `name` in
+    /// `open name = ...`).
+    ///
+    /// NOTE keywords can still be used as rule names, which are always
+    /// followed by `=` (instead of another name).
+    fn keyword(&mut self, name: &mut &'a str, keyword: &str) -> Result<bool, ImportError> {
+        self.skip_trivia();
+        let found = *name == keyword && !self.rest().starts_with('=');
+        if found {
+            *name = self.ident()?;
+            self.skip_trivia();
+        }
+        Ok(found)
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        self.skip_trivia();
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let n = rest[..len].parse().ok()?;
+        self.pos += len;
+        Some(n)
+    }
+
+    fn choice(&mut self) -> Result<RuleWithFields, ImportError> {
+        self.eat("|");
+        let mut cases = vec![self.sequence()?];
+        while self.eat("|") {
+            if cases.len() == self.limits.max_alternatives {
+                return Err(self.error("too many alternatives"));
+            }
+            cases.push(self.sequence()?);
+        }
+        Ok(any_of(cases).finish(self.cx))
+    }
+
+    fn sequence(&mut self) -> Result<RuleWithFields, ImportError> {
+        let mut elems = vec![self.term()?];
+        while self.eat("~") {
+            elems.push(self.term()?);
+        }
+        Ok(seq(elems).finish(self.cx))
+    }
+
+    fn term(&mut self) -> Result<RuleWithFields, ImportError> {
+        if self.depth == self.limits.max_depth {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        let rule = self.term_unlimited();
+        self.depth -= 1;
+        rule
+    }
+
+    fn term_unlimited(&mut self) -> Result<RuleWithFields, ImportError> {
+        let cx = self.cx;
+        self.skip_trivia();
+        let start = self.pos;
+
+        if self.eat("#") {
+            let tag = self.ident()?;
+            self.expect("=")?;
+            return Ok(self.term()?.field(tag).finish(cx));
+        }
+        if self.eat("!") || self.eat("&") {
+            // NOTE the predicate is replaced, so its calls are too.
+            let calls = self.calls.len();
+            self.term()?;
+            self.calls.truncate(calls);
+            let name = cx.intern(self.src[start..self.pos].trim_end());
+            self.predicates.insert(name);
+            return Ok(guard(&cx[name]).finish(cx));
+        }
+
+        let mut rule = self.primary()?;
+        loop {
+            rule = if self.eat("?") {
+                rule.opt().finish(cx)
+            } else if self.eat("*") {
+                rule.repeat_many().finish(cx)
+            } else if self.eat("+") {
+                rule.repeat_more().finish(cx)
+            } else if self.rest().starts_with('{') {
+                self.pos += 1;
+                let min = self.number();
+                let max = if self.eat(",") { self.number() } else { min };
+                self.expect("}")?;
+                if (min, max) == (None, None) {
+                    return Err(self.error("expected repetition bounds"));
+                }
+                if min.max(max).unwrap() > self.limits.max_nodes {
+                    return Err(self.error("repetition bounds too large"));
+                }
+                repeat_range(cx, rule, min.unwrap_or(0), max)
+            } else {
+                return Ok(rule);
+            };
+        }
+    }
+
+    fn primary(&mut self) -> Result<RuleWithFields, ImportError> {
+        let cx = self.cx;
+        self.skip_trivia();
+        if self.eat("(") {
+            let rule = self.choice()?;
+            self.expect(")")?;
+            return Ok(rule);
+        }
+        if self.eat("^") {
+            let s = self.string('"')?;
+            return Ok(seq(s.chars().map(|c| {
+                let (lower, upper) = (c.to_ascii_lowercase(), c.to_ascii_uppercase());
+                if lower == upper {
+                    eat(&c.to_string()[..]).finish(cx)
+                } else {
+                    (eat(&lower.to_string()[..]) | eat(&upper.to_string()[..])).finish(cx)
+                }
+            }))
+            .finish(cx));
+        }
+        if self.rest().starts_with('"') {
+            let s = self.string('"')?;
+            // NOTE `""` is how `export` writes `Rule::Empty`.
+            if s.is_empty() {
+                return Ok(empty().finish(cx));
+            }
+            return Ok(eat(&s[..]).finish(cx));
+        }
+        if self.rest().starts_with('\'') {
+            let start = self.char()?;
+            self.expect("..")?;
+            self.skip_trivia();
+            let end = self.char()?;
+            return Ok(eat(start..=end).finish(cx));
+        }
+
+        let start = self.pos;
+        let name = self.ident()?;
+        let range = |range: RangeInclusive<char>| eat(range).finish(cx);
+        Ok(match name {
+            "ANY" => range('\0'..=char::MAX),
+            "SOI" => soi().finish(cx),
+            "EOI" => eoi().finish(cx),
+            "ASCII_DIGIT" => range('0'..='9'),
+            "ASCII_NONZERO_DIGIT" => range('1'..='9'),
+            "ASCII_BIN_DIGIT" => range('0'..='1'),
+            "ASCII_OCT_DIGIT" => range('0'..='7'),
+            "ASCII_HEX_DIGIT" => {
+                any_of([range('0'..='9'), range('a'..='f'), range('A'..='F')]).finish(cx)
+            }
+            "ASCII_ALPHA_LOWER" => range('a'..='z'),
+            "ASCII_ALPHA_UPPER" => range('A'..='Z'),
+            "ASCII_ALPHA" => any_of([range('a'..='z'), range('A'..='Z')]).finish(cx),
+            "ASCII_ALPHANUMERIC" => {
+                any_of([range('a'..='z'), range('A'..='Z'), range('0'..='9')]).finish(cx)
+            }
+            "ASCII" => range('\0'..='\x7f'),
+            "NEWLINE" => (eat("\n") | eat("\r\n") | eat("\r")).finish(cx),
+            "PUSH" | "POP" | "POP_ALL" | "PEEK" | "PEEK_ALL" | "DROP" => {
+                return Err(self.error(format!("unsupported stack operation `{}`", name)));
+            }
+            _ => {
+                self.calls.push((name.to_string(), start..self.pos));
+                call(name).finish(cx)
+            }
+        })
+    }
+
+    fn escape(&mut self) -> Result<char, ImportError> {
+        let (c, len) =
+            scannerless::unescape(self.rest()).ok_or_else(|| self.error("invalid escape"))?;
+        self.pos += len;
+        Ok(c)
+    }
+
+    /// Parse a string literal, delimited by `quote`.
+    fn string(&mut self, quote: char) -> Result<String, ImportError> {
+        self.skip_trivia();
+        if !self.rest().starts_with(quote) {
+            return Err(self.error(format!("expected `{}`", quote)));
+        }
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = self
+                .rest()
+                .chars()
+                .next()
+                .ok_or_else(|| self.error("unterminated literal"))?;
+            self.pos += c.len_utf8();
+            match c {
+                _ if c == quote => return Ok(s),
+                '\\' => s.push(self.escape()?),
+                _ => s.push(c),
+            }
+        }
+    }
+
+    fn char(&mut self) -> Result<char, ImportError> {
+        let s = self.string('\'')?;
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(c),
+            _ => Err(self.error("expected a single character")),
+        }
+    }
+}
+
+/// `rule{min, max}` (with no upper bound for `max == None`).
+fn repeat_range<Pat: Eq + Hash>(
+    cx: &Context<Pat>,
+    rule: RuleWithFields,
+    min: usize,
+    max: Option<usize>,
+) -> RuleWithFields {
+    let required = seq(std::iter::repeat_n(rule, min)).finish(cx);
+    let optional = match max {
+        None => rule.repeat_many().finish(cx),
+        Some(max) => (min..max).fold(empty().finish(cx), |rest, _| (rule + rest).opt().finish(cx)),
+    };
+    (required + optional).finish(cx)
+}
+
+/// Patterns which can be written in pest's syntax.
+pub trait PestPat {
+    fn to_pest(&self) -> String;
+}
+
+fn escape(s: &str, quote: char) -> String {
+    s.chars()
+        .map(|c| match c {
+            '\n' => "\\n".to_string(),
+            '\r' => "\\r".to_string(),
+            '\t' => "\\t".to_string(),
+            '\0' => "\\0".to_string(),
+            '\\' => "\\\\".to_string(),
+            _ if c == quote => format!("\\{}", c),
+            _ if c.is_control() => format!("\\u{{{:x}}}", c as u32),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+impl<S: AsRef<str>> PestPat for scannerless::Pat<S> {
+    fn to_pest(&self) -> String {
+        match self {
+            scannerless::Pat::String(s) => format!("\"{}\"", escape(s.as_ref(), '"')),
+            &scannerless::Pat::Range('\0', char::MAX) => "ANY".to_string(),
+            &scannerless::Pat::Range(start, end) => format!(
+                "'{}'..'{}'",
+                escape(&start.to_string(), '\''),
+                escape(&end.to_string(), '\'')
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ExportError {
+    /// The name of the rule containing the unsupported part.
+    pub rule: String,
+    pub unsupported: String,
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` (in rule `{}`) has no pest equivalent",
+            self.unsupported, self.rule
+        )
+    }
+}
+
+/// Export `grammar` as a pest grammar, with rule modifiers (see
+/// `Modifier::from_modifiers`), and fields turned into tags.
+///
+/// The string terminals of rules with `ignore_case` are exported as `^"..."`
+/// (and character ranges in them aren't supported, other than `ANY`).
+///
+/// `Guard`s are only supported if they were imported from predicates (i.e.
+/// they're named after their source text), and other zero-width rules
+/// except for `Anchor::{Start,End}` (i.e. `SOI` and `EOI`) aren't supported
+/// (so flags and features should be resolved beforehand).
+///
+/// `Capture`s become `PUSH(...) ~ ... ~ DROP`, and `Backref`s `PEEK`, which
+/// is only correct for `Backref`s to the innermost `Capture` around them.
+///
+/// The grammar's trivia (see `Grammar::set_trivia`), if any, is exported as
+/// a silent `WHITESPACE` rule, unless the grammar already has one (which is
+/// then assumed to be part of the trivia, as is the case after `import`).
+///
+/// FIXME rule names aren't sanitized, e.g. `Expr<+NoStruct>`.
+pub fn export<Pat>(cx: &Context<Pat>, grammar: &Grammar) -> Result<String, ExportError>
+where
+    Pat: Eq + Hash + fmt::Debug + PestPat,
+{
+    let mut pest = String::new();
+    if let Some(trivia) = grammar.trivia() {
+        if !grammar
+            .rules()
+            .keys()
+            .any(|&name| &cx[name] == "WHITESPACE")
+        {
+            let trivia = export_rule(cx, trivia, Prec::Choice, false).map_err(|unsupported| {
+                ExportError {
+                    rule: "<trivia>".to_string(),
+                    unsupported,
+                }
+            })?;
+            pest += &format!("WHITESPACE = _{{ {} }}\n", trivia);
+        }
+    }
+    for (&name, &rule) in grammar.rules() {
+        let modifiers = grammar.modifiers(name);
+        let error = |unsupported| ExportError {
+            rule: cx[name].to_string(),
+            unsupported,
+        };
+        let modifier = Modifier::from_modifiers(modifiers)
+            .map_err(|()| error(format!("{:?}", modifiers)))?
+            .map_or("", |m| m.prefix());
+        let rule = export_rule(cx, rule, Prec::Choice, modifiers.ignore_case).map_err(error)?;
+        pest += &format!("{} = {}{{ {} }}\n", &cx[name], modifier, rule);
+    }
+    Ok(pest)
+}
+
+/// Operator precedence, from loosest to tightest binding.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Prec {
+    Choice,
+    Sequence,
+    /// Tags and predicates, i.e. `#tag = x` and `!x` / `&x`.
+    Prefix,
+    Term,
+}
+
+fn export_rule<Pat>(
+    cx: &Context<Pat>,
+    rule: RuleWithFields,
+    prec: Prec,
+    ignore_case: bool,
+) -> Result<String, String>
+where
+    Pat: Eq + Hash + fmt::Debug + PestPat,
+{
+    let group = |s: String, s_prec: Prec| {
+        if s_prec < prec {
+            format!("({})", s)
+        } else {
+            s
+        }
+    };
+    if let Fields::Leaf(Some(field)) = cx[rule.fields] {
+        let tagged = export_rule(
+            cx,
+            RuleWithFields {
+                rule: rule.rule,
+                fields: field.sub,
+            },
+            Prec::Term,
+            ignore_case,
+        )?;
+        return Ok(group(
+            format!("#{} = {}", &cx[field.name], tagged),
+            Prec::Prefix,
+        ));
+    }
+    let child = |child: IRule, i, prec| {
+        export_rule(
+            cx,
+            RuleWithFields {
+                rule: child,
+                fields: rule.fields.child(cx, i),
+            },
+            prec,
+            ignore_case,
+        )
+    };
+    let list = |rules: &[IRule], sep: &str, prec| -> Result<_, String> {
+        Ok(rules
+            .iter()
+            .enumerate()
+            .map(|(i, &rule)| child(rule, i, prec))
+            .collect::<Result<Vec<_>, _>>()?
+            .join(sep))
+    };
+    Ok(match cx[rule.rule] {
+        Rule::Empty => "\"\"".to_string(),
+        Rule::Anchor(crate::rule::Anchor::Start) => "SOI".to_string(),
+        Rule::Anchor(crate::rule::Anchor::End) => "EOI".to_string(),
+        Rule::Guard(name) if cx[name].starts_with(['!', '&']) => {
+            group(cx[name].to_string(), Prec::Prefix)
+        }
+        Rule::Anchor(_)
+        | Rule::Guard(_)
+        | Rule::FlagGuard(..)
+        | Rule::WithFlag(..)
+        | Rule::Feature(..) => return Err(rule.rule.node_desc(cx)),
+        Rule::Eat(ref pat) | Rule::EatUntil(ref pat) => {
+            let mut pat = pat.to_pest();
+            if ignore_case && pat != "ANY" {
+                if !pat.starts_with('"') {
+                    return Err(rule.rule.node_desc(cx));
+                }
+                pat.insert(0, '^');
+            }
+            match cx[rule.rule] {
+                Rule::EatUntil(_) => format!("(!{} ~ ANY)*", pat),
+                _ => pat,
+            }
+        }
+        Rule::Call(name) => cx[name].to_string(),
+        Rule::Capture(_, delim, body) => group(
+            format!(
+                "PUSH({}) ~ {} ~ DROP",
+                child(delim, 0, Prec::Choice)?,
+                child(body, 1, Prec::Prefix)?
+            ),
+            Prec::Sequence,
+        ),
+        Rule::Backref(_) => "PEEK".to_string(),
+        Rule::Silent(inner) => child(inner, 0, prec)?,
+        Rule::Concat(ref elems) => group(list(elems, " ~ ", Prec::Prefix)?, Prec::Sequence),
+        Rule::Or(ref cases) => group(list(cases, " | ", Prec::Sequence)?, Prec::Choice),
+        Rule::Opt(rule) => format!("{}?", child(rule, 0, Prec::Term)?),
+        Rule::RepeatMany(elem, None) => format!("{}*", child(elem, 0, Prec::Term)?),
+        Rule::RepeatMore(elem, None) => format!("{}+", child(elem, 0, Prec::Term)?),
+        Rule::RepeatMany(elem, Some((sep, kind))) | Rule::RepeatMore(elem, Some((sep, kind))) => {
+            let elem = child(elem, 0, Prec::Term)?;
+            let sep = child(sep, 1, Prec::Term)?;
+            let mut s = format!("{} ~ ({} ~ {})*", elem, sep, elem);
+            if kind.allows_leading() {
+                s = format!("{}? ~ {}", sep, s);
+            }
+            if kind.allows_trailing() {
+                s += &format!(" ~ {}?", sep);
+            }
+            match cx[rule.rule] {
+                Rule::RepeatMany(..) => format!("({})?", s),
+                _ => group(s, Prec::Sequence),
+            }
+        }
+    })
+}
@@ -0,0 +1,149 @@
+//! Operator-precedence expression grammars, declared as a table of operators
+//! (with binding powers) instead of by hand-stratifying rules (see `expand`).
+
+use crate::compat::prelude::*;
+use crate::context::{Context, IStr};
+use crate::rule::{any_of, call, seq, RuleWithFields};
+use crate::Grammar;
+use core::hash::Hash;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Assoc {
+    /// `a op b op c` is `(a op b) op c`.
+    Left,
+    /// `a op b op c` is `a op (b op c)`.
+    Right,
+    /// `a op b op c` isn't allowed (e.g. comparisons in some languages).
+    None,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fixity {
+    Prefix,
+    Infix(Assoc),
+    Postfix,
+}
+
+#[derive(Copy, Clone)]
+pub struct Operator {
+    pub fixity: Fixity,
+    /// Higher binding powers bind tighter, e.g. `*` should have a higher
+    /// binding power than `+`, so that `a + b * c` is `a + (b * c)`.
+    pub binding_power: u32,
+    /// The operator itself, which can be any rule (e.g. the `[...]` of
+    /// indexing, as a postfix operator), and can have its own fields.
+    pub op: RuleWithFields,
+}
+
+/// An expression grammar, built from its `atom` (e.g. literals, and other
+/// expressions in parentheses, which can `call` the expression rule), and
+/// its `operators`, e.g.:
+///
+/// ```ignore
+/// Precedence::new(call("Atom").finish(cx))
+///     .infix(1, Assoc::Left, eat("+").finish(cx))
+///     .infix(2, Assoc::Left, eat("*").finish(cx))
+///     .prefix(3, eat("-").finish(cx))
+///     .expand(cx, cx.intern("Expr"))
+/// ```
+pub struct Precedence {
+    pub atom: RuleWithFields,
+    pub operators: Vec<Operator>,
+}
+
+impl Precedence {
+    pub fn new(atom: RuleWithFields) -> Self {
+        Precedence {
+            atom,
+            operators: vec![],
+        }
+    }
+
+    pub fn prefix(self, binding_power: u32, op: RuleWithFields) -> Self {
+        self.operator(Fixity::Prefix, binding_power, op)
+    }
+
+    pub fn infix(self, binding_power: u32, assoc: Assoc, op: RuleWithFields) -> Self {
+        self.operator(Fixity::Infix(assoc), binding_power, op)
+    }
+
+    pub fn postfix(self, binding_power: u32, op: RuleWithFields) -> Self {
+        self.operator(Fixity::Postfix, binding_power, op)
+    }
+
+    fn operator(mut self, fixity: Fixity, binding_power: u32, op: RuleWithFields) -> Self {
+        self.operators.push(Operator {
+            fixity,
+            binding_power,
+            op,
+        });
+        self
+    }
+
+    /// Expand into stratified rules, one per distinct binding power (from
+    /// the loosest, which is named `name`, to the tightest, which falls
+    /// back to the atom), to be added to a grammar with `Grammar::extend`.
+    ///
+    /// Each level's rule is an `Or` with one case per operator, and the
+    /// next (tighter) level as the last case, where operands are in `lhs`
+    /// and `rhs` fields (for infix operators), or an `operand` field (for
+    /// prefix/postfix operators), e.g. for `+` (left-associative):
+    /// `Expr = lhs:Expr "+" rhs:Expr.1 | Expr.1`.
+    ///
+    /// NOTE left-associative infix and postfix operators result in
+    /// left-recursive rules, which the generated parsers (and the
+    /// `interpreter`) handle, but some exporters (e.g. `pest`) don't.
+    pub fn expand<Pat: Eq + Hash>(&self, cx: &Context<Pat>, name: IStr) -> Grammar {
+        let mut binding_powers: Vec<_> = self.operators.iter().map(|op| op.binding_power).collect();
+        binding_powers.sort_unstable();
+        binding_powers.dedup();
+
+        let level_name = |level: usize| -> IStr {
+            if level == 0 {
+                name
+            } else {
+                cx.intern(&format!("{}.{}", &cx[name], level)[..])
+            }
+        };
+
+        let mut grammar = Grammar::new();
+        for (level, &binding_power) in binding_powers.iter().enumerate() {
+            let this_name = level_name(level);
+            let this = || call(&cx[this_name]);
+            let next = || -> RuleWithFields {
+                if level + 1 < binding_powers.len() {
+                    call(&cx[level_name(level + 1)]).finish(cx)
+                } else {
+                    self.atom
+                }
+            };
+
+            let mut cases = vec![];
+            for operator in &self.operators {
+                if operator.binding_power != binding_power {
+                    continue;
+                }
+                let op = operator.op;
+                cases.push(match operator.fixity {
+                    Fixity::Prefix => seq([op, this().field("operand").finish(cx)]).finish(cx),
+                    Fixity::Postfix => seq([this().field("operand").finish(cx), op]).finish(cx),
+                    Fixity::Infix(assoc) => {
+                        let (lhs, rhs) = match assoc {
+                            Assoc::Left => (this().finish(cx), next()),
+                            Assoc::Right => (next(), this().finish(cx)),
+                            Assoc::None => (next(), next()),
+                        };
+                        seq([lhs.field("lhs").finish(cx), op, rhs.field("rhs").finish(cx)])
+                            .finish(cx)
+                    }
+                });
+            }
+            cases.push(next());
+            grammar.define(this_name, any_of(cases).finish(cx));
+        }
+        if binding_powers.is_empty() {
+            grammar.define(name, self.atom);
+        }
+        grammar
+    }
+}
@@ -0,0 +1,231 @@
+//! Grammar-driven pretty-printing: inputs are parsed (see `Formatter`), and
+//! then re-emitted with their whitespace replaced according to the `Style`s
+//! of the rules they matched, through a Wadler-style document (see `Doc`).
+
+use crate::compat::prelude::*;
+use crate::compat::IndexMap;
+use crate::context::{Context, IStr};
+use crate::input::InputMatch;
+use crate::interpreter::{Derivation, Error, Interpreter};
+use crate::Grammar;
+use core::hash::Hash;
+
+/// A document to lay out within some width, where line breaks which don't
+/// fit in a `Group` (i.e. its contents won't fit on the rest of the line)
+/// are taken, and the others are replaced with spaces (or nothing).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Doc {
+    Text(String),
+    /// A space, or a line break.
+    Line,
+    /// Nothing, or a line break.
+    SoftLine,
+    /// Always a line break (which also breaks all the groups around it).
+    HardLine,
+    /// Indent every line started in the inner document by this many spaces.
+    Nest(usize, Box<Doc>),
+    /// Lay out the inner document on one line if it fits.
+    Group(Box<Doc>),
+    Concat(Vec<Doc>),
+}
+
+impl Doc {
+    /// The width of this document laid out on one line, or `None` if it
+    /// can't be, i.e. it contains a `HardLine`.
+    fn flat_width(&self) -> Option<usize> {
+        match self {
+            Doc::Text(text) => Some(text.chars().count()),
+            Doc::Line => Some(1),
+            Doc::SoftLine => Some(0),
+            Doc::HardLine => None,
+            Doc::Nest(_, doc) | Doc::Group(doc) => doc.flat_width(),
+            Doc::Concat(docs) => docs.iter().map(|doc| doc.flat_width()).sum(),
+        }
+    }
+
+    /// Lay out this document, breaking lines (and groups) to fit in `width`
+    /// (unless a single piece of text is already longer than that).
+    pub fn render(&self, width: usize) -> String {
+        let mut out = String::new();
+        let mut column = 0;
+        // NOTE this is a stack of the documents left to lay out (in
+        // reverse), each with its indentation, and whether it's "flat"
+        // (in a group laid out on one line).
+        let mut stack = vec![(0, false, self)];
+        while let Some((indent, flat, doc)) = stack.pop() {
+            match doc {
+                Doc::Text(text) => {
+                    out += text;
+                    column += text.chars().count();
+                }
+                Doc::Line | Doc::SoftLine if flat => {
+                    if let Doc::Line = doc {
+                        out.push(' ');
+                        column += 1;
+                    }
+                }
+                Doc::Line | Doc::SoftLine | Doc::HardLine => {
+                    // Avoid trailing whitespace, from spaces before breaks.
+                    out.truncate(out.trim_end_matches(' ').len());
+                    out.push('\n');
+                    out.extend(core::iter::repeat_n(' ', indent));
+                    column = indent;
+                }
+                Doc::Nest(more, doc) => stack.push((indent + more, flat, doc)),
+                Doc::Group(doc) => {
+                    let fits = flat || doc.flat_width().is_some_and(|w| column + w <= width);
+                    stack.push((indent, fits, doc));
+                }
+                Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|doc| (indent, flat, doc))),
+            }
+        }
+        out
+    }
+}
+
+/// How the whitespace in (the parts of the input matching) a rule should
+/// be replaced, when pretty-printing (see `Formatter`).
+///
+/// The parts of a rule are the pieces of text between whitespace, and the
+/// (pretty-printed) rules it calls, with the whitespace between them (if
+/// any) replaced by a single space, unless `spacing` is set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    /// Lay out the rule on one line, if it fits (see `Doc::Group`).
+    pub group: bool,
+    /// Indent all the parts except for the first and the last ones (e.g.
+    /// the contents of a block, but not its brackets), by this many spaces.
+    pub indent: usize,
+    /// What to put between parts (instead of whatever whitespace was there).
+    pub spacing: Option<Doc>,
+    /// Pieces of text (e.g. `{` or `,`) to allow a line break after, i.e.
+    /// replacing the following space with a `Doc::Line` (or nothing with
+    /// a `Doc::SoftLine`).
+    pub break_after: Vec<String>,
+    /// Like `break_after`, but for breaking lines before pieces of text
+    /// (e.g. `}`).
+    pub break_before: Vec<String>,
+    /// Keep the rule's input as-is (e.g. for string literals).
+    pub verbatim: bool,
+}
+
+/// Pretty-printing inputs of a grammar, according to the `Style`s of the
+/// rules (which default to keeping every part as-is, with the whitespace
+/// between them normalized to single spaces).
+pub struct Formatter<'a, Pat> {
+    cx: &'a Context<Pat>,
+    pub interpreter: Interpreter<'a, Pat>,
+    pub styles: IndexMap<IStr, Style>,
+    /// The width to fit lines in (where possible).
+    pub width: usize,
+}
+
+/// A part of a rule (see `Style`).
+enum Part {
+    Text(String),
+    Rule(Doc),
+}
+
+impl<'a, Pat: Eq + Hash> Formatter<'a, Pat>
+where
+    str: InputMatch<Pat>,
+{
+    pub fn new(cx: &'a Context<Pat>, grammar: &'a Grammar) -> Self {
+        Formatter {
+            cx,
+            interpreter: Interpreter::new(cx, grammar),
+            styles: IndexMap::default(),
+            width: 80,
+        }
+    }
+
+    /// Parse `input` with `rule`, and pretty-print it (if it matches).
+    pub fn format(&self, rule: IStr, input: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .interpreter
+            .explain(rule, input)?
+            .map(|derivation| self.doc(&derivation, input).render(self.width)))
+    }
+
+    /// The `Doc` for a `derivation` of (part of) `input`.
+    pub fn doc(&self, derivation: &Derivation, input: &str) -> Doc {
+        let style = self.styles.get(&self.cx.intern(&derivation.rule[..]));
+        let style = style.cloned().unwrap_or_default();
+        if style.verbatim {
+            return Doc::Text(input[derivation.span.clone()].to_string());
+        }
+
+        // All the parts, each with whether there was whitespace before it.
+        let mut parts = vec![];
+        let mut space = false;
+        let text_parts = |text: &str, parts: &mut Vec<_>, space: &mut bool| {
+            let mut rest = text;
+            while !rest.is_empty() {
+                let trimmed = rest.trim_start();
+                *space |= trimmed.len() < rest.len();
+                let len = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+                if len > 0 {
+                    parts.push((
+                        core::mem::take(space),
+                        Part::Text(trimmed[..len].to_string()),
+                    ));
+                }
+                rest = &trimmed[len..];
+            }
+        };
+        let mut pos = derivation.span.start;
+        for child in &derivation.children {
+            text_parts(&input[pos..child.span.start], &mut parts, &mut space);
+            let text = &input[child.span.clone()];
+            // NOTE rules matching only whitespace aren't parts.
+            if text.trim().is_empty() {
+                space |= !text.is_empty();
+            } else {
+                space |= text.starts_with(char::is_whitespace);
+                parts.push((space, Part::Rule(self.doc(child, input))));
+                space = text.ends_with(char::is_whitespace);
+            }
+            pos = child.span.end;
+        }
+        text_parts(&input[pos..derivation.span.end], &mut parts, &mut space);
+
+        let text = |part: &Part| match part {
+            Part::Text(text) => Some(text.clone()),
+            Part::Rule(_) => None,
+        };
+        let mut docs = vec![];
+        let mut nested = vec![];
+        let last = parts.len().saturating_sub(1);
+        for (i, (space, part)) in parts.iter().enumerate() {
+            let inner = style.indent > 0 && i > 0 && i < last;
+            if !inner && !nested.is_empty() {
+                let nested = Doc::Concat(core::mem::take(&mut nested));
+                docs.push(Doc::Nest(style.indent, Box::new(nested)));
+            }
+            if i > 0 {
+                let can_break = text(&parts[i - 1].1)
+                    .is_some_and(|prev| style.break_after.contains(&prev))
+                    || text(part).is_some_and(|next| style.break_before.contains(&next));
+                let gap = match (&style.spacing, can_break) {
+                    (Some(spacing), _) => spacing.clone(),
+                    (None, true) if *space => Doc::Line,
+                    (None, true) => Doc::SoftLine,
+                    (None, false) if *space => Doc::Text(" ".to_string()),
+                    (None, false) => Doc::Text(String::new()),
+                };
+                if inner { &mut nested } else { &mut docs }.push(gap);
+            }
+            let doc = match part {
+                Part::Text(text) => Doc::Text(text.clone()),
+                Part::Rule(doc) => doc.clone(),
+            };
+            if inner { &mut nested } else { &mut docs }.push(doc);
+        }
+        let doc = Doc::Concat(docs);
+        if style.group {
+            Doc::Group(Box::new(doc))
+        } else {
+            doc
+        }
+    }
+}
@@ -1,16 +1,23 @@
-use crate::rule::{call, eat, MatchesEmpty, MaybeKnown};
-use crate::scannerless::Pat as SPat;
+use crate::context::IStr;
+use crate::interpreter::{self, Derivation, FieldMatch};
+use crate::rule::{call, eat, MatchesEmpty, MatchesOverlap, MaybeKnown, Pattern};
+use crate::scannerless::{self, LiteralError, Pat as SPat};
 use flat_token::flatten;
 pub use flat_token::FlatToken;
 pub use proc_macro2::{
-    Delimiter, Ident, LexError, Literal, Punct, Spacing, Span, TokenStream, TokenTree,
+    Delimiter, Group, Ident, LexError, Literal, Punct, Spacing, Span, TokenStream, TokenTree,
 };
 use std::fmt;
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 use std::str::FromStr;
 
 pub type Context = crate::context::Context<Pat>;
 
+/// An interpreter for grammars over Rust tokens, matching against `Tokens`
+/// (or any `[FlatToken]`), with positions (e.g. in `Derivation`s) being
+/// indices of `FlatToken`s.
+pub type Interpreter<'a> = interpreter::Interpreter<'a, Pat, [FlatToken]>;
+
 pub fn builtin(cx: &Context) -> crate::Grammar {
     let mut g = crate::Grammar::new();
 
@@ -49,6 +56,444 @@ pub fn builtin(cx: &Context) -> crate::Grammar {
     g
 }
 
+/// A `TokenStream` (e.g. the input of a procedural macro), flattened into
+/// `FlatToken`s (with delimited groups turned into their delimiters, and the
+/// tokens between them), to be matched against (see `Interpreter`).
+#[derive(Clone, Debug)]
+pub struct Tokens {
+    tokens: Vec<FlatToken>,
+}
+
+impl Tokens {
+    pub fn new(stream: TokenStream) -> Self {
+        let mut tokens = vec![];
+        flatten(stream, &mut tokens);
+        Tokens { tokens }
+    }
+
+    /// Match all the tokens with `rule` (see `interpreter::Interpreter::explain`).
+    pub fn explain(
+        &self,
+        interpreter: &Interpreter<'_>,
+        rule: IStr,
+    ) -> Result<Option<Derivation>, interpreter::Error> {
+        interpreter.explain(rule, &self.tokens)
+    }
+
+    /// The span of the first token in `range` (or of the last token, if
+    /// `range` is empty and at the end), e.g. for reporting errors.
+    // FIXME should be joining up spans, but the API
+    // for that is still "semver-exempt" in `proc-macro2`.
+    pub fn span(&self, range: Range<usize>) -> Span {
+        self.tokens
+            .get(range.start)
+            .or_else(|| self.tokens.last())
+            .map_or_else(Span::call_site, |token| token.span())
+    }
+
+    /// The tokens in `range` (e.g. the `span` of a `Derivation`, or of a
+    /// field in one), with their delimited groups rebuilt, or `None` if
+    /// `range` doesn't contain both delimiters of every group it overlaps.
+    pub fn to_token_stream(&self, range: Range<usize>) -> Option<TokenStream> {
+        // The groups being rebuilt (outermost first), each with its opening
+        // delimiter, its span, and the tokens in it so far.
+        let mut groups = vec![];
+        let mut trees = vec![];
+        for token in &self.tokens[range] {
+            let tree = match *token {
+                FlatToken::Delim(open @ ('(' | '[' | '{'), span) => {
+                    groups.push((open, span, std::mem::take(&mut trees)));
+                    continue;
+                }
+                FlatToken::Delim(close, _) => {
+                    let (open, span, outer) = groups.pop()?;
+                    let delimiter = match (open, close) {
+                        ('(', ')') => Delimiter::Parenthesis,
+                        ('[', ']') => Delimiter::Bracket,
+                        ('{', '}') => Delimiter::Brace,
+                        _ => return None,
+                    };
+                    let inner = std::mem::replace(&mut trees, outer);
+                    let mut group = Group::new(delimiter, inner.into_iter().collect());
+                    group.set_span(span);
+                    TokenTree::Group(group)
+                }
+                FlatToken::Ident(ref tt) => TokenTree::Ident(tt.clone()),
+                FlatToken::Punct(ref tt) => TokenTree::Punct(tt.clone()),
+                FlatToken::Literal(ref tt) => TokenTree::Literal(tt.clone()),
+            };
+            trees.push(tree);
+        }
+        if !groups.is_empty() {
+            return None;
+        }
+        Some(trees.into_iter().collect())
+    }
+}
+
+impl Deref for Tokens {
+    type Target = [FlatToken];
+    fn deref(&self) -> &[FlatToken] {
+        &self.tokens
+    }
+}
+
+/// An error about some tokens (e.g. from `FromTokens`), which procedural
+/// macros can report with `to_compile_error`.
+#[derive(Clone, Debug)]
+pub struct Error {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(span: Span, message: impl Into<String>) -> Self {
+        Error {
+            span,
+            message: message.into(),
+        }
+    }
+
+    /// A `compile_error!(...)` invocation, reporting the error at its span,
+    /// to be returned by a procedural macro (instead of its usual output).
+    pub fn to_compile_error(&self) -> TokenStream {
+        let mut message = Literal::string(&self.message);
+        message.set_span(self.span);
+        let mut args = Group::new(Delimiter::Parenthesis, TokenTree::Literal(message).into());
+        args.set_span(self.span);
+        let mut bang = Punct::new('!', Spacing::Alone);
+        bang.set_span(self.span);
+        let mut semi = Punct::new(';', Spacing::Alone);
+        semi.set_span(self.span);
+        vec![
+            TokenTree::Ident(Ident::new("compile_error", self.span)),
+            TokenTree::Punct(bang),
+            TokenTree::Group(args),
+            TokenTree::Punct(semi),
+        ]
+        .into_iter()
+        .collect()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Types which can be parsed from Rust tokens, e.g. the value of a field in
+/// an attribute like `#[my_attr(size = 4, name = "x")]`, either a literal
+/// (`4`, `"x"`, `true`, etc.), or anything matched by a grammar (see
+/// `FieldsMatch`, and `#[derive(FromTokens)]`, in `grammer-derive`).
+pub trait FromTokens: Sized {
+    fn from_tokens(tokens: TokenStream) -> Result<Self, Error>;
+}
+
+impl FromTokens for TokenStream {
+    fn from_tokens(tokens: TokenStream) -> Result<Self, Error> {
+        Ok(tokens)
+    }
+}
+
+/// The only token tree in `tokens`, or an error mentioning `expected`.
+fn single_token(tokens: TokenStream, expected: &str) -> Result<TokenTree, Error> {
+    let mut trees = tokens.into_iter();
+    match (trees.next(), trees.next()) {
+        (Some(tree), None) => Ok(tree),
+        (Some(_), Some(extra)) => Err(Error::new(
+            extra.span(),
+            format!("expected {}, found more tokens", expected),
+        )),
+        (None, _) => Err(Error::new(
+            Span::call_site(),
+            format!("expected {}, found nothing", expected),
+        )),
+    }
+}
+
+impl FromTokens for TokenTree {
+    fn from_tokens(tokens: TokenStream) -> Result<Self, Error> {
+        single_token(tokens, "a token")
+    }
+}
+
+impl FromTokens for Ident {
+    fn from_tokens(tokens: TokenStream) -> Result<Self, Error> {
+        match single_token(tokens, "an identifier")? {
+            TokenTree::Ident(ident) => Ok(ident),
+            tree => Err(Error::new(tree.span(), "expected an identifier")),
+        }
+    }
+}
+
+impl FromTokens for Literal {
+    fn from_tokens(tokens: TokenStream) -> Result<Self, Error> {
+        match single_token(tokens, "a literal")? {
+            TokenTree::Literal(literal) => Ok(literal),
+            tree => Err(Error::new(tree.span(), "expected a literal")),
+        }
+    }
+}
+
+impl FromTokens for bool {
+    fn from_tokens(tokens: TokenStream) -> Result<Self, Error> {
+        match single_token(tokens, "`true` or `false`")? {
+            TokenTree::Ident(ident) if ident == "true" => Ok(true),
+            TokenTree::Ident(ident) if ident == "false" => Ok(false),
+            tree => Err(Error::new(tree.span(), "expected `true` or `false`")),
+        }
+    }
+}
+
+/// The text of a literal in `tokens`, which can be negative (i.e. start
+/// with a separate `-` token), along with its span.
+fn literal_text(tokens: TokenStream, expected: &str) -> Result<(String, Span), Error> {
+    let mut trees: Vec<_> = tokens.into_iter().collect();
+    let negative = match trees.first() {
+        Some(TokenTree::Punct(p)) if p.as_char() == '-' && trees.len() == 2 => {
+            trees.remove(0);
+            true
+        }
+        _ => false,
+    };
+    match single_token(trees.into_iter().collect(), expected)? {
+        TokenTree::Literal(literal) => {
+            let text = literal.to_string();
+            Ok((
+                if negative { format!("-{}", text) } else { text },
+                literal.span(),
+            ))
+        }
+        tree => Err(Error::new(tree.span(), format!("expected {}", expected))),
+    }
+}
+
+macro_rules! int_from_tokens {
+    ($($ty:ident)*) => {$(
+        impl FromTokens for $ty {
+            fn from_tokens(tokens: TokenStream) -> Result<Self, Error> {
+                let expected = concat!("an integer (`", stringify!($ty), "`)");
+                let (text, span) = literal_text(tokens, expected)?;
+                let text = text.replace('_', "");
+                let (negative, text) = match text.strip_prefix('-') {
+                    Some(text) => (true, text),
+                    None => (false, &text[..]),
+                };
+                // NOTE suffixes (e.g. `u8`) can't be confused with
+                // digits, as they start with `i` or `u` (even in hex).
+                let digits = &text[..text.find(['i', 'u']).unwrap_or(text.len())];
+                let (radix, digits) = match digits.get(..2) {
+                    Some("0x") => (16, &digits[2..]),
+                    Some("0o") => (8, &digits[2..]),
+                    Some("0b") => (2, &digits[2..]),
+                    _ => (10, digits),
+                };
+                let digits = if negative {
+                    format!("-{}", digits)
+                } else {
+                    digits.to_string()
+                };
+                $ty::from_str_radix(&digits, radix)
+                    .map_err(|error| Error::new(span, format!("{}: {}", expected, error)))
+            }
+        }
+    )*}
+}
+
+int_from_tokens!(u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize);
+
+macro_rules! float_from_tokens {
+    ($($ty:ident)*) => {$(
+        impl FromTokens for $ty {
+            fn from_tokens(tokens: TokenStream) -> Result<Self, Error> {
+                let expected = concat!("a number (`", stringify!($ty), "`)");
+                let (text, span) = literal_text(tokens, expected)?;
+                let text = text.replace('_', "");
+                let text = text
+                    .strip_suffix("f32")
+                    .or_else(|| text.strip_suffix("f64"))
+                    .unwrap_or(&text);
+                text.parse()
+                    .map_err(|error| Error::new(span, format!("{}: {}", expected, error)))
+            }
+        }
+    )*}
+}
+
+float_from_tokens!(f32 f64);
+
+impl FromTokens for String {
+    fn from_tokens(tokens: TokenStream) -> Result<Self, Error> {
+        let expected = "a string literal";
+        let (text, span) = literal_text(tokens, expected)?;
+        scannerless::parse_str_literal(&text).map_err(|error| literal_error(span, expected, error))
+    }
+}
+
+impl FromTokens for char {
+    fn from_tokens(tokens: TokenStream) -> Result<Self, Error> {
+        let expected = "a character literal";
+        let (text, span) = literal_text(tokens, expected)?;
+        scannerless::parse_char_literal(&text).map_err(|error| literal_error(span, expected, error))
+    }
+}
+
+/// The `Error` for the literal at `span` (which should've been `expected`)
+/// failing to parse, with `error`.
+fn literal_error(span: Span, expected: &str, error: LiteralError) -> Error {
+    Error::new(span, format!("{}: {}", expected, error))
+}
+
+/// The fields matched by a grammar rule (see `FieldsMatch::parse`), which
+/// can be parsed (with `FromTokens`) from the tokens they matched, e.g. by
+/// the code `#[derive(FromTokens)]` (in `grammer-derive`) generates.
+pub struct FieldsMatch {
+    tokens: Tokens,
+    span: Range<usize>,
+    fields: Vec<FieldMatch>,
+}
+
+impl FieldsMatch {
+    /// Match all of `tokens` with the rule named `rule`, and collect its
+    /// fields, including those of the rules it calls (outside of fields),
+    /// e.g. `size` and `name` for:
+    ///
+    /// ```text
+    /// Args = Arg* % ",";
+    /// Arg = "size" "=" size:LITERAL | "name" "=" name:LITERAL;
+    /// ```
+    ///
+    /// (the grammar should be built on top of `builtin`, for `LITERAL`).
+    pub fn parse(
+        cx: &Context,
+        grammar: &crate::Grammar,
+        rule: &str,
+        tokens: TokenStream,
+    ) -> Result<Self, Error> {
+        let tokens = Tokens::new(tokens);
+        let rule_name = rule;
+        let rule = cx.intern(rule_name);
+        if !grammar.rules().contains_key(&rule) {
+            return Err(Error::new(
+                Span::call_site(),
+                format!("no rule named `{}` in the grammar", rule_name),
+            ));
+        }
+
+        let interpreter = Interpreter::new(cx, grammar);
+        let interpreter_error =
+            |error: interpreter::Error| Error::new(Span::call_site(), error.to_string());
+        let derivation = match tokens.explain(&interpreter, rule) {
+            Ok(Some(derivation)) => derivation,
+            Ok(None) => {
+                let failures = interpreter
+                    .explain_failure(rule, &tokens, 1)
+                    .map_err(interpreter_error)?;
+                let at = failures.first().map_or(0, |failure| failure.at);
+                let mut expected: Vec<_> = failures
+                    .into_iter()
+                    .map(|f| f.label.unwrap_or(f.expected))
+                    .collect();
+                expected.sort();
+                expected.dedup();
+                let message = match &expected[..] {
+                    [expected] => format!("expected {}", expected),
+                    _ => format!("expected one of: {}", expected.join(", ")),
+                };
+                return Err(Error::new(tokens.span(at..at), message));
+            }
+            Err(error) => return Err(interpreter_error(error)),
+        };
+
+        let mut fields = vec![];
+        collect_fields(derivation, &mut fields);
+        fields.sort_by_key(|f| f.span.start);
+        Ok(FieldsMatch {
+            span: 0..tokens.len(),
+            tokens,
+            fields,
+        })
+    }
+
+    /// The span of the tokens matched (see `Tokens::span`).
+    pub fn span(&self) -> Span {
+        self.tokens.span(self.span.clone())
+    }
+
+    /// All the matches of the field `name`, in order.
+    pub fn matches<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a FieldMatch> + 'a {
+        self.fields.iter().filter(move |f| f.name == name)
+    }
+
+    fn parse_match<T: FromTokens>(&self, field: &FieldMatch) -> Result<T, Error> {
+        let span = self.tokens.span(field.span.clone());
+        let tokens = self
+            .tokens
+            .to_token_stream(field.span.clone())
+            .ok_or_else(|| {
+                Error::new(
+                    span,
+                    format!("field `{}` doesn't match whole groups", field.name),
+                )
+            })?;
+        T::from_tokens(tokens).map_err(|mut error| {
+            // NOTE there are no tokens to report errors at, for empty
+            // fields, so the field itself is used instead.
+            if field.span.is_empty() {
+                error.span = span;
+            }
+            error
+        })
+    }
+
+    /// Parse the field `name`, which has to be matched exactly once.
+    pub fn field<T: FromTokens>(&self, name: &str) -> Result<T, Error> {
+        let mut matches = self.matches(name);
+        match (matches.next(), matches.next()) {
+            (Some(field), None) => self.parse_match(field),
+            (Some(_), Some(duplicate)) => Err(Error::new(
+                self.tokens.span(duplicate.span.clone()),
+                format!("duplicate `{}`", name),
+            )),
+            (None, _) => Err(Error::new(self.span(), format!("missing `{}`", name))),
+        }
+    }
+
+    /// Parse the field `name`, if it was matched (at most once).
+    pub fn opt_field<T: FromTokens>(&self, name: &str) -> Result<Option<T>, Error> {
+        if self.matches(name).next().is_none() {
+            return Ok(None);
+        }
+        self.field(name).map(Some)
+    }
+
+    /// Parse every match of the field `name` (e.g. in a repetition).
+    pub fn fields<T: FromTokens>(&self, name: &str) -> Result<Vec<T>, Error> {
+        self.matches(name).map(|f| self.parse_match(f)).collect()
+    }
+}
+
+/// Collect the fields of `derivation`, and of its children which aren't
+/// part of any of those fields (as they only provide the field's tokens).
+fn collect_fields(derivation: Derivation, out: &mut Vec<FieldMatch>) {
+    let in_field = |span: &Range<usize>| {
+        derivation
+            .fields
+            .iter()
+            .any(|f| f.span.start <= span.start && span.end <= f.span.end)
+    };
+    let children: Vec<_> = derivation
+        .children
+        .into_iter()
+        .filter(|child| !in_field(&child.span))
+        .collect();
+    out.extend(derivation.fields);
+    for child in children {
+        collect_fields(child, out);
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Pat<Pats = Vec<FlatTokenPat<String>>>(pub Pats);
 
@@ -103,6 +548,23 @@ impl MatchesEmpty for Pat {
     }
 }
 
+// NOTE token patterns can't be matched against characters, so
+// the defaults (`Unknown` FIRST characters, and no `try_match`) apply.
+impl Pattern for Pat {}
+
+impl MatchesOverlap for Pat {
+    fn overlaps(&self, other: &Self) -> MaybeKnown<bool> {
+        MaybeKnown::Known(self.0.iter().zip(&other.0).all(|(a, b)| a.overlaps(b)))
+    }
+
+    fn contains(&self, other: &Self) -> MaybeKnown<bool> {
+        MaybeKnown::Known(
+            self.0.len() <= other.0.len()
+                && self.0.iter().zip(&other.0).all(|(a, b)| a.contains(b)),
+        )
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum FlatTokenPat<S: AsRef<str>> {
     Delim(char),
@@ -189,6 +651,61 @@ impl<S: AsRef<str>> FlatTokenPat<S> {
         }
     }
 
+    /// Whether any token is matched by both `self` and `other`.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        match (self, other) {
+            (FlatTokenPat::Delim(a), FlatTokenPat::Delim(b)) => a == b,
+            (FlatTokenPat::Ident(a), FlatTokenPat::Ident(b)) => match (a, b) {
+                (Some(a), Some(b)) => a.as_ref() == b.as_ref(),
+                _ => true,
+            },
+            (
+                FlatTokenPat::Punct { ch, joint },
+                FlatTokenPat::Punct {
+                    ch: other_ch,
+                    joint: other_joint,
+                },
+            ) => {
+                fn compatible<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> bool {
+                    match (a, b) {
+                        (Some(a), Some(b)) => a == b,
+                        _ => true,
+                    }
+                }
+                compatible(ch, other_ch) && compatible(joint, other_joint)
+            }
+            (FlatTokenPat::Literal, FlatTokenPat::Literal) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether `self` matches every token `other` matches.
+    pub fn contains(&self, other: &Self) -> bool {
+        fn covers<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> bool {
+            match (a, b) {
+                (None, _) => true,
+                (Some(a), Some(b)) => a == b,
+                (Some(_), None) => false,
+            }
+        }
+        match (self, other) {
+            (FlatTokenPat::Delim(a), FlatTokenPat::Delim(b)) => a == b,
+            (FlatTokenPat::Ident(a), FlatTokenPat::Ident(b)) => covers(
+                &a.as_ref().map(|a| a.as_ref()),
+                &b.as_ref().map(|b| b.as_ref()),
+            ),
+            (
+                FlatTokenPat::Punct { ch, joint },
+                FlatTokenPat::Punct {
+                    ch: other_ch,
+                    joint: other_joint,
+                },
+            ) => covers(ch, other_ch) && covers(joint, other_joint),
+            (FlatTokenPat::Literal, FlatTokenPat::Literal) => true,
+            _ => false,
+        }
+    }
+
     pub fn matches(&self, ft: &FlatToken) -> bool {
         match (ft, self) {
             (FlatToken::Delim(a, _), FlatTokenPat::Delim(b)) => a == b,
@@ -1,5 +1,5 @@
 use crate::input::{Input, InputMatch, Range};
-use crate::proc_macro::{FlatTokenPat, Span, TokenStream};
+use crate::proc_macro::{FlatTokenPat, Pat, Span, TokenStream};
 use flat_token::{flatten, FlatToken};
 use indexing::{proof::Provable, Container, Index, Unknown};
 use std::ops;
@@ -53,8 +53,8 @@ impl Input for TokenStream {
     }
 }
 
-impl InputMatch<[FlatTokenPat<&'_ str>]> for [FlatToken] {
-    fn match_left(&self, pat: &[FlatTokenPat<&str>]) -> Option<usize> {
+impl<S: AsRef<str>> InputMatch<[FlatTokenPat<S>]> for [FlatToken] {
+    fn match_left(&self, pat: &[FlatTokenPat<S>]) -> Option<usize> {
         if self
             .iter()
             .zip(pat)
@@ -67,7 +67,7 @@ impl InputMatch<[FlatTokenPat<&'_ str>]> for [FlatToken] {
             None
         }
     }
-    fn match_right(&self, pat: &[FlatTokenPat<&str>]) -> Option<usize> {
+    fn match_right(&self, pat: &[FlatTokenPat<S>]) -> Option<usize> {
         if self
             .iter()
             .zip(pat)
@@ -82,3 +82,12 @@ impl InputMatch<[FlatTokenPat<&'_ str>]> for [FlatToken] {
         }
     }
 }
+
+impl InputMatch<Pat> for [FlatToken] {
+    fn match_left(&self, pat: &Pat) -> Option<usize> {
+        self.match_left(&pat.0[..])
+    }
+    fn match_right(&self, pat: &Pat) -> Option<usize> {
+        self.match_right(&pat.0[..])
+    }
+}
@@ -0,0 +1,305 @@
+//! Profiling a grammar on a corpus of inputs (see `Profile`), using the
+//! `Event`s reported by `Interpreter::observe`, to find the rules and `Or`
+//! cases taking the most time (or backtracking the most), and the terminals
+//! tried most often, along with suggestions for speeding them up.
+
+use crate::context::{Context, IRule, IStr};
+use crate::input::IndexedInputMatch;
+use crate::interpreter::{Error, Event, Frame, Interpreter};
+use crate::rule::Rule;
+use indexmap::IndexMap;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Statistics about matching a rule (across all the inputs profiled).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RuleProfile {
+    pub calls: usize,
+    /// Calls which didn't match anything.
+    pub failed: usize,
+    /// Calls at a position the rule was already matched at (in the same
+    /// input), i.e. redone work, which `Interpreter::memo_limit` avoids.
+    pub repeated: usize,
+    /// The time spent matching the rule, including the rules it calls
+    /// (but only once for recursive calls).
+    pub time: Duration,
+}
+
+/// Statistics about matching a case of an `Or`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CaseProfile {
+    pub tried: usize,
+    /// How many of the `tried` times the case matched anything.
+    pub matched: usize,
+    /// The time spent on the case when it didn't match, i.e. wasted work.
+    pub failed_time: Duration,
+}
+
+/// Statistics about matching a terminal (an `Eat`, or a `Backref`).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TerminalProfile {
+    pub tried: usize,
+    pub matched: usize,
+}
+
+/// Statistics collected by matching some rule of a grammar against every
+/// input in a corpus (see `Profile::record`), to be turned into a `Report`.
+///
+/// Note that the `Interpreter` tries all cases of every `Or` (as `Or` is
+/// unordered), so the order of the cases only matters when the grammar is
+/// used with ordered choice (e.g. exported with `pest::export`).
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub inputs: usize,
+    pub rules: IndexMap<IStr, RuleProfile>,
+    /// Keyed by the rule the `Or` is in, and the `Or` itself.
+    pub ors: IndexMap<(IStr, IRule), Vec<CaseProfile>>,
+    pub terminals: IndexMap<IRule, TerminalProfile>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile::default()
+    }
+
+    /// Match `rule` against all of `input` (see `Interpreter::observe`),
+    /// collecting statistics about it, returning whether it matched.
+    pub fn record<Pat, I: ?Sized + IndexedInputMatch<Pat>>(
+        &mut self,
+        interpreter: &Interpreter<'_, Pat, I>,
+        rule: IStr,
+        input: &I,
+    ) -> Result<bool, Error> {
+        self.inputs += 1;
+
+        let mut stack: Vec<(Frame, Instant)> = vec![];
+        let mut seen = HashSet::new();
+        interpreter.observe(rule, input, |event| match *event {
+            Event::Enter(frame) => {
+                if let Frame::Rule { rule, at } = frame {
+                    let profile = self.rules.entry(rule).or_default();
+                    profile.calls += 1;
+                    if !seen.insert((rule, at)) {
+                        profile.repeated += 1;
+                    }
+                }
+                stack.push((frame, Instant::now()));
+            }
+            Event::Exit { frame, ref ends } => {
+                let (_, entered) = stack.pop().unwrap();
+                let elapsed = entered.elapsed();
+                match frame {
+                    Frame::Rule { rule, .. } => {
+                        // NOTE recursive calls are already included
+                        // in the time of the outermost call.
+                        let recursive = stack
+                            .iter()
+                            .any(|&(f, _)| matches!(f, Frame::Rule { rule: r, .. } if r == rule));
+                        let profile = &mut self.rules[&rule];
+                        if ends.is_empty() {
+                            profile.failed += 1;
+                        }
+                        if !recursive {
+                            profile.time += elapsed;
+                        }
+                    }
+                    Frame::Case {
+                        or, index, cases, ..
+                    } => {
+                        let in_rule = stack.iter().rev().find_map(|&(f, _)| match f {
+                            Frame::Rule { rule, .. } => Some(rule),
+                            Frame::Case { .. } => None,
+                        });
+                        let in_rule = match in_rule {
+                            Some(rule) => rule,
+                            None => return,
+                        };
+                        let profiles = self
+                            .ors
+                            .entry((in_rule, or))
+                            .or_insert_with(|| vec![CaseProfile::default(); cases]);
+                        let profile = &mut profiles[index];
+                        profile.tried += 1;
+                        if ends.is_empty() {
+                            profile.failed_time += elapsed;
+                        } else {
+                            profile.matched += 1;
+                        }
+                    }
+                }
+            }
+            Event::Terminal { terminal, end, .. } => {
+                let profile = self.terminals.entry(terminal).or_default();
+                profile.tried += 1;
+                if end.is_some() {
+                    profile.matched += 1;
+                }
+            }
+        })
+    }
+
+    /// How many times every case of every `Or` matched (across all the
+    /// rules it's in), e.g. for `Grammar::reorder_alternatives`.
+    pub fn case_frequencies(&self) -> IndexMap<IRule, Vec<usize>> {
+        let mut frequencies: IndexMap<IRule, Vec<usize>> = IndexMap::new();
+        for (&(_, or), cases) in &self.ors {
+            let frequencies = frequencies
+                .entry(or)
+                .or_insert_with(|| vec![0; cases.len()]);
+            for (frequency, case) in frequencies.iter_mut().zip(cases) {
+                *frequency += case.matched;
+            }
+        }
+        frequencies
+    }
+
+    /// Summarize the profile into the `top` hottest rules, and terminals,
+    /// along with suggestions for the rules and `Or`s which could be faster.
+    pub fn report<Pat: fmt::Debug>(&self, cx: &Context<Pat>, top: usize) -> Report {
+        let mut rules: Vec<_> = self
+            .rules
+            .iter()
+            .map(|(&name, &profile)| (cx[name].to_string(), profile))
+            .collect();
+        rules.sort_by_key(|&(_, profile)| Reverse(profile.time));
+        rules.truncate(top);
+
+        let mut terminals: Vec<_> = self
+            .terminals
+            .iter()
+            .map(|(&terminal, &profile)| (terminal.node_desc(cx), profile))
+            .collect();
+        terminals.sort_by_key(|&(_, profile)| Reverse(profile.tried));
+        terminals.truncate(top);
+
+        let mut suggestions = vec![];
+        for (&name, profile) in &self.rules {
+            // HACK arbitrary threshold, to only suggest memoization
+            // when most of the work matching the rule is redone work.
+            if profile.repeated * 2 > profile.calls {
+                suggestions.push(Suggestion {
+                    rule: cx[name].to_string(),
+                    node: cx[name].to_string(),
+                    cost: profile.time,
+                    message: format!(
+                        "{} of {} calls were at positions already matched, \
+                         consider setting `Interpreter::memo_limit`, or \
+                         factoring out common prefixes in the rules calling it",
+                        profile.repeated, profile.calls
+                    ),
+                });
+            }
+        }
+        for (&(name, or), cases) in &self.ors {
+            let case_rules = match cx[or] {
+                Rule::Or(ref cases) => cases,
+                _ => unreachable!(),
+            };
+            for (i, case) in cases.iter().enumerate() {
+                if case.tried == 0 {
+                    continue;
+                }
+                // NOTE terminals can't be ruled out any sooner.
+                let is_terminal = matches!(
+                    cx[case_rules[i]],
+                    Rule::Eat(_) | Rule::EatUntil(_) | Rule::Backref(_)
+                );
+                let failed = case.tried - case.matched;
+                if failed * 2 > case.tried && !is_terminal {
+                    suggestions.push(Suggestion {
+                        rule: cx[name].to_string(),
+                        node: case_rules[i].node_desc(cx),
+                        cost: case.failed_time,
+                        message: format!(
+                            "case {} failed {} of {} times it was tried, \
+                             consider adding lookahead (e.g. a `Guard`) to \
+                             rule it out sooner",
+                            i, failed, case.tried
+                        ),
+                    });
+                }
+            }
+            if cases.windows(2).any(|w| w[0].matched < w[1].matched) {
+                let matched: Vec<_> = cases.iter().map(|case| case.matched.to_string()).collect();
+                suggestions.push(Suggestion {
+                    rule: cx[name].to_string(),
+                    node: or.node_desc(cx),
+                    cost: cases.iter().map(|case| case.failed_time).sum(),
+                    message: format!(
+                        "the cases matched {} times (respectively), consider \
+                         moving the ones matching more often earlier (for \
+                         ordered choice)",
+                        matched.join(", ")
+                    ),
+                });
+            }
+        }
+        suggestions.sort_by_key(|suggestion| Reverse(suggestion.cost));
+
+        Report {
+            inputs: self.inputs,
+            rules,
+            terminals,
+            suggestions,
+        }
+    }
+}
+
+/// A way to (potentially) make matching faster, in a specific part of the
+/// grammar (see `Profile::report`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Suggestion {
+    /// The name of the rule the suggestion is about.
+    pub rule: String,
+    /// The part of the rule the suggestion is about (see `IRule::node_desc`).
+    pub node: String,
+    /// The time which could be saved (roughly), to rank suggestions by.
+    pub cost: Duration,
+    pub message: String,
+}
+
+/// The summary of a `Profile`, with the hottest rules (by time) and
+/// terminals (by how often they were tried), and suggestions (costliest
+/// first), which displays as a human-readable report.
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub inputs: usize,
+    pub rules: Vec<(String, RuleProfile)>,
+    pub terminals: Vec<(String, TerminalProfile)>,
+    pub suggestions: Vec<Suggestion>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "profiled {} inputs", self.inputs)?;
+        writeln!(f, "\nhottest rules:")?;
+        for (name, profile) in &self.rules {
+            writeln!(
+                f,
+                "  {:?} {} ({} calls, {} failed, {} repeated)",
+                profile.time, name, profile.calls, profile.failed, profile.repeated
+            )?;
+        }
+        writeln!(f, "\nmost tried terminals:")?;
+        for (terminal, profile) in &self.terminals {
+            writeln!(
+                f,
+                "  {} ({} tried, {} matched)",
+                terminal, profile.tried, profile.matched
+            )?;
+        }
+        if !self.suggestions.is_empty() {
+            writeln!(f, "\nsuggestions:")?;
+        }
+        for suggestion in &self.suggestions {
+            writeln!(
+                f,
+                "  in rule `{}`: `{}`\n    {}",
+                suggestion.rule, suggestion.node, suggestion.message
+            )?;
+        }
+        Ok(())
+    }
+}
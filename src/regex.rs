@@ -0,0 +1,303 @@
+//! Regular (over-)approximations of the languages of rules, as regexes
+//! (in the syntax of the `regex` crate), e.g. for pre-filtering inputs.
+
+use crate::compat::prelude::*;
+use crate::compat::{HashMap, IndexSet};
+use crate::context::{Context, IRule, IStr};
+use crate::rule::{Anchor, Rule};
+use crate::{scannerless, Grammar, RuleError};
+
+pub trait RegexPat {
+    /// A regex matching exactly what this pattern matches.
+    fn to_regex(&self) -> String;
+}
+
+/// Escape all the regex metacharacters in `s`, and any control characters.
+fn escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '\\' | '.' | '+' | '*' | '?' | '(' | ')' | '|' | '[' | ']' | '{' | '}' | '^' | '$'
+            | '#' | '&' | '-' | '~' => format!("\\{}", c),
+            _ if c.is_control() || c.is_whitespace() => format!("\\x{{{:x}}}", c as u32),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+impl<S: AsRef<str>> RegexPat for scannerless::Pat<S> {
+    fn to_regex(&self) -> String {
+        match self {
+            scannerless::Pat::String(s) => escape(s.as_ref()),
+            &scannerless::Pat::Range(start, end) if start == end => escape(&start.to_string()),
+            &scannerless::Pat::Range(start, end) => format!(
+                "[{}-{}]",
+                escape(&start.to_string()),
+                escape(&end.to_string())
+            ),
+        }
+    }
+}
+
+/// The result of `approx`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Regex {
+    pub regex: String,
+    /// Whether `regex` matches exactly the language of the rule, instead of
+    /// only approximating it (i.e. matching a superset of it).
+    pub exact: bool,
+}
+
+/// Compute a regex matching (at least) everything `rule` can match.
+///
+/// Rules which (directly or indirectly) call themselves are approximated,
+/// as any sequence of the terminals they can reach (e.g. `(?:\(|\)|x)*`,
+/// for a rule matching balanced parentheses around `x`), and so are the
+/// zero-width rules other than `Anchor::{Start,End}` (assumed to match).
+pub fn approx<Pat: RegexPat>(
+    cx: &Context<Pat>,
+    grammar: &Grammar,
+    rule: IStr,
+) -> Result<Regex, RuleError> {
+    let body = grammar
+        .rules()
+        .get(&rule)
+        .ok_or_else(|| RuleError::Undefined(cx[rule].to_string()))?;
+    Ok(approx_rule(cx, grammar, body.rule))
+}
+
+/// Like `approx`, but for any `rule` (e.g. the trivia), not just named ones.
+pub fn approx_rule<Pat: RegexPat>(cx: &Context<Pat>, grammar: &Grammar, rule: IRule) -> Regex {
+    let is_cycle = |scc: &&Vec<IStr>| {
+        let calls = grammar.rules()[&scc[0]].rule.call_names(cx);
+        scc.len() > 1 || calls.contains(&scc[0])
+    };
+    let recursive = grammar
+        .sccs(cx)
+        .iter()
+        .filter(is_cycle)
+        .flatten()
+        .copied()
+        .collect();
+    let mut approximator = Approximator {
+        cx,
+        grammar,
+        recursive,
+        exact: true,
+        calls: HashMap::default(),
+    };
+    let (regex, _) = approximator.approx(rule);
+    Regex {
+        regex,
+        exact: approximator.exact,
+    }
+}
+
+/// Operator precedence, from loosest to tightest binding.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Prec {
+    Alternation,
+    Concatenation,
+    Atom,
+}
+
+/// Anything at all (including newlines).
+const ANY: &str = r"[\s\S]*";
+
+struct Approximator<'a, Pat> {
+    cx: &'a Context<Pat>,
+    grammar: &'a Grammar,
+    /// Rules in cycles of `Call`s (see `Grammar::sccs`).
+    recursive: IndexSet<IStr>,
+    exact: bool,
+    /// Cached results for `Call`s to non-recursive rules.
+    calls: HashMap<IStr, (String, Prec)>,
+}
+
+fn group(s: String, s_prec: Prec, prec: Prec) -> String {
+    if s_prec < prec || (s.is_empty() && prec == Prec::Atom) {
+        format!("(?:{})", s)
+    } else {
+        s
+    }
+}
+
+/// Whether `s` is a single character, escape (e.g. `\.` or `\x{a}`),
+/// or character class (e.g. `[a-z]`), i.e. doesn't need grouping.
+fn is_single_atom(s: &str) -> bool {
+    let len = match s.as_bytes() {
+        [] => return false,
+        [b'\\', b'x', b'{', ..] => s.find('}').map_or(s.len(), |i| i + 1),
+        [b'\\', ..] => 1 + s[1..].chars().next().map_or(0, |c| c.len_utf8()),
+        [b'[', ..] => {
+            let mut escaped = false;
+            let end = s[1..].find(|c| {
+                let end = !escaped && c == ']';
+                escaped = !escaped && c == '\\';
+                end
+            });
+            end.map_or(s.len(), |i| i + 2)
+        }
+        _ => s.chars().next().unwrap().len_utf8(),
+    };
+    len == s.len()
+}
+
+impl<Pat: RegexPat> Approximator<'_, Pat> {
+    fn approx(&mut self, rule: IRule) -> (String, Prec) {
+        let cx = self.cx;
+        let atom = |this: &mut Self, rule| {
+            let (s, s_prec) = this.approx(rule);
+            group(s, s_prec, Prec::Atom)
+        };
+        match cx[rule] {
+            Rule::Empty => (String::new(), Prec::Atom),
+            // NOTE `\A`/`\z` always mean the start/end of the input,
+            // unlike `^`/`$` (which are per-line in some regex syntaxes).
+            Rule::Anchor(Anchor::Start) => (r"\A".to_string(), Prec::Atom),
+            Rule::Anchor(Anchor::End) => (r"\z".to_string(), Prec::Atom),
+            Rule::Anchor(Anchor::Column(_))
+            | Rule::FlagGuard(..)
+            | Rule::Guard(_)
+            | Rule::Feature(..) => {
+                self.exact = false;
+                (String::new(), Prec::Atom)
+            }
+            Rule::Eat(ref pat) => {
+                let s = pat.to_regex();
+                let prec = if is_single_atom(&s) {
+                    Prec::Atom
+                } else {
+                    Prec::Concatenation
+                };
+                (s, prec)
+            }
+            // NOTE without lookahead, this can only be approximated
+            // as consuming anything (which includes the pattern itself).
+            Rule::EatUntil(_) | Rule::Backref(_) => {
+                self.exact = false;
+                (ANY.to_string(), Prec::Atom)
+            }
+            Rule::Call(name) => self.approx_call(name),
+            Rule::WithFlag(_, _, rule) | Rule::Silent(rule) => self.approx(rule),
+            Rule::Capture(_, delim, body) => {
+                let (delim, delim_prec) = self.approx(delim);
+                let (body, body_prec) = self.approx(body);
+                (
+                    group(delim, delim_prec, Prec::Concatenation)
+                        + &group(body, body_prec, Prec::Concatenation),
+                    Prec::Concatenation,
+                )
+            }
+            Rule::Concat(ref rules) => {
+                let elems: Vec<_> = rules
+                    .iter()
+                    .map(|&rule| {
+                        let (s, s_prec) = self.approx(rule);
+                        group(s, s_prec, Prec::Concatenation)
+                    })
+                    .collect();
+                (elems.concat(), Prec::Concatenation)
+            }
+            Rule::Or(ref rules) => {
+                let mut cases: IndexSet<_> = IndexSet::default();
+                for &rule in rules {
+                    let (s, s_prec) = self.approx(rule);
+                    cases.insert(group(s, s_prec, Prec::Concatenation));
+                }
+                let cases: Vec<_> = cases.into_iter().collect();
+                (cases.join("|"), Prec::Alternation)
+            }
+            Rule::Opt(rule) => (format!("{}?", atom(self, rule)), Prec::Atom),
+            Rule::RepeatMany(elem, None) => (format!("{}*", atom(self, elem)), Prec::Atom),
+            Rule::RepeatMore(elem, None) => (format!("{}+", atom(self, elem)), Prec::Atom),
+            Rule::RepeatMany(elem, Some((sep, kind)))
+            | Rule::RepeatMore(elem, Some((sep, kind))) => {
+                let (elem, elem_prec) = self.approx(elem);
+                let elem = group(elem, elem_prec, Prec::Concatenation);
+                let (sep, sep_prec) = self.approx(sep);
+                let sep = group(sep, sep_prec, Prec::Concatenation);
+                let mut s = format!("{}(?:{}{})*", elem, sep, elem);
+                if kind.allows_leading() {
+                    s = format!("(?:{})?{}", sep, s);
+                }
+                if kind.allows_trailing() {
+                    s += &format!("(?:{})?", sep);
+                }
+                match cx[rule] {
+                    Rule::RepeatMany(..) => (format!("(?:{})?", s), Prec::Atom),
+                    _ => (s, Prec::Concatenation),
+                }
+            }
+        }
+    }
+
+    fn approx_call(&mut self, name: IStr) -> (String, Prec) {
+        if let Some(cached) = self.calls.get(&name) {
+            return cached.clone();
+        }
+        let result = match self.grammar.rules().get(&name) {
+            None => {
+                self.exact = false;
+                (ANY.to_string(), Prec::Atom)
+            }
+            Some(rule) if !self.recursive.contains(&name) => self.approx(rule.rule),
+            Some(_) => {
+                self.exact = false;
+                let mut terminals = IndexSet::default();
+                let mut any = false;
+                for &reachable in &self.grammar.reachable(self.cx)[&name] {
+                    self.terminals(
+                        self.grammar.rules()[&reachable].rule,
+                        &mut terminals,
+                        &mut any,
+                    );
+                }
+                if any {
+                    (ANY.to_string(), Prec::Atom)
+                } else if terminals.is_empty() {
+                    (String::new(), Prec::Atom)
+                } else {
+                    let terminals: Vec<_> = terminals.into_iter().collect();
+                    (format!("(?:{})*", terminals.join("|")), Prec::Atom)
+                }
+            }
+        };
+        self.calls.insert(name, result.clone());
+        result
+    }
+
+    /// Collect all the terminals in `rule` (without following `Call`s,
+    /// except for setting `any` if any of them are to undefined rules).
+    fn terminals(&self, rule: IRule, terminals: &mut IndexSet<String>, any: &mut bool) {
+        match self.cx[rule] {
+            Rule::Empty
+            | Rule::Anchor(_)
+            | Rule::FlagGuard(..)
+            | Rule::Guard(_)
+            | Rule::Feature(..) => {}
+            Rule::Eat(ref pat) => {
+                terminals.insert(pat.to_regex());
+            }
+            Rule::EatUntil(_) | Rule::Backref(_) => *any = true,
+            Rule::Call(name) => *any |= !self.grammar.rules().contains_key(&name),
+            Rule::WithFlag(_, _, rule) | Rule::Silent(rule) | Rule::Opt(rule) => {
+                self.terminals(rule, terminals, any)
+            }
+            Rule::Capture(_, delim, body) => {
+                self.terminals(delim, terminals, any);
+                self.terminals(body, terminals, any);
+            }
+            Rule::Concat(ref rules) | Rule::Or(ref rules) => {
+                for &rule in rules {
+                    self.terminals(rule, terminals, any);
+                }
+            }
+            Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => {
+                self.terminals(elem, terminals, any);
+                if let Some((sep, _)) = sep {
+                    self.terminals(sep, terminals, any);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! Reloading a grammar (e.g. from a `.pest` file) while it's in use, for
+//! long-running services (see `GrammarHandle`).
+
+use crate::context::Context;
+use crate::diagnostics::{Args, Catalog, Diagnostic, Localize, Severity};
+use crate::ids::FrozenGrammar;
+use crate::limits::Limits;
+use crate::rule::MatchesEmpty;
+use crate::versions::{self, MigrationReport};
+use crate::Grammar;
+use std::fmt;
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+/// Turns the contents of a grammar file into a `Grammar`.
+type Loader<'cx, Pat> =
+    Box<dyn Fn(&Context<Pat>, &str) -> Result<Grammar, ReloadError> + Send + Sync + 'cx>;
+
+#[derive(Debug)]
+pub enum ReloadError {
+    Io(io::Error),
+    /// The loader (passed to `GrammarHandle::load`) failed, e.g. on a syntax error.
+    Load(String),
+    /// The pest grammar (see `GrammarHandle::pest`) failed to import.
+    Import(crate::pest::ImportError),
+    /// The grammar was loaded, but `Grammar::validate` found errors in it.
+    Invalid(Vec<Diagnostic>),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReloadError::Io(error) => write!(f, "{}", error),
+            ReloadError::Load(error) => write!(f, "{}", error),
+            ReloadError::Import(error) => write!(f, "{}", error),
+            ReloadError::Invalid(diagnostics) => {
+                let errors: Vec<_> = diagnostics
+                    .iter()
+                    .filter(|d| d.severity() == Severity::Error)
+                    .map(|d| d.to_string())
+                    .collect();
+                write!(f, "{}", errors.join("\n"))
+            }
+        }
+    }
+}
+
+impl Localize for ReloadError {
+    /// One of `io-error` and `load-error` (with an `error` argument, in English),
+    /// the id of the `pest::ImportError` (and its arguments), and `invalid-grammar`
+    /// (`errors`, though `localize` localizes each error instead).
+    fn message_id(&self) -> &'static str {
+        match self {
+            ReloadError::Io(_) => "io-error",
+            ReloadError::Load(_) => "load-error",
+            ReloadError::Import(error) => error.message_id(),
+            ReloadError::Invalid(_) => "invalid-grammar",
+        }
+    }
+
+    fn args(&self) -> Args {
+        match self {
+            ReloadError::Io(error) => vec![("error", error.to_string())],
+            ReloadError::Load(error) => vec![("error", error.clone())],
+            ReloadError::Import(error) => error.args(),
+            ReloadError::Invalid(_) => vec![("errors", self.to_string())],
+        }
+    }
+
+    /// For `Invalid`, every error is localized (see `Diagnostic::localize`),
+    /// instead of using the `invalid-grammar` message.
+    fn localize(&self, catalog: &dyn Catalog) -> String {
+        match self {
+            ReloadError::Invalid(diagnostics) => {
+                let errors: Vec<_> = diagnostics
+                    .iter()
+                    .filter(|d| d.severity() == Severity::Error)
+                    .map(|d| d.localize(catalog).to_string())
+                    .collect();
+                errors.join("\n")
+            }
+            _ => catalog
+                .message(self.message_id(), &self.args())
+                .unwrap_or_else(|| self.to_string()),
+        }
+    }
+}
+
+/// A successful reload, and how the grammar differed from the one before.
+#[derive(Clone, Debug)]
+pub struct Reload {
+    /// Incremented by every reload which changed the grammar (starting at
+    /// `0` for the first grammar loaded).
+    pub generation: u64,
+    pub report: MigrationReport,
+}
+
+/// A grammar loaded from a file, which can be reloaded (and re-validated)
+/// without disrupting parses using it, as they keep using the grammar they
+/// started with, while new ones (see `current`) use the reloaded grammar.
+///
+/// FIXME everything interned while loading grammars (even rejected
+/// ones) is kept in the `Context`, so reloading often keeps using more memory.
+pub struct GrammarHandle<'cx, Pat> {
+    cx: &'cx Context<Pat>,
+    path: PathBuf,
+    loader: Loader<'cx, Pat>,
+    /// The longest sentences compared between versions of the grammar (see
+    /// `versions::migration_report`), `0` to only compare rule definitions.
+    pub max_sentence_len: usize,
+    current: RwLock<Arc<FrozenGrammar>>,
+    /// NOTE this also serializes reloads, so every report in the
+    /// changelog is relative to the grammar in the one before it.
+    state: Mutex<State>,
+}
+
+struct State {
+    modified: Option<SystemTime>,
+    /// The `Grammar::fingerprint` of the current grammar.
+    fingerprint: u64,
+    changelog: Vec<Reload>,
+}
+
+impl<'cx, Pat> GrammarHandle<'cx, Pat>
+where
+    Pat: Eq + Hash + MatchesEmpty + fmt::Debug,
+{
+    /// Load the grammar at `path`, by passing its contents to `loader`.
+    pub fn load(
+        cx: &'cx Context<Pat>,
+        path: impl Into<PathBuf>,
+        loader: impl Fn(&Context<Pat>, &str) -> Result<Grammar, String> + Send + Sync + 'cx,
+    ) -> Result<Self, ReloadError> {
+        Self::with_loader(cx, path, move |cx, src| {
+            loader(cx, src).map_err(ReloadError::Load)
+        })
+    }
+
+    fn with_loader(
+        cx: &'cx Context<Pat>,
+        path: impl Into<PathBuf>,
+        loader: impl Fn(&Context<Pat>, &str) -> Result<Grammar, ReloadError> + Send + Sync + 'cx,
+    ) -> Result<Self, ReloadError> {
+        let path = path.into();
+        let modified = modified(&path);
+        let grammar = load(cx, &path, &loader)?;
+        let fingerprint = grammar.fingerprint(cx);
+        Ok(GrammarHandle {
+            cx,
+            path,
+            loader: Box::new(loader),
+            max_sentence_len: 3,
+            current: RwLock::new(Arc::new(grammar.freeze(cx))),
+            state: Mutex::new(State {
+                modified,
+                fingerprint,
+                changelog: vec![],
+            }),
+        })
+    }
+
+    /// Load the pest grammar at `path` (see `pest::import_file`).
+    ///
+    /// FIXME only `path` itself is checked by `reload_if_modified`,
+    /// not the files it `include`s.
+    pub fn pest(cx: &'cx Context<Pat>, path: impl Into<PathBuf>) -> Result<Self, ReloadError>
+    where
+        Pat: for<'s> From<&'s str> + From<RangeInclusive<char>>,
+    {
+        let path = path.into();
+        let base = path.clone();
+        Self::with_loader(cx, path, move |cx, src| {
+            crate::pest::import_from(cx, src, &base, &Limits::default())
+                .map(|imported| imported.grammar)
+                .map_err(ReloadError::Import)
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The grammar new parses should use (which stays valid even if the
+    /// grammar is reloaded while it's still in use).
+    pub fn current(&self) -> Arc<FrozenGrammar> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Reload the grammar, and (if it's valid) swap it in for `current`.
+    ///
+    /// If the grammar didn't change (see `Grammar::fingerprint`), nothing is
+    /// swapped, and the returned report is empty (and not added to the
+    /// `changelog`). Note that the report can also be empty for a changed
+    /// grammar, e.g. if only its trivia or modifiers changed.
+    pub fn reload(&self) -> Result<Reload, ReloadError> {
+        let mut state = self.state.lock().unwrap();
+        state.modified = modified(&self.path);
+        let grammar = load(self.cx, &self.path, &*self.loader)?;
+        let fingerprint = grammar.fingerprint(self.cx);
+        let generation = state.changelog.last().map_or(0, |reload| reload.generation);
+        if fingerprint == state.fingerprint {
+            return Ok(Reload {
+                generation,
+                report: MigrationReport::default(),
+            });
+        }
+        let report =
+            versions::migration_report(self.cx, &self.current(), &grammar, self.max_sentence_len);
+
+        let reload = Reload {
+            generation: generation + 1,
+            report,
+        };
+        *self.current.write().unwrap() = Arc::new(grammar.freeze(self.cx));
+        state.fingerprint = fingerprint;
+        state.changelog.push(reload.clone());
+        Ok(reload)
+    }
+
+    /// Like `reload`, but only if the file was modified since it was last
+    /// (re)loaded (e.g. for polling it periodically).
+    pub fn reload_if_modified(&self) -> Result<Option<Reload>, ReloadError> {
+        let last = self.state.lock().unwrap().modified;
+        match modified(&self.path) {
+            Some(modified) if Some(modified) == last => Ok(None),
+            _ => self.reload().map(Some),
+        }
+    }
+
+    /// All the reloads which changed the grammar, oldest first.
+    pub fn changelog(&self) -> Vec<Reload> {
+        self.state.lock().unwrap().changelog.clone()
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load<Pat>(
+    cx: &Context<Pat>,
+    path: &Path,
+    loader: impl Fn(&Context<Pat>, &str) -> Result<Grammar, ReloadError>,
+) -> Result<Grammar, ReloadError>
+where
+    Pat: MatchesEmpty + fmt::Debug,
+{
+    let src = fs::read_to_string(path).map_err(ReloadError::Io)?;
+    let grammar = loader(cx, &src)?;
+    let diagnostics = grammar.validate(cx);
+    if diagnostics.iter().any(|d| d.severity() == Severity::Error) {
+        return Err(ReloadError::Invalid(diagnostics));
+    }
+    Ok(grammar)
+}
@@ -0,0 +1,150 @@
+//! Tracking how field paths (see `rule::FieldPath`) change when a grammar
+//! is transformed, so that code written against the original grammar (e.g.
+//! generated ASTs) can find its fields again, see `PathRemap`.
+
+use crate::compat::prelude::*;
+use crate::compat::IndexMap;
+use crate::context::{Context, IStr};
+use crate::lift::Origin;
+use crate::rule::FieldPath;
+use crate::Grammar;
+use core::hash::Hash;
+
+/// The paths to all the (outermost) named fields in every rule of a grammar,
+/// grouped by name (see `IFields::paths`), taken before transforming it.
+#[derive(Clone, Debug, Default)]
+pub struct FieldPaths {
+    rules: IndexMap<IStr, IndexMap<IStr, Vec<FieldPath>>>,
+}
+
+impl FieldPaths {
+    pub fn of<Pat: Eq + Hash>(cx: &Context<Pat>, grammar: &Grammar) -> Self {
+        FieldPaths {
+            rules: grammar
+                .rules()
+                .iter()
+                .map(|(&name, rule)| (name, rule.fields.paths(cx)))
+                .collect(),
+        }
+    }
+
+    /// The name of the field at `path` in `rule`, if there is one.
+    fn name_at(&self, rule: IStr, path: &FieldPath) -> Option<IStr> {
+        self.rules.get(&rule)?.iter().find_map(|(&name, paths)| {
+            if paths.contains(path) {
+                Some(name)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Where every field of an old grammar ended up in a new one, as a list of
+/// `(rule, path)` locations, which is empty if the field was removed, and can
+/// have more than one location, if its rule was duplicated (e.g. by
+/// `Grammar::instantiate_flags_remapped`).
+///
+/// Remaps of successive transformations can be combined with `then`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PathRemap {
+    map: IndexMap<(IStr, FieldPath), Vec<(IStr, FieldPath)>>,
+}
+
+impl PathRemap {
+    /// Match up the fields in `old` and `new`, by name (and in order, for
+    /// names found at more than one path), between each rule in `new`, and
+    /// the rule in `old` it came from, according to `origin`.
+    pub fn by_name(
+        old: &FieldPaths,
+        new: &FieldPaths,
+        origin: impl Fn(IStr) -> Option<IStr>,
+    ) -> Self {
+        let mut map: IndexMap<_, Vec<_>> = old
+            .rules
+            .iter()
+            .flat_map(|(&rule, fields)| {
+                fields
+                    .values()
+                    .flatten()
+                    .map(move |path| ((rule, path.clone()), vec![]))
+            })
+            .collect();
+        for (&new_rule, new_fields) in &new.rules {
+            let old_rule = match origin(new_rule) {
+                Some(old_rule) => old_rule,
+                None => continue,
+            };
+            let old_fields = match old.rules.get(&old_rule) {
+                Some(old_fields) => old_fields,
+                None => continue,
+            };
+            for (name, new_paths) in new_fields {
+                let old_paths = old_fields.get(name).map_or(&[][..], |paths| &paths[..]);
+                for (old_path, new_path) in old_paths.iter().zip(new_paths) {
+                    map[&(old_rule, old_path.clone())].push((new_rule, new_path.clone()));
+                }
+            }
+        }
+        PathRemap { map }
+    }
+
+    /// Map the fields of a grammar to their locations after `Grammar::lift_inline`,
+    /// which moves fields from inside lifted parts to their new rules, given
+    /// `origins` (as returned by `lift_inline`).
+    pub fn lifted(old: &FieldPaths, new: &FieldPaths, origins: &IndexMap<IStr, Origin>) -> Self {
+        let mut remap = PathRemap::by_name(old, &FieldPaths::default(), |_| None);
+        for (&new_rule, new_fields) in &new.rules {
+            // NOTE lifting replaces parts of a rule with `Call`s in
+            // the same place, so nothing else in the rule moves, and the
+            // paths in lifted rules only need the path to the lifted part.
+            let (old_rule, prefix) = match origins.get(&new_rule) {
+                Some(origin) => (origin.rule, &origin.path[..]),
+                None => (new_rule, &[][..]),
+            };
+            for (&name, new_paths) in new_fields {
+                for new_path in new_paths {
+                    let old_path = FieldPath::from([prefix, new_path.as_slice()].concat());
+                    if old.name_at(old_rule, &old_path) == Some(name) {
+                        remap.map[&(old_rule, old_path)].push((new_rule, new_path.clone()));
+                    }
+                }
+            }
+        }
+        remap
+    }
+
+    /// The locations of the field found at `path` in `rule`, in the old
+    /// grammar (or `None`, if there was no field there).
+    pub fn get(&self, rule: IStr, path: &FieldPath) -> Option<&[(IStr, FieldPath)]> {
+        self.map
+            .get(&(rule, path.clone()))
+            .map(|locations| &locations[..])
+    }
+
+    /// All the fields in the old grammar, with their locations.
+    pub fn iter(&self) -> impl Iterator<Item = (&(IStr, FieldPath), &[(IStr, FieldPath)])> {
+        self.map
+            .iter()
+            .map(|(field, locations)| (field, &locations[..]))
+    }
+
+    /// Combine this remap with `next`, which starts from the grammar this
+    /// one ends at, into a remap from this one's old grammar to `next`'s new one.
+    pub fn then(&self, next: &PathRemap) -> PathRemap {
+        PathRemap {
+            map: self
+                .map
+                .iter()
+                .map(|(field, locations)| {
+                    let locations = locations
+                        .iter()
+                        .flat_map(|(rule, path)| next.get(*rule, path).unwrap_or_default())
+                        .cloned()
+                        .collect();
+                    (field.clone(), locations)
+                })
+                .collect(),
+        }
+    }
+}
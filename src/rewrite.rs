@@ -0,0 +1,307 @@
+//! Pattern-based rewriting of rules, e.g. replacing every `("," X)*`
+//! with `X* % ","`, without having to write a `Folder` for each rewrite.
+
+use crate::compat::prelude::*;
+use crate::compat::HashMap;
+use crate::context::{Context, IFields, IRule, IStr};
+use crate::rule::{fold_or_cases, seq, Fields, Folder, Rule, RuleWithFields, SepKind};
+use core::hash::Hash;
+
+/// A rewrite from `pattern` to `replacement`, where `Call`s to any of the
+/// `wildcards` names act as placeholders (instead of calling rules):
+/// in `pattern` they match any rule (but the same one, for each use of
+/// the same wildcard), and in `replacement` they stand for what they matched.
+///
+/// Fields are preserved: a rule only matches `pattern` if it has the same
+/// fields (except for anything matched by wildcards, which keeps its fields).
+pub struct Rewrite {
+    pub wildcards: Vec<IStr>,
+    pub pattern: RuleWithFields,
+    pub replacement: RuleWithFields,
+}
+
+pub type Bindings = HashMap<IStr, RuleWithFields>;
+
+impl Rewrite {
+    pub fn new<Pat: Eq + Hash>(
+        cx: &Context<Pat>,
+        wildcards: &[&str],
+        pattern: RuleWithFields,
+        replacement: RuleWithFields,
+    ) -> Self {
+        let rewrite = Rewrite {
+            wildcards: wildcards.iter().map(|&name| cx.intern(name)).collect(),
+            pattern,
+            replacement,
+        };
+
+        let mut in_pattern = vec![];
+        rewrite.collect_wildcards(cx, pattern, &mut in_pattern);
+        let mut in_replacement = vec![];
+        rewrite.collect_wildcards(cx, replacement, &mut in_replacement);
+        for name in in_replacement {
+            assert!(
+                in_pattern.contains(&name),
+                "wildcard `{}` used in replacement, but not in pattern",
+                &cx[name]
+            );
+        }
+
+        rewrite
+    }
+
+    fn collect_wildcards<Pat: Eq + Hash>(
+        &self,
+        cx: &Context<Pat>,
+        rule: RuleWithFields,
+        out: &mut Vec<IStr>,
+    ) {
+        struct Collector<'a, 'cx, Pat> {
+            cx: &'cx Context<Pat>,
+            rewrite: &'a Rewrite,
+            out: &'a mut Vec<IStr>,
+        }
+
+        impl<'cx, Pat: Eq + Hash> Folder<'cx, Pat> for Collector<'_, 'cx, Pat> {
+            fn cx(&self) -> &'cx Context<Pat> {
+                self.cx
+            }
+            fn fold_leaf(&mut self, rule: RuleWithFields) -> RuleWithFields {
+                if let Some(name) = self.rewrite.wildcard(self.cx, rule) {
+                    self.out.push(name);
+                }
+                rule
+            }
+        }
+
+        rule.fold(&mut Collector {
+            cx,
+            rewrite: self,
+            out,
+        });
+    }
+
+    fn wildcard<Pat>(&self, cx: &Context<Pat>, rule: RuleWithFields) -> Option<IStr> {
+        match cx[rule.rule] {
+            Rule::Call(name) if self.wildcards.contains(&name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Match `rule` (only at the top-level) against the pattern,
+    /// returning what each of the wildcards matched, on success.
+    pub fn matches<Pat: Eq + Hash>(
+        &self,
+        cx: &Context<Pat>,
+        rule: RuleWithFields,
+    ) -> Option<Bindings> {
+        let mut bindings = HashMap::default();
+        if self.match_into(cx, self.pattern, rule, &mut bindings) {
+            Some(bindings)
+        } else {
+            None
+        }
+    }
+
+    fn match_into<Pat: Eq + Hash>(
+        &self,
+        cx: &Context<Pat>,
+        pattern: RuleWithFields,
+        rule: RuleWithFields,
+        bindings: &mut Bindings,
+    ) -> bool {
+        if cx[pattern.fields] == Fields::Leaf(None) {
+            if let Some(name) = self.wildcard(cx, pattern) {
+                let bound = *bindings.entry(name).or_insert(rule);
+                return (bound.rule, bound.fields) == (rule.rule, rule.fields);
+            }
+        }
+
+        match (&cx[pattern.fields], &cx[rule.fields]) {
+            (Fields::Leaf(Some(a)), Fields::Leaf(Some(b))) => {
+                return a.name == b.name
+                    && self.match_into(
+                        cx,
+                        RuleWithFields {
+                            rule: pattern.rule,
+                            fields: a.sub,
+                        },
+                        RuleWithFields {
+                            rule: rule.rule,
+                            fields: b.sub,
+                        },
+                        bindings,
+                    );
+            }
+            (Fields::Leaf(Some(_)), _) | (_, Fields::Leaf(Some(_))) => return false,
+            _ => {}
+        }
+
+        let mut children = |a: &[IRule], b: &[IRule]| {
+            self.match_children(cx, (pattern.fields, a), (rule.fields, b), bindings)
+        };
+        match (&cx[pattern.rule], &cx[rule.rule]) {
+            (Rule::Empty, _)
+            | (Rule::Anchor(_), _)
+            | (Rule::Eat(_), _)
+            | (Rule::EatUntil(_), _)
+            | (Rule::Call(_), _)
+            | (Rule::FlagGuard(..), _)
+            | (Rule::Guard(_), _)
+            | (Rule::Feature(..), _) => pattern.rule == rule.rule,
+            (&Rule::WithFlag(a_flag, a_value, a), &Rule::WithFlag(b_flag, b_value, b)) => {
+                (a_flag, a_value) == (b_flag, b_value) && children(&[a], &[b])
+            }
+            (Rule::Concat(a), Rule::Concat(b)) | (Rule::Or(a), Rule::Or(b)) => children(a, b),
+            (&Rule::Silent(a), &Rule::Silent(b)) | (&Rule::Opt(a), &Rule::Opt(b)) => {
+                children(&[a], &[b])
+            }
+            (&Rule::RepeatMany(a, None), &Rule::RepeatMany(b, None))
+            | (&Rule::RepeatMore(a, None), &Rule::RepeatMore(b, None)) => children(&[a], &[b]),
+            (
+                &Rule::RepeatMany(a, Some((a_sep, a_kind))),
+                &Rule::RepeatMany(b, Some((b_sep, b_kind))),
+            )
+            | (
+                &Rule::RepeatMore(a, Some((a_sep, a_kind))),
+                &Rule::RepeatMore(b, Some((b_sep, b_kind))),
+            ) => a_kind == b_kind && children(&[a, a_sep], &[b, b_sep]),
+            _ => false,
+        }
+    }
+
+    fn match_children<Pat: Eq + Hash>(
+        &self,
+        cx: &Context<Pat>,
+        (pattern_fields, pattern_children): (IFields, &[IRule]),
+        (rule_fields, rule_children): (IFields, &[IRule]),
+        bindings: &mut Bindings,
+    ) -> bool {
+        pattern_children.len() == rule_children.len()
+            && pattern_children
+                .iter()
+                .zip(rule_children)
+                .enumerate()
+                .all(|(i, (&a, &b))| {
+                    let a = RuleWithFields {
+                        rule: a,
+                        fields: pattern_fields.child(cx, i),
+                    };
+                    let b = RuleWithFields {
+                        rule: b,
+                        fields: rule_fields.child(cx, i),
+                    };
+                    self.match_into(cx, a, b, bindings)
+                })
+    }
+
+    /// Replace the wildcards in the replacement with what they matched.
+    pub fn substitute<Pat: Eq + Hash>(
+        &self,
+        cx: &Context<Pat>,
+        bindings: &Bindings,
+    ) -> RuleWithFields {
+        struct Substitute<'a, 'cx, Pat> {
+            cx: &'cx Context<Pat>,
+            rewrite: &'a Rewrite,
+            bindings: &'a Bindings,
+        }
+
+        impl<'cx, Pat: Eq + Hash> Folder<'cx, Pat> for Substitute<'_, 'cx, Pat> {
+            fn cx(&self) -> &'cx Context<Pat> {
+                self.cx
+            }
+            fn fold_leaf(&mut self, rule: RuleWithFields) -> RuleWithFields {
+                match self.rewrite.wildcard(self.cx, rule) {
+                    Some(name) => self.bindings[&name],
+                    None => rule,
+                }
+            }
+        }
+
+        self.replacement.fold(&mut Substitute {
+            cx,
+            rewrite: self,
+            bindings,
+        })
+    }
+
+    /// Apply the rewrite everywhere in `rule`, bottom-up, i.e. the parts
+    /// of a rule are rewritten before trying to match the whole rule.
+    pub fn apply<Pat: Eq + Hash>(&self, cx: &Context<Pat>, rule: RuleWithFields) -> RuleWithFields {
+        rule.fold(&mut Rewriter { cx, rewrite: self })
+    }
+}
+
+pub(crate) struct Rewriter<'a, 'cx, Pat> {
+    pub(crate) cx: &'cx Context<Pat>,
+    pub(crate) rewrite: &'a Rewrite,
+}
+
+impl<Pat: Eq + Hash> Rewriter<'_, '_, Pat> {
+    fn try_rewrite(&self, rule: RuleWithFields) -> RuleWithFields {
+        match self.rewrite.matches(self.cx, rule) {
+            Some(bindings) => self.rewrite.substitute(self.cx, &bindings),
+            None => rule,
+        }
+    }
+}
+
+impl<'cx, Pat: Eq + Hash> Folder<'cx, Pat> for Rewriter<'_, 'cx, Pat> {
+    fn cx(&self) -> &'cx Context<Pat> {
+        self.cx
+    }
+    fn fold_leaf(&mut self, rule: RuleWithFields) -> RuleWithFields {
+        self.try_rewrite(rule)
+    }
+    fn fold_concat(&mut self, elems: impl Iterator<Item = RuleWithFields>) -> RuleWithFields {
+        let cx = self.cx;
+        let rule = seq(elems.map(|elem| elem.fold(self))).finish(cx);
+        self.try_rewrite(rule)
+    }
+    fn fold_or(&mut self, cases: impl Iterator<Item = (usize, RuleWithFields)>) -> RuleWithFields {
+        let rule = fold_or_cases(self, cases);
+        self.try_rewrite(rule)
+    }
+    fn fold_with_flag(&mut self, flag: IStr, value: bool, rule: RuleWithFields) -> RuleWithFields {
+        let rule = rule.fold(self);
+        let rule = if value {
+            rule.with_flag(&self.cx[flag]).finish(self.cx)
+        } else {
+            rule.without_flag(&self.cx[flag]).finish(self.cx)
+        };
+        self.try_rewrite(rule)
+    }
+    fn fold_silent(&mut self, rule: RuleWithFields) -> RuleWithFields {
+        let rule = rule.fold(self).silent().finish(self.cx);
+        self.try_rewrite(rule)
+    }
+    fn fold_opt(&mut self, rule: RuleWithFields) -> RuleWithFields {
+        let rule = rule.fold(self).opt().finish(self.cx);
+        self.try_rewrite(rule)
+    }
+    fn fold_repeat_many(
+        &mut self,
+        elem: RuleWithFields,
+        sep: Option<(RuleWithFields, SepKind)>,
+    ) -> RuleWithFields {
+        let elem = elem.fold(self);
+        let rule = match sep.map(|(sep, kind)| (sep.fold(self), kind)) {
+            None => elem.repeat_many().finish(self.cx),
+            Some((sep, kind)) => elem.repeat_many_sep(sep, kind).finish(self.cx),
+        };
+        self.try_rewrite(rule)
+    }
+    fn fold_repeat_more(
+        &mut self,
+        elem: RuleWithFields,
+        sep: Option<(RuleWithFields, SepKind)>,
+    ) -> RuleWithFields {
+        let elem = elem.fold(self);
+        let rule = match sep.map(|(sep, kind)| (sep.fold(self), kind)) {
+            None => elem.repeat_more().finish(self.cx),
+            Some((sep, kind)) => elem.repeat_more_sep(sep, kind).finish(self.cx),
+        };
+        self.try_rewrite(rule)
+    }
+}
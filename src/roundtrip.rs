@@ -0,0 +1,272 @@
+//! Checking that grammars survive being exported to (and imported back from)
+//! every format supporting both, by comparing the sentences (up to a bounded
+//! length, see `enumerate::sentences`) of every rule before and after.
+//!
+//! Converters are expected to be lossy in places (see e.g. `pest::Imported`),
+//! so this is meant to be run over a corpus of grammars known to convert
+//! cleanly, to catch (otherwise silent) changes to the languages they accept.
+
+use crate::enumerate::{self, Sentence};
+use crate::{ebnf, pest, scannerless, Grammar};
+use std::fmt;
+
+type Context = scannerless::Context<String>;
+
+/// A grammar format with both an importer and an exporter.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// `.pest` files (see `pest::import` and `pest::export`).
+    Pest,
+    /// W3C EBNF (see `ebnf::import` and `ebnf::export`).
+    Ebnf,
+    /// tree-sitter `grammar.json` files (see `tree_sitter::import` and
+    /// `tree_sitter::export`).
+    #[cfg(feature = "tree-sitter")]
+    TreeSitter,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Pest => "pest",
+            Format::Ebnf => "ebnf",
+            #[cfg(feature = "tree-sitter")]
+            Format::TreeSitter => "tree-sitter",
+        })
+    }
+}
+
+impl Format {
+    /// All the formats available (which depends on enabled features).
+    pub fn all() -> Vec<Format> {
+        vec![
+            Format::Pest,
+            Format::Ebnf,
+            #[cfg(feature = "tree-sitter")]
+            Format::TreeSitter,
+        ]
+    }
+
+    pub fn import(self, cx: &Context, src: &str) -> Result<Grammar, String> {
+        match self {
+            Format::Pest => pest::import(cx, src)
+                .map(|imported| imported.grammar)
+                .map_err(|e| e.to_string()),
+            Format::Ebnf => ebnf::import(cx, src).map_err(|e| e.to_string()),
+            #[cfg(feature = "tree-sitter")]
+            Format::TreeSitter => {
+                let mut imported =
+                    crate::tree_sitter::import(cx, src).map_err(|e| e.to_string())?;
+                // NOTE `PATTERN`s are left undefined by the importer, but
+                // the ones `tree_sitter::export` produces for ranges can
+                // be turned back into them, for a lossless round-trip.
+                for (&name, regex) in &imported.patterns {
+                    if let Some(range) = range_pattern(regex) {
+                        imported
+                            .grammar
+                            .define(name, crate::rule::eat(range).finish(cx));
+                    }
+                }
+                Ok(imported.grammar)
+            }
+        }
+    }
+
+    pub fn export(self, cx: &Context, grammar: &Grammar) -> Result<String, String> {
+        match self {
+            Format::Pest => pest::export(cx, grammar).map_err(|e| e.to_string()),
+            Format::Ebnf => ebnf::export(cx, grammar).map_err(|e| e.to_string()),
+            #[cfg(feature = "tree-sitter")]
+            Format::TreeSitter => crate::tree_sitter::export(cx, grammar, "roundtrip")
+                .map(|json| json.to_string())
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Parse a regex of the form `[a-z]`, as `tree_sitter::export` produces
+/// for `Pat::Range`, back into the range.
+#[cfg(feature = "tree-sitter")]
+fn range_pattern(regex: &str) -> Option<std::ops::RangeInclusive<char>> {
+    fn char(chars: &mut std::str::Chars<'_>) -> Option<char> {
+        match chars.next()? {
+            '\\' => match chars.next()? {
+                'u' => {
+                    let hex = chars.as_str().strip_prefix('{')?.split('}').next()?;
+                    let c = char::from_u32(u32::from_str_radix(hex, 16).ok()?)?;
+                    chars.nth(hex.len() + 1)?;
+                    Some(c)
+                }
+                c => Some(c),
+            },
+            '-' | '[' | ']' | '^' => None,
+            c => Some(c),
+        }
+    }
+    let mut chars = regex.strip_prefix('[')?.strip_suffix(']')?.chars();
+    let start = char(&mut chars)?;
+    if chars.next()? != '-' {
+        return None;
+    }
+    let end = char(&mut chars)?;
+    if chars.next().is_some() {
+        return None;
+    }
+    Some(start..=end)
+}
+
+#[derive(Clone, Debug)]
+pub enum Error {
+    /// The original source couldn't be imported.
+    Import(String),
+    Export(String),
+    /// The exported source couldn't be imported back.
+    Reimport(String),
+    /// A rule of the original grammar is missing after the round-trip
+    /// (new rules, e.g. pest's `WHITESPACE`, are ignored, however).
+    MissingRule(String),
+    /// The first (i.e. shortest) sentence found to be accepted by `rule`
+    /// before the round-trip but not after (if `lost`), or vice versa.
+    Diverged {
+        rule: String,
+        sentence: String,
+        lost: bool,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Import(err) => write!(f, "import failed: {}", err),
+            Error::Export(err) => write!(f, "export failed: {}", err),
+            Error::Reimport(err) => write!(f, "re-import failed: {}", err),
+            Error::MissingRule(rule) => write!(f, "rule `{}` is missing", rule),
+            Error::Diverged {
+                rule,
+                sentence,
+                lost,
+            } => write!(
+                f,
+                "`{}` {} `{}`",
+                rule,
+                if *lost {
+                    "no longer accepts"
+                } else {
+                    "now also accepts"
+                },
+                sentence
+            ),
+        }
+    }
+}
+
+/// Export `grammar` as `via`, import the result back, and check that every
+/// rule in `grammar` still accepts the same sentences (of at most `max_len`
+/// terminals), returning the re-imported grammar if so.
+///
+/// Like `enumerate::sentences`, this ignores the grammar's trivia.
+pub fn round_trip(
+    cx: &Context,
+    grammar: &Grammar,
+    via: Format,
+    max_len: usize,
+) -> Result<Grammar, Error> {
+    let exported = via.export(cx, grammar).map_err(Error::Export)?;
+    let reimported = via.import(cx, &exported).map_err(Error::Reimport)?;
+
+    let before = enumerate::sentences(cx, grammar, max_len);
+    let after = enumerate::sentences(cx, &reimported, max_len);
+    let desc = |sentence: &Sentence| {
+        let terminals: Vec<_> = sentence.iter().map(|t| t.node_desc(cx)).collect();
+        terminals.join(" ")
+    };
+    for (&name, before) in &before {
+        let after = after
+            .get(&name)
+            .ok_or_else(|| Error::MissingRule(cx[name].to_string()))?;
+        let lost = before.iter().find(|s| !after.contains(*s));
+        let gained = after.iter().find(|s| !before.contains(*s));
+        // NOTE sentences are enumerated shortest derivations first,
+        // so the shorter of the two is reported, for a smaller reproduction.
+        let divergence = match (lost, gained) {
+            (Some(lost), Some(gained)) if gained.len() < lost.len() => Some((gained, false)),
+            (Some(lost), _) => Some((lost, true)),
+            (None, Some(gained)) => Some((gained, false)),
+            (None, None) => None,
+        };
+        if let Some((sentence, lost)) = divergence {
+            return Err(Error::Diverged {
+                rule: cx[name].to_string(),
+                sentence: desc(sentence),
+                lost,
+            });
+        }
+    }
+    Ok(reimported)
+}
+
+/// A round-trip failure (see `check_corpus`).
+#[derive(Clone, Debug)]
+pub struct Failure {
+    /// The position of the failing source in the corpus.
+    pub index: usize,
+    pub from: Format,
+    /// The format round-tripped through (`None` if importing failed).
+    pub via: Option<Format>,
+    pub error: Error,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{} ({}", self.index, self.from)?;
+        if let Some(via) = self.via {
+            write!(f, " -> {}", via)?;
+        }
+        write!(f, "): {}", self.error)
+    }
+}
+
+/// Import every source in `corpus` (each paired with its format), and
+/// round-trip it through every format (see `round_trip`), returning all
+/// the failures (one per source and format, at most).
+pub fn check_corpus(corpus: &[(Format, &str)], max_len: usize) -> Vec<Failure> {
+    let mut failures = vec![];
+    for (index, &(from, src)) in corpus.iter().enumerate() {
+        // NOTE a fresh `Context` keeps memory usage from piling up,
+        // as nothing is shared between sources.
+        let cx = Context::new();
+        let grammar = match from.import(&cx, src) {
+            Ok(grammar) => grammar,
+            Err(err) => {
+                failures.push(Failure {
+                    index,
+                    from,
+                    via: None,
+                    error: Error::Import(err),
+                });
+                continue;
+            }
+        };
+        for via in Format::all() {
+            if let Err(error) = round_trip(&cx, &grammar, via, max_len) {
+                failures.push(Failure {
+                    index,
+                    from,
+                    via: Some(via),
+                    error,
+                });
+            }
+        }
+    }
+    failures
+}
+
+/// Like `check_corpus`, but panicking with all the failures (if any), for
+/// use in tests.
+pub fn assert_corpus(corpus: &[(Format, &str)], max_len: usize) {
+    let failures = check_corpus(corpus, max_len);
+    if !failures.is_empty() {
+        let failures: Vec<_> = failures.iter().map(|f| f.to_string()).collect();
+        panic!("round-trip failures:\n{}", failures.join("\n"));
+    }
+}
@@ -1,14 +1,16 @@
+use crate::compat::prelude::*;
+use crate::compat::{IndexMap, IndexSet};
 use crate::context::{Context, IFields, IRule, IStr};
+use crate::diagnostics::{self, Code, Diagnostic};
 use crate::forest::NodeShape;
-use indexmap::IndexMap;
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::fmt;
-use std::hash::Hash;
-use std::iter;
-use std::ops::{Add, BitAnd, BitOr};
-
-#[derive(Copy, Clone)]
+use alloc::collections::btree_map::Entry;
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::iter;
+use core::ops::{Add, BitAnd, BitOr, RangeInclusive};
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct RuleWithFields {
     pub rule: IRule,
     pub fields: IFields,
@@ -17,16 +19,190 @@ pub struct RuleWithFields {
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum SepKind {
     Simple,
+    /// A separator is also allowed after the last element.
     Trailing,
+    /// A separator is also allowed before the first element, e.g. the `|`
+    /// before the first case of a `match` arm, or of a union type.
+    Leading,
+    /// Both `Leading` and `Trailing`.
+    Both,
+}
+
+impl SepKind {
+    pub fn allows_leading(self) -> bool {
+        matches!(self, SepKind::Leading | SepKind::Both)
+    }
+
+    pub fn allows_trailing(self) -> bool {
+        matches!(self, SepKind::Trailing | SepKind::Both)
+    }
+
+    /// The operator for this kind of separator in rule descriptions (see
+    /// `IRule::node_desc`), i.e. `%` and `%%` (for `Trailing`), prefixed
+    /// by `^` for `Leading` (and `Both`), e.g. `A* ^% B`.
+    pub(crate) fn op(self) -> &'static str {
+        match self {
+            SepKind::Simple => "%",
+            SepKind::Trailing => "%%",
+            SepKind::Leading => "^%",
+            SepKind::Both => "^%%",
+        }
+    }
+}
+
+/// How a separated repetition treats the edge cases of its input, i.e. no
+/// elements at all, a lone separator (with no elements, e.g. `(,)`), and
+/// a trailing separator (see `SepKind`), for `repeat_sep`, e.g. pest-like
+/// `%` and `%%` are `SepFlags::new(SepKind::Simple)` and `Trailing`, with
+/// `allow_empty` for `*` (instead of `+`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SepFlags {
+    pub kind: SepKind,
+    /// Whether no elements are accepted, i.e. `repeat_many_sep`, instead
+    /// of `repeat_more_sep`.
+    pub allow_empty: bool,
+    /// Whether a separator on its own is accepted (in addition to any
+    /// number of elements, as per the other flags).
+    pub allow_lone_sep: bool,
+}
+
+impl SepFlags {
+    /// At least one element, separated by separators of `kind`.
+    pub fn new(kind: SepKind) -> Self {
+        SepFlags {
+            kind,
+            allow_empty: false,
+            allow_lone_sep: false,
+        }
+    }
+}
+
+/// Whether trivia (see `Grammar::set_trivia`) is allowed in a rule, like
+/// pest's `@`, `$` and `!` rule modifiers.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Atomicity {
+    /// Trivia is allowed, unless the rule is only ever called (directly
+    /// or indirectly) from atomic rules.
+    #[default]
+    Inherited,
+    /// No trivia in the rule, or in the rules it calls (unless they're
+    /// `NonAtomic`), whose derivations are also left out (pest's `@`).
+    Atomic,
+    /// Like `Atomic`, but keeping the derivations of the rules it calls
+    /// (pest's `$`).
+    CompoundAtomic,
+    /// Trivia is allowed, even when called from atomic rules (pest's `!`).
+    NonAtomic,
+}
+
+/// Per-rule modifiers (see `Grammar::set_modifiers`).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Modifiers {
+    pub atomicity: Atomicity,
+    /// The rule is left out of derivations, with its children (and fields)
+    /// taking its place in the derivation of its caller (pest's `_`).
+    pub silent: bool,
+    /// The terminals in the rule (but not in the rules it calls) match
+    /// regardless of case, e.g. for SQL keywords (like pest's `^"..."`).
+    pub ignore_case: bool,
+    /// The rule is an alternation of tokens which is meant to cover all of
+    /// them, e.g. one case per variant of a token enum, which is checked by
+    /// `tokens::check_exhaustive`, once the full set of tokens is known.
+    pub exhaustive: bool,
+    /// How the rule's derivations are shaped, in the derivations of the
+    /// rules calling it (see `Shape`), other than `Flatten` (i.e. `silent`).
+    pub shape: Shape,
+    /// The rule can be extended with more alternatives (see
+    /// `Grammar::extend_rule`), e.g. by grammars for language extensions.
+    pub open: bool,
+    /// The rule is reserved syntax (see `Grammar::reserve`), e.g. `=>` kept
+    /// for future use, which still matches, but deriving it is an error (see
+    /// `interpreter::Error::Reserved`), with this message.
+    pub reserved: Option<IStr>,
+    /// A human-readable name for the rule (see `Grammar::set_label`), e.g.
+    /// "a statement", to report instead of the terminals it starts with,
+    /// when it's expected, but fails to match (see `interpreter::Failure`).
+    pub label: Option<IStr>,
+}
+
+/// Directives for shaping derivations (see `interpreter::Derivation`) while
+/// they're built, to leave out nodes only there due to grammar structure,
+/// for rules (see `Modifiers::shape`) and fields (see `Grammar::set_field_shape`).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Shape {
+    #[default]
+    Keep,
+    /// Left out entirely, along with everything nested in it.
+    Skip,
+    /// Replaced by everything nested in it, i.e. its children and fields
+    /// (for rules, an alias for `Modifiers::silent`, see `Grammar::set_modifiers`),
+    /// or its nested fields.
+    Flatten,
+    /// Replaced by the one thing nested in it, if there's only one, i.e. a
+    /// child (for rules with no fields), or a nested field (for fields).
+    Lift,
+}
+
+impl Modifiers {
+    pub fn is_default(self) -> bool {
+        self == Modifiers::default()
+    }
+}
+
+/// Zero-width assertions about the position in the input.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Anchor {
+    /// Start of the input (`SOI`).
+    Start,
+    /// End of the input (`EOI`).
+    End,
+    /// Start of a line, after any indentation, at exactly this
+    /// (0-based) column, with tabs expanded (see `input::indentation_width`),
+    /// e.g. for layout-sensitive grammars.
+    ///
+    /// Only supported by inputs with a notion of lines and columns
+    /// (see `Input::line_column`), and never matches otherwise.
+    Column(usize),
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Rule<Pat> {
     Empty,
+    Anchor(Anchor),
     Eat(Pat),
+    /// Consume input up to (but not including) the first position at which
+    /// the pattern matches, or the end of the input, if there's no such
+    /// position, e.g. for the contents of comments (see `until`).
+    EatUntil(Pat),
     Call(IStr),
 
-    Concat([IRule; 2]),
+    /// Zero-width, only matching if the flag is set (or unset, for `false`),
+    /// see `Grammar::instantiate_flags`.
+    FlagGuard(IStr, bool),
+    /// Zero-width, only matching if the user-supplied predicate of the same
+    /// name (see `parser::Guards`) holds, at that position in the input.
+    /// As that's not known statically, analyses have to assume either way.
+    Guard(IStr),
+    /// Zero-width, only matching if the feature is enabled (or disabled,
+    /// for `false`), see `Grammar::configure`.
+    Feature(IStr, bool),
+    /// Set (or unset, for `false`) a flag for the inner rule, and the rules
+    /// it calls (flags are inherited through `Call`s), e.g. `Expr<+NoStruct>`.
+    WithFlag(IStr, bool, IRule),
+    /// Match the inner rule as a single opaque node, with none of its
+    /// structure or fields exposed (like pest's silent rules), e.g. for
+    /// keywords or punctuation, so they don't need any bookkeeping.
+    Silent(IRule),
+    /// Match the first rule (e.g. the `#`s of a raw string, or the tag of a
+    /// heredoc), capturing the input it matched under the name, then the
+    /// second rule, in which (and in the rules it calls) `Backref`s to that
+    /// name match exactly the same input again, e.g. for closing delimiters.
+    Capture(IStr, IRule, IRule),
+    /// Match exactly the input captured by the innermost `Capture` of the
+    /// same name (failing outside of any such `Capture`).
+    Backref(IStr),
+
+    Concat(Vec<IRule>),
     Or(Vec<IRule>),
 
     Opt(IRule),
@@ -70,6 +246,147 @@ impl Fields {
     }
 }
 
+/// Path of child indices from the root of a `Fields` tree, down through
+/// `Fields::Aggregate` nodes, e.g. `1.0` is the first child of the second child.
+// FIXME nearly all paths are short, use a small-vec representation.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FieldPath(Vec<usize>);
+
+impl FieldPath {
+    pub fn root() -> Self {
+        FieldPath(vec![])
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[usize] {
+        &self.0
+    }
+
+    /// Returns the same path, but starting from a parent node,
+    /// of which the current root is the `idx`-th child.
+    pub fn prepended(&self, idx: usize) -> Self {
+        FieldPath(iter::once(idx).chain(self.0.iter().cloned()).collect())
+    }
+
+    /// Returns the path to the `idx`-th child of the node at this path.
+    pub fn appended(&self, idx: usize) -> Self {
+        FieldPath(self.0.iter().cloned().chain(iter::once(idx)).collect())
+    }
+
+    /// Returns the path relative to the `idx`-th child of the root,
+    /// if this path goes through that child, or `None` otherwise.
+    pub fn strip_prefix(&self, idx: usize) -> Option<Self> {
+        match self.0.split_first() {
+            Some((&first, rest)) if first == idx => Some(FieldPath(rest.to_vec())),
+            _ => None,
+        }
+    }
+}
+
+impl From<Vec<usize>> for FieldPath {
+    fn from(path: Vec<usize>) -> Self {
+        FieldPath(path)
+    }
+}
+
+impl fmt::Display for FieldPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut path = self.0.iter();
+        match path.next() {
+            None => f.write_str("."),
+            Some(first) => {
+                write!(f, "{}", first)?;
+                for i in path {
+                    write!(f, ".{}", i)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for FieldPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl IFields {
+    /// Returns the fields of the `idx`-th child, which are empty for
+    /// leaves, and when the aggregate has no fields past `idx`.
+    pub fn child<Pat: Eq + Hash>(self, cx: &Context<Pat>, idx: usize) -> IFields {
+        match cx[self] {
+            Fields::Aggregate(ref children) => children.get(idx).cloned(),
+            Fields::Leaf(_) => None,
+        }
+        .unwrap_or_else(|| cx.intern(Fields::Leaf(None)))
+    }
+
+    /// Returns the field found at the end of `path`, if any.
+    pub fn get<Pat: Eq + Hash>(self, cx: &Context<Pat>, path: &FieldPath) -> Option<Field> {
+        let fields = path
+            .as_slice()
+            .iter()
+            .fold(self, |fields, &i| fields.child(cx, i));
+        match cx[fields] {
+            Fields::Leaf(field) => field,
+            Fields::Aggregate(_) => None,
+        }
+    }
+
+    /// Collects the names of all the fields, including those nested in
+    /// other fields (unlike `paths`), in the order they're first found in.
+    pub fn names<Pat>(self, cx: &Context<Pat>) -> IndexSet<IStr> {
+        let mut names = IndexSet::default();
+        self.collect_names(cx, &mut names);
+        names
+    }
+
+    fn collect_names<Pat>(self, cx: &Context<Pat>, names: &mut IndexSet<IStr>) {
+        match cx[self] {
+            Fields::Leaf(None) => {}
+            Fields::Leaf(Some(field)) => {
+                names.insert(field.name);
+                field.sub.collect_names(cx, names);
+            }
+            Fields::Aggregate(ref children) => {
+                for &child in children {
+                    child.collect_names(cx, names);
+                }
+            }
+        }
+    }
+
+    /// Collects the paths to all the (outermost) named fields, grouped by name.
+    /// The same name can appear at more than one path, e.g. in several
+    /// cases of an `Or`, so each name maps to all of its paths.
+    pub fn paths<Pat>(self, cx: &Context<Pat>) -> IndexMap<IStr, Vec<FieldPath>> {
+        let mut paths = IndexMap::default();
+        self.collect_paths(cx, &FieldPath::root(), &mut paths);
+        paths
+    }
+
+    fn collect_paths<Pat>(
+        self,
+        cx: &Context<Pat>,
+        prefix: &FieldPath,
+        paths: &mut IndexMap<IStr, Vec<FieldPath>>,
+    ) {
+        match cx[self] {
+            Fields::Leaf(None) => {}
+            Fields::Leaf(Some(field)) => paths.entry(field.name).or_default().push(prefix.clone()),
+            Fields::Aggregate(ref children) => {
+                for (i, &child) in children.iter().enumerate() {
+                    child.collect_paths(cx, &prefix.appended(i), paths);
+                }
+            }
+        }
+    }
+}
+
 /// Helpers for building rules without needing a `Context` until the very end.
 ///
 /// NOTE: the module is private to disallow referring to the trait / types,
@@ -95,6 +412,26 @@ mod build {
 
     pub trait Finish<Pat> {
         fn finish(self, cx: &Context<Pat>) -> RuleWithFields;
+
+        /// Finish as the elements of a `Concat`, appending them to `elems`.
+        /// Only `Concat` overrides this, so that building long `a + b + c + ...`
+        /// chains doesn't rebuild (and intern) every intermediate `Concat`.
+        fn finish_concat_elems(self, cx: &Context<Pat>, elems: &mut Vec<RuleWithFields>)
+        where
+            Self: Sized,
+        {
+            elems.push(self.finish(cx));
+        }
+
+        /// Finish as the cases of an `Or`, appending them to `cases`.
+        /// Only `Or` overrides this, so that building long `a | b | c | ...`
+        /// chains doesn't rebuild (and intern) every intermediate `Or`.
+        fn finish_or_cases(self, cx: &Context<Pat>, cases: &mut Vec<RuleWithFields>)
+        where
+            Self: Sized,
+        {
+            cases.push(self.finish(cx));
+        }
     }
 
     impl<Pat> Finish<Pat> for RuleWithFields {
@@ -114,8 +451,74 @@ mod build {
         }
     }
 
+    pub struct AtAnchor(Anchor);
+
+    impl<Pat: Eq + Hash> Finish<Pat> for AtAnchor {
+        fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
+            RuleWithFields {
+                rule: cx.intern(Rule::Anchor(self.0)),
+                fields: cx.intern(Fields::Leaf(None)),
+            }
+        }
+    }
+
+    pub struct FlagGuard<'a>(&'a str, bool);
+
+    impl<Pat: Eq + Hash> Finish<Pat> for FlagGuard<'_> {
+        fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
+            RuleWithFields {
+                rule: cx.intern(Rule::FlagGuard(cx.intern(self.0), self.1)),
+                fields: cx.intern(Fields::Leaf(None)),
+            }
+        }
+    }
+
+    pub struct Feature<'a>(&'a str, bool);
+
+    impl<Pat: Eq + Hash> Finish<Pat> for Feature<'_> {
+        fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
+            RuleWithFields {
+                rule: cx.intern(Rule::Feature(cx.intern(self.0), self.1)),
+                fields: cx.intern(Fields::Leaf(None)),
+            }
+        }
+    }
+
+    pub struct Guard<'a>(&'a str);
+
+    impl<Pat: Eq + Hash> Finish<Pat> for Guard<'_> {
+        fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
+            RuleWithFields {
+                rule: cx.intern(Rule::Guard(cx.intern(self.0))),
+                fields: cx.intern(Fields::Leaf(None)),
+            }
+        }
+    }
+
+    pub struct Backref<'a>(&'a str);
+
+    impl<Pat: Eq + Hash> Finish<Pat> for Backref<'_> {
+        fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
+            RuleWithFields {
+                rule: cx.intern(Rule::Backref(cx.intern(self.0))),
+                fields: cx.intern(Fields::Leaf(None)),
+            }
+        }
+    }
+
     pub struct Eat<Pat>(Pat);
 
+    pub struct EatUntil<Pat>(Pat);
+
+    impl<Pat: Eq + Hash> Finish<Pat> for EatUntil<Pat> {
+        fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
+            RuleWithFields {
+                rule: cx.intern(Rule::EatUntil(self.0)),
+                fields: cx.intern(Fields::Leaf(None)),
+            }
+        }
+    }
+
     impl<Pat: Eq + Hash> Finish<Pat> for Eat<Pat> {
         fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
             RuleWithFields {
@@ -163,6 +566,43 @@ mod build {
         }
     }
 
+    pub struct WithFlag<'a, R>(R, &'a str, bool);
+
+    impl<Pat: Eq + Hash, R: Finish<Pat>> Finish<Pat> for WithFlag<'_, R> {
+        fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
+            let rule = self.0.finish(cx);
+            RuleWithFields {
+                rule: cx.intern(Rule::WithFlag(cx.intern(self.1), self.2, rule.rule)),
+                fields: Fields::aggregate(cx, iter::once(rule.fields)),
+            }
+        }
+    }
+
+    pub struct Capture<'a, D, B>(&'a str, D, B);
+
+    impl<Pat: Eq + Hash, D: Finish<Pat>, B: Finish<Pat>> Finish<Pat> for Capture<'_, D, B> {
+        fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
+            let delim = self.1.finish(cx);
+            let body = self.2.finish(cx);
+            RuleWithFields {
+                rule: cx.intern(Rule::Capture(cx.intern(self.0), delim.rule, body.rule)),
+                fields: Fields::aggregate(cx, [delim.fields, body.fields].into_iter()),
+            }
+        }
+    }
+
+    pub struct Silent<R>(R);
+
+    impl<Pat: Eq + Hash, R: Finish<Pat>> Finish<Pat> for Silent<R> {
+        fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
+            let rule = self.0.finish(cx);
+            RuleWithFields {
+                rule: cx.intern(Rule::Silent(rule.rule)),
+                fields: cx.intern(Fields::Leaf(None)),
+            }
+        }
+    }
+
     pub struct RepeatMany<E>(E);
 
     impl<Pat: Eq + Hash, E: Finish<Pat>> Finish<Pat> for RepeatMany<E> {
@@ -215,23 +655,121 @@ mod build {
         }
     }
 
+    /// `RepeatManySep` or `RepeatMoreSep` (depending on `allow_empty`), and
+    /// also a lone separator, if `allow_lone_sep` (see `SepFlags`).
+    pub struct RepeatSep<E, S>(E, S, SepFlags);
+
+    impl<Pat: Eq + Hash, E: Finish<Pat>, S: Finish<Pat>> Finish<Pat> for RepeatSep<E, S> {
+        fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
+            let RepeatSep(elem, sep, flags) = self;
+            let sep = sep.finish(cx);
+            let repeat = if flags.allow_empty {
+                RepeatManySep(elem, sep, flags.kind).finish(cx)
+            } else {
+                RepeatMoreSep(elem, sep, flags.kind).finish(cx)
+            };
+            if flags.allow_lone_sep {
+                finish_or(cx, [repeat, sep].into_iter())
+            } else {
+                repeat
+            }
+        }
+    }
+
+    /// Build a `Concat` out of `elems`, in one pass, dropping `Empty`s and
+    /// flattening any `Concat`s (that don't have a field on them as a whole).
+    fn finish_concat<Pat: Eq + Hash>(
+        cx: &Context<Pat>,
+        elems: impl Iterator<Item = RuleWithFields>,
+    ) -> RuleWithFields {
+        let empty = Empty.finish(cx);
+
+        let mut rules = vec![];
+        let mut fields = vec![];
+        for elem in elems {
+            match (&cx[elem.rule], &cx[elem.fields]) {
+                (Rule::Empty, Fields::Leaf(None)) => {}
+                (Rule::Concat(elem_rules), Fields::Leaf(None))
+                | (Rule::Concat(elem_rules), Fields::Aggregate(_)) => {
+                    rules.extend(elem_rules.iter().cloned());
+                    fields.extend((0..elem_rules.len()).map(|i| elem.fields.child(cx, i)));
+                }
+                _ => {
+                    rules.push(elem.rule);
+                    fields.push(elem.fields);
+                }
+            }
+        }
+
+        match rules.len() {
+            0 => empty,
+            1 => RuleWithFields {
+                rule: rules[0],
+                fields: fields[0],
+            },
+            _ => RuleWithFields {
+                rule: cx.intern(Rule::Concat(rules)),
+                fields: Fields::aggregate(cx, fields.into_iter()),
+            },
+        }
+    }
+
     pub struct Concat<A, B>(A, B);
 
     impl<Pat: Eq + Hash, A: Finish<Pat>, B: Finish<Pat>> Finish<Pat> for Concat<A, B> {
         fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
-            let a = self.0.finish(cx);
-            let b = self.1.finish(cx);
+            let mut elems = vec![];
+            self.finish_concat_elems(cx, &mut elems);
+            finish_concat(cx, elems.into_iter())
+        }
 
-            match (&cx[a.rule], &cx[b.rule]) {
-                (Rule::Empty, _) if cx[a.fields] == Fields::Leaf(None) => return b,
-                (_, Rule::Empty) if cx[b.fields] == Fields::Leaf(None) => return a,
-                _ => {}
-            }
+        fn finish_concat_elems(self, cx: &Context<Pat>, elems: &mut Vec<RuleWithFields>) {
+            self.0.finish_concat_elems(cx, elems);
+            self.1.finish_concat_elems(cx, elems);
+        }
+    }
 
-            RuleWithFields {
-                rule: cx.intern(Rule::Concat([a.rule, b.rule])),
-                fields: Fields::aggregate(cx, [a.fields, b.fields].iter().cloned()),
-            }
+    pub struct Seq<I>(I);
+
+    impl<Pat: Eq + Hash, I: Iterator> Finish<Pat> for Seq<I>
+    where
+        I::Item: Start,
+        <I::Item as Start>::Out: Finish<Pat>,
+    {
+        fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
+            finish_concat(cx, self.0.map(|elem| elem.start().finish(cx)))
+        }
+    }
+
+    /// Build an `Or` out of `cases`, in one pass. Like `a | b | ...`,
+    /// the first case is flattened into the result if it's an `Or` itself.
+    fn finish_or<Pat: Eq + Hash>(
+        cx: &Context<Pat>,
+        mut cases: impl Iterator<Item = RuleWithFields>,
+    ) -> RuleWithFields {
+        let first = cases.next().expect("`Or` with no cases");
+
+        let (mut rules, mut fields) = match (&cx[first.rule], &cx[first.fields]) {
+            (Rule::Or(rules), Fields::Leaf(None)) | (Rule::Or(rules), Fields::Aggregate(_)) => (
+                rules.clone(),
+                (0..rules.len())
+                    .map(|i| first.fields.child(cx, i))
+                    .collect(),
+            ),
+            _ => (vec![first.rule], vec![first.fields]),
+        };
+        for case in cases {
+            rules.push(case.rule);
+            fields.push(case.fields);
+        }
+
+        if rules.len() == 1 {
+            return first;
+        }
+
+        RuleWithFields {
+            rule: cx.intern(Rule::Or(rules)),
+            fields: Fields::aggregate(cx, fields.into_iter()),
         }
     }
 
@@ -239,35 +777,26 @@ mod build {
 
     impl<Pat: Eq + Hash, A: Finish<Pat>, B: Finish<Pat>> Finish<Pat> for Or<A, B> {
         fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
-            let a = self.0.finish(cx);
-            let b = self.1.finish(cx);
-
-            match (&cx[a.rule], &cx[a.fields]) {
-                (Rule::Or(a_rules), Fields::Leaf(None)) => RuleWithFields {
-                    rule: cx.intern(Rule::Or(
-                        a_rules.iter().cloned().chain(iter::once(b.rule)).collect(),
-                    )),
-                    fields: Fields::aggregate(
-                        cx,
-                        iter::repeat(a.fields)
-                            .take(a_rules.len())
-                            .chain(iter::once(b.fields)),
-                    ),
-                },
-                (Rule::Or(a_rules), Fields::Aggregate(a_children)) => RuleWithFields {
-                    rule: cx.intern(Rule::Or(
-                        a_rules.iter().cloned().chain(iter::once(b.rule)).collect(),
-                    )),
-                    fields: Fields::aggregate(
-                        cx,
-                        a_children.iter().cloned().chain(iter::once(b.fields)),
-                    ),
-                },
-                _ => RuleWithFields {
-                    rule: cx.intern(Rule::Or(vec![a.rule, b.rule])),
-                    fields: Fields::aggregate(cx, [a.fields, b.fields].iter().cloned()),
-                },
-            }
+            let mut cases = vec![];
+            self.finish_or_cases(cx, &mut cases);
+            finish_or(cx, cases.into_iter())
+        }
+
+        fn finish_or_cases(self, cx: &Context<Pat>, cases: &mut Vec<RuleWithFields>) {
+            self.0.finish_or_cases(cx, cases);
+            cases.push(self.1.finish(cx));
+        }
+    }
+
+    pub struct AnyOf<I>(I);
+
+    impl<Pat: Eq + Hash, I: Iterator> Finish<Pat> for AnyOf<I>
+    where
+        I::Item: Start,
+        <I::Item as Start>::Out: Finish<Pat>,
+    {
+        fn finish(self, cx: &Context<Pat>) -> RuleWithFields {
+            finish_or(cx, self.0.map(|case| case.start().finish(cx)))
         }
     }
 
@@ -295,12 +824,160 @@ mod build {
         build::Build(build::Empty)
     }
 
-    pub fn eat<Pat>(pat: impl Into<Pat>) -> build::Build<build::Eat<Pat>> {
-        build::Build(build::Eat(pat.into()))
+    /// Zero-width rule only matching at the start of the input.
+    pub fn soi() -> build::Build<build::AtAnchor> {
+        build::Build(build::AtAnchor(Anchor::Start))
+    }
+
+    /// Zero-width rule only matching at the end of the input.
+    pub fn eoi() -> build::Build<build::AtAnchor> {
+        build::Build(build::AtAnchor(Anchor::End))
+    }
+
+    /// Zero-width rule only matching at the given (0-based) column,
+    /// if everything before it on the same line is whitespace.
+    pub fn at_column(column: usize) -> build::Build<build::AtAnchor> {
+        build::Build(build::AtAnchor(Anchor::Column(column)))
+    }
+
+    /// Zero-width rule only matching if the flag `name` is set.
+    pub fn if_flag(name: &str) -> build::Build<build::FlagGuard<'_>> {
+        build::Build(build::FlagGuard(name, true))
+    }
+
+    /// Zero-width rule only matching if the flag `name` isn't set.
+    pub fn unless_flag(name: &str) -> build::Build<build::FlagGuard<'_>> {
+        build::Build(build::FlagGuard(name, false))
+    }
+
+    /// Zero-width rule only matching if the feature `name` is enabled,
+    /// e.g. `if_feature("edition2018") + eat("async")`.
+    pub fn if_feature(name: &str) -> build::Build<build::Feature<'_>> {
+        build::Build(build::Feature(name, true))
+    }
+
+    /// Zero-width rule only matching if the feature `name` isn't enabled.
+    pub fn unless_feature(name: &str) -> build::Build<build::Feature<'_>> {
+        build::Build(build::Feature(name, false))
+    }
+
+    /// Zero-width rule only matching if the predicate `name` holds.
+    pub fn guard(name: &str) -> build::Build<build::Guard<'_>> {
+        build::Build(build::Guard(name))
+    }
+
+    pub fn eat<Pat>(pat: impl Into<Pat>) -> build::Build<build::Eat<Pat>> {
+        build::Build(build::Eat(pat.into()))
+    }
+
+    /// Consume input up to the first occurrence of `pat` (or the end of the
+    /// input), without consuming `pat` itself, e.g. for block comments:
+    /// `eat("/*") + until("*/") + eat("*/")`.
+    pub fn until<Pat>(pat: impl Into<Pat>) -> build::Build<build::EatUntil<Pat>> {
+        build::Build(build::EatUntil(pat.into()))
+    }
+
+    /// Match `delim`, capturing the input it matched as `name`, then `body`,
+    /// in which `backref(name)` matches the same input again, e.g. heredocs:
+    /// `eat("<<") + capture("tag", call("Ident"), call("Line").repeat_many() + backref("tag"))`.
+    pub fn capture<D: Start, B: Start>(
+        name: &str,
+        delim: D,
+        body: B,
+    ) -> build::Build<build::Capture<'_, D::Out, B::Out>> {
+        build::Build(build::Capture(name, delim.start(), body.start()))
+    }
+
+    /// Match exactly the input captured as `name` (see `capture`).
+    pub fn backref(name: &str) -> build::Build<build::Backref<'_>> {
+        build::Build(build::Backref(name))
+    }
+
+    pub fn call(name: &str) -> build::Build<build::Call<'_>> {
+        build::Build(build::Call(name))
+    }
+
+    /// Build a `Concat` of all the `elems` at once, which is equivalent
+    /// to `elem0 + elem1 + ... + elemN`, but doesn't require writing
+    /// out the chain (e.g. when the elements come from a list).
+    pub fn seq<I: IntoIterator>(elems: I) -> build::Build<build::Seq<I::IntoIter>>
+    where
+        I::Item: Start,
+    {
+        build::Build(build::Seq(elems.into_iter()))
+    }
+
+    /// Build an `Or` of all the `cases` at once, which is equivalent
+    /// to `case0 | case1 | ... | caseN`, but doesn't require writing
+    /// out the chain (e.g. when the cases come from a list of tokens).
+    pub fn any_of<I: IntoIterator>(cases: I) -> build::Build<build::AnyOf<I::IntoIter>>
+    where
+        I::Item: Start,
+    {
+        build::Build(build::AnyOf(cases.into_iter()))
+    }
+
+    pub type Delimited<O, I, C> = Concat<Concat<O, I>, C>;
+
+    /// `open + inner + close`, e.g. `delimited(eat("("), call("Expr"), eat(")"))`.
+    pub fn delimited<O: Start, I: Start, C: Start>(
+        open: O,
+        inner: I,
+        close: C,
+    ) -> Build<Delimited<O::Out, I::Out, C::Out>> {
+        Build(Concat(Concat(open.start(), inner.start()), close.start()))
+    }
+
+    /// `prefix + inner`, e.g. `preceded(eat("return"), call("Expr").opt())`.
+    pub fn preceded<P: Start, I: Start>(prefix: P, inner: I) -> Build<Concat<P::Out, I::Out>> {
+        Build(Concat(prefix.start(), inner.start()))
+    }
+
+    /// `inner + suffix`, e.g. `terminated(call("Expr"), eat(";"))`.
+    pub fn terminated<I: Start, S: Start>(inner: I, suffix: S) -> Build<Concat<I::Out, S::Out>> {
+        Build(Concat(inner.start(), suffix.start()))
+    }
+
+    /// `delimited(eat("("), inner, eat(")"))`.
+    pub fn paren<Pat: From<&'static str>, I: Start>(
+        inner: I,
+    ) -> Build<Delimited<Eat<Pat>, I::Out, Eat<Pat>>> {
+        delimited(eat("("), inner, eat(")"))
+    }
+
+    /// `delimited(eat("["), inner, eat("]"))`.
+    pub fn bracketed<Pat: From<&'static str>, I: Start>(
+        inner: I,
+    ) -> Build<Delimited<Eat<Pat>, I::Out, Eat<Pat>>> {
+        delimited(eat("["), inner, eat("]"))
+    }
+
+    /// `delimited(eat("{"), inner, eat("}"))`.
+    pub fn braced<Pat: From<&'static str>, I: Start>(
+        inner: I,
+    ) -> Build<Delimited<Eat<Pat>, I::Out, Eat<Pat>>> {
+        delimited(eat("{"), inner, eat("}"))
+    }
+
+    /// Any number of `elem`s (including none), separated by commas, with
+    /// a trailing comma allowed (after at least one `elem`) if `kind` is
+    /// `SepKind::Trailing`, i.e. `elem.repeat_many_sep(eat(","), kind)`.
+    ///
+    /// This should be preferred over e.g. `(elem ("," elem)* ","?)?`,
+    /// which is easy to get subtly wrong (e.g. by allowing a lone `,`).
+    pub fn comma_list<Pat: From<&'static str>, E: Start>(
+        elem: E,
+        kind: SepKind,
+    ) -> Build<RepeatManySep<E::Out, Eat<Pat>>> {
+        Build(RepeatManySep(elem.start(), Eat(",".into()), kind))
     }
 
-    pub fn call(name: &str) -> build::Build<build::Call<'_>> {
-        build::Build(build::Call(name))
+    /// Like `comma_list`, but requiring at least one `elem`.
+    pub fn comma_list1<Pat: From<&'static str>, E: Start>(
+        elem: E,
+        kind: SepKind,
+    ) -> Build<RepeatMoreSep<E::Out, Eat<Pat>>> {
+        Build(RepeatMoreSep(elem.start(), Eat(",".into()), kind))
     }
 
     /// Helper macro to provide methods and operator overloads on both
@@ -316,6 +993,21 @@ mod build {
                     Build(Opt(self.start()))
                 }
 
+                pub fn with_flag<'a>(self, name: &'a str) -> Build<WithFlag<'a, <Self as Start>::Out>> {
+                    Build(WithFlag(self.start(), name, true))
+                }
+
+                pub fn without_flag<'a>(
+                    self,
+                    name: &'a str,
+                ) -> Build<WithFlag<'a, <Self as Start>::Out>> {
+                    Build(WithFlag(self.start(), name, false))
+                }
+
+                pub fn silent(self) -> Build<Silent<<Self as Start>::Out>> {
+                    Build(Silent(self.start()))
+                }
+
                 pub fn repeat_many(self) -> Build<RepeatMany<<Self as Start>::Out>> {
                     Build(RepeatMany(self.start()))
                 }
@@ -339,6 +1031,16 @@ mod build {
                 ) -> Build<RepeatMoreSep<<Self as Start>::Out, S::Out>> {
                     Build(RepeatMoreSep(self.start(), sep.start(), kind))
                 }
+
+                /// Like `repeat_many_sep`/`repeat_more_sep`, but also with
+                /// control over accepting a lone `sep` (see `SepFlags`).
+                pub fn repeat_sep<S: Start>(
+                    self,
+                    sep: S,
+                    flags: SepFlags,
+                ) -> Build<RepeatSep<<Self as Start>::Out, S::Out>> {
+                    Build(RepeatSep(self.start(), sep.start(), flags))
+                }
             }
 
             impl<$($g,)* Other: Start> Add<Other> for $Self {
@@ -363,7 +1065,22 @@ mod build {
     builder_impls!(impl<> RuleWithFields);
 }
 
-pub use self::build::{call, eat, empty};
+pub use self::build::{
+    any_of, at_column, backref, call, capture, eat, empty, eoi, guard, if_feature, if_flag, seq,
+    soi, unless_feature, unless_flag, until,
+};
+
+/// Everything needed to build rules, including higher-level combinators
+/// for common idioms (e.g. `comma_list`), meant to be glob-imported.
+pub mod prelude {
+    pub use super::build::{
+        braced, bracketed, comma_list, comma_list1, delimited, paren, preceded, terminated,
+    };
+    pub use super::{
+        any_of, at_column, backref, call, capture, eat, empty, eoi, guard, if_feature, if_flag,
+        seq, soi, unless_feature, unless_flag, until, RuleWithFields, SepFlags, SepKind,
+    };
+}
 
 impl IRule {
     pub fn node_desc<Pat>(self, cx: &Context<Pat>) -> String
@@ -372,10 +1089,41 @@ impl IRule {
     {
         match cx[self] {
             Rule::Empty => "".to_string(),
+            Rule::Anchor(Anchor::Start) => "SOI".to_string(),
+            Rule::Anchor(Anchor::End) => "EOI".to_string(),
+            Rule::Anchor(Anchor::Column(column)) => format!("COL({})", column),
             Rule::Eat(ref pat) => format!("{:?}", pat),
+            Rule::EatUntil(ref pat) => format!("..{:?}", pat),
+            Rule::Backref(name) => format!("${}", &cx[name]),
             Rule::Call(r) => cx[r].to_string(),
-            Rule::Concat([left, right]) => {
-                format!("({} {})", left.node_desc(cx), right.node_desc(cx))
+            Rule::Guard(name) => format!("?{}", &cx[name]),
+            Rule::Feature(feature, enabled) => {
+                format!("#[{}{}]", if enabled { "" } else { "!" }, &cx[feature])
+            }
+            Rule::FlagGuard(flag, value) => {
+                format!("[{}{}]", if value { "+" } else { "!" }, &cx[flag])
+            }
+            Rule::WithFlag(flag, value, rule) => format!(
+                "{}<{}{}>",
+                rule.node_desc(cx),
+                if value { "+" } else { "!" },
+                &cx[flag]
+            ),
+            Rule::Silent(rule) => format!("_{{{}}}", rule.node_desc(cx)),
+            Rule::Capture(name, delim, body) => format!(
+                "(${}={} {})",
+                &cx[name],
+                delim.node_desc(cx),
+                body.node_desc(cx)
+            ),
+            Rule::Concat(ref elems) => {
+                assert!(elems.len() > 1);
+                let mut desc = format!("({}", elems[0].node_desc(cx));
+                for rule in &elems[1..] {
+                    desc += " ";
+                    desc += &rule.node_desc(cx);
+                }
+                desc + ")"
             }
             Rule::Or(ref cases) => {
                 assert!(cases.len() > 1);
@@ -388,18 +1136,22 @@ impl IRule {
             }
             Rule::Opt(rule) => format!("{}?", rule.node_desc(cx)),
             Rule::RepeatMany(elem, None) => format!("{}*", elem.node_desc(cx)),
-            Rule::RepeatMany(elem, Some((sep, SepKind::Simple))) => {
-                format!("{}* % {}", elem.node_desc(cx), sep.node_desc(cx))
-            }
-            Rule::RepeatMany(elem, Some((sep, SepKind::Trailing))) => {
-                format!("{}* %% {}", elem.node_desc(cx), sep.node_desc(cx))
+            Rule::RepeatMany(elem, Some((sep, kind))) => {
+                format!(
+                    "{}* {} {}",
+                    elem.node_desc(cx),
+                    kind.op(),
+                    sep.node_desc(cx)
+                )
             }
             Rule::RepeatMore(elem, None) => format!("{}+", elem.node_desc(cx)),
-            Rule::RepeatMore(elem, Some((sep, SepKind::Simple))) => {
-                format!("{}+ % {}", elem.node_desc(cx), sep.node_desc(cx))
-            }
-            Rule::RepeatMore(elem, Some((sep, SepKind::Trailing))) => {
-                format!("{}+ %% {}", elem.node_desc(cx), sep.node_desc(cx))
+            Rule::RepeatMore(elem, Some((sep, kind))) => {
+                format!(
+                    "{}+ {} {}",
+                    elem.node_desc(cx),
+                    kind.op(),
+                    sep.node_desc(cx)
+                )
             }
         }
     }
@@ -410,132 +1162,346 @@ impl IRule {
         named_rules: Option<&IndexMap<IStr, RuleWithFields>>,
     ) -> NodeShape<Self> {
         match cx[self] {
-            Rule::Empty | Rule::Eat(_) => NodeShape::Opaque,
+            Rule::Empty
+            | Rule::Anchor(_)
+            | Rule::Eat(_)
+            | Rule::EatUntil(_)
+            | Rule::Backref(_)
+            | Rule::FlagGuard(..)
+            | Rule::Guard(_)
+            | Rule::Feature(..)
+            | Rule::Silent(_) => NodeShape::Opaque,
+            Rule::Capture(_, delim, body) => NodeShape::Split(delim, body),
             Rule::Call(name) => match named_rules.map(|rules| &rules[&name]) {
                 Some(rule) if cx[rule.fields] != Fields::Leaf(None) => NodeShape::Alias(rule.rule),
                 _ => NodeShape::Opaque,
             },
-            Rule::Concat([left, right]) => NodeShape::Split(left, right),
+            // NOTE the elements are children of the same node, so that
+            // their paths in the forest are the same as their field paths.
+            Rule::Concat(ref elems) => NodeShape::Seq(elems.len()),
             Rule::Or(ref cases) => NodeShape::Choice(cases.len()),
+            Rule::WithFlag(_, _, rule) => NodeShape::Alias(rule),
             Rule::Opt(rule) => NodeShape::Opt(rule),
             Rule::RepeatMany(elem, sep) => NodeShape::Opt(cx.intern(Rule::RepeatMore(elem, sep))),
-            Rule::RepeatMore(rule, None) => {
-                NodeShape::Split(rule, cx.intern(Rule::RepeatMany(rule, None)))
-            }
-            Rule::RepeatMore(elem, Some((sep, SepKind::Simple))) => NodeShape::Split(
+            // NOTE repeats are split into two children with explicit ranges
+            // (see `node_shape_seq_get`), so trivia may be skipped in between.
+            Rule::RepeatMore(..) => NodeShape::Seq(2),
+        }
+    }
+
+    /// The `i`th child of a `NodeShape::Seq` node (see `node_shape`).
+    pub fn node_shape_seq_get<Pat: Eq + Hash>(self, cx: &Context<Pat>, i: usize) -> Self {
+        let [first, second] = match cx[self] {
+            Rule::Concat(ref elems) => return elems[i],
+            Rule::RepeatMore(elem, None) => [elem, cx.intern(Rule::RepeatMany(elem, None))],
+            Rule::RepeatMore(elem, Some((sep, SepKind::Simple))) => [
                 elem,
-                cx.intern(Rule::Opt(cx.intern(Rule::Concat([sep, self])))),
-            ),
-            Rule::RepeatMore(elem, Some((sep, SepKind::Trailing))) => NodeShape::Split(
+                cx.intern(Rule::Opt(cx.intern(Rule::Concat(vec![sep, self])))),
+            ],
+            Rule::RepeatMore(elem, Some((sep, SepKind::Trailing))) => [
                 elem,
-                cx.intern(Rule::Opt(cx.intern(Rule::Concat([
+                cx.intern(Rule::Opt(cx.intern(Rule::Concat(vec![
                     sep,
                     cx.intern(Rule::RepeatMany(elem, Some((sep, SepKind::Trailing)))),
                 ])))),
-            ),
-        }
+            ],
+            // NOTE the leading separator is split off first, leaving
+            // the rest as `A+ % B` (or `A+ %% B`, for `Both`).
+            Rule::RepeatMore(elem, Some((sep, kind @ (SepKind::Leading | SepKind::Both)))) => {
+                let rest_kind = if kind.allows_trailing() {
+                    SepKind::Trailing
+                } else {
+                    SepKind::Simple
+                };
+                [
+                    cx.intern(Rule::Opt(sep)),
+                    cx.intern(Rule::RepeatMore(elem, Some((sep, rest_kind)))),
+                ]
+            }
+            _ => unreachable!(),
+        };
+        [first, second][i]
     }
 
-    fn can_be_empty<Pat: MatchesEmpty>(
+    pub(crate) fn can_be_empty<Pat: MatchesEmpty>(
         self,
-        cache: &mut HashMap<Self, MaybeKnown<bool>>,
+        cache: &mut BTreeMap<Self, MaybeKnown<bool>>,
         cx: &Context<Pat>,
         grammar: &crate::Grammar,
     ) -> MaybeKnown<bool> {
+        // NOTE this is iterative (with an explicit stack of the rules being
+        // checked), as long chains of calls could otherwise overflow the stack.
+        // Rules being checked are cached as `Unknown`, to stop at cycles,
+        // and only `Known` results are kept, once a rule is done.
         match cache.entry(self) {
             Entry::Occupied(entry) => return *entry.get(),
             Entry::Vacant(entry) => {
                 entry.insert(MaybeKnown::Unknown);
             }
         };
-        let r = match cx[self] {
-            Rule::Empty | Rule::Opt(_) | Rule::RepeatMany(..) => MaybeKnown::Known(true),
-            Rule::Eat(ref pat) => pat.matches_empty(),
-            Rule::Call(rule) => grammar.rules[&rule].rule.can_be_empty(cache, cx, grammar),
-            Rule::Concat([left, right]) => {
-                left.can_be_empty(cache, cx, grammar) & right.can_be_empty(cache, cx, grammar)
-            }
-            Rule::Or(ref rules) => rules.iter().fold(MaybeKnown::Known(false), |prev, rule| {
-                prev | rule.can_be_empty(cache, cx, grammar)
-            }),
-            Rule::RepeatMore(elem, _) => elem.can_be_empty(cache, cx, grammar),
-        };
-        match r {
-            MaybeKnown::Known(_) => *cache.get_mut(&self).unwrap() = r,
-            MaybeKnown::Unknown => {
-                cache.remove(&self);
+        let mut stack = vec![self.can_be_empty_frame(cx, grammar)];
+        let mut done = None;
+        loop {
+            let frame = stack.last_mut().unwrap();
+            if let Some(r) = done.take() {
+                frame.acc = if frame.any {
+                    frame.acc | r
+                } else {
+                    frame.acc & r
+                };
+            }
+            match frame.deps.pop() {
+                Some(dep) => match cache.entry(dep) {
+                    Entry::Occupied(entry) => done = Some(*entry.get()),
+                    Entry::Vacant(entry) => {
+                        entry.insert(MaybeKnown::Unknown);
+                        stack.push(dep.can_be_empty_frame(cx, grammar));
+                    }
+                },
+                None => {
+                    let CanBeEmptyFrame { rule, acc, .. } = stack.pop().unwrap();
+                    match acc {
+                        MaybeKnown::Known(_) => *cache.get_mut(&rule).unwrap() = acc,
+                        MaybeKnown::Unknown => {
+                            cache.remove(&rule);
+                        }
+                    }
+                    if stack.is_empty() {
+                        return acc;
+                    }
+                    done = Some(acc);
+                }
             }
         }
-        r
     }
 
-    pub(crate) fn check_non_empty_opt<Pat: MatchesEmpty>(
+    /// The start of checking whether `self` can be empty (see `can_be_empty`),
+    /// i.e. the rules it depends on (if any), and how to combine them.
+    fn can_be_empty_frame<Pat: MatchesEmpty>(
         self,
-        cache: &mut HashMap<IRule, MaybeKnown<bool>>,
         cx: &Context<Pat>,
         grammar: &crate::Grammar,
-    ) {
-        match cx[self] {
-            Rule::Empty | Rule::Eat(_) | Rule::Call(_) => {}
-            Rule::Concat([left, right]) => {
-                left.check_non_empty_opt(cache, cx, grammar);
-                right.check_non_empty_opt(cache, cx, grammar);
+    ) -> CanBeEmptyFrame {
+        let known = |acc| (vec![], acc, false);
+        let (mut deps, acc, any) = match cx[self] {
+            Rule::Empty
+            | Rule::Anchor(_)
+            | Rule::FlagGuard(..)
+            | Rule::Guard(_)
+            | Rule::Feature(..)
+            | Rule::EatUntil(_)
+            | Rule::Opt(_)
+            | Rule::RepeatMany(..) => known(MaybeKnown::Known(true)),
+            Rule::Eat(ref pat) => known(pat.matches_empty()),
+            // NOTE this depends on what was captured, which could
+            // be checked with the `Capture`, but isn't (yet).
+            Rule::Backref(_) => known(MaybeKnown::Unknown),
+            Rule::Capture(_, delim, body) => (vec![delim, body], MaybeKnown::Known(true), false),
+            // NOTE undefined rules are reported separately (see `check_call_names`).
+            Rule::Call(rule) => match grammar.rules().get(&rule) {
+                Some(rule) => (vec![rule.rule], MaybeKnown::Known(true), false),
+                None => known(MaybeKnown::Unknown),
+            },
+            Rule::Concat(ref elems) => (elems.clone(), MaybeKnown::Known(true), false),
+            Rule::Or(ref rules) => (rules.clone(), MaybeKnown::Known(false), true),
+            Rule::WithFlag(_, _, rule) | Rule::Silent(rule) | Rule::RepeatMore(rule, _) => {
+                (vec![rule], MaybeKnown::Known(true), false)
             }
-            Rule::Or(ref rules) => {
+        };
+        // NOTE reversed, to be popped (i.e. checked) in order.
+        deps.reverse();
+        CanBeEmptyFrame {
+            rule: self,
+            deps,
+            acc,
+            any,
+        }
+    }
+
+    pub(crate) fn check_non_empty_opt<Pat>(
+        self,
+        cache: &mut BTreeMap<IRule, MaybeKnown<bool>>,
+        cx: &Context<Pat>,
+        grammar: &crate::Grammar,
+        rule_name: IStr,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) where
+        Pat: MatchesEmpty + fmt::Debug,
+    {
+        // NOTE `ids` are the message ids for "can" and "might".
+        let mut check = |rule: IRule, code, what, ids: [&'static str; 2]| {
+            let (message, message_id) = match rule.can_be_empty(cache, cx, grammar) {
+                MaybeKnown::Known(false) => return,
+                MaybeKnown::Known(true) => (format!("{} can match empty", what), ids[0]),
+                MaybeKnown::Unknown => (format!("{} might match empty", what), ids[1]),
+            };
+            diagnostics.push(Diagnostic {
+                code,
+                rule: cx[rule_name].to_string(),
+                node: self.node_desc(cx),
+                message,
+                help: None,
+                message_id,
+                args: vec![],
+            });
+        };
+        match cx[self] {
+            Rule::Empty
+            | Rule::Anchor(_)
+            | Rule::Eat(_)
+            | Rule::EatUntil(_)
+            | Rule::Backref(_)
+            | Rule::Call(_)
+            | Rule::FlagGuard(..)
+            | Rule::Guard(_)
+            | Rule::Feature(..) => {}
+            Rule::Concat(ref rules) | Rule::Or(ref rules) => {
                 for rule in rules {
-                    rule.check_non_empty_opt(cache, cx, grammar);
+                    rule.check_non_empty_opt(cache, cx, grammar, rule_name, diagnostics);
                 }
             }
+            Rule::WithFlag(_, _, rule) | Rule::Silent(rule) => {
+                rule.check_non_empty_opt(cache, cx, grammar, rule_name, diagnostics)
+            }
+            Rule::Capture(_, delim, body) => {
+                delim.check_non_empty_opt(cache, cx, grammar, rule_name, diagnostics);
+                body.check_non_empty_opt(cache, cx, grammar, rule_name, diagnostics);
+            }
             Rule::Opt(rule) => {
-                assert_eq!(
-                    rule.can_be_empty(cache, cx, grammar),
-                    MaybeKnown::Known(false)
+                check(
+                    rule,
+                    Code::EmptyOpt,
+                    "optional rule",
+                    ["empty-opt", "maybe-empty-opt"],
                 );
-                rule.check_non_empty_opt(cache, cx, grammar)
+                rule.check_non_empty_opt(cache, cx, grammar, rule_name, diagnostics)
             }
             Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => {
-                assert_eq!(
-                    elem.can_be_empty(cache, cx, grammar),
-                    MaybeKnown::Known(false)
+                check(
+                    elem,
+                    Code::EmptyRepeat,
+                    "repeat element",
+                    ["empty-repeat", "maybe-empty-repeat"],
                 );
-                elem.check_non_empty_opt(cache, cx, grammar);
+                elem.check_non_empty_opt(cache, cx, grammar, rule_name, diagnostics);
                 if let Some((sep, _)) = sep {
-                    sep.check_non_empty_opt(cache, cx, grammar);
+                    sep.check_non_empty_opt(cache, cx, grammar, rule_name, diagnostics);
                 }
             }
         }
     }
 
-    pub(crate) fn check_call_names<Pat>(self, cx: &Context<Pat>, grammar: &crate::Grammar) {
+    /// The rules directly nested in this rule, numbered like `Fields`
+    /// children (e.g. a repetition's element is `0`, and its separator `1`).
+    pub fn children<Pat>(self, cx: &Context<Pat>) -> Vec<IRule> {
         match cx[self] {
-            Rule::Empty | Rule::Eat(_) => {}
-            Rule::Call(rule) => {
-                assert!(
-                    grammar.rules.contains_key(&rule),
-                    "no rule named `{}`",
-                    &cx[rule]
-                );
+            Rule::Empty
+            | Rule::Anchor(_)
+            | Rule::Eat(_)
+            | Rule::EatUntil(_)
+            | Rule::Backref(_)
+            | Rule::Call(_)
+            | Rule::FlagGuard(..)
+            | Rule::Guard(_)
+            | Rule::Feature(..) => vec![],
+            Rule::WithFlag(_, _, rule) | Rule::Silent(rule) | Rule::Opt(rule) => vec![rule],
+            Rule::Capture(_, delim, body) => vec![delim, body],
+            Rule::Concat(ref rules) | Rule::Or(ref rules) => rules.clone(),
+            Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => {
+                let mut children = vec![elem];
+                children.extend(sep.map(|(sep, _)| sep));
+                children
             }
-            Rule::Concat([left, right]) => {
-                left.check_call_names(cx, grammar);
-                right.check_call_names(cx, grammar);
+        }
+    }
+
+    /// Collects the names of all the rules called from this rule.
+    pub fn call_names<Pat>(self, cx: &Context<Pat>) -> IndexSet<IStr> {
+        let mut names = IndexSet::default();
+        self.collect_call_names(cx, &mut names);
+        names
+    }
+
+    fn collect_call_names<Pat>(self, cx: &Context<Pat>, names: &mut IndexSet<IStr>) {
+        match cx[self] {
+            Rule::Empty
+            | Rule::Anchor(_)
+            | Rule::Eat(_)
+            | Rule::EatUntil(_)
+            | Rule::Backref(_)
+            | Rule::FlagGuard(..)
+            | Rule::Guard(_)
+            | Rule::Feature(..) => {}
+            Rule::Call(rule) => {
+                names.insert(rule);
             }
-            Rule::Or(ref rules) => {
+            Rule::Concat(ref rules) | Rule::Or(ref rules) => {
                 for rule in rules {
-                    rule.check_call_names(cx, grammar);
+                    rule.collect_call_names(cx, names);
                 }
             }
-            Rule::Opt(rule) => rule.check_call_names(cx, grammar),
+            Rule::WithFlag(_, _, rule) | Rule::Silent(rule) | Rule::Opt(rule) => {
+                rule.collect_call_names(cx, names)
+            }
+            Rule::Capture(_, delim, body) => {
+                delim.collect_call_names(cx, names);
+                body.collect_call_names(cx, names);
+            }
             Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => {
-                elem.check_call_names(cx, grammar);
+                elem.collect_call_names(cx, names);
                 if let Some((sep, _)) = sep {
-                    sep.check_call_names(cx, grammar);
+                    sep.collect_call_names(cx, names);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn check_call_names<Pat>(
+        self,
+        cx: &Context<Pat>,
+        grammar: &crate::Grammar,
+        rule_name: IStr,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) {
+        for name in self.call_names(cx) {
+            if !grammar.rules().contains_key(&name) {
+                let defined = grammar.rules().keys().map(|&name| &cx[name]);
+                let similar: Vec<_> = diagnostics::similar_names(&cx[name], defined)
+                    .into_iter()
+                    .map(|name| format!("`{}`", name))
+                    .collect();
+                let mut args = vec![("name", cx[name].to_string())];
+                if !similar.is_empty() {
+                    args.push(("similar", similar.join(", ")));
                 }
+                diagnostics.push(Diagnostic {
+                    code: Code::UndefinedRule,
+                    rule: cx[rule_name].to_string(),
+                    node: cx[name].to_string(),
+                    message: format!("no rule named `{}`", &cx[name]),
+                    help: if similar.is_empty() {
+                        None
+                    } else {
+                        Some(format!("did you mean rule {}?", similar.join(" or ")))
+                    },
+                    message_id: "undefined-rule",
+                    args,
+                });
             }
         }
     }
 }
 
+/// A rule being checked by `IRule::can_be_empty`, with the rules it depends
+/// on still to be checked, and their results so far, combined into `acc`
+/// (with `|` if `any` is set, i.e. for `Or`, and with `&` otherwise).
+struct CanBeEmptyFrame {
+    rule: IRule,
+    deps: Vec<IRule>,
+    acc: MaybeKnown<bool>,
+    any: bool,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum MaybeKnown<T> {
     Known(T),
@@ -572,17 +1538,129 @@ pub trait MatchesEmpty {
     fn matches_empty(&self) -> MaybeKnown<bool>;
 }
 
+/// Relations between the sets of inputs two patterns can match (at the same
+/// position), e.g. for finding conflicts between the cases of an `Or`.
+pub trait MatchesOverlap {
+    /// Whether there is any input both `self` and `other` match
+    /// (though not necessarily consuming the same amount of it).
+    fn overlaps(&self, other: &Self) -> MaybeKnown<bool>;
+
+    /// Whether `self` matches every input `other` matches.
+    fn contains(&self, other: &Self) -> MaybeKnown<bool>;
+}
+
+/// What analyses and backends can know about a pattern type, beyond it
+/// matching empty inputs or not (while building rules only needs `Into<Pat>`,
+/// e.g. for `eat`), with conservative defaults for anything else, so that
+/// custom pattern types only need to implement what they can support.
+pub trait Pattern: MatchesEmpty + fmt::Debug {
+    /// The characters a (non-empty) match can start with, as sorted and
+    /// disjoint ranges, or `Unknown` if it could be any of them (e.g. for
+    /// token patterns, which aren't matched against characters).
+    fn first_chars(&self) -> MaybeKnown<Vec<RangeInclusive<char>>> {
+        MaybeKnown::Unknown
+    }
+
+    /// A human-readable description, e.g. for diagnostics (`Debug` by default).
+    fn describe(&self) -> String {
+        format!("{:?}", self)
+    }
+
+    /// Match at `pos` in `input`, returning the length of the match, if
+    /// matching against text is supported at all (otherwise, `Unknown`).
+    fn try_match(&self, input: &str, pos: usize) -> MaybeKnown<Option<usize>> {
+        let _ = (input, pos);
+        MaybeKnown::Unknown
+    }
+
+    // NOTE the set operations below take and return lists of patterns,
+    // to be matched as an `Or` (e.g. `any_of(pats.into_iter().map(eat))`),
+    // as their results often can't be expressed as a single pattern (e.g.
+    // "any character except `"`" needs two ranges), and return `None` when
+    // the pattern type (or the specific patterns) don't support them, e.g.
+    // for "any character except `"` and `\`" (in a string literal rule):
+    // `Pat::negate(&Pat::union(&["\"".into()], &["\\".into()])?)?`.
+
+    /// Patterns matching what any of `a` or `b` match.
+    fn union(a: &[Self], b: &[Self]) -> Option<Vec<Self>>
+    where
+        Self: Sized,
+    {
+        let _ = (a, b);
+        None
+    }
+
+    /// Patterns matching what both (any of) `a` and (any of) `b` match.
+    fn intersect(a: &[Self], b: &[Self]) -> Option<Vec<Self>>
+    where
+        Self: Sized,
+    {
+        let _ = (a, b);
+        None
+    }
+
+    /// Patterns matching (single characters or tokens) none of `pats` match.
+    fn negate(pats: &[Self]) -> Option<Vec<Self>>
+    where
+        Self: Sized,
+    {
+        let _ = pats;
+        None
+    }
+}
+
+/// Fold every case of an `Or` (ignoring their indices), and combine them
+/// back into an `Or` (the default `Folder::fold_or`).
+pub fn fold_or_cases<'cx, Pat: 'cx + Eq + Hash>(
+    folder: &mut impl Folder<'cx, Pat>,
+    cases: impl Iterator<Item = (usize, RuleWithFields)>,
+) -> RuleWithFields {
+    let cx = folder.cx();
+    any_of(cases.map(|(_, case)| case.fold(folder))).finish(cx)
+}
+
 pub trait Folder<'cx, Pat: 'cx + Eq + Hash>: Sized {
     fn cx(&self) -> &'cx Context<Pat>;
+    /// Whether to leave `rule` (and everything in it) as-is, without folding
+    /// (or rebuilding) it at all, e.g. when the folder knows it wouldn't
+    /// change anything in it, to only pay for the parts that do change.
+    fn keep_as_is(&mut self, _rule: RuleWithFields) -> bool {
+        false
+    }
     fn fold_leaf(&mut self, rule: RuleWithFields) -> RuleWithFields {
         rule
     }
-    fn fold_concat(&mut self, left: RuleWithFields, right: RuleWithFields) -> RuleWithFields {
-        (left.fold(self) + right.fold(self)).finish(self.cx())
+    fn fold_concat(&mut self, elems: impl Iterator<Item = RuleWithFields>) -> RuleWithFields {
+        let cx = self.cx();
+        seq(elems.map(|elem| elem.fold(self))).finish(cx)
+    }
+    /// Fold the cases of an `Or`, each paired with its index in the `Or`,
+    /// so that specific cases can be treated differently (the default is
+    /// just `fold_or_cases`, to also be used for reassembling in overrides).
+    fn fold_or(&mut self, cases: impl Iterator<Item = (usize, RuleWithFields)>) -> RuleWithFields {
+        fold_or_cases(self, cases)
     }
-    fn fold_or(&mut self, mut rules: impl Iterator<Item = RuleWithFields>) -> RuleWithFields {
-        let first = rules.next().unwrap().fold(self);
-        rules.fold(first, |or, rule| (or | rule.fold(self)).finish(self.cx()))
+    fn fold_with_flag(&mut self, flag: IStr, value: bool, rule: RuleWithFields) -> RuleWithFields {
+        let rule = rule.fold(self);
+        let flag = &self.cx()[flag];
+        if value {
+            rule.with_flag(flag).finish(self.cx())
+        } else {
+            rule.without_flag(flag).finish(self.cx())
+        }
+    }
+    fn fold_silent(&mut self, rule: RuleWithFields) -> RuleWithFields {
+        rule.fold(self).silent().finish(self.cx())
+    }
+    fn fold_capture(
+        &mut self,
+        name: IStr,
+        delim: RuleWithFields,
+        body: RuleWithFields,
+    ) -> RuleWithFields {
+        let delim = delim.fold(self);
+        let body = body.fold(self);
+        capture(&self.cx()[name], delim, body).finish(self.cx())
     }
     fn fold_opt(&mut self, rule: RuleWithFields) -> RuleWithFields {
         rule.fold(self).opt().finish(self.cx())
@@ -615,8 +1693,11 @@ pub trait Folder<'cx, Pat: 'cx + Eq + Hash>: Sized {
 
 impl RuleWithFields {
     pub fn fold<'cx, Pat: 'cx + Eq + Hash>(self, folder: &mut impl Folder<'cx, Pat>) -> Self {
+        if folder.keep_as_is(self) {
+            return self;
+        }
         let cx = folder.cx();
-        let aggregate_fields = match cx[self.fields] {
+        match cx[self.fields] {
             Fields::Leaf(Some(field)) => {
                 let mut rule = RuleWithFields {
                     rule: self.rule,
@@ -629,27 +1710,41 @@ impl RuleWithFields {
                 })));
                 return rule;
             }
-            Fields::Leaf(None) => &[][..],
-            Fields::Aggregate(ref children) => children,
-        };
+            Fields::Leaf(None) | Fields::Aggregate(_) => {}
+        }
         let field_rule = |rule, i| RuleWithFields {
             rule,
-            fields: aggregate_fields
-                .get(i)
-                .cloned()
-                .unwrap_or_else(|| cx.intern(Fields::Leaf(None))),
+            fields: self.fields.child(cx, i),
         };
         match cx[self.rule] {
-            Rule::Empty | Rule::Eat(_) | Rule::Call(_) => return folder.fold_leaf(self),
-            Rule::Concat([left, right]) => {
-                folder.fold_concat(field_rule(left, 0), field_rule(right, 1))
+            Rule::Empty
+            | Rule::Anchor(_)
+            | Rule::Eat(_)
+            | Rule::EatUntil(_)
+            | Rule::Backref(_)
+            | Rule::Call(_)
+            | Rule::FlagGuard(..)
+            | Rule::Guard(_)
+            | Rule::Feature(..) => return folder.fold_leaf(self),
+            Rule::WithFlag(flag, value, rule) => {
+                folder.fold_with_flag(flag, value, field_rule(rule, 0))
             }
+            Rule::Concat(ref elems) => folder.fold_concat(
+                elems
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &elem)| field_rule(elem, i)),
+            ),
             Rule::Or(ref rules) => folder.fold_or(
                 rules
                     .iter()
                     .enumerate()
-                    .map(|(i, &rule)| field_rule(rule, i)),
+                    .map(|(i, &rule)| (i, field_rule(rule, i))),
             ),
+            Rule::Silent(rule) => folder.fold_silent(field_rule(rule, 0)),
+            Rule::Capture(name, delim, body) => {
+                folder.fold_capture(name, field_rule(delim, 0), field_rule(body, 1))
+            }
             Rule::Opt(rule) => folder.fold_opt(field_rule(rule, 0)),
             Rule::RepeatMany(elem, sep) => folder.fold_repeat_many(
                 field_rule(elem, 0),
@@ -667,78 +1762,477 @@ impl RuleWithFields {
         cx: &Context<Pat>,
         whitespace: RuleWithFields,
     ) -> Self {
+        self.fold(&mut WhitespaceInserter::new(cx, whitespace))
+    }
+}
+
+pub(crate) struct WhitespaceInserter<'cx, Pat> {
+    cx: &'cx Context<Pat>,
+    whitespace: RuleWithFields,
+}
+
+impl<'cx, Pat: Eq + Hash> WhitespaceInserter<'cx, Pat> {
+    pub(crate) fn new(cx: &'cx Context<Pat>, whitespace: RuleWithFields) -> Self {
         assert_eq!(cx[whitespace.fields], Fields::Leaf(None));
 
-        struct WhitespaceInserter<'cx, Pat> {
-            cx: &'cx Context<Pat>,
-            whitespace: RuleWithFields,
-        }
-
-        impl<'cx, Pat: Eq + Hash> Folder<'cx, Pat> for WhitespaceInserter<'cx, Pat> {
-            fn cx(&self) -> &'cx Context<Pat> {
-                self.cx
-            }
-            // FIXME(eddyb) this will insert too many whitespace rules,
-            // e.g. `A B? C` becomes `A WS B? WS C`, which when `B` is
-            // missing, is `A WS WS C`. Even worse, `A? B` ends up as
-            // `A? WS B`, which has an incorrect leading whitespace.
-            fn fold_concat(
-                &mut self,
-                left: RuleWithFields,
-                right: RuleWithFields,
-            ) -> RuleWithFields {
-                (left.fold(self) + self.whitespace + right.fold(self)).finish(self.cx())
-            }
-            fn fold_repeat_many(
-                &mut self,
-                elem: RuleWithFields,
-                sep: Option<(RuleWithFields, SepKind)>,
-            ) -> RuleWithFields {
-                let elem = elem.fold(self);
-                let sep = sep.map(|(sep, kind)| (sep.fold(self), kind));
+        WhitespaceInserter { cx, whitespace }
+    }
+}
+
+impl<'cx, Pat: Eq + Hash> Folder<'cx, Pat> for WhitespaceInserter<'cx, Pat> {
+    fn cx(&self) -> &'cx Context<Pat> {
+        self.cx
+    }
+    // FIXME(eddyb) this will insert too many whitespace rules,
+    // e.g. `A B? C` becomes `A WS B? WS C`, which when `B` is
+    // missing, is `A WS WS C`. Even worse, `A? B` ends up as
+    // `A? WS B`, which has an incorrect leading whitespace.
+    fn fold_concat(&mut self, elems: impl Iterator<Item = RuleWithFields>) -> RuleWithFields {
+        let elems: Vec<_> = elems.map(|elem| elem.fold(self)).collect();
+        let whitespace = self.whitespace;
+        seq(elems.into_iter().enumerate().flat_map(|(i, elem)| {
+            let sep = if i == 0 { None } else { Some(whitespace) };
+            sep.into_iter().chain(iter::once(elem))
+        }))
+        .finish(self.cx)
+    }
+    fn fold_repeat_many(
+        &mut self,
+        elem: RuleWithFields,
+        sep: Option<(RuleWithFields, SepKind)>,
+    ) -> RuleWithFields {
+        // A* ^% B => (A+ ^% B)?, with whitespace inserted as for `A+ ^% B`
+        if let Some((_, kind)) = sep {
+            if kind.allows_leading() {
+                return self.fold_repeat_more(elem, sep).opt().finish(self.cx);
+            }
+        }
+        let elem = elem.fold(self);
+        let sep = sep.map(|(sep, kind)| (sep.fold(self), kind));
+        match sep {
+            // A* => A* % WS
+            None => elem
+                .repeat_more_sep(self.whitespace, SepKind::Simple)
+                .finish(self.cx),
+            // A* % B => A* % (WS B WS)
+            Some((sep, SepKind::Simple)) => elem
+                .repeat_more_sep(self.whitespace + sep + self.whitespace, SepKind::Simple)
+                .finish(self.cx),
+            // FIXME(cad97) this will insert too many whitespace rules
+            // A* %% B => ???
+            // Currently, A* %% (WS B WS), which allows trailing whitespace incorrectly
+            Some((sep, SepKind::Trailing)) => elem
+                .repeat_more_sep(self.whitespace + sep + self.whitespace, SepKind::Trailing)
+                .finish(self.cx),
+            Some((_, SepKind::Leading | SepKind::Both)) => unreachable!(),
+        }
+    }
+    fn fold_repeat_more(
+        &mut self,
+        elem: RuleWithFields,
+        sep: Option<(RuleWithFields, SepKind)>,
+    ) -> RuleWithFields {
+        let elem = elem.fold(self);
+        let sep = sep.map(|(sep, kind)| (sep.fold(self), kind));
+        match sep {
+            // A+ => A+ % WS
+            None => elem
+                .repeat_more_sep(self.whitespace, SepKind::Simple)
+                .finish(self.cx),
+            // A+ % B => A+ % (WS B WS)
+            Some((sep, SepKind::Simple)) => elem
+                .fold(self)
+                .repeat_more_sep(self.whitespace + sep + self.whitespace, SepKind::Simple)
+                .finish(self.cx),
+            // A+ %% B => A+ % (WS B WS) (WS B)?
+            Some((sep, SepKind::Trailing)) => (elem
+                .repeat_more_sep(self.whitespace + sep + self.whitespace, SepKind::Simple)
+                + (self.whitespace + sep).opt())
+            .finish(self.cx),
+            // A+ ^% B => (B WS)? A+ % (WS B WS)
+            Some((sep, SepKind::Leading)) => ((sep + self.whitespace).opt()
+                + elem.repeat_more_sep(self.whitespace + sep + self.whitespace, SepKind::Simple))
+            .finish(self.cx),
+            // A+ ^%% B => (B WS)? A+ % (WS B WS) (WS B)?
+            Some((sep, SepKind::Both)) => ((sep + self.whitespace).opt()
+                + elem.repeat_more_sep(self.whitespace + sep + self.whitespace, SepKind::Simple)
+                + (self.whitespace + sep).opt())
+            .finish(self.cx),
+        }
+    }
+}
+
+impl IRule {
+    /// Collects the flags tested (by `FlagGuard`s) in this rule,
+    /// not including the rules called from it.
+    pub(crate) fn guard_flags<Pat>(self, cx: &Context<Pat>) -> BTreeSet<IStr> {
+        let mut flags = BTreeSet::new();
+        self.collect_guard_flags(cx, &mut flags);
+        flags
+    }
+
+    fn collect_guard_flags<Pat>(self, cx: &Context<Pat>, flags: &mut BTreeSet<IStr>) {
+        match cx[self] {
+            Rule::Empty
+            | Rule::Anchor(_)
+            | Rule::Eat(_)
+            | Rule::EatUntil(_)
+            | Rule::Backref(_)
+            | Rule::Call(_)
+            | Rule::Guard(_)
+            | Rule::Feature(..) => {}
+            Rule::FlagGuard(flag, _) => {
+                flags.insert(flag);
+            }
+            Rule::Concat(ref rules) | Rule::Or(ref rules) => {
+                for rule in rules {
+                    rule.collect_guard_flags(cx, flags);
+                }
+            }
+            Rule::WithFlag(_, _, rule) | Rule::Silent(rule) | Rule::Opt(rule) => {
+                rule.collect_guard_flags(cx, flags)
+            }
+            Rule::Capture(_, delim, body) => {
+                delim.collect_guard_flags(cx, flags);
+                body.collect_guard_flags(cx, flags);
+            }
+            Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => {
+                elem.collect_guard_flags(cx, flags);
+                if let Some((sep, _)) = sep {
+                    sep.collect_guard_flags(cx, flags);
+                }
+            }
+        }
+    }
+}
+
+impl IRule {
+    /// Hash the structure of this rule, i.e. without depending on the
+    /// interning order (unlike hashing `IRule`s themselves).
+    pub(crate) fn hash_structure<Pat: Hash>(self, cx: &Context<Pat>, state: &mut impl Hasher) {
+        let hash_str = |s: IStr, state: &mut _| cx[s].hash(state);
+        match cx[self] {
+            Rule::Empty => state.write_u8(0),
+            Rule::Anchor(anchor) => {
+                state.write_u8(1);
+                anchor.hash(state);
+            }
+            Rule::Eat(ref pat) => {
+                state.write_u8(2);
+                pat.hash(state);
+            }
+            Rule::Call(name) => {
+                state.write_u8(3);
+                hash_str(name, state);
+            }
+            Rule::FlagGuard(flag, value) => {
+                state.write_u8(4);
+                hash_str(flag, state);
+                value.hash(state);
+            }
+            Rule::Guard(name) => {
+                state.write_u8(5);
+                hash_str(name, state);
+            }
+            Rule::Feature(feature, value) => {
+                state.write_u8(6);
+                hash_str(feature, state);
+                value.hash(state);
+            }
+            Rule::WithFlag(flag, value, rule) => {
+                state.write_u8(7);
+                hash_str(flag, state);
+                value.hash(state);
+                rule.hash_structure(cx, state);
+            }
+            Rule::Silent(rule) => {
+                state.write_u8(13);
+                rule.hash_structure(cx, state);
+            }
+            Rule::EatUntil(ref pat) => {
+                state.write_u8(14);
+                pat.hash(state);
+            }
+            Rule::Capture(name, delim, body) => {
+                state.write_u8(15);
+                hash_str(name, state);
+                delim.hash_structure(cx, state);
+                body.hash_structure(cx, state);
+            }
+            Rule::Backref(name) => {
+                state.write_u8(16);
+                hash_str(name, state);
+            }
+            Rule::Concat(ref rules) | Rule::Or(ref rules) => {
+                state.write_u8(if let Rule::Concat(_) = cx[self] { 8 } else { 9 });
+                rules.len().hash(state);
+                for rule in rules {
+                    rule.hash_structure(cx, state);
+                }
+            }
+            Rule::Opt(rule) => {
+                state.write_u8(10);
+                rule.hash_structure(cx, state);
+            }
+            Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => {
+                state.write_u8(if let Rule::RepeatMany(..) = cx[self] {
+                    11
+                } else {
+                    12
+                });
+                elem.hash_structure(cx, state);
+                match sep {
+                    None => state.write_u8(0),
+                    Some((sep, kind)) => {
+                        state.write_u8(1);
+                        sep.hash_structure(cx, state);
+                        kind.hash(state);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl IFields {
+    /// Like `IRule::hash_structure`, but for fields.
+    pub(crate) fn hash_structure<Pat>(self, cx: &Context<Pat>, state: &mut impl Hasher) {
+        match cx[self] {
+            Fields::Leaf(None) => state.write_u8(0),
+            Fields::Leaf(Some(field)) => {
+                state.write_u8(1);
+                cx[field.name].hash(state);
+                field.sub.hash_structure(cx, state);
+            }
+            Fields::Aggregate(ref children) => {
+                state.write_u8(2);
+                children.len().hash(state);
+                for child in children {
+                    child.hash_structure(cx, state);
+                }
+            }
+        }
+    }
+}
+
+/// Like `Folder`, but for rebuilding rules with some parts removed, where
+/// `None` stands for "can never match", and is propagated outwards,
+/// e.g. removing `Or` cases, or turning `A?` into just `Empty`.
+pub(crate) trait Pruner<'cx, Pat: 'cx + Eq + Hash>: Sized {
+    fn cx(&self) -> &'cx Context<Pat>;
+    fn prune_leaf(&mut self, rule: RuleWithFields) -> Option<RuleWithFields> {
+        Some(rule)
+    }
+    fn prune_with_flag(
+        &mut self,
+        flag: IStr,
+        value: bool,
+        rule: RuleWithFields,
+    ) -> Option<RuleWithFields> {
+        let rule = rule.prune(self)?;
+        let flag = &self.cx()[flag];
+        Some(if value {
+            rule.with_flag(flag).finish(self.cx())
+        } else {
+            rule.without_flag(flag).finish(self.cx())
+        })
+    }
+}
+
+impl RuleWithFields {
+    pub(crate) fn prune<'cx, Pat: 'cx + Eq + Hash>(
+        self,
+        pruner: &mut impl Pruner<'cx, Pat>,
+    ) -> Option<Self> {
+        let cx = pruner.cx();
+        if let Fields::Leaf(Some(field)) = cx[self.fields] {
+            let sub = RuleWithFields {
+                rule: self.rule,
+                fields: field.sub,
+            }
+            .prune(pruner)?;
+            return Some(RuleWithFields {
+                rule: sub.rule,
+                fields: cx.intern(Fields::Leaf(Some(Field {
+                    name: field.name,
+                    sub: sub.fields,
+                }))),
+            });
+        }
+        let field_rule = |rule, i| RuleWithFields {
+            rule,
+            fields: self.fields.child(cx, i),
+        };
+        Some(match cx[self.rule] {
+            Rule::Empty
+            | Rule::Anchor(_)
+            | Rule::Eat(_)
+            | Rule::EatUntil(_)
+            | Rule::Backref(_)
+            | Rule::Call(_)
+            | Rule::FlagGuard(..)
+            | Rule::Feature(..)
+            | Rule::Guard(_) => return pruner.prune_leaf(self),
+            Rule::WithFlag(flag, value, rule) => {
+                return pruner.prune_with_flag(flag, value, field_rule(rule, 0))
+            }
+            Rule::Silent(rule) => field_rule(rule, 0).prune(pruner)?.silent().finish(cx),
+            Rule::Capture(name, delim, body) => capture(
+                &cx[name],
+                field_rule(delim, 0).prune(pruner)?,
+                field_rule(body, 1).prune(pruner)?,
+            )
+            .finish(cx),
+            Rule::Concat(ref elems) => {
+                let elems = elems
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &elem)| field_rule(elem, i).prune(pruner))
+                    .collect::<Option<Vec<_>>>()?;
+                seq(elems).finish(cx)
+            }
+            Rule::Or(ref cases) => {
+                let cases: Vec<_> = cases
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, &case)| field_rule(case, i).prune(pruner))
+                    .collect();
+                if cases.is_empty() {
+                    return None;
+                }
+                any_of(cases).finish(cx)
+            }
+            Rule::Opt(rule) => match field_rule(rule, 0).prune(pruner) {
+                Some(rule) => rule.opt().finish(cx),
+                None => empty().finish(cx),
+            },
+            Rule::RepeatMany(elem, sep) => {
+                let elem = match field_rule(elem, 0).prune(pruner) {
+                    Some(elem) => elem,
+                    None => return Some(empty().finish(cx)),
+                };
                 match sep {
-                    // A* => A* % WS
-                    None => elem
-                        .repeat_more_sep(self.whitespace, SepKind::Simple)
-                        .finish(self.cx),
-                    // A* % B => A* % (WS B WS)
-                    Some((sep, SepKind::Simple)) => elem
-                        .repeat_more_sep(self.whitespace + sep + self.whitespace, SepKind::Simple)
-                        .finish(self.cx),
-                    // FIXME(cad97) this will insert too many whitespace rules
-                    // A* %% B => ???
-                    // Currently, A* %% (WS B WS), which allows trailing whitespace incorrectly
-                    Some((sep, SepKind::Trailing)) => elem
-                        .repeat_more_sep(self.whitespace + sep + self.whitespace, SepKind::Trailing)
-                        .finish(self.cx),
-                }
-            }
-            fn fold_repeat_more(
-                &mut self,
-                elem: RuleWithFields,
-                sep: Option<(RuleWithFields, SepKind)>,
-            ) -> RuleWithFields {
-                let elem = elem.fold(self);
-                let sep = sep.map(|(sep, kind)| (sep.fold(self), kind));
+                    None => elem.repeat_many().finish(cx),
+                    Some((sep, kind)) => match field_rule(sep, 1).prune(pruner) {
+                        Some(sep) => elem.repeat_many_sep(sep, kind).finish(cx),
+                        // A* % FAIL => A?
+                        None => elem.opt().finish(cx),
+                    },
+                }
+            }
+            Rule::RepeatMore(elem, sep) => {
+                let elem = field_rule(elem, 0).prune(pruner)?;
                 match sep {
-                    // A+ => A+ % WS
-                    None => elem
-                        .repeat_more_sep(self.whitespace, SepKind::Simple)
-                        .finish(self.cx),
-                    // A+ % B => A+ % (WS B WS)
-                    Some((sep, SepKind::Simple)) => elem
-                        .fold(self)
-                        .repeat_more_sep(self.whitespace + sep + self.whitespace, SepKind::Simple)
-                        .finish(self.cx),
-                    // A+ %% B => A+ % (WS B WS) (WS B)?
-                    Some((sep, SepKind::Trailing)) => (elem
-                        .repeat_more_sep(self.whitespace + sep + self.whitespace, SepKind::Simple)
-                        + (self.whitespace + sep).opt())
-                    .finish(self.cx),
+                    None => elem.repeat_more().finish(cx),
+                    Some((sep, kind)) => match field_rule(sep, 1).prune(pruner) {
+                        Some(sep) => elem.repeat_more_sep(sep, kind).finish(cx),
+                        // A+ % FAIL => A
+                        None => elem,
+                    },
+                }
+            }
+        })
+    }
+}
+
+/// Resolves `FlagGuard`s and `WithFlag`s, for one rule instantiated with
+/// `flags` set (see `Grammar::instantiate_flags`).
+pub(crate) struct FlagInstantiator<'a, 'cx, Pat> {
+    pub(crate) cx: &'cx Context<Pat>,
+    pub(crate) flags: BTreeSet<IStr>,
+    /// The flags (transitively) tested by each rule, i.e. the only ones
+    /// worth instantiating that rule for.
+    pub(crate) relevant_flags: &'a BTreeMap<IStr, BTreeSet<IStr>>,
+    /// The (non-trivial) rule instances called, to instantiate later.
+    pub(crate) calls: Vec<(IStr, BTreeSet<IStr>)>,
+}
+
+impl<Pat: Eq + Hash> FlagInstantiator<'_, '_, Pat> {
+    /// The name of `name` instantiated with `flags` set, e.g. `Expr<+NoStruct>`.
+    pub(crate) fn instance_name(cx: &Context<Pat>, name: IStr, flags: &BTreeSet<IStr>) -> IStr {
+        if flags.is_empty() {
+            return name;
+        }
+        let flags: Vec<_> = flags
+            .iter()
+            .map(|&flag| format!("+{}", &cx[flag]))
+            .collect();
+        cx.intern(&format!("{}<{}>", &cx[name], flags.join(","))[..])
+    }
+}
+
+impl<'cx, Pat: Eq + Hash> Pruner<'cx, Pat> for FlagInstantiator<'_, 'cx, Pat> {
+    fn cx(&self) -> &'cx Context<Pat> {
+        self.cx
+    }
+    fn prune_leaf(&mut self, rule: RuleWithFields) -> Option<RuleWithFields> {
+        let cx = self.cx;
+        match cx[rule.rule] {
+            Rule::Call(name) => {
+                // Not defined in the grammar, leave it to `Grammar::check`.
+                let relevant = match self.relevant_flags.get(&name) {
+                    Some(relevant) => relevant,
+                    None => return Some(rule),
+                };
+                let flags: BTreeSet<_> = self.flags.intersection(relevant).cloned().collect();
+                let instance = Self::instance_name(cx, name, &flags);
+                if !flags.is_empty() {
+                    self.calls.push((name, flags));
+                }
+                Some(RuleWithFields {
+                    rule: cx.intern(Rule::Call(instance)),
+                    fields: rule.fields,
+                })
+            }
+            Rule::FlagGuard(flag, value) => {
+                if self.flags.contains(&flag) == value {
+                    Some(empty().finish(cx))
+                } else {
+                    None
                 }
             }
+            _ => Some(rule),
         }
+    }
+    fn prune_with_flag(
+        &mut self,
+        flag: IStr,
+        value: bool,
+        rule: RuleWithFields,
+    ) -> Option<RuleWithFields> {
+        let old_flags = self.flags.clone();
+        if value {
+            self.flags.insert(flag);
+        } else {
+            self.flags.remove(&flag);
+        }
+        let rule = rule.prune(self);
+        self.flags = old_flags;
+        rule
+    }
+}
+
+/// Resolves `Feature`s, given the set of enabled features
+/// (see `Grammar::configure`).
+pub(crate) struct FeatureConfigurer<'a, 'cx, Pat> {
+    pub(crate) cx: &'cx Context<Pat>,
+    pub(crate) features: &'a BTreeSet<IStr>,
+}
 
-        self.fold(&mut WhitespaceInserter { cx, whitespace })
+impl<'cx, Pat: Eq + Hash> Pruner<'cx, Pat> for FeatureConfigurer<'_, 'cx, Pat> {
+    fn cx(&self) -> &'cx Context<Pat> {
+        self.cx
+    }
+    fn prune_leaf(&mut self, rule: RuleWithFields) -> Option<RuleWithFields> {
+        match self.cx[rule.rule] {
+            Rule::Feature(feature, enabled) => {
+                if self.features.contains(&feature) == enabled {
+                    Some(empty().finish(self.cx))
+                } else {
+                    None
+                }
+            }
+            _ => Some(rule),
+        }
     }
 }
@@ -1,7 +1,10 @@
-use crate::rule::{MatchesEmpty, MaybeKnown};
-use std::char;
-use std::fmt;
-use std::ops::{self, Bound, RangeBounds};
+use crate::compat::prelude::*;
+use crate::diagnostics::{Args, Localize};
+use crate::input::{self, IndexedInputMatch, InputMatch, Latin1, Rope, Utf16};
+use crate::rule::{MatchesEmpty, MatchesOverlap, MaybeKnown, Pattern};
+use core::char;
+use core::fmt;
+use core::ops::{self, Bound, RangeBounds};
 
 pub type Context<S = String> = crate::context::Context<Pat<S>>;
 
@@ -90,6 +93,97 @@ range_impls! {
     ops::RangeToInclusive<char>
 }
 
+impl<S: AsRef<str>> InputMatch<Pat<S>> for str {
+    fn match_left(&self, pat: &Pat<S>) -> Option<usize> {
+        match pat {
+            Pat::String(s) => self.match_left(s.as_ref()),
+            &Pat::Range(start, end) => self.match_left(&(start..=end)),
+        }
+    }
+    fn match_right(&self, pat: &Pat<S>) -> Option<usize> {
+        match pat {
+            Pat::String(s) => self.match_right(s.as_ref()),
+            &Pat::Range(start, end) => self.match_right(&(start..=end)),
+        }
+    }
+    fn match_left_ignore_case(&self, pat: &Pat<S>) -> Option<usize> {
+        match pat {
+            Pat::String(s) => self.match_left_ignore_case(s.as_ref()),
+            &Pat::Range(start, end) => self.match_left_ignore_case(&(start..=end)),
+        }
+    }
+}
+
+/// `IndexedInputMatch::match_at_ignore_case` for inputs which can be decoded
+/// one character at a time (see `Utf16::char_at`), like `Rope`.
+fn decoded_match_ignore_case_at<S: AsRef<str>>(
+    char_at: impl Fn(usize) -> Option<(char, usize)>,
+    pos: usize,
+    pat: &Pat<S>,
+) -> Option<usize> {
+    match pat {
+        Pat::String(s) => input::decoded_match_str_ignore_case_at(char_at, pos, s.as_ref()),
+        &Pat::Range(start, end) => {
+            let (c, len) = char_at(pos)?;
+            if input::in_range_ignore_case(c, start, end) {
+                Some(len)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+impl<S: AsRef<str>> IndexedInputMatch<Pat<S>> for Rope<'_> {
+    fn match_at(&self, pos: usize, pat: &Pat<S>) -> Option<usize> {
+        match pat {
+            Pat::String(s) => {
+                let s = s.as_ref();
+                if self.starts_with_at(pos, s) {
+                    Some(s.len())
+                } else {
+                    None
+                }
+            }
+            &Pat::Range(start, end) => {
+                let c = self.char_at(pos)?;
+                if start <= c && c <= end {
+                    Some(c.len_utf8())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+    fn match_at_ignore_case(&self, pos: usize, pat: &Pat<S>) -> Option<usize> {
+        decoded_match_ignore_case_at(|pos| self.char_at(pos).map(|c| (c, c.len_utf8())), pos, pat)
+    }
+}
+
+macro_rules! decoded_input_impls {
+    ($($input:ident),*) => {
+        $(impl<S: AsRef<str>> IndexedInputMatch<Pat<S>> for $input<'_> {
+            fn match_at(&self, pos: usize, pat: &Pat<S>) -> Option<usize> {
+                match pat {
+                    Pat::String(s) => self.match_str_at(pos, s.as_ref()),
+                    &Pat::Range(start, end) => {
+                        let (c, len) = self.char_at(pos)?;
+                        if start <= c && c <= end {
+                            Some(len)
+                        } else {
+                            None
+                        }
+                    }
+                }
+            }
+            fn match_at_ignore_case(&self, pos: usize, pat: &Pat<S>) -> Option<usize> {
+                decoded_match_ignore_case_at(|pos| self.char_at(pos), pos, pat)
+            }
+        })*
+    }
+}
+decoded_input_impls!(Utf16, Latin1);
+
 impl<S: AsRef<str>> MatchesEmpty for Pat<S> {
     fn matches_empty(&self) -> MaybeKnown<bool> {
         MaybeKnown::Known(match self {
@@ -98,3 +192,389 @@ impl<S: AsRef<str>> MatchesEmpty for Pat<S> {
         })
     }
 }
+
+impl<S: AsRef<str> + fmt::Debug> Pattern for Pat<S> {
+    fn first_chars(&self) -> MaybeKnown<Vec<ops::RangeInclusive<char>>> {
+        MaybeKnown::Known(match self {
+            Pat::String(s) => s
+                .as_ref()
+                .chars()
+                .next()
+                .map(|c| c..=c)
+                .into_iter()
+                .collect(),
+            &Pat::Range(start, end) => {
+                if start <= end {
+                    vec![start..=end]
+                } else {
+                    vec![]
+                }
+            }
+        })
+    }
+
+    fn try_match(&self, input: &str, pos: usize) -> MaybeKnown<Option<usize>> {
+        MaybeKnown::Known(input.get(pos..).and_then(|rest| rest.match_left(self)))
+    }
+
+    // NOTE the set operations are only supported on patterns which
+    // match exactly one character, i.e. ranges and single-character strings.
+
+    fn union(a: &[Self], b: &[Self]) -> Option<Vec<Self>> {
+        let mut ranges = char_ranges(a)?;
+        ranges.extend(char_ranges(b)?);
+        Some(range_pats(normalize_ranges(ranges)))
+    }
+
+    fn intersect(a: &[Self], b: &[Self]) -> Option<Vec<Self>> {
+        let (a, b) = (
+            normalize_ranges(char_ranges(a)?),
+            normalize_ranges(char_ranges(b)?),
+        );
+        let mut ranges = vec![];
+        for &(a_start, a_end) in &a {
+            for &(b_start, b_end) in &b {
+                let (start, end) = (a_start.max(b_start), a_end.min(b_end));
+                if start <= end {
+                    ranges.push((start, end));
+                }
+            }
+        }
+        Some(range_pats(normalize_ranges(ranges)))
+    }
+
+    fn negate(pats: &[Self]) -> Option<Vec<Self>> {
+        Some(range_pats(negate_ranges(char_ranges(pats)?)))
+    }
+}
+
+/// The (disjoint) character ranges covering every character in none of `ranges`.
+pub(crate) fn negate_ranges(ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    let mut negated = vec![];
+    let mut next = Some('\0');
+    for (start, end) in normalize_ranges(ranges) {
+        if let Some(next) = next {
+            if let Some(before) = char_before(start).filter(|&before| next <= before) {
+                negated.push((next, before));
+            }
+        }
+        next = char_after(end);
+    }
+    if let Some(next) = next {
+        negated.push((next, char::MAX));
+    }
+    negated
+}
+
+/// The (possibly overlapping) character ranges matched by `pats`, if they
+/// all match exactly one character (i.e. they're ranges or single characters).
+fn char_ranges<S: AsRef<str>>(pats: &[Pat<S>]) -> Option<Vec<(char, char)>> {
+    pats.iter()
+        .map(|pat| match *pat {
+            Pat::String(ref s) => {
+                let mut chars = s.as_ref().chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some((c, c)),
+                    _ => None,
+                }
+            }
+            Pat::Range(start, end) => Some((start, end)),
+        })
+        .collect()
+}
+
+/// Sort and merge `ranges`, leaving them disjoint and non-adjacent
+/// (and dropping empty ones).
+fn normalize_ranges(mut ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    ranges.retain(|&(start, end)| start <= end);
+    ranges.sort();
+    let mut merged: Vec<(char, char)> = vec![];
+    for (start, end) in ranges {
+        if let Some(last) = merged.last_mut() {
+            if start <= last.1 || Some(start) == char_after(last.1) {
+                last.1 = last.1.max(end);
+                continue;
+            }
+        }
+        merged.push((start, end));
+    }
+    merged
+}
+
+fn range_pats<S>(ranges: Vec<(char, char)>) -> Vec<Pat<S>> {
+    ranges
+        .into_iter()
+        .map(|(start, end)| Pat::Range(start, end))
+        .collect()
+}
+
+impl<S: AsRef<str>> MatchesOverlap for Pat<S> {
+    fn overlaps(&self, other: &Self) -> MaybeKnown<bool> {
+        MaybeKnown::Known(match (self, other) {
+            (Pat::String(a), Pat::String(b)) => {
+                let (a, b) = (a.as_ref(), b.as_ref());
+                a.starts_with(b) || b.starts_with(a)
+            }
+            (&Pat::Range(a_start, a_end), &Pat::Range(b_start, b_end)) => {
+                a_start <= a_end && b_start <= b_end && a_start <= b_end && b_start <= a_end
+            }
+            (Pat::String(s), &Pat::Range(start, end))
+            | (&Pat::Range(start, end), Pat::String(s)) => match s.as_ref().chars().next() {
+                Some(c) => start <= c && c <= end,
+                None => start <= end,
+            },
+        })
+    }
+
+    fn contains(&self, other: &Self) -> MaybeKnown<bool> {
+        MaybeKnown::Known(match (self, other) {
+            (Pat::String(a), Pat::String(b)) => b.as_ref().starts_with(a.as_ref()),
+            (&Pat::Range(a_start, a_end), &Pat::Range(b_start, b_end)) => {
+                b_start > b_end || (a_start <= b_start && b_end <= a_end)
+            }
+            (Pat::String(s), &Pat::Range(start, end)) => {
+                let mut chars = s.as_ref().chars();
+                match (chars.next(), chars.next()) {
+                    (None, _) => true,
+                    (Some(c), None) => start > end || (start == c && end == c),
+                    (Some(_), Some(_)) => start > end,
+                }
+            }
+            (&Pat::Range(start, end), Pat::String(s)) => match s.as_ref().chars().next() {
+                Some(c) => start <= c && c <= end,
+                None => false,
+            },
+        })
+    }
+}
+
+// NOTE these skip over the surrogate range, which has no `char`s.
+pub(crate) fn char_before(c: char) -> Option<char> {
+    match c {
+        '\0' => None,
+        '\u{e000}' => Some('\u{d7ff}'),
+        _ => char::from_u32(c as u32 - 1),
+    }
+}
+
+pub(crate) fn char_after(c: char) -> Option<char> {
+    match c {
+        char::MAX => None,
+        '\u{d7ff}' => Some('\u{e000}'),
+        _ => char::from_u32(c as u32 + 1),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct LiteralError {
+    /// Byte offset in the literal (including its quotes/prefix).
+    pub at: usize,
+    pub message: String,
+    message_id: &'static str,
+}
+
+impl fmt::Display for LiteralError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at {})", self.message, self.at)
+    }
+}
+
+impl LiteralError {
+    fn new(at: usize, message_id: &'static str, message: &str) -> Self {
+        LiteralError {
+            at,
+            message: message.to_string(),
+            message_id,
+        }
+    }
+}
+
+impl Localize for LiteralError {
+    /// One of `expected-double-quote`, `expected-single-quote`, `unterminated-string`,
+    /// `unterminated-raw-string`, `unterminated-char`, `unescaped-double-quote`,
+    /// `invalid-escape`, `byte-string-unsupported`, `empty-char`, `multiple-chars`
+    /// and `empty-char-range`, all with an `at` argument.
+    fn message_id(&self) -> &'static str {
+        self.message_id
+    }
+
+    fn args(&self) -> Args {
+        vec![("at", self.at.to_string())]
+    }
+}
+
+/// Decode the escape at the start of `rest` (just after a `\`), i.e. one of
+/// `\n`, `\r`, `\t`, `\0`, `\\`, `\"`, `\'`, `\xNN` or `\u{N...}`, returning
+/// the character it stands for and its length (not including the `\`).
+pub(crate) fn unescape(rest: &str) -> Option<(char, usize)> {
+    Some(match rest.chars().next()? {
+        'n' => ('\n', 1),
+        'r' => ('\r', 1),
+        't' => ('\t', 1),
+        '0' => ('\0', 1),
+        c @ ('\\' | '"' | '\'') => (c, 1),
+        'x' => {
+            let hex = rest.get(1..3)?;
+            (char::from_u32(u32::from_str_radix(hex, 16).ok()?)?, 3)
+        }
+        'u' if rest[1..].starts_with('{') => {
+            let end = rest.find('}')?;
+            let hex = rest[2..end].replace('_', "");
+            if hex.is_empty() || hex.len() > 6 {
+                return None;
+            }
+            (
+                char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?,
+                end + 1,
+            )
+        }
+        _ => return None,
+    })
+}
+
+/// Parse a Rust string literal (e.g. `"a\tb"` or `r#"a"b"#`), as found in
+/// the textual grammar format (see `grammar_grammar`'s `StrLit`), into the
+/// string it denotes, supporting all the escapes `unescape` does, and line
+/// continuations (`\` followed by a newline, skipping leading whitespace).
+pub fn parse_str_literal(lit: &str) -> Result<String, LiteralError> {
+    let error = LiteralError::new;
+
+    if let Some(raw) = lit.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        let body = raw[hashes..]
+            .strip_prefix('"')
+            .ok_or_else(|| error(1 + hashes, "expected-double-quote", "expected `\"`"))?;
+        let closing = format!("\"{}", "#".repeat(hashes));
+        return match body.strip_suffix(&closing[..]) {
+            Some(s) if !s.contains(&closing[..]) => Ok(s.to_string()),
+            _ => Err(error(
+                lit.len(),
+                "unterminated-raw-string",
+                "unterminated raw string literal",
+            )),
+        };
+    }
+    if lit.starts_with('b') {
+        return Err(error(
+            0,
+            "byte-string-unsupported",
+            "byte string literals are not supported",
+        ));
+    }
+
+    let body = lit
+        .strip_prefix('"')
+        .ok_or_else(|| error(0, "expected-double-quote", "expected `\"`"))?;
+    let body = body.strip_suffix('"').ok_or_else(|| {
+        error(
+            lit.len(),
+            "unterminated-string",
+            "unterminated string literal",
+        )
+    })?;
+    let mut s = String::new();
+    let mut i = 0;
+    while let Some(c) = body[i..].chars().next() {
+        match c {
+            '\\' if body[i + 1..].starts_with(['\n', '\r']) => {
+                i = body.len() - body[i + 1..].trim_start().len();
+            }
+            '\\' => {
+                let (c, len) = unescape(&body[i + 1..])
+                    .ok_or_else(|| error(1 + i, "invalid-escape", "invalid escape"))?;
+                s.push(c);
+                i += 1 + len;
+            }
+            '"' => {
+                return Err(error(
+                    1 + i,
+                    "unescaped-double-quote",
+                    "unescaped `\"` in string literal",
+                ))
+            }
+            _ => {
+                s.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+    Ok(s)
+}
+
+/// Parse a Rust character literal (e.g. `'\n'`), as found in the textual
+/// grammar format (see `grammar_grammar`'s `CharLit`), into its character.
+pub fn parse_char_literal(lit: &str) -> Result<char, LiteralError> {
+    let error = LiteralError::new;
+    let body = lit
+        .strip_prefix('\'')
+        .ok_or_else(|| error(0, "expected-single-quote", "expected `'`"))?
+        .strip_suffix('\'')
+        .ok_or_else(|| {
+            error(
+                lit.len(),
+                "unterminated-char",
+                "unterminated character literal",
+            )
+        })?;
+    let (c, len) = match body.strip_prefix('\\') {
+        Some(escape) => {
+            let (c, len) =
+                unescape(escape).ok_or_else(|| error(1, "invalid-escape", "invalid escape"))?;
+            (c, 1 + len)
+        }
+        None => {
+            let c = body
+                .chars()
+                .next()
+                .ok_or_else(|| error(1, "empty-char", "empty character literal"))?;
+            (c, c.len_utf8())
+        }
+    };
+    if len != body.len() {
+        return Err(error(
+            1 + len,
+            "multiple-chars",
+            "character literal must be one character",
+        ));
+    }
+    Ok(c)
+}
+
+impl Pat {
+    /// Pattern for a `StrLit` (see `parse_str_literal`).
+    pub fn from_str_literal(lit: &str) -> Result<Self, LiteralError> {
+        parse_str_literal(lit).map(Pat::String)
+    }
+
+    /// Pattern for a range of `CharLit`s (see `parse_char_literal`), which
+    /// is unbounded at either end for `None` (e.g. `..='z'`).
+    pub fn from_char_literal_range(
+        start: Option<&str>,
+        end: Option<&str>,
+        inclusive: bool,
+    ) -> Result<Self, LiteralError> {
+        let start = start.map(parse_char_literal).transpose()?;
+        let end = end.map(parse_char_literal).transpose()?;
+        let start = start.map_or(Bound::Unbounded, Bound::Included);
+        let end = match end {
+            None => Bound::Unbounded,
+            Some(end) if inclusive => Bound::Included(end),
+            Some('\0') => {
+                return Err(LiteralError::new(
+                    0,
+                    "empty-char-range",
+                    "empty character range",
+                ))
+            }
+            Some(end) => Bound::Excluded(end),
+        };
+        match Pat::from((start, end)) {
+            Pat::Range(start, end) if start > end => Err(LiteralError::new(
+                0,
+                "empty-char-range",
+                "empty character range",
+            )),
+            pat => Ok(pat),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Mapping byte offsets (and `Span`s) to lines and columns, in one or more
+//! source files, with each file's line starts computed once (see `SourceFile`),
+//! instead of rescanning the text before every offset being looked up.
+
+use crate::compat::prelude::*;
+use crate::input::{LineColumn, LineColumnRange};
+use core::fmt;
+use core::ops::Range;
+
+/// A file in a `SourceMap` (the index it was added at).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileId(pub usize);
+
+/// A byte range in a file in a `SourceMap`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Span {
+    pub file: FileId,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(file: FileId, range: Range<usize>) -> Self {
+        Span {
+            file,
+            start: range.start,
+            end: range.end,
+        }
+    }
+
+    pub fn range(self) -> Range<usize> {
+        self.start..self.end
+    }
+}
+
+pub struct SourceFile {
+    pub name: String,
+    pub src: String,
+    /// The byte offset of the start of every line (the first one being `0`).
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: impl Into<String>, src: impl Into<String>) -> Self {
+        let src = src.into();
+        let line_starts = [0]
+            .into_iter()
+            .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        SourceFile {
+            name: name.into(),
+            src,
+            line_starts,
+        }
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// The text of the (0-based) line `line`, without its newline.
+    pub fn line(&self, line: usize) -> &str {
+        let start = self.line_starts[line];
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .map_or(self.src.len(), |&next| next - 1);
+        &self.src[start..end]
+    }
+
+    /// The line and column (in characters) of the byte offset `offset`.
+    pub fn line_column(&self, offset: usize) -> LineColumn {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        LineColumn {
+            line,
+            column: self.src[self.line_starts[line]..offset].chars().count(),
+        }
+    }
+
+    pub fn line_column_range(&self, range: Range<usize>) -> LineColumnRange {
+        LineColumnRange {
+            start: self.line_column(range.start),
+            end: self.line_column(range.end),
+        }
+    }
+}
+
+/// A set of source files (e.g. a grammar split across several files),
+/// for `Span`s pointing into any of them.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    pub fn add(&mut self, name: impl Into<String>, src: impl Into<String>) -> FileId {
+        self.files.push(SourceFile::new(name, src));
+        FileId(self.files.len() - 1)
+    }
+
+    pub fn file(&self, file: FileId) -> &SourceFile {
+        &self.files[file.0]
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = (FileId, &SourceFile)> {
+        self.files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| (FileId(i), file))
+    }
+
+    /// The text `span` covers.
+    pub fn text(&self, span: Span) -> &str {
+        &self.file(span.file).src[span.range()]
+    }
+
+    /// The location of `span`, e.g. `foo.pest:3:5-3:9` (all 1-based).
+    pub fn describe(&self, span: Span) -> Location<'_> {
+        let file = self.file(span.file);
+        Location {
+            file: &file.name,
+            range: file.line_column_range(span.range()),
+        }
+    }
+}
+
+/// A `Span`, resolved to lines and columns, e.g. for printing.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Location<'a> {
+    pub file: &'a str,
+    pub range: LineColumnRange,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{:?}", self.file, self.range.start)?;
+        if self.range.end != self.range.start {
+            write!(f, "-{:?}", self.range.end)?;
+        }
+        Ok(())
+    }
+}
+
+/// The line and column of the byte offset `offset` in `src`, for a single
+/// lookup (otherwise, `SourceFile::line_column` avoids rescanning `src`).
+pub fn line_column(src: &str, offset: usize) -> LineColumn {
+    let prefix = &src[..offset];
+    let line_start = prefix.rfind('\n').map_or(0, |i| i + 1);
+    LineColumn {
+        line: prefix.matches('\n').count(),
+        column: prefix[line_start..].chars().count(),
+    }
+}
@@ -0,0 +1,82 @@
+//! Naming the rules invented by transformations (e.g. `Grammar::lift_inline`,
+//! or `Grammar::extract_common`), after the rule they came from, and keeping
+//! track of where they came from (see `Grammar::synthetic_origin`).
+//!
+//! Names look like `Expr__alt_2`, i.e. `{parent}__{kind}_{n}`, with `n`
+//! counting (from `1`) the rules of that `kind` created for that `parent`,
+//! skipping any names already taken. They only depend on the grammar being
+//! transformed (not e.g. on hashing), so they're the same across runs, and
+//! rules don't get renamed by changes to other (unrelated) rules.
+
+use crate::compat::prelude::*;
+use crate::compat::{IndexMap, IndexSet};
+use crate::context::{Context, IStr};
+use crate::Grammar;
+use core::fmt;
+use core::hash::Hash;
+
+/// What a synthetic rule was created for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// An `Or` lifted out of its rule (see `Grammar::lift_inline`).
+    Alt,
+    /// An `Opt`/repeat operand lifted out of its rule (see `Grammar::lift_inline`).
+    Group,
+    /// A part repeated across rules (see `Grammar::extract_common`).
+    Shared,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Alt => "alt",
+            Kind::Group => "group",
+            Kind::Shared => "shared",
+        })
+    }
+}
+
+/// Where a synthetic rule came from: the rule it was named after (for
+/// `Kind::Shared`, the first of the rules it was found in), and why.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub parent: IStr,
+    pub kind: Kind,
+}
+
+/// Picks names for the synthetic rules created by one transformation.
+pub(crate) struct Namer {
+    /// All the rule names in use (or already picked), to avoid.
+    taken: IndexSet<IStr>,
+    counts: IndexMap<(IStr, Kind), usize>,
+    /// The names picked so far, to be recorded in the transformed grammar.
+    pub(crate) picked: IndexMap<IStr, Origin>,
+}
+
+impl Namer {
+    pub(crate) fn new(grammar: &Grammar) -> Self {
+        Namer {
+            taken: grammar
+                .rules()
+                .keys()
+                .chain(grammar.synthetic.keys())
+                .copied()
+                .collect(),
+            counts: IndexMap::default(),
+            picked: IndexMap::default(),
+        }
+    }
+
+    pub(crate) fn fresh<Pat: Eq + Hash>(&mut self, cx: &Context<Pat>, origin: Origin) -> IStr {
+        let count = self.counts.entry((origin.parent, origin.kind)).or_default();
+        loop {
+            *count += 1;
+            let name = format!("{}__{}_{}", &cx[origin.parent], origin.kind, count);
+            let name = cx.intern(&name[..]);
+            if self.taken.insert(name) {
+                self.picked.insert(name, origin);
+                return name;
+            }
+        }
+    }
+}
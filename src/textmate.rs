@@ -0,0 +1,85 @@
+//! Exporting the lexical rules of a grammar as a TextMate grammar (i.e. a
+//! `.tmLanguage.json` file), for syntax highlighting (see `export`).
+
+use crate::compat::prelude::*;
+use crate::context::Context;
+use crate::diagnostics::json_str;
+use crate::regex::{self, RegexPat};
+use crate::Grammar;
+use core::fmt;
+use core::hash::Hash;
+
+#[derive(Debug)]
+pub struct ExportError {
+    /// The name of the rule which couldn't be exported.
+    pub rule: String,
+    pub reason: ExportErrorReason,
+}
+
+#[derive(Debug)]
+pub enum ExportErrorReason {
+    Undefined,
+    /// The rule isn't regular (or uses zero-width rules), so a regex for
+    /// it would only approximate it (see `regex::Regex::exact`).
+    NotRegular,
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            ExportErrorReason::Undefined => write!(f, "rule `{}` is not defined", self.rule),
+            ExportErrorReason::NotRegular => write!(
+                f,
+                "rule `{}` cannot be matched exactly by a regex",
+                self.rule
+            ),
+        }
+    }
+}
+
+/// Export the rules named in `scopes` as a TextMate grammar, named `name`,
+/// with `scope_name` as its top-level scope (e.g. `"source.foo"`), and one
+/// pattern (highlighted with the paired scope, e.g. `"constant.numeric"`)
+/// per rule, in the order given (which TextMate uses to break ties).
+///
+/// Only rules whose regexes are exact (see `regex::approx`) are allowed,
+/// and, as TextMate matches each line separately, they shouldn't be able
+/// to match newlines (which isn't checked).
+pub fn export<Pat: Eq + Hash + RegexPat>(
+    cx: &Context<Pat>,
+    grammar: &Grammar,
+    name: &str,
+    scope_name: &str,
+    scopes: &[(&str, &str)],
+) -> Result<String, ExportError> {
+    let mut patterns = vec![];
+    for &(rule, scope) in scopes {
+        let error = |reason| ExportError {
+            rule: rule.to_string(),
+            reason,
+        };
+        let regex = regex::approx(cx, grammar, cx.intern(rule))
+            .map_err(|_| error(ExportErrorReason::Undefined))?;
+        if !regex.exact {
+            return Err(error(ExportErrorReason::NotRegular));
+        }
+        patterns.push(format!(
+            "    {{ \"name\": {}, \"match\": {} }}",
+            json_str(scope),
+            json_str(&regex.regex)
+        ));
+    }
+
+    let mut json = String::new();
+    json += "{\n";
+    json += &format!("  \"name\": {},\n", json_str(name));
+    json += &format!("  \"scopeName\": {},\n", json_str(scope_name));
+    json += "  \"patterns\": [\n";
+    json += &patterns.join(",\n");
+    if !patterns.is_empty() {
+        json += "\n";
+    }
+    json += "  ]\n";
+    json += "}\n";
+    Ok(json)
+}
@@ -0,0 +1,242 @@
+//! Balanced delimiters, i.e. "token trees" (like Rust's), for matching whole
+//! groups (e.g. `(...)`) without parsing their contents, which can instead
+//! be parsed lazily, by another rule (e.g. chosen by what precedes them).
+//!
+//! Splitting the input into groups first (see `Delimiters::split`) also
+//! keeps syntax errors contained, as each group can be parsed on its own.
+
+use crate::compat::prelude::*;
+use crate::context::IStr;
+use crate::input::InputMatch;
+use crate::interpreter::{self, Derivation, FieldMatch, Interpreter};
+use crate::rule::{any_of, call, eat};
+use crate::scannerless::{self, char_after, char_before};
+use crate::Grammar;
+use core::char;
+use core::fmt;
+use core::hash::Hash;
+use core::ops::Range;
+
+/// Pairs of opening and closing delimiters, e.g. `('(', ')')`.
+#[derive(Clone, Debug)]
+pub struct Delimiters {
+    pub pairs: Vec<(char, char)>,
+}
+
+/// Parentheses, square brackets and curly braces.
+impl Default for Delimiters {
+    fn default() -> Self {
+        Delimiters::new(&[('(', ')'), ('[', ']'), ('{', '}')])
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenTree {
+    Group(Group),
+    /// Text between delimiters (never empty).
+    Text(Range<usize>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Group {
+    pub open: char,
+    pub close: char,
+    /// The whole group, including its delimiters (in bytes).
+    pub span: Range<usize>,
+    pub children: Vec<TokenTree>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Unbalanced {
+    /// Opening delimiter at `open_at` never closed (the innermost one,
+    /// if there are several).
+    Unclosed { open_at: usize },
+    /// Closing delimiter at `close_at` without a matching opening one.
+    Unopened { close_at: usize },
+    /// Closing delimiter at `close_at` not matching the opening one, at
+    /// `open_at`, of the innermost group still open (e.g. `(]`).
+    Mismatched { open_at: usize, close_at: usize },
+}
+
+impl fmt::Display for Unbalanced {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Unbalanced::Unclosed { open_at } => {
+                write!(f, "unclosed delimiter (at {})", open_at)
+            }
+            Unbalanced::Unopened { close_at } => {
+                write!(f, "unexpected closing delimiter (at {})", close_at)
+            }
+            Unbalanced::Mismatched { open_at, close_at } => write!(
+                f,
+                "mismatched closing delimiter (at {}, opened at {})",
+                close_at, open_at
+            ),
+        }
+    }
+}
+
+impl Delimiters {
+    pub fn new(pairs: &[(char, char)]) -> Self {
+        let delimiters = Delimiters {
+            pairs: pairs.to_vec(),
+        };
+        let mut chars: Vec<_> = pairs
+            .iter()
+            .flat_map(|&(open, close)| [open, close])
+            .collect();
+        chars.sort_unstable();
+        chars.dedup();
+        assert_eq!(
+            chars.len(),
+            pairs.len() * 2,
+            "delimiters must all be distinct"
+        );
+        delimiters
+    }
+
+    /// Scannerless rules for balanced groups, named `GROUP` (a delimited
+    /// group, containing any number of `TOKEN_TREE`s), and `TOKEN_TREE`
+    /// (either a `GROUP`, or any single character other than a delimiter).
+    ///
+    /// These are usually used with `Group::explain`, to parse the contents
+    /// of a `GROUP` (e.g. the body of a macro invocation) lazily.
+    pub fn rules<S: Eq + Hash>(&self, cx: &scannerless::Context<S>) -> Grammar {
+        let mut g = Grammar::new();
+
+        let delim = |c| eat(scannerless::Pat::Range(c, c));
+        let groups = self
+            .pairs
+            .iter()
+            .map(|&(open, close)| delim(open) + call("TOKEN_TREE").repeat_many() + delim(close));
+        g.define(cx.intern("GROUP"), any_of(groups).finish(cx));
+
+        // All the characters other than delimiters, as a list of ranges.
+        let mut delims: Vec<_> = self
+            .pairs
+            .iter()
+            .flat_map(|&(open, close)| [open, close])
+            .collect();
+        delims.sort_unstable();
+        let mut others = vec![];
+        let mut start = Some('\0');
+        for c in delims {
+            if let (Some(s), Some(end)) = (start, char_before(c)) {
+                if s <= end {
+                    others.push(eat(scannerless::Pat::Range(s, end)));
+                }
+            }
+            start = char_after(c);
+        }
+        if let Some(start) = start {
+            others.push(eat(scannerless::Pat::Range(start, char::MAX)));
+        }
+
+        g.define(
+            cx.intern("TOKEN_TREE"),
+            (call("GROUP") | any_of(others)).finish(cx),
+        );
+
+        g
+    }
+
+    /// Split all of `input` into token trees, or find the first place where
+    /// the delimiters aren't balanced.
+    pub fn split(&self, input: &str) -> Result<Vec<TokenTree>, Unbalanced> {
+        // The groups still open (outermost first), with their start offset
+        // and the token trees found in them so far, and the top-level ones.
+        let mut open: Vec<(char, usize, Vec<TokenTree>)> = vec![];
+        let mut top = vec![];
+        let mut text_start = 0;
+
+        for (i, c) in input.char_indices() {
+            let is_open = self.pairs.iter().any(|&(o, _)| o == c);
+            let is_close = self.pairs.iter().any(|&(_, cl)| cl == c);
+            if !is_open && !is_close {
+                continue;
+            }
+
+            let trees = open.last_mut().map_or(&mut top, |(_, _, trees)| trees);
+            if text_start < i {
+                trees.push(TokenTree::Text(text_start..i));
+            }
+            text_start = i + c.len_utf8();
+
+            if is_open {
+                open.push((c, i, vec![]));
+                continue;
+            }
+            let (open_char, open_at, children) = match open.pop() {
+                Some(group) => group,
+                None => return Err(Unbalanced::Unopened { close_at: i }),
+            };
+            if !self.pairs.contains(&(open_char, c)) {
+                return Err(Unbalanced::Mismatched {
+                    open_at,
+                    close_at: i,
+                });
+            }
+            let group = TokenTree::Group(Group {
+                open: open_char,
+                close: c,
+                span: open_at..text_start,
+                children,
+            });
+            open.last_mut()
+                .map_or(&mut top, |(_, _, trees)| trees)
+                .push(group);
+        }
+
+        if let Some(&(_, open_at, _)) = open.last() {
+            return Err(Unbalanced::Unclosed { open_at });
+        }
+        if text_start < input.len() {
+            top.push(TokenTree::Text(text_start..input.len()));
+        }
+        Ok(top)
+    }
+}
+
+impl Group {
+    /// The contents of the group, without its delimiters (in bytes).
+    pub fn contents(&self) -> Range<usize> {
+        self.span.start + self.open.len_utf8()..self.span.end - self.close.len_utf8()
+    }
+
+    /// Parse the contents of the group (taken from `input`, which the group
+    /// was found in), with `rule`, like `Interpreter::explain` (but with all
+    /// the spans in the resulting `Derivation` relative to `input`).
+    pub fn explain<Pat: Eq + Hash>(
+        &self,
+        interpreter: &Interpreter<'_, Pat>,
+        rule: IStr,
+        input: &str,
+    ) -> Result<Option<Derivation>, interpreter::Error>
+    where
+        str: InputMatch<Pat>,
+    {
+        let contents = self.contents();
+        let mut derivation = interpreter.explain(rule, &input[contents.clone()])?;
+        if let Some(derivation) = &mut derivation {
+            shift(derivation, contents.start);
+        }
+        Ok(derivation)
+    }
+}
+
+fn shift(derivation: &mut Derivation, offset: usize) {
+    derivation.span = derivation.span.start + offset..derivation.span.end + offset;
+    for child in &mut derivation.children {
+        shift(child, offset);
+    }
+    for field in &mut derivation.fields {
+        shift_field(field, offset);
+    }
+}
+
+fn shift_field(field: &mut FieldMatch, offset: usize) {
+    field.span = field.span.start + offset..field.span.end + offset;
+    for field in &mut field.fields {
+        shift_field(field, offset);
+    }
+}
@@ -0,0 +1,692 @@
+//! Turning scannerless grammars into token-level ones, where terminals are
+//! replaced by abstract token names (see `Grammar::anonymize_terminals`),
+//! e.g. for exporting to LR tools, or documenting the lexical layer apart,
+//! optionally along with a lexer for the tokens (see `Grammar::tokenize`).
+
+use crate::compat::prelude::*;
+use crate::compat::{IndexMap, IndexSet};
+use crate::context::{Context, IRule, IStr};
+use crate::diagnostics::{Code, Diagnostic};
+use crate::enumerate;
+use crate::interpreter::Interpreter;
+use crate::regex::{self, Regex};
+use crate::rule::{call, Fields, Folder, Rule, RuleWithFields};
+use crate::scannerless::Pat as SPat;
+use crate::Grammar;
+use core::fmt;
+use core::hash::Hash;
+
+/// The tokens of a token-level grammar, i.e. the terminal (an `Eat` or an
+/// `EatUntil`) every token name stands for, in the order they were found in.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TokenTable {
+    tokens: IndexMap<IStr, IRule>,
+}
+
+impl TokenTable {
+    /// The terminal the token `name` stands for.
+    pub fn terminal(&self, name: IStr) -> Option<IRule> {
+        self.tokens.get(&name).copied()
+    }
+
+    /// The name of the token standing for `terminal`.
+    pub fn token(&self, terminal: IRule) -> Option<IStr> {
+        self.tokens
+            .iter()
+            .find(|&(_, &t)| t == terminal)
+            .map(|(&name, _)| name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// All the `(token, terminal)` pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (IStr, IRule)> + '_ {
+        self.tokens
+            .iter()
+            .map(|(&name, &terminal)| (name, terminal))
+    }
+
+    /// A grammar defining every token as a rule matching its terminal, which
+    /// can be `extend`ed into the token-level grammar, to get back one that
+    /// matches the same inputs as the original (scannerless) grammar.
+    pub fn lexical_grammar<Pat: Eq + Hash>(&self, cx: &Context<Pat>) -> Grammar {
+        let mut grammar = Grammar::new();
+        for (&name, &terminal) in &self.tokens {
+            grammar.define(
+                name,
+                RuleWithFields {
+                    rule: terminal,
+                    fields: cx.intern(Fields::Leaf(None)),
+                },
+            );
+        }
+        grammar
+    }
+}
+
+/// Replaces terminals with `Call`s to tokens (see `anonymize_terminals`).
+struct Anonymizer<'a, S> {
+    cx: &'a Context<SPat<S>>,
+    /// All the rule and token names in use, to avoid when picking new ones.
+    taken: IndexSet<IStr>,
+    tokens: IndexMap<IRule, IStr>,
+}
+
+impl<'a, S: AsRef<str> + Eq + Hash> Anonymizer<'a, S> {
+    fn new(cx: &'a Context<SPat<S>>, grammar: &Grammar) -> Self {
+        Anonymizer {
+            cx,
+            taken: grammar.rules().keys().copied().collect(),
+            tokens: IndexMap::default(),
+        }
+    }
+
+    fn into_token_table(self) -> TokenTable {
+        TokenTable {
+            tokens: (self.tokens.into_iter())
+                .map(|(terminal, name)| (name, terminal))
+                .collect(),
+        }
+    }
+}
+
+impl<'a, S: AsRef<str> + Eq + Hash> Folder<'a, SPat<S>> for Anonymizer<'a, S> {
+    fn cx(&self) -> &'a Context<SPat<S>> {
+        self.cx
+    }
+    fn fold_leaf(&mut self, rule: RuleWithFields) -> RuleWithFields {
+        let cx = self.cx;
+        let base = match cx[rule.rule] {
+            Rule::Eat(ref pat) => token_name(pat),
+            Rule::EatUntil(ref pat) => format!("UNTIL_{}", token_name(pat)),
+            _ => return rule,
+        };
+        let taken = &mut self.taken;
+        let name = *self.tokens.entry(rule.rule).or_insert_with(|| {
+            let mut i = 1;
+            loop {
+                let name = if i == 1 {
+                    base.clone()
+                } else {
+                    format!("{}_{}", base, i)
+                };
+                let name = cx.intern(&name[..]);
+                if taken.insert(name) {
+                    break name;
+                }
+                i += 1;
+            }
+        });
+        call(&cx[name]).finish(cx)
+    }
+}
+
+pub(crate) fn anonymize_terminals<S: AsRef<str> + Eq + Hash>(
+    cx: &Context<SPat<S>>,
+    grammar: Grammar,
+) -> (Grammar, TokenTable) {
+    let mut anonymizer = Anonymizer::new(cx, &grammar);
+    let mut anonymized = grammar.empty_like();
+    for (&name, rule) in grammar.rules() {
+        anonymized.define(name, rule.fold(&mut anonymizer));
+    }
+    (anonymized, anonymizer.into_token_table())
+}
+
+/// The rules of `grammar` which look lexical, i.e. which have no fields
+/// (which tokens couldn't keep), and can be matched exactly by a regex
+/// (see `regex::approx`), e.g. to pass to `Grammar::tokenize`.
+///
+/// This can include rules which only happen to be regular (e.g. one for
+/// `key = value` pairs, with both sides being lexical), which should be
+/// left out of the lexer, so the result is only a starting point.
+pub fn lexical_rules<S: AsRef<str> + Eq + Hash>(
+    cx: &Context<SPat<S>>,
+    grammar: &Grammar,
+) -> IndexSet<IStr> {
+    grammar
+        .rules()
+        .iter()
+        .filter(|(&name, rule)| {
+            rule.fields.names(cx).is_empty() && regex::approx(cx, grammar, name).unwrap().exact
+        })
+        .map(|(&name, _)| name)
+        .collect()
+}
+
+/// Check every rule of the token-level `grammar` marked `exhaustive` (see
+/// `rule::Modifiers`) against `universe`, i.e. the full set of tokens (e.g.
+/// the variants of a token enum), reporting the tokens it doesn't cover.
+///
+/// A rule covers the tokens it's an alternation of, where every case is a
+/// `Call` to either a token, or another (non-token) rule which is itself
+/// an alternation of tokens, so that e.g. `BinOp = AddOp | MulOp` covers
+/// all the tokens in both `AddOp` and `MulOp`. Any other cases (e.g.
+/// sequences starting with a token) don't cover any tokens.
+pub fn check_exhaustive<Pat: fmt::Debug>(
+    cx: &Context<Pat>,
+    grammar: &Grammar,
+    universe: &IndexSet<IStr>,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    for (&name, rule) in grammar.rules() {
+        if !grammar.modifiers(name).exhaustive {
+            continue;
+        }
+        let mut covered = IndexSet::default();
+        let mut visited = IndexSet::default();
+        covered_tokens(cx, grammar, universe, rule.rule, &mut visited, &mut covered);
+        let missing: Vec<_> = universe
+            .iter()
+            .filter(|token| !covered.contains(*token))
+            .map(|&token| format!("`{}`", &cx[token]))
+            .collect();
+        if missing.is_empty() {
+            continue;
+        }
+        diagnostics.push(Diagnostic {
+            code: Code::NonExhaustive,
+            rule: cx[name].to_string(),
+            node: rule.rule.node_desc(cx),
+            message: format!(
+                "{} of {} tokens not covered: {}",
+                missing.len(),
+                universe.len(),
+                missing.join(", ")
+            ),
+            help: Some(
+                "add the missing tokens as cases, or remove the `exhaustive` modifier".to_string(),
+            ),
+            message_id: "non-exhaustive",
+            args: vec![
+                ("missing", missing.join(", ")),
+                ("missing_count", missing.len().to_string()),
+                ("total", universe.len().to_string()),
+            ],
+        });
+    }
+    diagnostics
+}
+
+fn covered_tokens<Pat>(
+    cx: &Context<Pat>,
+    grammar: &Grammar,
+    universe: &IndexSet<IStr>,
+    rule: IRule,
+    visited: &mut IndexSet<IStr>,
+    covered: &mut IndexSet<IStr>,
+) {
+    match cx[rule] {
+        Rule::Call(name) if universe.contains(&name) => {
+            covered.insert(name);
+        }
+        // NOTE `visited` also guards against cycles, which
+        // can't cover anything not already covered elsewhere.
+        Rule::Call(name) if visited.insert(name) => {
+            if let Some(callee) = grammar.rules().get(&name) {
+                covered_tokens(cx, grammar, universe, callee.rule, visited, covered);
+            }
+        }
+        Rule::Or(ref cases) => {
+            for &case in cases {
+                covered_tokens(cx, grammar, universe, case, visited, covered);
+            }
+        }
+        Rule::Silent(rule) => covered_tokens(cx, grammar, universe, rule, visited, covered),
+        _ => {}
+    }
+}
+
+/// A scannerless grammar split into a lexer and a token-level grammar (see
+/// `Grammar::tokenize`), along with the places where lexing with maximal
+/// munch wouldn't agree with the original grammar (see `MunchConflict`).
+pub struct Tokenized {
+    /// The rules which aren't lexical, with all their terminals replaced
+    /// by tokens (see `Grammar::anonymize_terminals`), and `Call`s to the
+    /// lexical rules kept as-is, as tokens (named after those rules).
+    ///
+    /// There is no trivia, as the lexer skips it (see `Lexer::skip`).
+    pub grammar: Grammar,
+    pub lexer: Lexer,
+    pub conflicts: Vec<MunchConflict>,
+}
+
+/// The lexer for a token-level grammar, which repeatedly takes the longest
+/// match of any token (i.e. maximal munch), with ties going to the token
+/// listed first, i.e. the ones for terminals (e.g. keywords), which come
+/// before those for lexical rules (e.g. identifiers).
+pub struct Lexer {
+    /// Every token, with a regex matching it (see `regex::approx`).
+    pub tokens: Vec<(IStr, Regex)>,
+    /// A regex matching the trivia (if any), to skip between tokens.
+    pub skip: Option<Regex>,
+    /// The terminal tokens (see `TokenTable`).
+    pub terminals: TokenTable,
+    /// All the lexical rules (and the rules they call, even if those
+    /// aren't lexical), along with a rule for every terminal token,
+    /// e.g. for matching every token with an `Interpreter`.
+    pub grammar: Grammar,
+}
+
+/// Two tokens, `first` and `second`, which can be adjacent in the token-level
+/// grammar, but the original grammar allows matching them without anything
+/// (i.e. any trivia) between them, e.g. as `example`, which a lexer instead
+/// lexes as (starting with) the `longer` token, e.g. `>=` for `>` and `=`, or
+/// an identifier for a keyword followed by an identifier (in which case
+/// both grammars only agree if there's whitespace between the two tokens).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MunchConflict {
+    pub first: IStr,
+    pub second: IStr,
+    pub longer: IStr,
+    pub example: String,
+}
+
+/// How many terminals the sample inputs for every token (to check for
+/// `MunchConflict`s with) can have, and how many samples to use.
+const MAX_SAMPLE_LEN: usize = 3;
+const MAX_SAMPLES: usize = 8;
+
+pub(crate) fn tokenize<S: AsRef<str> + Eq + Hash>(
+    cx: &Context<SPat<S>>,
+    grammar: Grammar,
+    mut lexical: impl FnMut(IStr) -> bool,
+) -> Tokenized {
+    let lexical: IndexSet<_> = grammar
+        .rules()
+        .keys()
+        .copied()
+        .filter(|&name| lexical(name))
+        .collect();
+    let reachable = grammar.reachable(cx);
+    let trivia_calls = grammar.trivia().map(|trivia| trivia.rule.call_names(cx));
+    let lexer_rules: IndexSet<_> = lexical
+        .iter()
+        .copied()
+        .chain(trivia_calls.into_iter().flatten())
+        .flat_map(|name| reachable.get(&name).into_iter().flatten().copied())
+        .collect();
+    let skip = grammar
+        .trivia()
+        .map(|trivia| regex::approx_rule(cx, &grammar, trivia.rule));
+
+    let mut anonymizer = Anonymizer::new(cx, &grammar);
+    // NOTE the trivia is skipped by the lexer, instead of the token grammar.
+    let mut token_grammar = grammar.empty_like();
+    token_grammar.trivia = None;
+    let mut lexer_grammar = Grammar::new();
+    let mut called: IndexSet<_> = IndexSet::default();
+    for (&name, &rule) in grammar.rules() {
+        if lexer_rules.contains(&name) {
+            lexer_grammar.define(name, rule);
+        }
+        if !lexical.contains(&name) {
+            let rule = rule.fold(&mut anonymizer);
+            called.extend(rule.rule.call_names(cx));
+            token_grammar.define(name, rule);
+        }
+    }
+    token_grammar.forget_undefined();
+    let terminals = anonymizer.into_token_table();
+    for (name, terminal) in terminals.iter() {
+        lexer_grammar.define(
+            name,
+            RuleWithFields {
+                rule: terminal,
+                fields: cx.intern(Fields::Leaf(None)),
+            },
+        );
+    }
+    let tokens: IndexSet<_> = terminals
+        .iter()
+        .map(|(name, _)| name)
+        .chain(lexical.iter().copied().filter(|name| called.contains(name)))
+        .collect();
+
+    let lexer = Lexer {
+        tokens: tokens
+            .iter()
+            .map(|&name| (name, regex::approx(cx, &lexer_grammar, name).unwrap()))
+            .collect(),
+        skip,
+        terminals,
+        grammar: lexer_grammar,
+    };
+    let conflicts = munch_conflicts(cx, &token_grammar, &lexer, &tokens);
+    Tokenized {
+        grammar: token_grammar,
+        lexer,
+        conflicts,
+    }
+}
+
+/// Check every pair of tokens which can be adjacent in `grammar` (see
+/// `MunchConflict`), by lexing concatenations of (short) samples of both.
+///
+/// FIXME this is only as good as the samples (which are exhaustive
+/// for terminals, but not for lexical rules), an exact check would need to
+/// intersect the regular languages of the tokens instead.
+fn munch_conflicts<S: AsRef<str> + Eq + Hash>(
+    cx: &Context<SPat<S>>,
+    grammar: &Grammar,
+    lexer: &Lexer,
+    tokens: &IndexSet<IStr>,
+) -> Vec<MunchConflict> {
+    let interpreter = Interpreter::new(cx, &lexer.grammar);
+    // The token (and length) the lexer would take at the start of `text`.
+    let longest = |text: &str| {
+        let mut longest: Option<(IStr, usize)> = None;
+        for &token in tokens {
+            if let Ok(Some(len)) = interpreter.parse_prefix(token, text, 0) {
+                if longest.is_none_or(|(_, longest_len)| len > longest_len) {
+                    longest = Some((token, len));
+                }
+            }
+        }
+        longest
+    };
+    let sentences = enumerate::sentences(cx, &lexer.grammar, MAX_SAMPLE_LEN);
+    let samples: IndexMap<_, Vec<_>> = tokens
+        .iter()
+        .map(|&token| {
+            let texts = sentences[&token]
+                .iter()
+                .filter_map(|sentence| {
+                    sentence
+                        .iter()
+                        .map(|&terminal| match cx[terminal] {
+                            Rule::Eat(SPat::String(ref s)) => Some(s.as_ref().to_string()),
+                            Rule::Eat(SPat::Range(c, _)) => Some(c.to_string()),
+                            _ => None,
+                        })
+                        .collect::<Option<String>>()
+                })
+                // Only samples lexed back as the same token are useful.
+                .filter(|text| longest(text) == Some((token, text.len())))
+                .take(MAX_SAMPLES)
+                .collect();
+            (token, texts)
+        })
+        .collect();
+
+    let mut conflicts = vec![];
+    for (first, second) in adjacent_tokens(cx, grammar, tokens) {
+        let examples = samples[&first]
+            .iter()
+            .flat_map(|a| samples[&second].iter().map(move |b| (a, b)));
+        for (a, b) in examples {
+            let example = format!("{}{}", a, b);
+            match longest(&example) {
+                Some((longer, len)) if len > a.len() => {
+                    conflicts.push(MunchConflict {
+                        first,
+                        second,
+                        longer,
+                        example,
+                    });
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    conflicts
+}
+
+/// The tokens a rule can start and end with, and whether it can be empty.
+#[derive(Clone, Default, PartialEq, Eq)]
+struct Edges {
+    first: IndexSet<IStr>,
+    last: IndexSet<IStr>,
+    nullable: bool,
+}
+
+impl Edges {
+    fn nullable() -> Self {
+        Edges {
+            nullable: true,
+            ..Edges::default()
+        }
+    }
+
+    fn token(token: IStr) -> Self {
+        Edges {
+            first: [token].into_iter().collect(),
+            last: [token].into_iter().collect(),
+            nullable: false,
+        }
+    }
+
+    fn then(self, next: Edges) -> Self {
+        let mut first = self.first;
+        if self.nullable {
+            first.extend(next.first);
+        }
+        let mut last = next.last;
+        if next.nullable {
+            last.extend(self.last);
+        }
+        Edges {
+            first,
+            last,
+            nullable: self.nullable && next.nullable,
+        }
+    }
+
+    fn or(mut self, other: Edges) -> Self {
+        self.first.extend(other.first);
+        self.last.extend(other.last);
+        self.nullable |= other.nullable;
+        self
+    }
+}
+
+struct Adjacency<'a, Pat> {
+    cx: &'a Context<Pat>,
+    tokens: &'a IndexSet<IStr>,
+    rules: IndexMap<IStr, Edges>,
+}
+
+impl<Pat> Adjacency<'_, Pat> {
+    fn edges(&self, rule: IRule) -> Edges {
+        match self.cx[rule] {
+            Rule::Empty
+            | Rule::Anchor(_)
+            | Rule::FlagGuard(..)
+            | Rule::Guard(_)
+            | Rule::Feature(..) => Edges::nullable(),
+            // NOTE terminals were all replaced with tokens, and
+            // `Call`s to undefined rules never match (like backrefs here).
+            Rule::Eat(_) | Rule::EatUntil(_) | Rule::Backref(_) => Edges::default(),
+            Rule::Call(name) if self.tokens.contains(&name) => Edges::token(name),
+            Rule::Call(name) => self.rules.get(&name).cloned().unwrap_or_default(),
+            Rule::WithFlag(_, _, rule) | Rule::Silent(rule) => self.edges(rule),
+            Rule::Opt(rule) => self.edges(rule).or(Edges::nullable()),
+            Rule::Capture(_, delim, body) => self.edges(delim).then(self.edges(body)),
+            Rule::Concat(ref elems) => elems.iter().fold(Edges::nullable(), |edges, &elem| {
+                edges.then(self.edges(elem))
+            }),
+            Rule::Or(ref cases) => cases
+                .iter()
+                .fold(Edges::default(), |edges, &case| edges.or(self.edges(case))),
+            Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => {
+                let elem = self.edges(elem);
+                let mut edges = match sep {
+                    Some((sep, _)) => elem.clone().then(self.edges(sep)).then(elem),
+                    None => elem.clone().then(elem),
+                };
+                if let Some((sep, kind)) = sep {
+                    let opt_sep = self.edges(sep).or(Edges::nullable());
+                    if kind.allows_leading() {
+                        edges = opt_sep.clone().then(edges);
+                    }
+                    if kind.allows_trailing() {
+                        edges = edges.then(opt_sep);
+                    }
+                }
+                match self.cx[rule] {
+                    Rule::RepeatMany(..) => edges.or(Edges::nullable()),
+                    _ => edges,
+                }
+            }
+        }
+    }
+
+    /// Add all the pairs of tokens which can be adjacent in `rule` to `pairs`.
+    fn pairs(&self, rule: IRule, pairs: &mut IndexSet<(IStr, IStr)>) {
+        let mut add = |a: &Edges, b: &Edges| {
+            pairs.extend(
+                a.last
+                    .iter()
+                    .flat_map(|&x| b.first.iter().map(move |&y| (x, y))),
+            );
+        };
+        let seq = |elems: &[IRule], add: &mut dyn FnMut(&Edges, &Edges)| {
+            let edges: Vec<_> = elems.iter().map(|&elem| self.edges(elem)).collect();
+            for (i, a) in edges.iter().enumerate() {
+                for b in &edges[i + 1..] {
+                    add(a, b);
+                    if !b.nullable {
+                        break;
+                    }
+                }
+            }
+        };
+        match self.cx[rule] {
+            Rule::Concat(ref elems) => seq(elems, &mut add),
+            Rule::Capture(_, delim, body) => seq(&[delim, body], &mut add),
+            Rule::RepeatMany(elem, sep) | Rule::RepeatMore(elem, sep) => match sep {
+                Some((sep, _)) => seq(&[elem, sep, elem], &mut add),
+                None => seq(&[elem, elem], &mut add),
+            },
+            _ => {}
+        }
+        for child in rule.children(self.cx) {
+            self.pairs(child, pairs);
+        }
+    }
+}
+
+/// All the pairs of `tokens` which can be adjacent in `grammar`.
+fn adjacent_tokens<Pat>(
+    cx: &Context<Pat>,
+    grammar: &Grammar,
+    tokens: &IndexSet<IStr>,
+) -> IndexSet<(IStr, IStr)> {
+    let mut adjacency = Adjacency {
+        cx,
+        tokens,
+        rules: IndexMap::default(),
+    };
+    // NOTE this is a fixpoint, as the edges of rules only ever grow.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (&name, rule) in grammar.rules() {
+            let edges = adjacency.edges(rule.rule);
+            if adjacency.rules.get(&name) != Some(&edges) {
+                adjacency.rules.insert(name, edges);
+                changed = true;
+            }
+        }
+    }
+    let mut pairs = IndexSet::default();
+    for rule in grammar.rules().values() {
+        adjacency.pairs(rule.rule, &mut pairs);
+    }
+    pairs
+}
+
+/// The name of a token for `pat`, in `SCREAMING_SNAKE_CASE` (other than
+/// for characters in ranges, which are kept as-is), e.g. `WHILE` for
+/// `"while"`, `EQ_EQ` for `"=="`, or `DIGIT` for `'0'..='9'`.
+fn token_name<S: AsRef<str>>(pat: &SPat<S>) -> String {
+    match pat {
+        SPat::String(s) => {
+            let s = s.as_ref();
+            let mut words = vec![];
+            let mut word = String::new();
+            for c in s.chars() {
+                if c.is_alphanumeric() || (c == '_' && !word.is_empty()) {
+                    word.extend(c.to_uppercase());
+                    continue;
+                }
+                if !word.is_empty() {
+                    words.push(core::mem::take(&mut word));
+                }
+                words.push(char_name(c));
+            }
+            if !word.is_empty() {
+                words.push(word);
+            }
+            if words.is_empty() {
+                "EMPTY".to_string()
+            } else {
+                words.join("_")
+            }
+        }
+        SPat::Range('0', '9') => "DIGIT".to_string(),
+        SPat::Range('a', 'z') => "LOWER".to_string(),
+        SPat::Range('A', 'Z') => "UPPER".to_string(),
+        SPat::Range('\0', char::MAX) => "ANY_CHAR".to_string(),
+        &SPat::Range(start, end) => {
+            let name = |c: char| {
+                if c.is_alphanumeric() {
+                    c.to_string()
+                } else {
+                    char_name(c)
+                }
+            };
+            format!("RANGE_{}_{}", name(start), name(end))
+        }
+    }
+}
+
+fn char_name(c: char) -> String {
+    let name = match c {
+        '+' => "PLUS",
+        '-' => "MINUS",
+        '*' => "STAR",
+        '/' => "SLASH",
+        '%' => "PERCENT",
+        '^' => "CARET",
+        '!' => "BANG",
+        '&' => "AMP",
+        '|' => "PIPE",
+        '=' => "EQ",
+        '<' => "LT",
+        '>' => "GT",
+        '@' => "AT",
+        '.' => "DOT",
+        ',' => "COMMA",
+        ';' => "SEMI",
+        ':' => "COLON",
+        '#' => "POUND",
+        '$' => "DOLLAR",
+        '?' => "QUESTION",
+        '~' => "TILDE",
+        '(' => "LPAREN",
+        ')' => "RPAREN",
+        '[' => "LBRACKET",
+        ']' => "RBRACKET",
+        '{' => "LBRACE",
+        '}' => "RBRACE",
+        '\'' => "QUOTE",
+        '"' => "DQUOTE",
+        '`' => "BACKTICK",
+        '\\' => "BACKSLASH",
+        '_' => "UNDERSCORE",
+        ' ' => "SPACE",
+        '\t' => "TAB",
+        '\n' => "NEWLINE",
+        '\r' => "CR",
+        _ => return format!("U{:04X}", c as u32),
+    };
+    name.to_string()
+}
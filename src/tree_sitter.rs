@@ -0,0 +1,512 @@
+//! Interoperability with tree-sitter grammars, in their `grammar.json` form.
+
+use crate::context::{Context, IStr};
+use crate::diagnostics::{Args, Localize};
+use crate::rule::{any_of, call, eat, empty, seq, Fields, Rule, RuleWithFields, SepKind};
+use crate::scannerless;
+use crate::Grammar;
+use indexmap::IndexMap;
+use serde_json::{json, Value};
+use std::fmt;
+use std::hash::Hash;
+
+#[derive(Debug)]
+pub enum ImportError {
+    Json(serde_json::Error),
+    /// Valid JSON, but `node` is missing its `field`, or it's not the
+    /// `expected` kind of value (e.g. `"array"`).
+    MissingField {
+        node: String,
+        field: String,
+        expected: &'static str,
+    },
+    /// A rule with a `type` tree-sitter doesn't have (e.g. a typo).
+    UnknownRuleType(String),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Json(err) => write!(f, "invalid JSON: {}", err),
+            ImportError::MissingField {
+                node,
+                field,
+                expected,
+            } => write!(
+                f,
+                "malformed tree-sitter grammar: missing `{}` {} in {}",
+                field, expected, node
+            ),
+            ImportError::UnknownRuleType(ty) => {
+                write!(
+                    f,
+                    "malformed tree-sitter grammar: unknown rule type `{}`",
+                    ty
+                )
+            }
+        }
+    }
+}
+
+impl Localize for ImportError {
+    /// One of `invalid-json` (with an `error` argument, from `serde_json`, in
+    /// English), `tree-sitter-missing-field` (`node`, `field` and `expected`,
+    /// i.e. `string`, `array`, `object` or `value`), and
+    /// `tree-sitter-unknown-rule-type` (`type`).
+    fn message_id(&self) -> &'static str {
+        match self {
+            ImportError::Json(_) => "invalid-json",
+            ImportError::MissingField { .. } => "tree-sitter-missing-field",
+            ImportError::UnknownRuleType(_) => "tree-sitter-unknown-rule-type",
+        }
+    }
+
+    fn args(&self) -> Args {
+        match self {
+            ImportError::Json(err) => vec![("error", err.to_string())],
+            ImportError::MissingField {
+                node,
+                field,
+                expected,
+            } => vec![
+                ("node", node.clone()),
+                ("field", field.clone()),
+                ("expected", expected.to_string()),
+            ],
+            ImportError::UnknownRuleType(ty) => vec![("type", ty.clone())],
+        }
+    }
+}
+
+impl From<serde_json::Error> for ImportError {
+    fn from(err: serde_json::Error) -> Self {
+        ImportError::Json(err)
+    }
+}
+
+/// The result of importing a tree-sitter grammar, which is only partially
+/// representable as a `Grammar`, with the rest recorded alongside it.
+///
+/// Precedences, conflicts, aliases and the distinction between tokens and
+/// other rules have no equivalent, and are ignored.
+pub struct Imported {
+    /// The `name` of the tree-sitter grammar.
+    pub name: String,
+    pub grammar: Grammar,
+    /// Rules implemented by external scanners, left undefined in `grammar`.
+    pub externals: Vec<IStr>,
+    /// Regexes (from `PATTERN`s) which can't be converted, each replaced by
+    /// a `Call` to a rule with the name it's associated with here, which is
+    /// left undefined in `grammar`.
+    pub patterns: IndexMap<IStr, String>,
+}
+
+/// Import a tree-sitter `grammar.json` (as generated by `tree-sitter generate`).
+///
+/// The rules keep their order, so the first one is still the start rule.
+pub fn import<Pat>(cx: &Context<Pat>, json: &str) -> Result<Imported, ImportError>
+where
+    Pat: Eq + Hash + for<'s> From<&'s str>,
+{
+    let json: Value = serde_json::from_str(json)?;
+    let mut importer = Importer {
+        cx,
+        patterns: IndexMap::new(),
+    };
+
+    let name = str_field(&json, "name")?.to_string();
+
+    let mut grammar = Grammar::new();
+    let rules = json
+        .get("rules")
+        .and_then(Value::as_object)
+        .ok_or_else(|| missing(&json, "rules", "object"))?;
+    for (name, rule) in rules {
+        grammar.define(cx.intern(&name[..]), importer.rule(rule)?);
+    }
+
+    let mut externals = vec![];
+    for external in array_field(&json, "externals").unwrap_or(&[]) {
+        // NOTE externals can also be `STRING`s, which only serve to
+        // allow the external scanner to produce them, so they're skipped.
+        if external.get("type").and_then(Value::as_str) == Some("SYMBOL") {
+            externals.push(cx.intern(str_field(external, "name")?));
+        }
+    }
+
+    // NOTE `extras` are what can appear between any two tokens
+    // (usually whitespace and comments), i.e. the grammar's trivia.
+    if let Ok(extras) = array_field(&json, "extras") {
+        if !extras.is_empty() {
+            let extras = extras
+                .iter()
+                .map(|extra| importer.rule(extra))
+                .collect::<Result<Vec<_>, _>>()?;
+            grammar.set_trivia(any_of(extras).finish(cx));
+        }
+    }
+
+    Ok(Imported {
+        name,
+        grammar,
+        externals,
+        patterns: importer.patterns,
+    })
+}
+
+fn missing(node: &Value, field: &str, expected: &'static str) -> ImportError {
+    ImportError::MissingField {
+        node: node.to_string(),
+        field: field.to_string(),
+        expected,
+    }
+}
+
+fn str_field<'a>(node: &'a Value, key: &str) -> Result<&'a str, ImportError> {
+    node.get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| missing(node, key, "string"))
+}
+
+fn array_field<'a>(node: &'a Value, key: &str) -> Result<&'a [Value], ImportError> {
+    node.get(key)
+        .and_then(Value::as_array)
+        .map(|array| &array[..])
+        .ok_or_else(|| missing(node, key, "array"))
+}
+
+fn content(node: &Value) -> Result<&Value, ImportError> {
+    node.get("content")
+        .ok_or_else(|| missing(node, "content", "value"))
+}
+
+struct Importer<'cx, Pat> {
+    cx: &'cx Context<Pat>,
+    patterns: IndexMap<IStr, String>,
+}
+
+impl<Pat> Importer<'_, Pat>
+where
+    Pat: Eq + Hash + for<'s> From<&'s str>,
+{
+    fn rule(&mut self, node: &Value) -> Result<RuleWithFields, ImportError> {
+        let cx = self.cx;
+        Ok(match str_field(node, "type")? {
+            "BLANK" => empty().finish(cx),
+            "STRING" => eat(str_field(node, "value")?).finish(cx),
+            "PATTERN" => {
+                let regex = str_field(node, "value")?;
+                let name = match self.patterns.iter().find(|(_, r)| *r == regex) {
+                    Some((&name, _)) => name,
+                    None => {
+                        let name = cx.intern(&format!("PATTERN#{}", self.patterns.len())[..]);
+                        self.patterns.insert(name, regex.to_string());
+                        name
+                    }
+                };
+                call(&cx[name]).finish(cx)
+            }
+            "SYMBOL" => call(str_field(node, "name")?).finish(cx),
+            "SEQ" => {
+                let elems = array_field(node, "members")?
+                    .iter()
+                    .map(|elem| self.rule(elem))
+                    .collect::<Result<Vec<_>, _>>()?;
+                seq(elems).finish(cx)
+            }
+            "CHOICE" => {
+                // NOTE tree-sitter's `optional(x)` is `choice(x, blank())`.
+                let members = array_field(node, "members")?;
+                let is_blank =
+                    |member: &Value| member.get("type").and_then(Value::as_str) == Some("BLANK");
+                let cases = members
+                    .iter()
+                    .filter(|member| !is_blank(member))
+                    .map(|case| self.rule(case))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if cases.is_empty() {
+                    empty().finish(cx)
+                } else if members.iter().any(is_blank) {
+                    any_of(cases).opt().finish(cx)
+                } else {
+                    any_of(cases).finish(cx)
+                }
+            }
+            "REPEAT" => self.rule(content(node)?)?.repeat_many().finish(cx),
+            "REPEAT1" => self.rule(content(node)?)?.repeat_more().finish(cx),
+            "FIELD" => {
+                let name = str_field(node, "name")?;
+                self.rule(content(node)?)?.field(name).finish(cx)
+            }
+            "PREC" | "PREC_LEFT" | "PREC_RIGHT" | "PREC_DYNAMIC" | "ALIAS" | "TOKEN"
+            | "IMMEDIATE_TOKEN" | "RESERVED" => self.rule(content(node)?)?,
+            ty => return Err(ImportError::UnknownRuleType(ty.to_string())),
+        })
+    }
+}
+
+/// Patterns which can be exported as tree-sitter terminals.
+pub trait TreeSitterPat {
+    /// The tree-sitter rule (as in `grammar.json`) matching the same input.
+    fn to_tree_sitter(&self) -> Value;
+}
+
+impl<S: AsRef<str>> TreeSitterPat for scannerless::Pat<S> {
+    fn to_tree_sitter(&self) -> Value {
+        match self {
+            scannerless::Pat::String(s) => json!({ "type": "STRING", "value": s.as_ref() }),
+            &scannerless::Pat::Range(start, end) => {
+                let escape = |c: char| match c {
+                    '\\' | ']' | '[' | '^' | '-' => format!("\\{}", c),
+                    _ if c.is_control() || c.is_whitespace() => format!("\\u{{{:x}}}", c as u32),
+                    _ => c.to_string(),
+                };
+                json!({
+                    "type": "PATTERN",
+                    "value": format!("[{}-{}]", escape(start), escape(end)),
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ExportError {
+    /// The name of the rule containing the unsupported part.
+    pub rule: String,
+    pub unsupported: String,
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` (in rule `{}`) has no tree-sitter equivalent",
+            self.unsupported, self.rule
+        )
+    }
+}
+
+/// Export `grammar` as a tree-sitter `grammar.json`, named `name`.
+///
+/// The grammar's trivia (see `Grammar::set_trivia`) become the `extras`
+/// (one per case, if it's an `Or`), which are otherwise empty, and
+/// zero-width rules (`Anchor`s, `Guard`s, etc.) can't be exported at all
+/// (so flags and features should be resolved beforehand).
+///
+/// FIXME rule names aren't sanitized, e.g. `Expr<+NoStruct>`.
+pub fn export<Pat>(cx: &Context<Pat>, grammar: &Grammar, name: &str) -> Result<Value, ExportError>
+where
+    Pat: Eq + Hash + fmt::Debug + TreeSitterPat,
+{
+    let mut rules = serde_json::Map::new();
+    for (&rule_name, &rule) in grammar.rules() {
+        let rule = export_rule(cx, rule).map_err(|unsupported| ExportError {
+            rule: cx[rule_name].to_string(),
+            unsupported,
+        })?;
+        rules.insert(cx[rule_name].to_string(), rule);
+    }
+    let mut extras = vec![];
+    if let Some(trivia) = grammar.trivia() {
+        let trivia_error = |unsupported| ExportError {
+            rule: "<trivia>".to_string(),
+            unsupported,
+        };
+        match cx[trivia.rule] {
+            Rule::Or(ref cases) => {
+                for (i, &case) in cases.iter().enumerate() {
+                    let case = RuleWithFields {
+                        rule: case,
+                        fields: trivia.fields.child(cx, i),
+                    };
+                    extras.push(export_rule(cx, case).map_err(trivia_error)?);
+                }
+            }
+            _ => extras.push(export_rule(cx, trivia).map_err(trivia_error)?),
+        }
+    }
+    Ok(json!({
+        "name": name,
+        "extras": extras,
+        "rules": rules,
+    }))
+}
+
+/// Like `export`, but producing a tree-sitter `grammar.js` instead, using the
+/// usual `sep1` helper function for separated repetitions.
+pub fn export_js<Pat>(
+    cx: &Context<Pat>,
+    grammar: &Grammar,
+    name: &str,
+) -> Result<String, ExportError>
+where
+    Pat: Eq + Hash + fmt::Debug + TreeSitterPat,
+{
+    let json = export(cx, grammar, name)?;
+
+    let mut js = String::new();
+    js += "module.exports = grammar({\n";
+    js += &format!("  name: {},\n", Value::from(name));
+    let extras: Vec<_> = json["extras"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(to_js)
+        .collect();
+    js += &format!("  extras: $ => [{}],\n", extras.join(", "));
+    js += "  rules: {\n";
+    for (rule_name, rule) in json["rules"].as_object().unwrap() {
+        js += &format!("    {}: $ => {},\n", js_key(rule_name), to_js(rule));
+    }
+    js += "  },\n";
+    js += "});\n";
+    js += "\n";
+    js += "function sep1(rule, separator) {\n";
+    js += "  return seq(rule, repeat(seq(separator, rule)));\n";
+    js += "}\n";
+    Ok(js)
+}
+
+fn export_rule<Pat>(cx: &Context<Pat>, rule: RuleWithFields) -> Result<Value, String>
+where
+    Pat: Eq + Hash + fmt::Debug + TreeSitterPat,
+{
+    if let Fields::Leaf(Some(field)) = cx[rule.fields] {
+        let content = export_rule(
+            cx,
+            RuleWithFields {
+                rule: rule.rule,
+                fields: field.sub,
+            },
+        )?;
+        return Ok(json!({ "type": "FIELD", "name": &cx[field.name], "content": content }));
+    }
+    let child = |child, i| {
+        export_rule(
+            cx,
+            RuleWithFields {
+                rule: child,
+                fields: rule.fields.child(cx, i),
+            },
+        )
+    };
+    let sep1 = |elem: Value, sep: Value| {
+        json!({ "type": "SEQ", "members": [
+            elem.clone(),
+            { "type": "REPEAT", "content": { "type": "SEQ", "members": [sep, elem] } },
+        ] })
+    };
+    let repeat_more = |elem, sep: Option<(_, SepKind)>| -> Result<_, String> {
+        let elem = child(elem, 0)?;
+        Ok(match sep {
+            None => json!({ "type": "REPEAT1", "content": elem }),
+            Some((sep, SepKind::Simple)) => sep1(elem, child(sep, 1)?),
+            Some((sep, kind)) => {
+                let sep = child(sep, 1)?;
+                let opt_sep =
+                    json!({ "type": "CHOICE", "members": [sep.clone(), { "type": "BLANK" }] });
+                let mut members = vec![];
+                if kind.allows_leading() {
+                    members.push(opt_sep.clone());
+                }
+                members.push(sep1(elem, sep));
+                if kind.allows_trailing() {
+                    members.push(opt_sep);
+                }
+                json!({ "type": "SEQ", "members": members })
+            }
+        })
+    };
+    Ok(match cx[rule.rule] {
+        Rule::Empty => json!({ "type": "BLANK" }),
+        Rule::Eat(ref pat) => pat.to_tree_sitter(),
+        Rule::Call(name) => json!({ "type": "SYMBOL", "name": &cx[name] }),
+        Rule::Silent(rule) => child(rule, 0)?,
+        Rule::Anchor(_)
+        | Rule::EatUntil(_)
+        | Rule::Capture(..)
+        | Rule::Backref(_)
+        | Rule::FlagGuard(..)
+        | Rule::WithFlag(..)
+        | Rule::Guard(_)
+        | Rule::Feature(..) => return Err(rule.rule.node_desc(cx)),
+        Rule::Concat(ref elems) => json!({
+            "type": "SEQ",
+            "members": elems
+                .iter()
+                .enumerate()
+                .map(|(i, &elem)| child(elem, i))
+                .collect::<Result<Vec<_>, _>>()?,
+        }),
+        Rule::Or(ref cases) => json!({
+            "type": "CHOICE",
+            "members": cases
+                .iter()
+                .enumerate()
+                .map(|(i, &case)| child(case, i))
+                .collect::<Result<Vec<_>, _>>()?,
+        }),
+        Rule::Opt(rule) => {
+            json!({ "type": "CHOICE", "members": [child(rule, 0)?, { "type": "BLANK" }] })
+        }
+        Rule::RepeatMany(elem, None) => json!({ "type": "REPEAT", "content": child(elem, 0)? }),
+        Rule::RepeatMany(elem, sep) => json!({
+            "type": "CHOICE",
+            "members": [repeat_more(elem, sep)?, { "type": "BLANK" }],
+        }),
+        Rule::RepeatMore(elem, sep) => repeat_more(elem, sep)?,
+    })
+}
+
+fn js_key(name: &str) -> String {
+    let is_ident = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if is_ident {
+        name.to_string()
+    } else {
+        Value::from(name).to_string()
+    }
+}
+
+fn to_js(node: &Value) -> String {
+    let list = |nodes: &[Value]| nodes.iter().map(to_js).collect::<Vec<_>>().join(", ");
+    let members = node["members"].as_array().map_or(&[][..], |m| &m[..]);
+    match node["type"].as_str().unwrap() {
+        "BLANK" => "blank()".to_string(),
+        "STRING" => node["value"].to_string(),
+        "PATTERN" => format!("/{}/", node["value"].as_str().unwrap().replace('/', "\\/")),
+        "SYMBOL" => {
+            let name = node["name"].as_str().unwrap();
+            if js_key(name) == name {
+                format!("$.{}", name)
+            } else {
+                format!("$[{}]", js_key(name))
+            }
+        }
+        "FIELD" => format!("field({}, {})", node["name"], to_js(&node["content"])),
+        "REPEAT" => format!("repeat({})", to_js(&node["content"])),
+        "REPEAT1" => format!("repeat1({})", to_js(&node["content"])),
+        "SEQ" => match members {
+            // `sep1(elem, sep)` (see `export_rule`).
+            [elem, repeat]
+                if repeat["type"] == "REPEAT"
+                    && repeat["content"]["type"] == "SEQ"
+                    && repeat["content"]["members"].as_array().map(|m| m.len()) == Some(2)
+                    && repeat["content"]["members"][1] == *elem =>
+            {
+                format!(
+                    "sep1({}, {})",
+                    to_js(elem),
+                    to_js(&repeat["content"]["members"][0])
+                )
+            }
+            _ => format!("seq({})", list(members)),
+        },
+        "CHOICE" => match members {
+            [rule, blank] if blank["type"] == "BLANK" => format!("optional({})", to_js(rule)),
+            _ => format!("choice({})", list(members)),
+        },
+        ty => unreachable!("unexpected rule type `{}`", ty),
+    }
+}
@@ -0,0 +1,145 @@
+//! Multiple versions of the same grammar, and how they differ.
+
+use crate::compat::prelude::*;
+use crate::compat::IndexMap;
+use crate::context::{Context, IStr};
+use crate::enumerate::{self, Sentence};
+use crate::Grammar;
+use core::fmt;
+use core::hash::Hash;
+
+/// Snapshots of a grammar, labeled by version (in the order added).
+#[derive(Default)]
+pub struct Versions {
+    grammars: IndexMap<String, Grammar>,
+}
+
+impl Versions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, version: &str, grammar: Grammar) {
+        let old = self.grammars.insert(version.to_string(), grammar);
+        assert!(old.is_none(), "version `{}` already exists", version);
+    }
+
+    pub fn get(&self, version: &str) -> Option<&Grammar> {
+        self.grammars.get(version)
+    }
+
+    pub fn versions(&self) -> impl Iterator<Item = &str> {
+        self.grammars.keys().map(|version| &version[..])
+    }
+
+    /// Compare versions `from` and `to` (see `migration_report`).
+    pub fn migration_report<Pat: Eq + Hash>(
+        &self,
+        cx: &Context<Pat>,
+        from: &str,
+        to: &str,
+        max_len: usize,
+    ) -> MigrationReport {
+        let get = |version| {
+            self.get(version)
+                .unwrap_or_else(|| panic!("no version `{}`", version))
+        };
+        migration_report(cx, get(from), get(to), max_len)
+    }
+}
+
+/// Compare the `old` and `new` versions of a grammar, also enumerating the
+/// sentences (of at most `max_len` terminals) of every rule present in both,
+/// to find which ones `new` no longer accepts.
+pub fn migration_report<Pat: Eq + Hash>(
+    cx: &Context<Pat>,
+    old: &Grammar,
+    new: &Grammar,
+    max_len: usize,
+) -> MigrationReport {
+    let mut report = MigrationReport {
+        added: new
+            .rules()
+            .keys()
+            .filter(|&name| !old.rules().contains_key(name))
+            .copied()
+            .collect(),
+        removed: old
+            .rules()
+            .keys()
+            .filter(|&name| !new.rules().contains_key(name))
+            .copied()
+            .collect(),
+        changed: vec![],
+        invalidated: IndexMap::default(),
+    };
+
+    let old_sentences = enumerate::sentences(cx, old, max_len);
+    let new_sentences = enumerate::sentences(cx, new, max_len);
+    for (name, old_rule) in old.rules() {
+        let new_rule = match new.rules().get(name) {
+            Some(rule) => rule,
+            None => continue,
+        };
+        // NOTE interning makes this a structural comparison.
+        if (old_rule.rule, old_rule.fields) != (new_rule.rule, new_rule.fields) {
+            report.changed.push(*name);
+        }
+        let invalidated: Vec<_> = old_sentences[name]
+            .iter()
+            .filter(|sentence| !new_sentences[name].contains(*sentence))
+            .cloned()
+            .collect();
+        if !invalidated.is_empty() {
+            report.invalidated.insert(*name, invalidated);
+        }
+    }
+    report
+}
+
+/// The differences between two versions of a grammar (see `Versions`).
+#[derive(Clone, Debug, Default)]
+pub struct MigrationReport {
+    pub added: Vec<IStr>,
+    pub removed: Vec<IStr>,
+    /// Rules present in both versions, but defined differently (note that
+    /// they may still accept the same sentences, and the rules calling them
+    /// aren't considered changed, even if they accept different sentences).
+    pub changed: Vec<IStr>,
+    /// Sentences accepted by a rule only in the older version.
+    pub invalidated: IndexMap<IStr, Vec<Sentence>>,
+}
+
+impl MigrationReport {
+    /// Whether there are no differences (e.g. the same grammar was reloaded).
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.invalidated.is_empty()
+    }
+
+    pub fn desc<Pat>(&self, cx: &Context<Pat>) -> String
+    where
+        Pat: fmt::Debug,
+    {
+        let mut desc = String::new();
+        for (kind, names) in [
+            ("added", &self.added),
+            ("removed", &self.removed),
+            ("changed", &self.changed),
+        ] {
+            for &name in names {
+                desc += &format!("{} rule `{}`\n", kind, &cx[name]);
+            }
+        }
+        for (&name, sentences) in &self.invalidated {
+            desc += &format!("`{}` no longer accepts:\n", &cx[name]);
+            for sentence in sentences {
+                let terminals: Vec<_> = sentence.iter().map(|t| t.node_desc(cx)).collect();
+                desc += &format!("    {}\n", terminals.join(" "));
+            }
+        }
+        desc
+    }
+}
@@ -0,0 +1,74 @@
+//! Checks that exports and generated code are byte-for-byte identical
+//! when the same grammar is built twice (see "Determinism" in the crate
+//! docs), as every `HashMap`/`HashSet` gets a different random seed.
+
+#![cfg(feature = "std")]
+
+use grammer::build::{self, CodegenOptions};
+use grammer::{ebnf, pest, scannerless};
+
+const GRAMMAR: &str = r#"
+WHITESPACE = _{ " " | "\t" | "\n" }
+COMMENT = _{ "//" ~ ('a'..'z' | " ")* ~ "\n" }
+
+file = { item* }
+item = { fn_def | struct_def | use_decl }
+fn_def = { "fn" ~ ident ~ "(" ~ params? ~ ")" ~ block }
+params = { ident ~ ("," ~ ident)* ~ ","? }
+struct_def = { "struct" ~ ident ~ "{" ~ (field ~ ",")* ~ "}" }
+field = { ident ~ ":" ~ ident }
+use_decl = { "use" ~ path ~ ";" }
+path = { ident ~ ("::" ~ ident)* }
+block = { "{" ~ stmt* ~ "}" }
+stmt = { expr ~ ";" }
+expr = { term ~ (op ~ term)* }
+term = { number | string | path | "(" ~ expr ~ ")" }
+op = { "+" | "-" | "*" | "/" }
+number = @{ ASCII_DIGIT+ }
+string = ${ "\"" ~ inner ~ "\"" }
+inner = @{ (ASCII_ALPHANUMERIC | " ")* }
+ident = @{ (ASCII_ALPHA | "_") ~ (ASCII_ALPHANUMERIC | "_")* }
+"#;
+
+/// Build `GRAMMAR` in a fresh `Context`, and produce everything it can be
+/// turned into.
+fn outputs() -> Vec<(&'static str, String)> {
+    let cx = scannerless::Context::<String>::new();
+    let grammar = pest::import(&cx, GRAMMAR).unwrap().grammar;
+    vec![
+        ("pest", pest::export(&cx, &grammar).unwrap()),
+        ("ebnf", ebnf::export(&cx, &grammar).unwrap()),
+        (
+            "build::generate",
+            build::generate(&cx, &grammar, &CodegenOptions::default()),
+        ),
+        (
+            "build::generate (ast)",
+            build::generate(
+                &cx,
+                &grammar,
+                &CodegenOptions {
+                    ast: true,
+                    ..CodegenOptions::default()
+                },
+            ),
+        ),
+        #[cfg(feature = "tree-sitter")]
+        (
+            "tree-sitter",
+            grammer::tree_sitter::export(&cx, &grammar, "determinism")
+                .unwrap()
+                .to_string(),
+        ),
+    ]
+}
+
+#[test]
+fn outputs_are_deterministic() {
+    let first = outputs();
+    for _ in 0..4 {
+        for ((name, a), (_, b)) in first.iter().zip(outputs()) {
+            assert!(*a == b, "{} output differs between builds", name);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! Runs the grammars in `tests/corpus` through every format (see
+//! `roundtrip::assert_corpus`), to catch converters silently changing
+//! the languages they accept.
+
+#![cfg(feature = "std")]
+
+use grammer::roundtrip::{self, Format};
+
+// NOTE sentences are enumerated up to this many terminals, which is enough
+// to go through every rule of the corpus grammars at least once.
+const MAX_LEN: usize = 6;
+
+#[test]
+fn pest_corpus() {
+    roundtrip::assert_corpus(
+        &[
+            (Format::Pest, include_str!("corpus/arithmetic.pest")),
+            (Format::Pest, include_str!("corpus/empty.pest")),
+            (Format::Pest, include_str!("corpus/json.pest")),
+            (Format::Pest, include_str!("corpus/lists.pest")),
+        ],
+        MAX_LEN,
+    );
+}
+
+#[test]
+fn ebnf_corpus() {
+    roundtrip::assert_corpus(
+        &[(Format::Ebnf, include_str!("corpus/classes.ebnf"))],
+        MAX_LEN,
+    );
+}
+
+#[cfg(feature = "tree-sitter")]
+#[test]
+fn tree_sitter_corpus() {
+    roundtrip::assert_corpus(
+        &[(Format::TreeSitter, include_str!("corpus/statements.json"))],
+        MAX_LEN,
+    );
+}